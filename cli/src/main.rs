@@ -0,0 +1,589 @@
+use {
+    riscv::{
+        cache::{Cache, CacheConfig, Replacement, WritePolicy},
+        coverage::Coverage,
+        dtb, elf,
+        framebuffer::{Framebuffer, PixelFormat},
+        load_word,
+        predictor::{Predictor, PredictorConfig, Scheme},
+        profile::Profiler,
+        replay::Replay,
+        timing::{Latencies, Timing},
+        virtio::{Disk, DiskMode, VirtioBlock, SECTOR_SIZE},
+        Engine, Error, Machine, MEMORY_SIZE, MEMORY_START, PAGE_SIZE,
+    },
+    std::{
+        fs::OpenOptions,
+        io::{Read, Seek, SeekFrom, Write},
+        path::PathBuf,
+        process::ExitCode,
+        sync::mpsc::{self, Receiver},
+        time::Instant,
+    },
+};
+
+/// The number of instructions between exchanges of the UART's input and output with the console.
+const SLICE: u64 = 100_000;
+
+const USAGE: &str = "\
+Usage: cli [OPTIONS] <ELF>
+
+Runs an RV32I program until it signals its end (like the riscv-tests do) or faults. The UART at
+0x10000000 is connected to stdin and stdout.
+
+Options:
+  --engine <ENGINE>   interpreter or blocks [default: blocks]
+  --limit <N>         stop after N instructions
+  --memory <SIZE>     the size of RAM at 0x80000000 in bytes, a multiple of 4096, optionally
+                      with a K or M suffix for KiB or MiB [default: 64K]
+  --stats             print the number of instructions and the speed
+  --timing            estimate the cycles of a 5-stage in-order core (with --stats)
+  --icache <CACHE>    simulate an L1 instruction cache (with --stats)
+  --dcache <CACHE>    simulate an L1 data cache (with --stats)
+  --predictor <PRED>  simulate a branch predictor (with --stats)
+  --profile <FILE>    print a flat profile, the hottest instructions and the instruction mix,
+                      and write the folded call stacks to FILE (e.g., for inferno-flamegraph)
+  --coverage <FILE>   write the line and branch coverage to FILE in the lcov format, which needs
+                      DWARF line tables (compile with -g)
+  --disk <FILE>       add a virtio block device at 0x10001000 with the disk image FILE
+  --disk-mode <MODE>  read-write, copy-on-write (which keeps FILE unchanged) or read-only
+                      [default: read-write]
+  --framebuffer <FB>  add a framebuffer at 0x50000000
+  --screenshot <FILE> write the framebuffer to FILE at the end, a .png or else a .ppm image
+  --boot              start in a boot ROM at 0x1000, which jumps to the program with the hart ID
+                      in a0 and the address of a device tree describing the machine in a1
+  --bootargs <ARGS>   the kernel command line in the device tree, implies --boot
+  --dump-dtb <FILE>   write the device tree to FILE
+  --record <FILE>     record the input from stdin and write the log to FILE at the end
+  --replay <FILE>     replay the input recorded in FILE instead of reading stdin
+  -h, --help          print this help
+
+A CACHE is SIZE:WAYS:LINE_SIZE in bytes, optionally followed by :lru, :fifo or :random
+(default lru) and :write-back or :write-through (default write-back), e.g., 4096:2:32:fifo.
+
+A PRED is not-taken, btfn, bimodal:ENTRIES or gshare:ENTRIES:HISTORY_BITS, optionally followed
+by :btb=ENTRIES and :ras=DEPTH (default 64 and 8), e.g., gshare:1024:10:ras=16. With --timing,
+only mispredicted branches and jumps pay a penalty.
+
+A FB is WIDTHxHEIGHT, optionally followed by :rgb565 or :rgb888 (default rgb565), e.g.,
+320x240:rgb888.";
+
+struct Args {
+    path: PathBuf,
+    engine: Engine,
+    limit: u64,
+    memory: usize,
+    stats: bool,
+    timing: bool,
+    icache: Option<Cache>,
+    dcache: Option<Cache>,
+    predictor: Option<Predictor>,
+    profile: Option<PathBuf>,
+    coverage: Option<PathBuf>,
+    disk: Option<PathBuf>,
+    disk_mode: DiskMode,
+    framebuffer: Option<Framebuffer>,
+    screenshot: Option<PathBuf>,
+    /// The kernel command line if booting through the boot ROM.
+    boot: Option<String>,
+    dump_dtb: Option<PathBuf>,
+    record: Option<PathBuf>,
+    replay: Option<PathBuf>,
+}
+
+fn parse_args() -> Result<Args, String> {
+    let mut path = None;
+    let mut engine = Engine::Blocks;
+    let mut limit = u64::MAX;
+    let mut memory = MEMORY_SIZE;
+    let mut stats = false;
+    let mut timing = false;
+    let mut icache = None;
+    let mut dcache = None;
+    let mut predictor = None;
+    let mut profile = None;
+    let mut coverage = None;
+    let mut disk = None;
+    let mut disk_mode = DiskMode::ReadWrite;
+    let mut framebuffer = None;
+    let mut screenshot = None;
+    let mut boot = None;
+    let mut dump_dtb = None;
+    let mut record = None;
+    let mut replay = None;
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-h" | "--help" => {
+                println!("{USAGE}");
+                std::process::exit(0);
+            }
+            "--engine" => {
+                engine = match args.next().as_deref() {
+                    Some("interpreter") => Engine::Interpreter,
+                    Some("blocks") => Engine::Blocks,
+                    _ => return Err("--engine expects interpreter or blocks".into()),
+                }
+            }
+            "--limit" => {
+                limit = args
+                    .next()
+                    .and_then(|limit| limit.parse().ok())
+                    .ok_or("--limit expects a number of instructions")?
+            }
+            "--memory" => memory = parse_memory(args.next())?,
+            "--stats" => stats = true,
+            "--timing" => timing = true,
+            "--icache" => icache = Some(parse_cache(args.next())?),
+            "--dcache" => dcache = Some(parse_cache(args.next())?),
+            "--predictor" => predictor = Some(parse_predictor(args.next())?),
+            "--profile" => {
+                profile = Some(PathBuf::from(
+                    args.next().ok_or("--profile expects a file")?,
+                ))
+            }
+            "--coverage" => {
+                coverage = Some(PathBuf::from(
+                    args.next().ok_or("--coverage expects a file")?,
+                ))
+            }
+            "--disk" => disk = Some(PathBuf::from(args.next().ok_or("--disk expects a file")?)),
+            "--disk-mode" => {
+                disk_mode = match args.next().as_deref() {
+                    Some("read-write") => DiskMode::ReadWrite,
+                    Some("copy-on-write") => DiskMode::CopyOnWrite,
+                    Some("read-only") => DiskMode::ReadOnly,
+                    _ => {
+                        return Err(
+                            "--disk-mode expects read-write, copy-on-write or read-only".into()
+                        )
+                    }
+                }
+            }
+            "--framebuffer" => framebuffer = Some(parse_framebuffer(args.next())?),
+            "--screenshot" => {
+                screenshot = Some(PathBuf::from(
+                    args.next().ok_or("--screenshot expects a file")?,
+                ))
+            }
+            "--boot" => boot = Some(boot.unwrap_or_default()),
+            "--bootargs" => boot = Some(args.next().ok_or("--bootargs expects arguments")?),
+            "--dump-dtb" => {
+                dump_dtb = Some(PathBuf::from(
+                    args.next().ok_or("--dump-dtb expects a file")?,
+                ))
+            }
+            "--record" => {
+                record = Some(PathBuf::from(args.next().ok_or("--record expects a file")?))
+            }
+            "--replay" => {
+                replay = Some(PathBuf::from(args.next().ok_or("--replay expects a file")?))
+            }
+            _ if arg.starts_with('-') => return Err(format!("unknown option {arg}")),
+            _ if path.is_none() => path = Some(PathBuf::from(arg)),
+            _ => return Err("expected a single ELF file".into()),
+        }
+    }
+    if screenshot.is_some() && framebuffer.is_none() {
+        return Err("--screenshot needs a --framebuffer".into());
+    }
+    if record.is_some() && replay.is_some() {
+        return Err("--record and --replay exclude each other".into());
+    }
+    Ok(Args {
+        path: path.ok_or("missing ELF file")?,
+        engine,
+        limit,
+        memory,
+        stats,
+        timing,
+        icache,
+        dcache,
+        predictor,
+        profile,
+        coverage,
+        disk,
+        disk_mode,
+        framebuffer,
+        screenshot,
+        boot,
+        dump_dtb,
+        record,
+        replay,
+    })
+}
+
+fn parse_cache(spec: Option<String>) -> Result<Cache, String> {
+    let spec = spec.ok_or("--icache and --dcache expect a cache")?;
+    let mut parts = spec.split(':');
+    let mut number = || {
+        parts
+            .next()
+            .and_then(|part| part.parse().ok())
+            .ok_or(format!(
+                "invalid cache {spec}, expected SIZE:WAYS:LINE_SIZE"
+            ))
+    };
+    let mut config = CacheConfig {
+        size: number()?,
+        ways: number()?,
+        line_size: number()?,
+        replacement: Replacement::default(),
+        write_policy: WritePolicy::default(),
+    };
+    for part in parts {
+        match part {
+            "lru" => config.replacement = Replacement::Lru,
+            "fifo" => config.replacement = Replacement::Fifo,
+            "random" => config.replacement = Replacement::Random,
+            "write-back" => config.write_policy = WritePolicy::WriteBack,
+            "write-through" => config.write_policy = WritePolicy::WriteThrough,
+            _ => return Err(format!("unknown cache option {part}")),
+        }
+    }
+    Cache::new(config).map_err(|error| error.to_string())
+}
+
+fn parse_predictor(spec: Option<String>) -> Result<Predictor, String> {
+    let spec = spec.ok_or("--predictor expects a predictor")?;
+    let mut parts = spec.split(':');
+    let name = parts.next();
+    let mut number = || {
+        parts
+            .next()
+            .and_then(|part| part.parse().ok())
+            .ok_or(format!("invalid predictor {spec}"))
+    };
+    let scheme = match name {
+        Some("not-taken") => Scheme::NotTaken,
+        Some("btfn") => Scheme::Btfn,
+        Some("bimodal") => Scheme::Bimodal { entries: number()? },
+        Some("gshare") => Scheme::Gshare {
+            entries: number()?,
+            history_bits: number()?,
+        },
+        _ => return Err(format!("unknown predictor {spec}")),
+    };
+    let mut config = PredictorConfig {
+        scheme,
+        btb_entries: 64,
+        ras_depth: 8,
+    };
+    for part in parts {
+        let (field, value) = match part.split_once('=') {
+            Some(("btb", value)) => (&mut config.btb_entries, value),
+            Some(("ras", value)) => (&mut config.ras_depth, value),
+            _ => return Err(format!("unknown predictor option {part}")),
+        };
+        *field = value
+            .parse()
+            .map_err(|_| format!("invalid predictor option {part}"))?;
+    }
+    Predictor::new(config).map_err(|error| error.to_string())
+}
+
+fn parse_memory(spec: Option<String>) -> Result<usize, String> {
+    let spec = spec.ok_or("--memory expects a size")?;
+    let (number, unit) = match spec.strip_suffix(['K', 'M']) {
+        Some(number) if spec.ends_with('K') => (number, 1 << 10),
+        Some(number) => (number, 1 << 20),
+        None => (spec.as_str(), 1),
+    };
+    number
+        .parse::<usize>()
+        .ok()
+        .and_then(|number| number.checked_mul(unit))
+        .filter(|size| size % PAGE_SIZE == 0 && *size <= u32::MAX as usize - MEMORY_START + 1)
+        .ok_or(format!(
+            "invalid RAM size {spec}, expected a multiple of 4096 up to 2 GiB"
+        ))
+}
+
+fn parse_framebuffer(spec: Option<String>) -> Result<Framebuffer, String> {
+    let spec = spec.ok_or("--framebuffer expects a framebuffer")?;
+    let (resolution, format) = match spec.split_once(':') {
+        Some((resolution, "rgb565")) => (resolution, PixelFormat::Rgb565),
+        Some((resolution, "rgb888")) => (resolution, PixelFormat::Rgb888),
+        Some((_, format)) => return Err(format!("unknown pixel format {format}")),
+        None => (spec.as_str(), PixelFormat::default()),
+    };
+    let (width, height) = resolution
+        .split_once('x')
+        .and_then(|(width, height)| Some((width.parse().ok()?, height.parse().ok()?)))
+        .ok_or(format!("invalid framebuffer {spec}, expected WIDTHxHEIGHT"))?;
+    Framebuffer::new(width, height, format).map_err(|error| error.to_string())
+}
+
+/// Writes the sectors of the virtio block device's disk written since the last call back to its
+/// image at `path`.
+fn write_back(virtio: &mut VirtioBlock, path: &PathBuf) -> Result<(), String> {
+    let dirty = virtio.disk.take_dirty();
+    if dirty.is_empty() {
+        return Ok(());
+    }
+    let failed = |error: std::io::Error| format!("failed to write {}: {error}", path.display());
+    let mut file = OpenOptions::new().write(true).open(path).map_err(failed)?;
+    for run in dirty {
+        let bytes = run.start as usize * SECTOR_SIZE..run.end as usize * SECTOR_SIZE;
+        file.seek(SeekFrom::Start(bytes.start as u64))
+            .and_then(|_| file.write_all(&virtio.disk.data()[bytes]))
+            .map_err(failed)?;
+    }
+    Ok(())
+}
+
+/// Prints the profile and writes the folded call stacks to `path`.
+fn report(profiler: &Profiler, path: &PathBuf) -> Result<(), String> {
+    let instret = profiler.instret().max(1) as f64;
+    eprintln!("\n    self %    total %  function");
+    for function in profiler.flat().iter().take(20) {
+        eprintln!(
+            "{:9.2} {:10.2}  {}",
+            100.0 * function.instructions as f64 / instret,
+            100.0 * function.total as f64 / instret,
+            function.name
+        );
+    }
+    eprintln!("\n   count  address   instruction");
+    for (pc, instruction, count) in profiler.hotspots().iter().take(10) {
+        eprintln!("{count:8}  {pc:08x}  {instruction:?}");
+    }
+    eprintln!("\n   count  instruction");
+    for (name, count) in profiler.instruction_mix() {
+        eprintln!("{count:8}  {name}");
+    }
+    std::fs::write(path, profiler.folded())
+        .map_err(|error| format!("failed to write {}: {error}", path.display()))
+}
+
+/// Reads stdin on a thread, so the machine can keep running while it waits for input.
+fn spawn_stdin_reader() -> Receiver<Vec<u8>> {
+    let (sender, receiver) = mpsc::channel();
+    std::thread::spawn(move || {
+        let mut buffer = [0; 256];
+        while let Ok(length @ 1..) = std::io::stdin().read(&mut buffer) {
+            if sender.send(buffer[..length].to_vec()).is_err() {
+                break;
+            }
+        }
+    });
+    receiver
+}
+
+/// Runs up to `limit` instructions like [`Machine::run`], in slices between which the UART's
+/// output is written to stdout and input from stdin is delivered to it.
+fn run(machine: &mut Machine, limit: u64) -> Result<bool, Error> {
+    let input = spawn_stdin_reader();
+    let mut pending = Vec::new();
+    let mut stdout = std::io::stdout();
+    let mut remaining = limit;
+    loop {
+        let slice = remaining.min(SLICE);
+        let result = machine.run(slice);
+        remaining -= slice;
+        let output = machine.devices.uart.take_output();
+        if !output.is_empty() {
+            // the program keeps running if stdout is closed
+            let _ = stdout.write_all(&output).and_then(|()| stdout.flush());
+        }
+        if result != Ok(false) || remaining == 0 {
+            return result;
+        }
+        pending.extend(input.try_iter().flatten());
+        let received = machine.receive(&pending);
+        pending.drain(..received);
+    }
+}
+
+fn main() -> ExitCode {
+    let args = match parse_args() {
+        Ok(args) => args,
+        Err(message) => {
+            eprintln!("error: {message}\n\n{USAGE}");
+            return ExitCode::FAILURE;
+        }
+    };
+
+    let mut machine = Machine::with_memory_size(args.memory);
+    machine.engine = args.engine;
+    if args.timing {
+        machine.timing = Timing::new(Latencies::default());
+    }
+    machine.caches.instruction = args.icache;
+    machine.caches.data = args.dcache;
+    machine.predictor = args.predictor;
+    machine.devices.framebuffer = args.framebuffer;
+    let mut lines = Vec::new();
+    let loaded = std::fs::read(&args.path)
+        .map_err(|error| error.to_string())
+        .and_then(|data| {
+            if args.profile.is_some() {
+                let symbols = elf::symbols(&data).map_err(|error| error.to_string())?;
+                machine.profiler = Some(Profiler::new(symbols));
+            }
+            if args.coverage.is_some() {
+                lines = elf::lines(&data).map_err(|error| error.to_string())?;
+                if lines.is_empty() {
+                    return Err("no DWARF line table, compile with -g".into());
+                }
+                machine.coverage = Some(Coverage::default());
+            }
+            elf::load(&mut machine, &data).map_err(|error| error.to_string())
+        });
+    if let Err(message) = loaded {
+        eprintln!("error: failed to load {}: {message}", args.path.display());
+        return ExitCode::FAILURE;
+    }
+    if let Some(path) = &args.disk {
+        let disk = std::fs::read(path)
+            .map_err(|error| error.to_string())
+            .and_then(|image| Disk::new(image, args.disk_mode).map_err(|error| error.to_string()));
+        match disk {
+            Ok(disk) => machine.devices.virtio = Some(VirtioBlock::new(disk)),
+            Err(message) => {
+                eprintln!("error: failed to load {}: {message}", path.display());
+                return ExitCode::FAILURE;
+            }
+        }
+    }
+    if args.record.is_some() {
+        machine.replay = Replay::record();
+    }
+    if let Some(path) = &args.replay {
+        let replay = std::fs::read(path)
+            .map_err(|error| error.to_string())
+            .and_then(|log| Replay::from_log(&log).map_err(|error| error.to_string()));
+        match replay {
+            Ok(replay) => machine.replay = replay,
+            Err(message) => {
+                eprintln!("error: failed to load {}: {message}", path.display());
+                return ExitCode::FAILURE;
+            }
+        }
+    }
+    let bootargs = args.boot.as_deref().unwrap_or_default();
+    if let Some(path) = &args.dump_dtb {
+        if let Err(error) = std::fs::write(path, dtb::device_tree(&machine, bootargs)) {
+            eprintln!("error: failed to write {}: {error}", path.display());
+            return ExitCode::FAILURE;
+        }
+    }
+    if args.boot.is_some() {
+        if let Err(error) = machine.boot(bootargs) {
+            eprintln!("error: failed to boot: {error}");
+            return ExitCode::FAILURE;
+        }
+    }
+
+    let start = Instant::now();
+    let result = run(&mut machine, args.limit);
+    let elapsed = start.elapsed();
+    if args.stats {
+        eprintln!(
+            "{} instructions in {:.3} s ({:.1} MIPS)",
+            machine.instret,
+            elapsed.as_secs_f64(),
+            machine.instret as f64 / elapsed.as_secs_f64() / 1e6
+        );
+        if args.timing {
+            eprintln!(
+                "{} cycles ({:.2} cycles per instruction)",
+                machine.cycle,
+                machine.cycle as f64 / machine.instret as f64
+            );
+        }
+        for (name, cache) in [
+            ("I-cache", &machine.caches.instruction),
+            ("D-cache", &machine.caches.data),
+        ] {
+            if let Some(Cache { stats, .. }) = cache {
+                eprintln!(
+                    "{name}: {} accesses, {} misses ({:.2} % hit rate), {} writebacks",
+                    stats.accesses(),
+                    stats.misses(),
+                    100.0 * stats.hit_rate(),
+                    stats.writebacks
+                );
+            }
+        }
+        if let Some(Predictor { stats, .. }) = &machine.predictor {
+            eprintln!(
+                "Predictor: {} branches, {} mispredicted, {} jumps, {} mispredicted ({:.2} % accuracy)",
+                stats.branches,
+                stats.mispredicted_branches,
+                stats.jumps,
+                stats.mispredicted_jumps,
+                100.0 * stats.accuracy()
+            );
+        }
+        let stats = machine.tlb.stats;
+        if stats.lookups() > 0 {
+            eprintln!(
+                "TLB: {} lookups, {} misses ({:.2} % hit rate), {} flushes, {} page faults",
+                stats.lookups(),
+                stats.misses,
+                100.0 * stats.hit_rate(),
+                stats.flushes,
+                stats.page_faults
+            );
+        }
+    }
+    if let (Some(profiler), Some(path)) = (&machine.profiler, &args.profile) {
+        if let Err(message) = report(profiler, path) {
+            eprintln!("error: {message}");
+            return ExitCode::FAILURE;
+        }
+    }
+    if let (Some(coverage), Some(path)) = (&machine.coverage, &args.coverage) {
+        if let Err(error) = std::fs::write(path, coverage.lcov(&lines, &machine.memory)) {
+            eprintln!("error: failed to write {}: {error}", path.display());
+            return ExitCode::FAILURE;
+        }
+    }
+    if let (Some(virtio), Some(path)) = (&mut machine.devices.virtio, &args.disk) {
+        if let Err(message) = write_back(virtio, path) {
+            eprintln!("error: {message}");
+            return ExitCode::FAILURE;
+        }
+    }
+    if let Some(path) = &args.record {
+        if let Err(error) = std::fs::write(path, machine.replay.log()) {
+            eprintln!("error: failed to write {}: {error}", path.display());
+            return ExitCode::FAILURE;
+        }
+    }
+    if args.replay.is_some() && !machine.replay.finished() {
+        eprintln!("note: the run ended before all recorded input was replayed");
+    }
+    if let (Some(framebuffer), Some(path)) = (&machine.devices.framebuffer, &args.screenshot) {
+        let image = match path.extension() {
+            Some(extension) if extension.eq_ignore_ascii_case("png") => framebuffer.png(),
+            _ => framebuffer.ppm(),
+        };
+        if let Err(error) = std::fs::write(path, image) {
+            eprintln!("error: failed to write {}: {error}", path.display());
+            return ExitCode::FAILURE;
+        }
+    }
+    match result {
+        // riscv-tests write 1 to `tohost` if they passed, else the failed test case
+        Ok(true) => match machine
+            .tohost
+            .and_then(|tohost| load_word(&machine.memory, tohost).ok())
+        {
+            Some(value) if value != 1 => {
+                eprintln!("error: test case {} failed (tohost = {value})", value >> 1);
+                ExitCode::FAILURE
+            }
+            _ => ExitCode::SUCCESS,
+        },
+        Ok(false) => {
+            eprintln!("stopped after {} instructions", machine.instret);
+            ExitCode::SUCCESS
+        }
+        Err(error) => {
+            eprintln!("error: {error}");
+            ExitCode::FAILURE
+        }
+    }
+}
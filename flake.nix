@@ -180,11 +180,13 @@
               '';
               buildPhase = ''
                 cd isa
-                make rv32ui
+                make rv32ui rv32si
               '';
               installPhase = ''
                 mkdir $out
                 cp rv32ui-p-*[^dump] $out
+                cp rv32ui-v-*[^dump] $out
+                cp rv32si-p-*[^dump] $out
               '';
               dontPatch = true;
             };
@@ -0,0 +1,50 @@
+use {
+    criterion::{criterion_group, criterion_main, BatchSize, Criterion, Throughput},
+    riscv::{programs::LOOP, step, DecodeCache, Machine, MEMORY_SIZE},
+};
+
+fn machine(decode_cache: DecodeCache) -> Machine {
+    let mut machine = Machine::new();
+    for (i, code) in LOOP.iter().enumerate() {
+        machine.memory[4 * i..4 * i + 4].copy_from_slice(&code.to_le_bytes());
+    }
+    machine.decode_cache = decode_cache;
+    machine
+}
+
+fn run(mut machine: Machine) -> Machine {
+    while !machine.step().unwrap() {}
+    machine
+}
+
+fn decode_cache(c: &mut Criterion) {
+    let instructions = run(machine(DecodeCache::new(MEMORY_SIZE))).instret;
+    let mut group = c.benchmark_group("decode_cache");
+    // reported as instructions per second
+    group.throughput(Throughput::Elements(instructions));
+    group.bench_function("step", |b| {
+        b.iter_batched_ref(
+            || machine(DecodeCache::disabled()),
+            |machine| while !step(&mut machine.registers, &mut machine.memory).unwrap() {},
+            BatchSize::SmallInput,
+        )
+    });
+    group.bench_function("disabled", |b| {
+        b.iter_batched(
+            || machine(DecodeCache::disabled()),
+            run,
+            BatchSize::SmallInput,
+        )
+    });
+    group.bench_function("enabled", |b| {
+        b.iter_batched(
+            || machine(DecodeCache::new(MEMORY_SIZE)),
+            run,
+            BatchSize::SmallInput,
+        )
+    });
+    group.finish();
+}
+
+criterion_group!(benches, decode_cache);
+criterion_main!(benches);
@@ -0,0 +1,37 @@
+use {
+    criterion::{criterion_group, criterion_main, BatchSize, Criterion, Throughput},
+    riscv::{programs::LOOP, Engine, Machine},
+};
+
+fn machine(engine: Engine) -> Machine {
+    let mut machine = Machine::new();
+    for (i, code) in LOOP.iter().enumerate() {
+        machine.memory[4 * i..4 * i + 4].copy_from_slice(&code.to_le_bytes());
+    }
+    machine.engine = engine;
+    machine
+}
+
+fn run(mut machine: Machine) -> Machine {
+    assert!(machine.run(u64::MAX).unwrap());
+    machine
+}
+
+fn engines(c: &mut Criterion) {
+    let instructions = run(machine(Engine::Interpreter)).instret;
+    let mut group = c.benchmark_group("engines");
+    // reported as instructions per second
+    group.throughput(Throughput::Elements(instructions));
+    for (engine, name) in [
+        (Engine::Interpreter, "interpreter"),
+        (Engine::Blocks, "blocks"),
+    ] {
+        group.bench_function(name, |b| {
+            b.iter_batched(|| machine(engine), run, BatchSize::SmallInput)
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, engines);
+criterion_main!(benches);
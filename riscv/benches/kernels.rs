@@ -0,0 +1,191 @@
+use {
+    criterion::{criterion_group, criterion_main, BatchSize, Criterion, Throughput},
+    riscv::{
+        programs::{BUBBLE_SORT, FIBONACCI},
+        step, DecodeCache, Engine, Machine, MEMORY_SIZE, MEMORY_START,
+    },
+};
+
+/// Calls the kernel following it `s4` times, then signals the end.
+const DRIVER: &[u32] = &[
+    0x010000ef, // loop: jal ra, kernel
+    0xfffa0a13, // addi s4, s4, -1
+    0xfe0a1ce3, // bnez s4, loop
+    0xc0001073, // unimp
+];
+const RET: u32 = 0x00008067;
+
+// Like CoreMark's matrix and CRC kernels: multiplies two 8x8 matrices of words at 0x80001000 and
+// 0x80001100 with shift-and-add into 0x80001200, then computes the CRC-16 of the result, 4 times.
+const COREMARK: &[u32] = &[
+    0x80001437, // lui s0, 0x80001
+    0x10040493, // addi s1, s0, 256
+    0x20040913, // addi s2, s0, 512
+    0x00000293, // li t0, 0
+    0x04000313, // li t1, 64
+    0x00229393, // init: slli t2, t0, 2
+    0x00740e33, // add t3, s0, t2
+    0x005e2023, // sw t0, 0(t3)
+    0x00748e33, // add t3, s1, t2
+    0x0552ce93, // xori t4, t0, 85
+    0x01de2023, // sw t4, 0(t3)
+    0x00128293, // addi t0, t0, 1
+    0xfe6292e3, // bne t0, t1, init
+    0x00400993, // li s3, 4
+    0x00800f13, // li t5, 8
+    0x00000513, // outer: li a0, 0
+    0x00000593, // row: li a1, 0
+    0x00000613, // col: li a2, 0
+    0x00000693, // li a3, 0
+    0x00351293, // dot: slli t0, a0, 3
+    0x00c282b3, // add t0, t0, a2
+    0x00229293, // slli t0, t0, 2
+    0x008282b3, // add t0, t0, s0
+    0x0002a303, // lw t1, 0(t0)
+    0x00361293, // slli t0, a2, 3
+    0x00b282b3, // add t0, t0, a1
+    0x00229293, // slli t0, t0, 2
+    0x009282b3, // add t0, t0, s1
+    0x0002a383, // lw t2, 0(t0)
+    0x00000e13, // li t3, 0
+    0x0013fe93, // mul: andi t4, t2, 1
+    0x000e8463, // beqz t4, skip
+    0x006e0e33, // add t3, t3, t1
+    0x00131313, // skip: slli t1, t1, 1
+    0x0013d393, // srli t2, t2, 1
+    0xfe0396e3, // bnez t2, mul
+    0x01c686b3, // add a3, a3, t3
+    0x00160613, // addi a2, a2, 1
+    0xfbe61ae3, // bne a2, t5, dot
+    0x00351293, // slli t0, a0, 3
+    0x00b282b3, // add t0, t0, a1
+    0x00229293, // slli t0, t0, 2
+    0x012282b3, // add t0, t0, s2
+    0x00d2a023, // sw a3, 0(t0)
+    0x00158593, // addi a1, a1, 1
+    0xf9e598e3, // bne a1, t5, col
+    0x00150513, // addi a0, a0, 1
+    0xf9e512e3, // bne a0, t5, row
+    0x00000513, // li a0, 0
+    0x00090293, // mv t0, s2
+    0x10090313, // addi t1, s2, 256
+    0x0000a737, // lui a4, 0xa
+    0x00170713, // addi a4, a4, 1
+    0x0002c383, // crc_byte: lbu t2, 0(t0)
+    0x00754533, // xor a0, a0, t2
+    0x00800e13, // li t3, 8
+    0x00157e93, // crc_bit: andi t4, a0, 1
+    0x00155513, // srli a0, a0, 1
+    0x000e8463, // beqz t4, crc_next
+    0x00e54533, // xor a0, a0, a4
+    0xfffe0e13, // crc_next: addi t3, t3, -1
+    0xfe0e16e3, // bnez t3, crc_bit
+    0x00128293, // addi t0, t0, 1
+    0xfc629ce3, // bne t0, t1, crc_byte
+    0xfff98993, // addi s3, s3, -1
+    0xf2099ce3, // bnez s3, outer
+    RET,
+];
+
+// Copies 16 KiB from 0x80004000 to 0x80008000, four words at a time.
+const MEMCPY: &[u32] = &[
+    0x80008537, // lui a0, 0x80008
+    0x800045b7, // lui a1, 0x80004
+    0x00004637, // lui a2, 0x4
+    0x00c586b3, // add a3, a1, a2
+    0x0005a283, // loop: lw t0, 0(a1)
+    0x0045a303, // lw t1, 4(a1)
+    0x0085a383, // lw t2, 8(a1)
+    0x00c5ae03, // lw t3, 12(a1)
+    0x00552023, // sw t0, 0(a0)
+    0x00652223, // sw t1, 4(a0)
+    0x00752423, // sw t2, 8(a0)
+    0x01c52623, // sw t3, 12(a0)
+    0x01058593, // addi a1, a1, 16
+    0x01050513, // addi a0, a0, 16
+    0xfcd5ece3, // bltu a1, a3, loop
+    RET,
+];
+
+/// The kernels and how often they are called, so each runs long enough to measure.
+const KERNELS: &[(&str, &[u32], u32)] = &[
+    ("fibonacci", FIBONACCI, 1000),
+    ("bubble_sort", BUBBLE_SORT, 1000),
+    ("coremark", COREMARK, 1),
+    ("memcpy", MEMCPY, 4),
+];
+
+fn machine(kernel: &[u32], calls: u32, decode_cache: DecodeCache, engine: Engine) -> Machine {
+    let mut machine = Machine::new();
+    for (i, code) in DRIVER.iter().chain(kernel).enumerate() {
+        machine.memory[4 * i..4 * i + 4].copy_from_slice(&code.to_le_bytes());
+    }
+    // the source of memcpy
+    for (i, byte) in machine.memory[0x4000..0x8000].iter_mut().enumerate() {
+        *byte = i as u8;
+    }
+    // the stack is at the end of RAM
+    machine.registers[2] = (MEMORY_START + MEMORY_SIZE) as u32;
+    machine.registers[20] = calls;
+    machine.decode_cache = decode_cache;
+    machine.engine = engine;
+    machine
+}
+
+fn run(mut machine: Machine) -> Machine {
+    assert!(machine.run(u64::MAX).unwrap());
+    machine
+}
+
+fn kernels(c: &mut Criterion) {
+    for &(name, kernel, calls) in KERNELS {
+        let expected = run(machine(
+            kernel,
+            calls,
+            DecodeCache::disabled(),
+            Engine::Interpreter,
+        ));
+        let actual = run(machine(
+            kernel,
+            calls,
+            DecodeCache::new(MEMORY_SIZE),
+            Engine::Blocks,
+        ));
+        assert_eq!(actual.registers, expected.registers);
+        assert!(actual.memory == expected.memory);
+
+        let mut group = c.benchmark_group(name);
+        // reported as instructions per second
+        group.throughput(Throughput::Elements(expected.instret));
+        group.bench_function("step", |b| {
+            b.iter_batched_ref(
+                || machine(kernel, calls, DecodeCache::disabled(), Engine::Interpreter),
+                |machine| while !step(&mut machine.registers, &mut machine.memory).unwrap() {},
+                BatchSize::SmallInput,
+            )
+        });
+        for (engine, decode_cache, engine_name) in [
+            (Engine::Interpreter, false, "interpreter"),
+            (Engine::Interpreter, true, "decode_cache"),
+            (Engine::Blocks, true, "blocks"),
+        ] {
+            group.bench_function(engine_name, |b| {
+                b.iter_batched(
+                    || {
+                        let decode_cache = match decode_cache {
+                            true => DecodeCache::new(MEMORY_SIZE),
+                            false => DecodeCache::disabled(),
+                        };
+                        machine(kernel, calls, decode_cache, engine)
+                    },
+                    run,
+                    BatchSize::SmallInput,
+                )
+            });
+        }
+        group.finish();
+    }
+}
+
+criterion_group!(benches, kernels);
+criterion_main!(benches);
@@ -1,38 +1,54 @@
 use {
-    riscv::{step, Memory, Registers, MEMORY_SIZE, MEMORY_START, PC},
-    std::{fs::File, io::Read, path::Path},
-    xmas_elf::program::SegmentData,
+    riscv::{elf, load_word, Engine, Machine, PC},
+    std::path::Path,
 };
 
+/// The rv32ui-v tests allocate their page tables and pages past the program.
+const MEMORY_SIZE: usize = 0x100000;
+
 fn main() {
-    for entry in glob::glob(
-        Path::new(
-            std::env::args()
-                .nth(1)
-                .as_deref()
-                .unwrap_or("riscv-tests/isa"),
-        )
-        .join("rv32ui-p-*")
-        .to_str()
-        .unwrap(),
-    )
-    .unwrap()
-    {
-        let path = entry.unwrap();
-        if path.is_dir() || path.extension().is_some() {
-            continue;
-        }
+    let directory = std::env::args()
+        .nth(1)
+        .unwrap_or_else(|| "riscv-tests/isa".into());
+    for pattern in ["rv32ui-p-*", "rv32ui-v-*", "rv32si-p-*"] {
+        let mut found = 0;
+        for entry in glob::glob(Path::new(&directory).join(pattern).to_str().unwrap()).unwrap() {
+            let path = entry.unwrap();
+            if path.is_dir() || path.extension().is_some() {
+                continue;
+            }
 
-        println!("ELF file: {:?}", path);
-        run(&path, false);
+            println!("ELF file: {:?}", path);
+            run(&path, false);
+            run_engine(&path, Engine::Blocks);
+            found += 1;
+        }
+        // a missing suite would otherwise pass without running anything
+        assert!(found > 0, "no {pattern} tests in {directory}");
     }
 }
 
-pub fn run(path: &std::path::Path, verbose: bool) {
-    let mut memory: Memory = [0; MEMORY_SIZE];
-    let mut registers: Registers = [0; 33];
-    load_elf(&mut memory, path);
-    registers[PC] = MEMORY_START as u32;
+/// Checks the value the test wrote to `tohost`: 1 if it passed, otherwise the number of the failed
+/// test case shifted left by one, with the lowest bit set.
+fn check(machine: &Machine) {
+    let tohost = machine
+        .tohost
+        .expect("riscv-tests programs have a tohost symbol");
+    let value = load_word(&machine.memory, tohost).unwrap();
+    assert_eq!(value, 1, "test case {} failed", value >> 1);
+}
+
+/// Runs the test with `engine`, which has to give the same result as single-stepping.
+pub fn run_engine(path: &Path, engine: Engine) {
+    let mut machine = load_elf(path);
+    machine.engine = engine;
+    assert!(machine.run(u64::MAX).unwrap());
+    check(&machine);
+    println!("Test succeeded with {engine:?} engine!");
+}
+
+pub fn run(path: &Path, verbose: bool) {
+    let mut machine = load_elf(path);
 
     if verbose {
         println!(
@@ -43,44 +59,35 @@ pub fn run(path: &std::path::Path, verbose: bool) {
 
     for i in 0.. {
         if verbose {
-            let pc = registers[PC];
-            let code = riscv::load_word(&memory, pc).unwrap();
-            let instruction = riscv::decode(code).unwrap();
+            let pc = machine.registers[PC];
+            let code = load_word(&machine.memory, pc).unwrap();
 
             // Uncomment to dump registers for range of instructions
             // if (0x80000198..=0x800001a8).contains(&pc) {
-            //     dump_registers(&registers);
+            //     println!("{}", riscv::dump_registers(&machine.registers));
             // }
 
-            println!("{:4} {:8x} {:08x} {:?}", i, pc, code, &instruction);
+            println!(
+                "{:4} {:8x} {:08x} {:?} ({:?})",
+                i,
+                pc,
+                code,
+                riscv::decode(code),
+                machine.csrs.privilege
+            );
         }
 
-        let done = step(&mut registers, &mut memory).unwrap();
-        if done {
+        if machine.step().unwrap() {
+            check(&machine);
             println!("Test succeeded!");
             break;
         }
     }
 }
 
-pub fn load_elf(memory: &mut Memory, path: &Path) {
-    let mut buffer = Vec::new();
-    {
-        let mut file = File::open(path).unwrap();
-        assert!(file.read_to_end(&mut buffer).unwrap() > 0);
-    }
-
-    let elf_file = xmas_elf::ElfFile::new(&buffer).unwrap();
-    for program_header in elf_file.program_iter() {
-        // TODO: revise this
-        if program_header.physical_addr() == 0 {
-            continue;
-        }
-        let address = program_header.physical_addr() as usize - MEMORY_START;
-        if let Ok(SegmentData::Undefined(data)) = program_header.get_data(&elf_file) {
-            memory[address..address + data.len()].copy_from_slice(data);
-        } else {
-            panic!("this should panic")
-        }
-    }
+pub fn load_elf(path: &Path) -> Machine {
+    let data = std::fs::read(path).unwrap();
+    let mut machine = Machine::with_memory_size(MEMORY_SIZE);
+    elf::load(&mut machine, &data).unwrap();
+    machine
 }
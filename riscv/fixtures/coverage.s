@@ -0,0 +1,27 @@
+# The fixture of the tests in elf.rs. Rebuild coverage.elf with
+#   llvm-mc -triple=riscv32 -mattr=-relax -g -fdebug-compilation-dir=. -filetype=obj \
+#     coverage.s -o coverage.o
+#   ld.lld -T coverage.ld coverage.o -o coverage.elf
+    .text
+    .globl _start
+_start:
+    li a0, 3
+    call count
+    li a1, 1
+    la t0, tohost
+    sw a1, 0(t0)
+
+    .type count, @function
+count:
+    addi a0, a0, -1
+    bnez a0, count
+    beqz a0, 1f
+    nop
+1:
+    ret
+    .size count, . - count
+
+    .data
+    .globl tohost
+tohost:
+    .word 0
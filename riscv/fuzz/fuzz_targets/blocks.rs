@@ -0,0 +1,49 @@
+#![no_main]
+
+use {
+    arbitrary::Arbitrary,
+    libfuzzer_sys::fuzz_target,
+    riscv::{Engine, Machine, MEMORY_SIZE, MEMORY_START},
+    riscv_fuzz::Word,
+};
+
+const MAX_STEPS: u64 = 1024;
+
+#[derive(Debug, Arbitrary)]
+struct Input {
+    registers: [u32; 31],
+    /// Bit mask of registers which are moved into RAM, so loads and stores don't just fault.
+    in_memory: u32,
+    /// The number of instructions per `Machine::run` of the blocks engine.
+    chunk: u8,
+    program: Vec<Word>,
+}
+
+fuzz_target!(|input: Input| {
+    let mut interpreter = Machine::new();
+    for (i, &value) in input.registers.iter().enumerate() {
+        interpreter.registers[i + 1] = if input.in_memory >> i & 1 == 1 {
+            MEMORY_START as u32 + value % MEMORY_SIZE as u32
+        } else {
+            value
+        };
+    }
+    for (i, word) in input.program.iter().take(MEMORY_SIZE / 4).enumerate() {
+        interpreter.memory[4 * i..4 * i + 4].copy_from_slice(&word.code().to_le_bytes());
+    }
+    let mut blocks = interpreter.clone();
+    blocks.engine = Engine::Blocks;
+
+    let expected = interpreter.run(MAX_STEPS);
+    let chunk = input.chunk.max(1) as u64;
+    let actual = loop {
+        match blocks.run(chunk.min(MAX_STEPS - blocks.instret)) {
+            Ok(false) if blocks.instret < MAX_STEPS => {}
+            result => break result,
+        }
+    };
+    assert_eq!(actual, expected);
+    assert_eq!(blocks.registers, interpreter.registers, "registers differ");
+    assert!(blocks.memory == interpreter.memory, "memory differs");
+    assert_eq!(blocks.instret, interpreter.instret);
+});
@@ -0,0 +1,10 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|code: u32| {
+    if let Some(instruction) = riscv::decode(code) {
+        assert_eq!(instruction.encode(), code, "{instruction:?}");
+        assert_eq!(riscv::decode(instruction.encode()), Some(instruction));
+    }
+});
@@ -0,0 +1,62 @@
+#![no_main]
+
+use {
+    arbitrary::Arbitrary,
+    libfuzzer_sys::fuzz_target,
+    riscv::{csr::Exception, Error, Registers, MEMORY_SIZE, MEMORY_START, PC},
+    riscv_fuzz::{Reference, Trap, Word},
+};
+
+const MAX_STEPS: usize = 1024;
+
+#[derive(Debug, Arbitrary)]
+struct Input {
+    registers: [u32; 31],
+    /// Bit mask of registers which are moved into RAM, so loads and stores don't just fault.
+    in_memory: u32,
+    program: Vec<Word>,
+}
+
+fuzz_target!(|input: Input| {
+    let mut registers: Registers = [0; 33];
+    let mut memory = vec![0; MEMORY_SIZE];
+    let mut reference = Reference::new(MEMORY_SIZE);
+
+    for (i, &value) in input.registers.iter().enumerate() {
+        let value = if input.in_memory >> i & 1 == 1 {
+            MEMORY_START as u32 + value % MEMORY_SIZE as u32
+        } else {
+            value
+        };
+        registers[i + 1] = value;
+        reference.x[i + 1] = value;
+    }
+    registers[PC] = MEMORY_START as u32;
+
+    for (i, word) in input.program.iter().take(MEMORY_SIZE / 4).enumerate() {
+        let address = MEMORY_START as u32 + 4 * i as u32;
+        riscv::store_word(&mut memory, address, word.code()).unwrap();
+        reference.write(address, 4, word.code()).unwrap();
+    }
+
+    for _ in 0..MAX_STEPS {
+        let expected = reference.step();
+        let actual = riscv::step(&mut registers, &mut memory);
+        match (expected, &actual) {
+            (Ok(()), Ok(false)) => {}
+            (Err(Trap::IllegalInstruction), Err(Error::DecodeError { .. }))
+            | (Err(Trap::AccessFault), Err(Error::MemoryError { .. }))
+            | (
+                Err(Trap::MisalignedFetch),
+                Err(Error::Exception {
+                    exception: Exception::InstructionAddressMisaligned,
+                    ..
+                }),
+            ) => break,
+            _ => panic!("expected {expected:?}, got {actual:?}"),
+        }
+        assert_eq!(registers[..32], reference.x, "registers differ");
+        assert_eq!(registers[PC], reference.pc, "pc differs");
+    }
+    assert!(memory[..] == reference.memory[..], "memory differs");
+});
@@ -0,0 +1,244 @@
+//! An independent RV32I reference model to differentially test `riscv::step` against.
+//!
+//! It intentionally shares no code with the `riscv` crate: instructions are decoded straight from
+//! the bit layout given in the specification and memory is a plain byte vector. Only the
+//! unprivileged base instructions are modeled, SYSTEM instructions are treated as illegal.
+//!
+//! [`Word`] generates the programs of the fuzz targets.
+
+use arbitrary::Arbitrary;
+
+pub const MEMORY_START: u32 = 0x8000_0000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Trap {
+    IllegalInstruction,
+    AccessFault,
+    /// A jump or taken branch to an address which isn't 4-byte aligned.
+    MisalignedFetch,
+}
+
+pub struct Reference {
+    pub x: [u32; 32],
+    pub pc: u32,
+    pub memory: Vec<u8>,
+}
+
+impl Reference {
+    pub fn new(memory_size: usize) -> Reference {
+        Reference {
+            x: [0; 32],
+            pc: MEMORY_START,
+            memory: vec![0; memory_size],
+        }
+    }
+
+    fn offset(&self, address: u32, size: u32) -> Result<usize, Trap> {
+        // addresses below the RAM base wrap around to offsets above 2 GiB
+        let offset = address.wrapping_sub(MEMORY_START) as usize;
+        if offset + size as usize > self.memory.len() {
+            return Err(Trap::AccessFault);
+        }
+        Ok(offset)
+    }
+
+    pub fn read(&self, address: u32, size: u32) -> Result<u32, Trap> {
+        let offset = self.offset(address, size)?;
+        Ok((0..size as usize).fold(0, |value, i| {
+            value | (self.memory[offset + i] as u32) << (8 * i)
+        }))
+    }
+
+    pub fn write(&mut self, address: u32, size: u32, value: u32) -> Result<(), Trap> {
+        let offset = self.offset(address, size)?;
+        for i in 0..size as usize {
+            self.memory[offset + i] = (value >> (8 * i)) as u8;
+        }
+        Ok(())
+    }
+
+    /// Executes a single instruction. On a trap the architectural state is left untouched.
+    pub fn step(&mut self) -> Result<(), Trap> {
+        let code = self.read(self.pc, 4)?;
+
+        let opcode = code & 0x7f;
+        let rd = (code >> 7 & 0x1f) as usize;
+        let funct3 = code >> 12 & 0x7;
+        let rs1 = self.x[(code >> 15 & 0x1f) as usize];
+        let rs2 = self.x[(code >> 20 & 0x1f) as usize];
+        let funct7 = code >> 25;
+
+        let i_imm = (code as i32 >> 20) as u32;
+        let s_imm = ((code as i32 >> 25) << 5) as u32 | (code >> 7 & 0x1f);
+        let b_imm = ((code as i32 >> 31) << 12) as u32
+            | (code << 4 & 0x800)
+            | (code >> 20 & 0x7e0)
+            | (code >> 7 & 0x1e);
+        let u_imm = code & 0xffff_f000;
+        let j_imm = ((code as i32 >> 31) << 20) as u32
+            | (code & 0xf_f000)
+            | (code >> 9 & 0x800)
+            | (code >> 20 & 0x7fe);
+
+        let mut next_pc = self.pc.wrapping_add(4);
+        let mut write_back = None;
+        let mut jump = |target: u32| {
+            if target % 4 != 0 {
+                return Err(Trap::MisalignedFetch);
+            }
+            next_pc = target;
+            Ok(())
+        };
+
+        match opcode {
+            // LUI
+            0b0110111 => write_back = Some(u_imm),
+            // AUIPC
+            0b0010111 => write_back = Some(self.pc.wrapping_add(u_imm)),
+            // JAL
+            0b1101111 => {
+                jump(self.pc.wrapping_add(j_imm))?;
+                write_back = Some(self.pc.wrapping_add(4));
+            }
+            // JALR
+            0b1100111 if funct3 == 0 => {
+                jump(rs1.wrapping_add(i_imm) & !1)?;
+                write_back = Some(self.pc.wrapping_add(4));
+            }
+            // BRANCH
+            0b1100011 => {
+                let taken = match funct3 {
+                    0b000 => rs1 == rs2,
+                    0b001 => rs1 != rs2,
+                    0b100 => (rs1 as i32) < (rs2 as i32),
+                    0b101 => (rs1 as i32) >= (rs2 as i32),
+                    0b110 => rs1 < rs2,
+                    0b111 => rs1 >= rs2,
+                    _ => return Err(Trap::IllegalInstruction),
+                };
+                if taken {
+                    jump(self.pc.wrapping_add(b_imm))?;
+                }
+            }
+            // LOAD
+            0b0000011 => {
+                let address = rs1.wrapping_add(i_imm);
+                write_back = Some(match funct3 {
+                    0b000 => self.read(address, 1)? as i8 as u32,
+                    0b001 => self.read(address, 2)? as i16 as u32,
+                    0b010 => self.read(address, 4)?,
+                    0b100 => self.read(address, 1)?,
+                    0b101 => self.read(address, 2)?,
+                    _ => return Err(Trap::IllegalInstruction),
+                });
+            }
+            // STORE
+            0b0100011 => {
+                let address = rs1.wrapping_add(s_imm);
+                match funct3 {
+                    0b000 => self.write(address, 1, rs2)?,
+                    0b001 => self.write(address, 2, rs2)?,
+                    0b010 => self.write(address, 4, rs2)?,
+                    _ => return Err(Trap::IllegalInstruction),
+                }
+            }
+            // OP-IMM
+            0b0010011 => {
+                let shamt = i_imm & 0x1f;
+                write_back = Some(match (funct3, funct7) {
+                    (0b000, _) => rs1.wrapping_add(i_imm),
+                    (0b010, _) => ((rs1 as i32) < (i_imm as i32)) as u32,
+                    (0b011, _) => (rs1 < i_imm) as u32,
+                    (0b100, _) => rs1 ^ i_imm,
+                    (0b110, _) => rs1 | i_imm,
+                    (0b111, _) => rs1 & i_imm,
+                    (0b001, 0b000_0000) => rs1 << shamt,
+                    (0b101, 0b000_0000) => rs1 >> shamt,
+                    (0b101, 0b010_0000) => ((rs1 as i32) >> shamt) as u32,
+                    _ => return Err(Trap::IllegalInstruction),
+                });
+            }
+            // OP
+            0b0110011 => {
+                let shamt = rs2 & 0x1f;
+                write_back = Some(match (funct3, funct7) {
+                    (0b000, 0b000_0000) => rs1.wrapping_add(rs2),
+                    (0b000, 0b010_0000) => rs1.wrapping_sub(rs2),
+                    (0b001, 0b000_0000) => rs1 << shamt,
+                    (0b010, 0b000_0000) => ((rs1 as i32) < (rs2 as i32)) as u32,
+                    (0b011, 0b000_0000) => (rs1 < rs2) as u32,
+                    (0b100, 0b000_0000) => rs1 ^ rs2,
+                    (0b101, 0b000_0000) => rs1 >> shamt,
+                    (0b101, 0b010_0000) => ((rs1 as i32) >> shamt) as u32,
+                    (0b110, 0b000_0000) => rs1 | rs2,
+                    (0b111, 0b000_0000) => rs1 & rs2,
+                    _ => return Err(Trap::IllegalInstruction),
+                });
+            }
+            // MISC-MEM (FENCE and FENCE.I): a single hart without caches has nothing to order
+            0b0001111 if funct3 <= 1 => {}
+            _ => return Err(Trap::IllegalInstruction),
+        }
+
+        if let Some(value) = write_back {
+            if rd != 0 {
+                self.x[rd] = value;
+            }
+        }
+        self.pc = next_pc;
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Copy, Arbitrary)]
+pub enum Opcode {
+    Lui = 0b0110111,
+    Auipc = 0b0010111,
+    Jal = 0b1101111,
+    Jalr = 0b1100111,
+    Branch = 0b1100011,
+    Load = 0b0000011,
+    Store = 0b0100011,
+    OpImm = 0b0010011,
+    Op = 0b0110011,
+    MiscMem = 0b0001111,
+}
+
+#[derive(Debug, Arbitrary)]
+pub enum Word {
+    /// Any word, except for SYSTEM instructions, which the reference model doesn't implement.
+    Raw(u32),
+    /// A word with a valid opcode and, where it matters, a valid funct7.
+    Base {
+        opcode: Opcode,
+        alternate: bool,
+        fields: u32,
+    },
+}
+
+impl Word {
+    pub fn code(&self) -> u32 {
+        match *self {
+            Word::Raw(code) if code & 0x7f == 0b1110011 => code & !0x7f,
+            Word::Raw(code) => code,
+            Word::Base {
+                opcode,
+                alternate,
+                fields,
+            } => {
+                let code = fields & !0x7f | opcode as u32;
+                let has_funct7 = match opcode {
+                    Opcode::Op => true,
+                    // SLLI, SRLI and SRAI
+                    Opcode::OpImm => code >> 12 & 0b11 == 0b01,
+                    _ => false,
+                };
+                if has_funct7 {
+                    code & 0x01ff_ffff | (alternate as u32) << 30
+                } else {
+                    code
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,615 @@
+use crate::{
+    decode, load_byte, load_half_word, load_word, store_byte, store_half_word, store_word,
+    DecodeCache, Instruction, Memory, Registers, Retired, MEMORY_START, PAGE_SIZE, PC,
+};
+
+/// The longest straight-line sequence translated into a single block.
+const MAX_BLOCK_LENGTH: usize = 256;
+
+/// How [`Machine::run`](crate::Machine::run) executes instructions.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Engine {
+    /// Fetches, decodes and executes one instruction at a time, like [`Machine::step`](crate::Machine::step).
+    #[default]
+    Interpreter,
+    /// Translates basic blocks into arrays of ops, with registers and immediates resolved, and
+    /// runs them until a jump or a taken branch. SYSTEM instructions and FENCE.I, faulting
+    /// instructions and all instructions while the [`History`](crate::History) is enabled are
+    /// single-stepped instead.
+    Blocks,
+}
+
+/// Operands of an op with an immediate.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct I {
+    rd: u8,
+    rs1: u8,
+    imm: u32,
+}
+
+/// Operands of a register-register op.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct R {
+    rd: u8,
+    rs1: u8,
+    rs2: u8,
+}
+
+/// Operands of a store.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct S {
+    rs1: u8,
+    rs2: u8,
+    imm: u32,
+}
+
+/// Operands of a branch, with the target resolved.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct B {
+    rs1: u8,
+    rs2: u8,
+    target: u32,
+}
+
+/// A translated instruction. Registers are indices into [`Registers`], `x0` is reset to zero
+/// after every op.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Op {
+    // LUI, and AUIPC with the pc added in
+    Const {
+        rd: u8,
+        value: u32,
+    },
+    // OP-IMM, the shift amount is masked into `imm`
+    Addi(I),
+    Slti(I),
+    Sltiu(I),
+    Xori(I),
+    Ori(I),
+    Andi(I),
+    Slli(I),
+    Srli(I),
+    Srai(I),
+    // OP
+    Add(R),
+    Sub(R),
+    Sll(R),
+    Slt(R),
+    Sltu(R),
+    Xor(R),
+    Srl(R),
+    Sra(R),
+    Or(R),
+    And(R),
+    // LOAD
+    Lb(I),
+    Lh(I),
+    Lw(I),
+    Lbu(I),
+    Lhu(I),
+    // STORE
+    Sb(S),
+    Sh(S),
+    Sw(S),
+    // FENCE
+    Nop,
+    // JAL and JALR with the return address resolved, they end a block
+    Jal {
+        rd: u8,
+        link: u32,
+        target: u32,
+    },
+    Jalr {
+        rd: u8,
+        rs1: u8,
+        imm: u32,
+        link: u32,
+    },
+    // BRANCH, they end a block
+    Beq(B),
+    Bne(B),
+    Blt(B),
+    Bge(B),
+    Bltu(B),
+    Bgeu(B),
+}
+
+/// What to do after an op.
+enum Exit {
+    Next,
+    Jump(u32),
+    /// The op wrote `size` bytes at `address`.
+    Store(u32, u32),
+    /// The op faulted or raised an exception without changing any state.
+    Fault,
+}
+
+impl Op {
+    /// Translates `instruction` at `pc`. Returns `None` for instructions which must be
+    /// single-stepped.
+    fn translate(pc: u32, instruction: Instruction) -> Option<Op> {
+        use Instruction::*;
+        let r = |register: u32| register as u8;
+        // misaligned jumps and branches raise an exception when taken
+        let aligned = |target: u32| (target % 4 == 0).then_some(target);
+        Some(match instruction {
+            LUI(u) => Op::Const {
+                rd: r(u.rd()),
+                value: u.imm(),
+            },
+            AUIPC(u) => Op::Const {
+                rd: r(u.rd()),
+                value: pc.wrapping_add(u.imm()),
+            },
+            JAL(j) => Op::Jal {
+                rd: r(j.rd()),
+                link: pc.wrapping_add(4),
+                target: aligned(pc.wrapping_add(j.imm()))?,
+            },
+            JALR(i) => Op::Jalr {
+                rd: r(i.rd()),
+                rs1: r(i.rs1()),
+                imm: i.imm(),
+                link: pc.wrapping_add(4),
+            },
+            BEQ(b) | BNE(b) | BLT(b) | BGE(b) | BLTU(b) | BGEU(b) => {
+                let b = B {
+                    rs1: r(b.rs1()),
+                    rs2: r(b.rs2()),
+                    target: aligned(pc.wrapping_add(b.imm()))?,
+                };
+                match instruction {
+                    BEQ(_) => Op::Beq(b),
+                    BNE(_) => Op::Bne(b),
+                    BLT(_) => Op::Blt(b),
+                    BGE(_) => Op::Bge(b),
+                    BLTU(_) => Op::Bltu(b),
+                    _ => Op::Bgeu(b),
+                }
+            }
+            LB(i) | LH(i) | LW(i) | LBU(i) | LHU(i) | ADDI(i) | SLTI(i) | SLTIU(i) | XORI(i)
+            | ORI(i) | ANDI(i) | SLLI(i) | SRLI(i) | SRAI(i) => {
+                let mut i = I {
+                    rd: r(i.rd()),
+                    rs1: r(i.rs1()),
+                    imm: i.imm(),
+                };
+                match instruction {
+                    LB(_) => Op::Lb(i),
+                    LH(_) => Op::Lh(i),
+                    LW(_) => Op::Lw(i),
+                    LBU(_) => Op::Lbu(i),
+                    LHU(_) => Op::Lhu(i),
+                    ADDI(_) => Op::Addi(i),
+                    SLTI(_) => Op::Slti(i),
+                    SLTIU(_) => Op::Sltiu(i),
+                    XORI(_) => Op::Xori(i),
+                    ORI(_) => Op::Ori(i),
+                    ANDI(_) => Op::Andi(i),
+                    _ => {
+                        i.imm &= 0b1_1111;
+                        match instruction {
+                            SLLI(_) => Op::Slli(i),
+                            SRLI(_) => Op::Srli(i),
+                            _ => Op::Srai(i),
+                        }
+                    }
+                }
+            }
+            SB(s) | SH(s) | SW(s) => {
+                let s = S {
+                    rs1: r(s.rs1()),
+                    rs2: r(s.rs2()),
+                    imm: s.imm(),
+                };
+                match instruction {
+                    SB(_) => Op::Sb(s),
+                    SH(_) => Op::Sh(s),
+                    _ => Op::Sw(s),
+                }
+            }
+            ADD(o) | SUB(o) | SLL(o) | SLT(o) | SLTU(o) | XOR(o) | SRL(o) | SRA(o) | OR(o)
+            | AND(o) => {
+                let o = R {
+                    rd: r(o.rd()),
+                    rs1: r(o.rs1()),
+                    rs2: r(o.rs2()),
+                };
+                match instruction {
+                    ADD(_) => Op::Add(o),
+                    SUB(_) => Op::Sub(o),
+                    SLL(_) => Op::Sll(o),
+                    SLT(_) => Op::Slt(o),
+                    SLTU(_) => Op::Sltu(o),
+                    XOR(_) => Op::Xor(o),
+                    SRL(_) => Op::Srl(o),
+                    SRA(_) => Op::Sra(o),
+                    OR(_) => Op::Or(o),
+                    _ => Op::And(o),
+                }
+            }
+            FENCE(_) => Op::Nop,
+            _ => return None,
+        })
+    }
+
+    fn ends_block(&self) -> bool {
+        matches!(
+            self,
+            Op::Jal { .. }
+                | Op::Jalr { .. }
+                | Op::Beq(_)
+                | Op::Bne(_)
+                | Op::Blt(_)
+                | Op::Bge(_)
+                | Op::Bltu(_)
+                | Op::Bgeu(_)
+        )
+    }
+
+    #[inline(always)]
+    fn execute(&self, x: &mut Registers, memory: &mut Memory) -> Exit {
+        // the mask lets the compiler elide the bounds checks
+        let r = |register: u8| register as usize & 0b1_1111;
+        match *self {
+            Op::Const { rd, value } => x[r(rd)] = value,
+            Op::Addi(i) => x[r(i.rd)] = x[r(i.rs1)].wrapping_add(i.imm),
+            Op::Slti(i) => x[r(i.rd)] = ((x[r(i.rs1)] as i32) < (i.imm as i32)) as u32,
+            Op::Sltiu(i) => x[r(i.rd)] = (x[r(i.rs1)] < i.imm) as u32,
+            Op::Xori(i) => x[r(i.rd)] = x[r(i.rs1)] ^ i.imm,
+            Op::Ori(i) => x[r(i.rd)] = x[r(i.rs1)] | i.imm,
+            Op::Andi(i) => x[r(i.rd)] = x[r(i.rs1)] & i.imm,
+            Op::Slli(i) => x[r(i.rd)] = x[r(i.rs1)] << i.imm,
+            Op::Srli(i) => x[r(i.rd)] = x[r(i.rs1)] >> i.imm,
+            // rust uses arithmetic right shift on signed integer types
+            Op::Srai(i) => x[r(i.rd)] = ((x[r(i.rs1)] as i32) >> i.imm) as u32,
+            Op::Add(o) => x[r(o.rd)] = x[r(o.rs1)].wrapping_add(x[r(o.rs2)]),
+            Op::Sub(o) => x[r(o.rd)] = x[r(o.rs1)].wrapping_sub(x[r(o.rs2)]),
+            Op::Sll(o) => x[r(o.rd)] = x[r(o.rs1)] << (x[r(o.rs2)] & 0b1_1111),
+            Op::Slt(o) => x[r(o.rd)] = ((x[r(o.rs1)] as i32) < (x[r(o.rs2)] as i32)) as u32,
+            Op::Sltu(o) => x[r(o.rd)] = (x[r(o.rs1)] < x[r(o.rs2)]) as u32,
+            Op::Xor(o) => x[r(o.rd)] = x[r(o.rs1)] ^ x[r(o.rs2)],
+            Op::Srl(o) => x[r(o.rd)] = x[r(o.rs1)] >> (x[r(o.rs2)] & 0b1_1111),
+            Op::Sra(o) => x[r(o.rd)] = ((x[r(o.rs1)] as i32) >> (x[r(o.rs2)] & 0b1_1111)) as u32,
+            Op::Or(o) => x[r(o.rd)] = x[r(o.rs1)] | x[r(o.rs2)],
+            Op::And(o) => x[r(o.rd)] = x[r(o.rs1)] & x[r(o.rs2)],
+            Op::Lb(i) => match load_byte(memory, x[r(i.rs1)].wrapping_add(i.imm)) {
+                Ok(value) => x[r(i.rd)] = value as i8 as u32,
+                Err(_) => return Exit::Fault,
+            },
+            Op::Lh(i) => match load_half_word(memory, x[r(i.rs1)].wrapping_add(i.imm)) {
+                Ok(value) => x[r(i.rd)] = value as i16 as u32,
+                Err(_) => return Exit::Fault,
+            },
+            Op::Lw(i) => match load_word(memory, x[r(i.rs1)].wrapping_add(i.imm)) {
+                Ok(value) => x[r(i.rd)] = value,
+                Err(_) => return Exit::Fault,
+            },
+            Op::Lbu(i) => match load_byte(memory, x[r(i.rs1)].wrapping_add(i.imm)) {
+                Ok(value) => x[r(i.rd)] = value as u32,
+                Err(_) => return Exit::Fault,
+            },
+            Op::Lhu(i) => match load_half_word(memory, x[r(i.rs1)].wrapping_add(i.imm)) {
+                Ok(value) => x[r(i.rd)] = value as u32,
+                Err(_) => return Exit::Fault,
+            },
+            Op::Sb(s) => {
+                let address = x[r(s.rs1)].wrapping_add(s.imm);
+                return match store_byte(memory, address, x[r(s.rs2)] as u8) {
+                    Ok(()) => Exit::Store(address, 1),
+                    Err(_) => Exit::Fault,
+                };
+            }
+            Op::Sh(s) => {
+                let address = x[r(s.rs1)].wrapping_add(s.imm);
+                return match store_half_word(memory, address, x[r(s.rs2)] as u16) {
+                    Ok(()) => Exit::Store(address, 2),
+                    Err(_) => Exit::Fault,
+                };
+            }
+            Op::Sw(s) => {
+                let address = x[r(s.rs1)].wrapping_add(s.imm);
+                return match store_word(memory, address, x[r(s.rs2)]) {
+                    Ok(()) => Exit::Store(address, 4),
+                    Err(_) => Exit::Fault,
+                };
+            }
+            Op::Nop => {}
+            Op::Jal { rd, link, target } => {
+                x[r(rd)] = link;
+                return Exit::Jump(target);
+            }
+            Op::Jalr { rd, rs1, imm, link } => {
+                let target = x[r(rs1)].wrapping_add(imm) & !1;
+                if target % 4 != 0 {
+                    return Exit::Fault;
+                }
+                x[r(rd)] = link;
+                return Exit::Jump(target);
+            }
+            Op::Beq(b) if x[r(b.rs1)] == x[r(b.rs2)] => return Exit::Jump(b.target),
+            Op::Bne(b) if x[r(b.rs1)] != x[r(b.rs2)] => return Exit::Jump(b.target),
+            Op::Blt(b) if (x[r(b.rs1)] as i32) < (x[r(b.rs2)] as i32) => {
+                return Exit::Jump(b.target)
+            }
+            Op::Bge(b) if (x[r(b.rs1)] as i32) >= (x[r(b.rs2)] as i32) => {
+                return Exit::Jump(b.target)
+            }
+            Op::Bltu(b) if x[r(b.rs1)] < x[r(b.rs2)] => return Exit::Jump(b.target),
+            Op::Bgeu(b) if x[r(b.rs1)] >= x[r(b.rs2)] => return Exit::Jump(b.target),
+            // not taken
+            Op::Beq(_) | Op::Bne(_) | Op::Blt(_) | Op::Bge(_) | Op::Bltu(_) | Op::Bgeu(_) => {}
+        }
+        Exit::Next
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Block {
+    start: u32,
+    /// Empty if the first instruction must be single-stepped.
+    ops: Vec<Op>,
+}
+
+/// The translated blocks of the [`Engine::Blocks`] engine.
+///
+/// Like the [`DecodeCache`], stores to a page which holds translated instructions and FENCE.I
+/// discard the translations, here all of them. Writes to memory which don't go through
+/// [`BlockCache::retire`] require a [`BlockCache::clear`].
+#[derive(Debug, Clone, Default)]
+pub struct BlockCache {
+    /// One entry per word of RAM: the index of the block starting there plus one, or zero.
+    /// Allocated when running the first block.
+    entries: Vec<u32>,
+    blocks: Vec<Block>,
+    /// Whether a page holds translated instructions.
+    code_pages: Vec<bool>,
+}
+
+impl BlockCache {
+    pub fn new() -> BlockCache {
+        BlockCache::default()
+    }
+
+    /// Runs blocks until one has to be single-stepped or would execute more than `limit`
+    /// instructions, or a store writes a non-zero value to `tohost`. Returns the number of retired
+    /// instructions and whether the last one wrote to `tohost`. Stores are also invalidated in
+    /// `decode_cache`.
+    pub(crate) fn run(
+        &mut self,
+        registers: &mut Registers,
+        memory: &mut Memory,
+        decode_cache: &mut DecodeCache,
+        limit: u64,
+        tohost: Option<u32>,
+    ) -> (u64, bool) {
+        if self.entries.is_empty() {
+            self.entries = vec![0; memory.len() / 4];
+            self.code_pages = vec![false; memory.len().div_ceil(PAGE_SIZE)];
+        }
+        let mut retired = 0;
+        loop {
+            let offset = registers[PC].wrapping_sub(MEMORY_START as u32) as usize;
+            if offset % 4 != 0 || offset / 4 >= self.entries.len() {
+                return (retired, false);
+            }
+            let index = match self.entries[offset / 4] {
+                0 => self.translate(registers[PC], memory),
+                entry => entry as usize - 1,
+            };
+            let block = &self.blocks[index];
+            if block.ops.is_empty() || block.ops.len() as u64 > limit - retired {
+                return (retired, false);
+            }
+
+            let mut next_pc = block.start.wrapping_add(4 * block.ops.len() as u32);
+            let mut invalidate = false;
+            let mut done = false;
+            for (i, op) in block.ops.iter().enumerate() {
+                let exit = op.execute(registers, memory);
+                registers[0] = 0;
+                match exit {
+                    Exit::Next => {}
+                    Exit::Jump(target) => next_pc = target,
+                    Exit::Store(address, size) => {
+                        decode_cache.invalidate(address, size);
+                        // the rest of this block may be stale
+                        invalidate = self.is_code(address, size);
+                        done = tohost == Some(address)
+                            && (0..size).any(|byte| load_byte(memory, address + byte) != Ok(0));
+                        if invalidate || done {
+                            next_pc = block.start.wrapping_add(4 * i as u32 + 4);
+                            retired += i as u64 + 1;
+                            break;
+                        }
+                    }
+                    Exit::Fault => {
+                        // single-stepping the op takes the trap or reports the error
+                        registers[PC] = block.start.wrapping_add(4 * i as u32);
+                        return (retired + i as u64, false);
+                    }
+                }
+            }
+            if invalidate {
+                self.clear();
+            } else if !done {
+                retired += block.ops.len() as u64;
+            }
+            registers[PC] = next_pc;
+            if done {
+                return (retired, true);
+            }
+        }
+    }
+
+    /// Translates the block starting at `pc`, which is a valid, aligned address. Returns its
+    /// index.
+    fn translate(&mut self, pc: u32, memory: &Memory) -> usize {
+        let mut ops = Vec::new();
+        let mut address = pc;
+        while ops.len() < MAX_BLOCK_LENGTH {
+            let Some(op) = load_word(memory, address)
+                .ok()
+                .and_then(decode)
+                .and_then(|instruction| Op::translate(address, instruction))
+            else {
+                break;
+            };
+            ops.push(op);
+            if op.ends_block() {
+                break;
+            }
+            address = address.wrapping_add(4);
+        }
+        if !ops.is_empty() {
+            let offset = pc.wrapping_sub(MEMORY_START as u32) as usize;
+            for page in [offset, offset + 4 * ops.len() - 1] {
+                self.code_pages[page / PAGE_SIZE] = true;
+            }
+        }
+        self.blocks.push(Block { start: pc, ops });
+        let index = self.blocks.len() - 1;
+        self.entries[pc.wrapping_sub(MEMORY_START as u32) as usize / 4] = index as u32 + 1;
+        index
+    }
+
+    fn is_code(&self, address: u32, size: u32) -> bool {
+        let offset = address.wrapping_sub(MEMORY_START as u32) as usize;
+        [offset, offset.saturating_add(size as usize - 1)]
+            .iter()
+            .any(|offset| self.code_pages.get(offset / PAGE_SIZE) == Some(&true))
+    }
+
+    /// Discards what the retired instruction made stale.
+    pub fn retire(&mut self, retired: &Retired) {
+        if let Instruction::FENCE_I(_) = retired.instruction {
+            self.clear();
+        }
+        if let Some(store) = retired.store {
+            self.invalidate(store.address, store.size);
+        }
+    }
+
+    /// Discards all blocks if the `size` bytes at `address` hold translated instructions.
+    pub fn invalidate(&mut self, address: u32, size: u32) {
+        if self.is_code(address, size) {
+            self.clear();
+        }
+    }
+
+    pub fn clear(&mut self) {
+        if !self.blocks.is_empty() {
+            self.entries.fill(0);
+            self.blocks.clear();
+            self.code_pages.fill(false);
+        }
+    }
+}
+
+/// The blocks hold no architectural state, see [`DecodeCache`].
+impl PartialEq for BlockCache {
+    fn eq(&self, _: &BlockCache) -> bool {
+        true
+    }
+}
+
+impl Eq for BlockCache {}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        programs::{BUBBLE_SORT, FIBONACCI},
+        test_utils, Engine, Error, History, Machine, MEMORY_START, PC,
+    };
+
+    // both programs return to `ra` = 0, which fails to fetch
+    fn machine(program: &[u32], engine: Engine) -> Machine {
+        let mut machine = test_utils::machine(program);
+        machine.registers[2] = MEMORY_START as u32 + 0xa0;
+        machine.engine = engine;
+        machine
+    }
+
+    /// Runs `program` with both engines and checks that they end in the same state.
+    fn run(program: &[u32], limit: u64) -> (Machine, Result<bool, Error>) {
+        let mut interpreter = machine(program, Engine::Interpreter);
+        let mut blocks = machine(program, Engine::Blocks);
+        let expected = interpreter.run(limit);
+        let actual = blocks.run(limit);
+        assert_eq!(actual, expected);
+        assert_eq!(blocks.registers, interpreter.registers);
+        assert!(blocks.memory == interpreter.memory);
+        assert_eq!(blocks.instret, interpreter.instret);
+        (blocks, actual)
+    }
+
+    #[test]
+    fn same_as_interpreter() {
+        let (machine, result) = run(BUBBLE_SORT, u64::MAX);
+        assert!(matches!(result, Err(Error::MemoryError { pc: 0, .. })));
+        assert_eq!(
+            machine.memory[0x90..0xa0],
+            [1, 0, 0, 0, 2, 0, 0, 0, 4, 0, 0, 0, 5, 0, 0, 0]
+        );
+        let (machine, _) = run(FIBONACCI, u64::MAX);
+        assert_eq!(machine.registers[10], 55);
+    }
+
+    #[test]
+    fn limit() {
+        // stop at every instruction until the program ends
+        for limit in 0.. {
+            let (machine, result) = run(BUBBLE_SORT, limit);
+            if result.is_err() {
+                assert_eq!(limit, 64);
+                break;
+            }
+            assert_eq!(machine.instret, limit);
+        }
+    }
+
+    #[test]
+    fn self_modifying_block() {
+        let (machine, result) = run(
+            &[
+                0x00000297, // auipc t0, 0
+                0x01050337, // lui t1, 0x1050
+                0x51330313, // addi t1, t1, 0x513 (t1 = `addi a0, a0, 16`)
+                0x0062a823, // sw t1, 16(t0)
+                0x00150513, // addi a0, a0, 1, overwritten in the same block
+                0xc0001073, // unimp
+            ],
+            u64::MAX,
+        );
+        assert_eq!(result, Ok(true));
+        assert_eq!(machine.registers[10], 16);
+    }
+
+    #[test]
+    fn fault_in_block() {
+        let (machine, result) = run(
+            &[
+                0x00550513, // addi a0, a0, 5
+                0x00002583, // lw a1, 0(zero)
+            ],
+            u64::MAX,
+        );
+        assert!(matches!(
+            result,
+            Err(Error::MemoryError { pc, address: 0, .. }) if pc == MEMORY_START as u32 + 4
+        ));
+        assert_eq!(machine.registers[PC], MEMORY_START as u32 + 4);
+        assert_eq!(machine.registers[10], 5);
+        assert_eq!(machine.instret, 1);
+    }
+
+    #[test]
+    fn history_single_steps() {
+        let mut machine = machine(BUBBLE_SORT, Engine::Blocks);
+        machine.history = History::new(100);
+        machine.run(50).unwrap();
+        assert_eq!(machine.history.len(), 50);
+        assert!(machine.run_back_to(MEMORY_START as u32));
+        assert_eq!(machine.instret, 0);
+    }
+}
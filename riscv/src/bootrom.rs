@@ -0,0 +1,169 @@
+//! A read-only boot ROM which hands over to a payload the way kernels and OpenSBI expect: it
+//! jumps to the payload's entry point with the hart ID in `a0` and the address of a device tree
+//! blob in `a1`. The ROM holds its code, the entry point and the blob:
+//!
+//! | Offset | Contents                   |
+//! |--------|----------------------------|
+//! | `0x0`  | `auipc t0, 0`              |
+//! | `0x4`  | `addi a1, t0, 32`          |
+//! | `0x8`  | `csrr a0, mhartid`         |
+//! | `0xc`  | `lw t0, 24(t0)`            |
+//! | `0x10` | `jr t0`                    |
+//! | `0x18` | the entry point            |
+//! | `0x20` | the device tree blob       |
+//!
+//! Instructions are fetched from the ROM, and it takes loads of bytes, halfwords and words, while
+//! stores fault.
+//!
+//! [`Machine::boot`] installs the ROM with the [device tree](crate::dtb) of the machine and the
+//! loaded payload's entry point.
+
+use crate::{devices::BOOT_ROM_START, dtb::device_tree, Machine, PC};
+
+/// The size of the ROM's address range.
+pub const BOOT_ROM_SIZE: u32 = 0xf000;
+/// The offset of the entry point.
+const ENTRY: usize = 0x18;
+/// The offset of the device tree blob.
+pub const DTB: u32 = 0x20;
+
+const CODE: [u32; 5] = [
+    0x0000_0297,
+    0x0202_8593,
+    0xf140_2573,
+    0x0182_a283,
+    0x0002_8067,
+];
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ConfigError {
+    /// The device tree blob doesn't fit into the ROM.
+    TooLarge,
+}
+
+impl std::fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            ConfigError::TooLarge => write!(
+                f,
+                "The device tree is larger than {} bytes",
+                BOOT_ROM_SIZE - DTB
+            ),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BootRom {
+    data: Vec<u8>,
+}
+
+impl BootRom {
+    /// A ROM which jumps to `entry` with `dtb` as the device tree.
+    pub fn new(entry: u32, dtb: &[u8]) -> Result<BootRom, ConfigError> {
+        if dtb.len() > (BOOT_ROM_SIZE - DTB) as usize {
+            return Err(ConfigError::TooLarge);
+        }
+        let mut data = CODE
+            .iter()
+            .flat_map(|code| code.to_le_bytes())
+            .collect::<Vec<u8>>();
+        data.resize(ENTRY, 0);
+        data.extend(entry.to_le_bytes());
+        data.resize(DTB as usize, 0);
+        data.extend(dtb);
+        Ok(BootRom { data })
+    }
+
+    /// Restores a ROM from its `data`, e.g., from a snapshot.
+    pub fn from_data(data: Vec<u8>) -> Result<BootRom, ConfigError> {
+        if data.len() > BOOT_ROM_SIZE as usize {
+            return Err(ConfigError::TooLarge);
+        }
+        Ok(BootRom { data })
+    }
+
+    /// The ROM's contents, which the rest of its address range reads as zeros.
+    pub fn data(&self) -> &[u8] {
+        &self.data
+    }
+
+    /// The entry point of the payload.
+    pub fn entry(&self) -> u32 {
+        self.load(ENTRY as u32, 4).unwrap_or(0)
+    }
+
+    /// Reads the `size` bytes at `offset`, `None` for misaligned accesses.
+    pub fn load(&self, offset: u32, size: u32) -> Option<u32> {
+        if !matches!(size, 1 | 2 | 4) || offset % size != 0 || offset >= BOOT_ROM_SIZE {
+            return None;
+        }
+        let offset = offset as usize;
+        Some((0..size as usize).rev().fold(0, |value, index| {
+            value << 8 | u32::from(self.data.get(offset + index).copied().unwrap_or(0))
+        }))
+    }
+}
+
+impl Machine {
+    /// Installs a boot ROM which jumps to the pc, e.g., the entry point of the loaded payload, with
+    /// the machine's device tree and `bootargs` as the kernel's command line, and starts executing
+    /// it. Call it after adding the optional devices, which the device tree describes.
+    pub fn boot(&mut self, bootargs: &str) -> Result<(), ConfigError> {
+        let dtb = device_tree(self, bootargs);
+        self.devices.rom = Some(BootRom::new(self.registers[PC], &dtb)?);
+        self.registers[PC] = BOOT_ROM_START;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        super::*,
+        crate::{
+            test_utils::{self, UNIMP},
+            Engine, MEMORY_START,
+        },
+    };
+
+    #[test]
+    fn contents() {
+        let rom = BootRom::new(0x8000_0000, &[0xd0, 0x0d, 0xfe, 0xed]).unwrap();
+        assert_eq!(rom.load(0x0, 4), Some(0x0000_0297));
+        assert_eq!(rom.load(0x10, 4), Some(0x0002_8067));
+        assert_eq!(rom.entry(), 0x8000_0000);
+        assert_eq!(rom.load(DTB, 4), Some(0xedfe_0dd0));
+        assert_eq!(rom.load(DTB, 2), Some(0x0dd0));
+        assert_eq!(rom.load(DTB + 3, 1), Some(0xed));
+        // past the blob
+        assert_eq!(rom.load(0x100, 4), Some(0));
+        assert_eq!(rom.load(DTB + 1, 2), None);
+        assert_eq!(rom.load(BOOT_ROM_SIZE, 1), None);
+        assert_eq!(
+            BootRom::new(0, &vec![0; (BOOT_ROM_SIZE - DTB) as usize + 1]),
+            Err(ConfigError::TooLarge)
+        );
+    }
+
+    #[test]
+    fn boot() {
+        for engine in [Engine::Interpreter, Engine::Blocks] {
+            // lw t1, 0(a1)
+            let mut machine = test_utils::machine(&[0x0005_a303, UNIMP]);
+            machine.engine = engine;
+            let start = MEMORY_START as u32;
+            machine.boot("console=ttyS0").unwrap();
+            assert_eq!(machine.registers[PC], BOOT_ROM_START);
+            assert_eq!(machine.run(10), Ok(true));
+            assert_eq!(machine.registers[PC], start + 8);
+            // a0, a1 and t1
+            assert_eq!(machine.registers[10], 0);
+            assert_eq!(machine.registers[11], BOOT_ROM_START + DTB);
+            assert_eq!(machine.registers[6], 0xedfe_0dd0);
+            assert_eq!(machine.instret, 7);
+        }
+    }
+}
@@ -0,0 +1,438 @@
+//! Models of L1 instruction and data caches, which only track which lines they hold. RAM stays
+//! the single copy of the data, so caches never change the behavior of a program, only their
+//! statistics tell how it would have performed.
+
+use crate::Retired;
+
+/// Which line of a set is replaced on a miss if all of them are valid.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Replacement {
+    /// The least recently used line.
+    #[default]
+    Lru,
+    /// The line filled first.
+    Fifo,
+    /// A pseudo-random line, the same for every run.
+    Random,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum WritePolicy {
+    /// Writes only mark the line dirty, it is written to memory when it is evicted. Write misses
+    /// allocate a line.
+    #[default]
+    WriteBack,
+    /// Every write goes to memory. Write misses don't allocate a line.
+    WriteThrough,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CacheConfig {
+    /// The capacity in bytes.
+    pub size: usize,
+    /// The number of lines per set, 1 for a direct-mapped cache.
+    pub ways: usize,
+    /// The size of a line in bytes.
+    pub line_size: usize,
+    pub replacement: Replacement,
+    pub write_policy: WritePolicy,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ConfigError {
+    /// The size, the number of ways or the line size is not a power of two.
+    NotPowerOfTwo,
+    /// The size is smaller than a single set.
+    TooSmall,
+}
+
+impl std::fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            ConfigError::NotPowerOfTwo => {
+                f.write_str("The size, ways and line size of a cache must be powers of two")
+            }
+            ConfigError::TooSmall => f.write_str("The cache is smaller than ways * line size"),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Stats {
+    pub reads: u64,
+    pub read_misses: u64,
+    pub writes: u64,
+    pub write_misses: u64,
+    /// Dirty lines written to memory when they were evicted.
+    pub writebacks: u64,
+}
+
+impl Stats {
+    pub fn accesses(&self) -> u64 {
+        self.reads + self.writes
+    }
+
+    pub fn misses(&self) -> u64 {
+        self.read_misses + self.write_misses
+    }
+
+    /// The share of accesses which hit, 0 if there were none.
+    pub fn hit_rate(&self) -> f64 {
+        match self.accesses() {
+            0 => 0.0,
+            accesses => 1.0 - self.misses() as f64 / accesses as f64,
+        }
+    }
+}
+
+/// A single access of a cache, e.g., for rendering it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Access {
+    pub address: u32,
+    pub write: bool,
+    pub set: usize,
+    /// The way holding the line after the access, `None` for write misses which don't allocate.
+    pub way: Option<usize>,
+    pub hit: bool,
+    /// The address of the line which was replaced, and whether it was dirty.
+    pub evicted: Option<(u32, bool)>,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+struct Line {
+    valid: bool,
+    dirty: bool,
+    tag: u32,
+    /// When the line was last used (LRU) or filled (FIFO), in accesses.
+    stamp: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Cache {
+    config: CacheConfig,
+    /// `ways` lines per set.
+    lines: Vec<Line>,
+    pub stats: Stats,
+    /// The most recent access.
+    pub last_access: Option<Access>,
+    /// The state of the xorshift generator for [`Replacement::Random`].
+    random: u32,
+}
+
+impl Cache {
+    pub fn new(config: CacheConfig) -> Result<Cache, ConfigError> {
+        let CacheConfig {
+            size,
+            ways,
+            line_size,
+            ..
+        } = config;
+        if ![size, ways, line_size].iter().all(|x| x.is_power_of_two()) {
+            return Err(ConfigError::NotPowerOfTwo);
+        }
+        if size < ways * line_size {
+            return Err(ConfigError::TooSmall);
+        }
+        Ok(Cache {
+            config,
+            lines: vec![Line::default(); size / line_size],
+            stats: Stats::default(),
+            last_access: None,
+            random: 0x2545_f491,
+        })
+    }
+
+    pub fn config(&self) -> &CacheConfig {
+        &self.config
+    }
+
+    pub fn sets(&self) -> usize {
+        self.lines.len() / self.config.ways
+    }
+
+    /// Returns the address of the line and whether it is dirty for each way of `set`, `None` for
+    /// invalid lines.
+    pub fn set(&self, set: usize) -> Vec<Option<(u32, bool)>> {
+        let ways = self.config.ways;
+        self.lines[set * ways..(set + 1) * ways]
+            .iter()
+            .map(|line| {
+                line.valid
+                    .then(|| (self.line_address(line.tag, set), line.dirty))
+            })
+            .collect()
+    }
+
+    fn line_address(&self, tag: u32, set: usize) -> u32 {
+        (tag * self.sets() as u32 + set as u32) * self.config.line_size as u32
+    }
+
+    /// Looks up the line containing `address`, filling it on a miss. Accesses are attributed to
+    /// the line of their first byte.
+    pub fn access(&mut self, address: u32, write: bool) -> Access {
+        let line_number = address / self.config.line_size as u32;
+        let sets = self.sets();
+        let set = line_number as usize % sets;
+        let tag = line_number / sets as u32;
+        let ways = self.config.ways;
+        let stamp = self.stats.accesses();
+        let replacement = self.config.replacement;
+        let write_back = self.config.write_policy == WritePolicy::WriteBack;
+        if write {
+            self.stats.writes += 1;
+        } else {
+            self.stats.reads += 1;
+        }
+
+        let lines = &mut self.lines[set * ways..(set + 1) * ways];
+        let access = if let Some(way) = lines.iter().position(|line| line.valid && line.tag == tag)
+        {
+            let line = &mut lines[way];
+            if replacement == Replacement::Lru {
+                line.stamp = stamp;
+            }
+            line.dirty |= write && write_back;
+            Access {
+                address,
+                write,
+                set,
+                way: Some(way),
+                hit: true,
+                evicted: None,
+            }
+        } else {
+            if write {
+                self.stats.write_misses += 1;
+            } else {
+                self.stats.read_misses += 1;
+            }
+            if write && !write_back {
+                Access {
+                    address,
+                    write,
+                    set,
+                    way: None,
+                    hit: false,
+                    evicted: None,
+                }
+            } else {
+                let way = match lines.iter().position(|line| !line.valid) {
+                    Some(way) => way,
+                    None if replacement == Replacement::Random => {
+                        self.random ^= self.random << 13;
+                        self.random ^= self.random >> 17;
+                        self.random ^= self.random << 5;
+                        self.random as usize % ways
+                    }
+                    None => (0..ways).min_by_key(|&way| lines[way].stamp).unwrap(),
+                };
+                let old = std::mem::replace(
+                    &mut lines[way],
+                    Line {
+                        valid: true,
+                        dirty: write,
+                        tag,
+                        stamp,
+                    },
+                );
+                let evicted = old
+                    .valid
+                    .then(|| (self.line_address(old.tag, set), old.dirty));
+                if old.valid && old.dirty {
+                    self.stats.writebacks += 1;
+                }
+                Access {
+                    address,
+                    write,
+                    set,
+                    way: Some(way),
+                    hit: false,
+                    evicted,
+                }
+            }
+        };
+        self.last_access = Some(access);
+        access
+    }
+
+    /// Invalidates all lines without writing back dirty ones, the statistics are kept.
+    pub fn invalidate(&mut self) {
+        self.lines.fill(Line::default());
+        self.last_access = None;
+    }
+}
+
+/// The L1 caches of the hart, both disabled by default.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Caches {
+    pub instruction: Option<Cache>,
+    pub data: Option<Cache>,
+}
+
+impl Caches {
+    pub fn enabled(&self) -> bool {
+        self.instruction.is_some() || self.data.is_some()
+    }
+
+    /// Simulates the accesses of a retired instruction.
+    pub fn retire(&mut self, retired: &Retired) {
+        if let Some(cache) = &mut self.instruction {
+            cache.access(retired.pc, false);
+        }
+        if let Some(cache) = &mut self.data {
+            if let Some(address) = retired.load {
+                cache.access(address, false);
+            } else if let Some(store) = retired.store {
+                cache.access(store.address, true);
+            } else {
+                cache.last_access = None;
+            }
+        }
+    }
+
+    pub fn invalidate(&mut self) {
+        for cache in [&mut self.instruction, &mut self.data]
+            .into_iter()
+            .flatten()
+        {
+            cache.invalidate();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        cache::{Cache, CacheConfig, Caches, ConfigError, Replacement, Stats, WritePolicy},
+        test_utils, MEMORY_START,
+    };
+
+    fn cache(size: usize, ways: usize, replacement: Replacement) -> Cache {
+        Cache::new(CacheConfig {
+            size,
+            ways,
+            line_size: 16,
+            replacement,
+            write_policy: WritePolicy::WriteBack,
+        })
+        .unwrap()
+    }
+
+    /// Returns which of the reads of `addresses` hit.
+    fn hits(cache: &mut Cache, addresses: &[u32]) -> Vec<bool> {
+        addresses
+            .iter()
+            .map(|&address| cache.access(address, false).hit)
+            .collect()
+    }
+
+    #[test]
+    fn direct_mapped() {
+        // 4 sets of 16 bytes, 0x00 and 0x40 map to the same set
+        let mut cache = cache(64, 1, Replacement::Lru);
+        assert_eq!(
+            hits(&mut cache, &[0x00, 0x04, 0x10, 0x40, 0x00, 0x1c]),
+            [false, true, false, false, false, true]
+        );
+        assert_eq!(cache.stats.read_misses, 4);
+        assert_eq!(cache.set(0), [Some((0x00, false))]);
+    }
+
+    #[test]
+    fn replacement() {
+        // a single set with 2 ways, 0x00, 0x10 and 0x20 map to it
+        let addresses = [0x00, 0x10, 0x00, 0x20, 0x00, 0x10];
+        let mut lru = cache(32, 2, Replacement::Lru);
+        assert_eq!(
+            hits(&mut lru, &addresses),
+            [false, false, true, false, true, false]
+        );
+        let mut fifo = cache(32, 2, Replacement::Fifo);
+        assert_eq!(
+            hits(&mut fifo, &addresses),
+            [false, false, true, false, false, false]
+        );
+        // deterministic
+        let mut random = cache(32, 2, Replacement::Random);
+        let mut again = random.clone();
+        assert_eq!(hits(&mut random, &addresses), hits(&mut again, &addresses));
+    }
+
+    #[test]
+    fn write_policies() {
+        let mut write_back = cache(32, 1, Replacement::Lru);
+        write_back.access(0x00, true);
+        assert_eq!(write_back.set(0), [Some((0x00, true))]);
+        let access = write_back.access(0x20, false);
+        assert_eq!(access.evicted, Some((0x00, true)));
+        assert_eq!(
+            write_back.stats,
+            Stats {
+                reads: 1,
+                read_misses: 1,
+                writes: 1,
+                write_misses: 1,
+                writebacks: 1,
+            }
+        );
+
+        let mut write_through = Cache::new(CacheConfig {
+            write_policy: WritePolicy::WriteThrough,
+            ..*write_back.config()
+        })
+        .unwrap();
+        let access = write_through.access(0x00, true);
+        assert_eq!(access.way, None);
+        assert_eq!(write_through.set(0), [None]);
+        write_through.access(0x00, false);
+        write_through.access(0x00, true);
+        assert_eq!(write_through.set(0), [Some((0x00, false))]);
+    }
+
+    #[test]
+    fn invalid_config() {
+        let config = CacheConfig {
+            size: 64,
+            ways: 2,
+            line_size: 16,
+            replacement: Replacement::Lru,
+            write_policy: WritePolicy::WriteBack,
+        };
+        assert_eq!(
+            Cache::new(CacheConfig { size: 48, ..config }),
+            Err(ConfigError::NotPowerOfTwo)
+        );
+        assert_eq!(
+            Cache::new(CacheConfig { ways: 8, ..config }),
+            Err(ConfigError::TooSmall)
+        );
+    }
+
+    #[test]
+    fn machine() {
+        let mut machine = test_utils::machine(&[
+            0x00012503, // lw a0, 0(sp)
+            0x00a12223, // sw a0, 4(sp)
+            0xff9ff06f, // j 0
+        ]);
+        machine.registers[2] = MEMORY_START as u32 + 0x100;
+        machine.caches = Caches {
+            instruction: Some(cache(64, 1, Replacement::Lru)),
+            data: Some(cache(64, 1, Replacement::Lru)),
+        };
+        machine.run(30).unwrap();
+        let Caches { instruction, data } = &machine.caches;
+        let (instruction, data) = (instruction.as_ref().unwrap(), data.as_ref().unwrap());
+        assert_eq!(
+            (instruction.stats.reads, instruction.stats.misses()),
+            (30, 1)
+        );
+        assert_eq!(
+            (data.stats.reads, data.stats.writes, data.stats.misses()),
+            (10, 10, 1)
+        );
+        assert_eq!(data.last_access, None);
+    }
+}
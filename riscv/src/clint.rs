@@ -0,0 +1,203 @@
+//! The core-local interruptor of SiFive's cores, which most RISC-V platforms copy. `msip` raises
+//! the machine software interrupt, and the machine timer interrupt is pending while `mtime` is at
+//! least `mtimecmp`.
+//!
+//! `mtime` ticks once per cycle of the machine. Like `mcycle`, it is derived from the machine's
+//! cycles, so stepping back also turns back time.
+
+use crate::csr::Interrupt;
+
+/// The size of the CLINT's address range.
+pub const CLINT_SIZE: u32 = 0x10000;
+
+// register offsets
+pub const MSIP: u32 = 0x0;
+pub const MTIMECMP: u32 = 0x4000;
+pub const MTIME: u32 = 0xbff8;
+
+/// The CLINT of a single hart.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Clint {
+    pub msip: bool,
+    /// `u64::MAX` after reset, so the timer interrupt isn't pending.
+    pub mtimecmp: u64,
+    /// Added to the machine's cycles by `mtime`, so it can be written.
+    pub time_offset: u64,
+}
+
+impl Default for Clint {
+    fn default() -> Clint {
+        Clint {
+            msip: false,
+            mtimecmp: u64::MAX,
+            time_offset: 0,
+        }
+    }
+}
+
+impl Clint {
+    pub fn mtime(&self, cycle: u64) -> u64 {
+        cycle.wrapping_add(self.time_offset)
+    }
+
+    /// The bits of the pending interrupts in `mip`.
+    pub fn interrupts(&self, cycle: u64) -> u32 {
+        let software = if self.msip {
+            Interrupt::MachineSoftware.mask()
+        } else {
+            0
+        };
+        let timer = if self.mtime(cycle) >= self.mtimecmp {
+            Interrupt::MachineTimer.mask()
+        } else {
+            0
+        };
+        software | timer
+    }
+
+    /// The number of cycles until the timer interrupt will be pending, `None` if never, since
+    /// `mtimecmp` is `u64::MAX`.
+    pub fn until_timer(&self, cycle: u64) -> Option<u64> {
+        (self.mtimecmp != u64::MAX).then(|| self.mtimecmp.saturating_sub(self.mtime(cycle)))
+    }
+
+    /// Reads the 32-bit register at `offset`, `None` if there is none.
+    pub fn load(&self, offset: u32, cycle: u64) -> Option<u32> {
+        let mtime = self.mtime(cycle);
+        Some(match offset {
+            MSIP => self.msip as u32,
+            MTIMECMP => self.mtimecmp as u32,
+            0x4004 => (self.mtimecmp >> 32) as u32,
+            MTIME => mtime as u32,
+            0xbffc => (mtime >> 32) as u32,
+            _ => return None,
+        })
+    }
+
+    /// Writes the 32-bit register at `offset`, `None` if there is none.
+    pub fn store(&mut self, offset: u32, value: u32, cycle: u64) -> Option<()> {
+        let half = |old: u64, high: bool| {
+            if high {
+                old & 0xffff_ffff | (value as u64) << 32
+            } else {
+                old & !0xffff_ffff | value as u64
+            }
+        };
+        match offset {
+            // only the lowest bit is implemented
+            MSIP => self.msip = value & 1 == 1,
+            MTIMECMP | 0x4004 => self.mtimecmp = half(self.mtimecmp, offset == 0x4004),
+            MTIME | 0xbffc => {
+                let mtime = half(self.mtime(cycle), offset == 0xbffc);
+                self.time_offset = mtime.wrapping_sub(cycle);
+            }
+            _ => return None,
+        }
+        Some(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        super::*,
+        crate::{blocks::Engine, store_word, test_utils, History, Machine, MEMORY_START, PC},
+    };
+
+    #[test]
+    fn registers() {
+        let mut clint = Clint::default();
+        assert_eq!(clint.interrupts(u64::MAX - 1), 0);
+        assert_eq!(clint.until_timer(0), None);
+
+        assert_eq!(clint.store(MSIP, 3, 0), Some(()));
+        assert_eq!(clint.load(MSIP, 0), Some(1));
+        assert_eq!(clint.interrupts(0), Interrupt::MachineSoftware.mask());
+        assert_eq!(clint.store(MSIP + 4, 1, 0), None);
+        assert_eq!(clint.load(MSIP + 4, 0), None);
+
+        // mtime is written half by half and keeps ticking with the cycles
+        assert_eq!(clint.store(MTIME + 4, 1, 10), Some(()));
+        assert_eq!(clint.mtime(10), 0x1_0000_000a);
+        assert_eq!(clint.store(MTIME, 0, 20), Some(()));
+        assert_eq!(clint.load(MTIME, 25), Some(5));
+        assert_eq!(clint.load(MTIME + 4, 25), Some(1));
+
+        assert_eq!(clint.store(MTIMECMP, 100, 0), Some(()));
+        assert_eq!(clint.store(MTIMECMP + 4, 1, 0), Some(()));
+        assert_eq!(clint.mtimecmp, 0x1_0000_0064);
+        assert_eq!(clint.until_timer(20), Some(100));
+        assert_eq!(clint.interrupts(119), Interrupt::MachineSoftware.mask());
+        assert_eq!(
+            clint.interrupts(120),
+            Interrupt::MachineSoftware.mask() | Interrupt::MachineTimer.mask()
+        );
+        assert_eq!(clint.until_timer(200), Some(0));
+    }
+
+    // Arms the timer, enables its interrupt and waits for it. The handler disarms the timer.
+    const TIMER: &[u32] = &[
+        0x00000297, // la t0, handler
+        0x03428293, //
+        0x30529073, // csrw mtvec, t0
+        0x020042b7, // li t0, 0x02004000
+        0x3e800313, // li t1, 1000
+        0x0062a023, // sw t1, 0(t0)
+        0x0002a223, // sw zero, 4(t0)
+        0x08000293, // li t0, 0x80
+        0x3042a073, // csrs mie, t0
+        0x30046073, // csrsi mstatus, 8
+        0x10500073, // wfi
+        0x00100513, // li a0, 1
+        0xc0001073, // unimp
+        // handler
+        0x342025f3, // csrr a1, mcause
+        0xc0102673, // rdtime a2
+        0x020042b7, // li t0, 0x02004004
+        0x00428293, //
+        0xfff00313, // li t1, -1
+        0x0062a023, // sw t1, 0(t0)
+        0x30200073, // mret
+    ];
+
+    #[test]
+    fn timer_interrupt() {
+        for engine in [Engine::Interpreter, Engine::Blocks] {
+            let mut machine = test_utils::machine(TIMER);
+            machine.engine = engine;
+            let start = MEMORY_START as u32;
+            machine.history = History::new(usize::MAX);
+
+            assert_eq!(machine.run(100), Ok(true));
+            assert_eq!(machine.registers[10], 1);
+            assert_eq!(machine.registers[11], Interrupt::MachineTimer.cause());
+            // WFI slept until the timer fired
+            assert!((1000..1010).contains(&machine.registers[12]));
+            assert_eq!(machine.csrs.mepc, start + 0x2c);
+            assert_eq!(machine.devices.clint.mtimecmp, u64::MAX << 32 | 1000);
+            assert_eq!(machine.instret, 11 + 7 + 2);
+            assert!(machine.cycle >= 1000);
+
+            // stepping back turns back time, but the CLINT keeps its state
+            while machine.step_back() {}
+            assert_eq!((machine.registers[PC], machine.cycle), (start, 0));
+        }
+    }
+
+    #[test]
+    fn distant_timer() {
+        let mut machine = Machine::new();
+        machine.history = History::new(usize::MAX);
+        store_word(&mut machine.memory, MEMORY_START as u32, 0x10500073).unwrap(); // wfi
+        machine.csrs.mie = Interrupt::MachineTimer.mask();
+        machine.cycle = 1000;
+        // `mtime` was written back to 0
+        machine.devices.clint.time_offset = 0u64.wrapping_sub(1000);
+        machine.devices.clint.mtimecmp = u64::MAX - 1;
+
+        assert_eq!(machine.step(), Ok(false));
+        assert_eq!(machine.cycle, u64::MAX);
+        assert!(machine.step_back());
+        assert_eq!(machine.cycle, 1000);
+    }
+}
@@ -0,0 +1,153 @@
+//! Instruction and branch coverage of guest programs, exported as lcov tracefiles with the line
+//! table of the program's debug information (see [`elf::lines`](crate::elf::lines)).
+
+use {
+    crate::{decode, elf::LineRange, load_word, Instruction, Memory, Retired},
+    std::{
+        collections::{BTreeMap, HashMap},
+        fmt::Write,
+    },
+};
+
+/// Records how often each instruction was executed and which way each conditional branch went.
+/// Stepping back doesn't revert the counts.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Coverage {
+    instructions: HashMap<u32, u64>,
+    /// How often the branch at each pc was taken and not taken.
+    branches: HashMap<u32, [u64; 2]>,
+}
+
+/// The coverage of a source line.
+#[derive(Debug, Default)]
+struct Line {
+    /// The executions of its most frequently executed instruction.
+    count: u64,
+    /// How often each of its branches was taken and not taken, `None` if it never executed.
+    branches: Vec<Option<[u64; 2]>>,
+}
+
+impl Coverage {
+    pub fn retire(&mut self, retired: &Retired) {
+        *self.instructions.entry(retired.pc).or_default() += 1;
+        if is_branch(&retired.instruction) {
+            let taken = retired.next_pc != retired.pc.wrapping_add(4);
+            self.branches.entry(retired.pc).or_default()[!taken as usize] += 1;
+        }
+    }
+
+    /// How often the instruction at `pc` was executed.
+    pub fn count(&self, pc: u32) -> u64 {
+        self.instructions.get(&pc).copied().unwrap_or(0)
+    }
+
+    /// How often the branch at `pc` was taken and not taken, `None` if it never executed.
+    pub fn branch(&self, pc: u32) -> Option<[u64; 2]> {
+        self.branches.get(&pc).copied()
+    }
+
+    /// Returns the coverage of the source lines in `lines` as an lcov tracefile, with the line
+    /// counts (`DA`) and the taken and not taken edges of each branch (`BRDA`). `memory` holds
+    /// the program, to find the branches which never executed.
+    pub fn lcov(&self, lines: &[LineRange], memory: &Memory) -> String {
+        let mut files: BTreeMap<&str, BTreeMap<u32, Line>> = BTreeMap::new();
+        for range in lines {
+            let line = files
+                .entry(&range.file)
+                .or_default()
+                .entry(range.line)
+                .or_default();
+            for pc in (range.start..range.end).step_by(4) {
+                line.count = line.count.max(self.count(pc));
+                let code = load_word(memory, pc).unwrap_or(0);
+                if decode(code).is_some_and(|instruction| is_branch(&instruction)) {
+                    line.branches.push(self.branch(pc));
+                }
+            }
+        }
+
+        let mut lcov = String::new();
+        for (file, lines) in files {
+            writeln!(lcov, "TN:\nSF:{file}").unwrap();
+            let (mut found, mut hit) = (0, 0);
+            for (number, line) in &lines {
+                for (block, branch) in line.branches.iter().enumerate() {
+                    // edge 0 is taken, edge 1 not taken
+                    for edge in 0..2 {
+                        let count = branch.map(|counts| counts[edge]);
+                        found += 1;
+                        hit += count.is_some_and(|count| count > 0) as u32;
+                        let count = count.map_or("-".into(), |count| count.to_string());
+                        writeln!(lcov, "BRDA:{number},{block},{edge},{count}").unwrap();
+                    }
+                }
+            }
+            writeln!(lcov, "BRF:{found}\nBRH:{hit}").unwrap();
+            for (number, line) in &lines {
+                writeln!(lcov, "DA:{number},{}", line.count).unwrap();
+            }
+            let hit = lines.values().filter(|line| line.count > 0).count();
+            writeln!(lcov, "LF:{}\nLH:{hit}\nend_of_record", lines.len()).unwrap();
+        }
+        lcov
+    }
+}
+
+fn is_branch(instruction: &Instruction) -> bool {
+    matches!(
+        instruction,
+        Instruction::BEQ(_)
+            | Instruction::BNE(_)
+            | Instruction::BLT(_)
+            | Instruction::BGE(_)
+            | Instruction::BLTU(_)
+            | Instruction::BGEU(_)
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{coverage::Coverage, elf::LineRange, test_utils, MEMORY_START};
+
+    const PROGRAM: &[u32] = &[
+        0x00300513, // li a0, 3
+        0xfff50513, // addi a0, a0, -1
+        0xfe051ee3, // bnez a0, -4
+        0x00050463, // beqz a0, 8
+        0x00000013, // nop (never executed)
+        0xc0001073, // unimp
+    ];
+
+    fn range(start: u32, end: u32, line: u32) -> LineRange {
+        LineRange {
+            start: MEMORY_START as u32 + start,
+            end: MEMORY_START as u32 + end,
+            file: "main.s".into(),
+            line,
+        }
+    }
+
+    #[test]
+    fn lcov() {
+        let mut machine = test_utils::machine(PROGRAM);
+        machine.coverage = Some(Coverage::default());
+        assert!(machine.run(100).unwrap());
+        let coverage = machine.coverage.unwrap();
+        assert_eq!(coverage.count(MEMORY_START as u32 + 4), 3);
+        assert_eq!(coverage.branch(MEMORY_START as u32 + 8), Some([2, 1]));
+
+        // the loop is on line 2, the second branch and the nop on line 3
+        let lines = [
+            range(0, 4, 1),
+            range(4, 12, 2),
+            range(12, 20, 3),
+            range(20, 24, 4),
+        ];
+        assert_eq!(
+            coverage.lcov(&lines, &machine.memory),
+            "TN:\nSF:main.s\n\
+             BRDA:2,0,0,2\nBRDA:2,0,1,1\nBRDA:3,0,0,1\nBRDA:3,0,1,0\nBRF:4\nBRH:3\n\
+             DA:1,1\nDA:2,3\nDA:3,1\nDA:4,1\nLF:4\nLH:4\nend_of_record\n"
+        );
+    }
+}
@@ -0,0 +1,795 @@
+//! The control and status registers of the machine and supervisor levels of the privileged
+//! architecture, and the privilege modes and traps they control.
+//!
+//! Interrupts are taken between instructions, see [`Csrs::pending_interrupt`]. `mip.MSIP`,
+//! `mip.MTIP`, `mip.MEIP` and `mip.SEIP` are driven by the [`devices`](crate::devices), writes to
+//! them are ignored. Counters other than `cycle`, `time` and `instret` and the environment
+//! configuration registers read as zero and ignore writes. `satp` selects the address translation
+//! of the [`mmu`](crate::mmu), `pmpcfg0`–`pmpcfg3` and `pmpaddr0`–`pmpaddr15` configure the
+//! [`pmp`](crate::pmp).
+
+use crate::{
+    devices::DEVICE_INTERRUPTS,
+    pmp::{self, PMP_A, PMP_ENTRIES, PMP_L, PMP_R, PMP_TOR, PMP_W},
+    Access,
+};
+
+/// The privilege mode the hart executes in.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Privilege {
+    User = 0,
+    Supervisor = 1,
+    #[default]
+    Machine = 3,
+}
+
+impl Privilege {
+    /// The mode encoded in `bits`, e.g., in `mstatus.MPP`. The reserved encoding 2 is treated as
+    /// supervisor mode.
+    fn from_bits(bits: u32) -> Privilege {
+        match bits & 0b11 {
+            0 => Privilege::User,
+            3 => Privilege::Machine,
+            _ => Privilege::Supervisor,
+        }
+    }
+}
+
+/// The synchronous exceptions, with their cause codes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Exception {
+    InstructionAddressMisaligned = 0,
+    InstructionAccessFault = 1,
+    IllegalInstruction = 2,
+    Breakpoint = 3,
+    LoadAddressMisaligned = 4,
+    LoadAccessFault = 5,
+    StoreAddressMisaligned = 6,
+    StoreAccessFault = 7,
+    UserEnvironmentCall = 8,
+    SupervisorEnvironmentCall = 9,
+    MachineEnvironmentCall = 11,
+    InstructionPageFault = 12,
+    LoadPageFault = 13,
+    StorePageFault = 15,
+}
+
+impl Exception {
+    /// The value written to `mcause` or `scause`.
+    pub fn cause(self) -> u32 {
+        self as u32
+    }
+}
+
+/// The interrupts, with their cause codes without the interrupt bit.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Interrupt {
+    SupervisorSoftware = 1,
+    MachineSoftware = 3,
+    SupervisorTimer = 5,
+    MachineTimer = 7,
+    SupervisorExternal = 9,
+    MachineExternal = 11,
+}
+
+impl Interrupt {
+    /// In the order the hart takes them if several are pending.
+    pub const PRIORITY: [Interrupt; 6] = [
+        Interrupt::MachineExternal,
+        Interrupt::MachineSoftware,
+        Interrupt::MachineTimer,
+        Interrupt::SupervisorExternal,
+        Interrupt::SupervisorSoftware,
+        Interrupt::SupervisorTimer,
+    ];
+
+    /// The value written to `mcause` or `scause`.
+    pub fn cause(self) -> u32 {
+        1 << 31 | self as u32
+    }
+
+    /// The bit of the interrupt in `mip` and `mie`.
+    pub const fn mask(self) -> u32 {
+        1 << self as u32
+    }
+}
+
+impl std::fmt::Display for Interrupt {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.write_str(match self {
+            Interrupt::SupervisorSoftware => "Supervisor software interrupt",
+            Interrupt::MachineSoftware => "Machine software interrupt",
+            Interrupt::SupervisorTimer => "Supervisor timer interrupt",
+            Interrupt::MachineTimer => "Machine timer interrupt",
+            Interrupt::SupervisorExternal => "Supervisor external interrupt",
+            Interrupt::MachineExternal => "Machine external interrupt",
+        })
+    }
+}
+
+impl std::fmt::Display for Exception {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.write_str(match self {
+            Exception::InstructionAddressMisaligned => "Instruction address misaligned",
+            Exception::InstructionAccessFault => "Instruction access fault",
+            Exception::IllegalInstruction => "Illegal instruction",
+            Exception::Breakpoint => "Breakpoint",
+            Exception::LoadAddressMisaligned => "Load address misaligned",
+            Exception::LoadAccessFault => "Load access fault",
+            Exception::StoreAddressMisaligned => "Store address misaligned",
+            Exception::StoreAccessFault => "Store access fault",
+            Exception::UserEnvironmentCall => "Environment call from U-mode",
+            Exception::SupervisorEnvironmentCall => "Environment call from S-mode",
+            Exception::MachineEnvironmentCall => "Environment call from M-mode",
+            Exception::InstructionPageFault => "Instruction page fault",
+            Exception::LoadPageFault => "Load page fault",
+            Exception::StorePageFault => "Store page fault",
+        })
+    }
+}
+
+// CSR addresses
+pub const SSTATUS: u32 = 0x100;
+pub const SIE: u32 = 0x104;
+pub const STVEC: u32 = 0x105;
+pub const SCOUNTEREN: u32 = 0x106;
+pub const SENVCFG: u32 = 0x10a;
+pub const SSCRATCH: u32 = 0x140;
+pub const SEPC: u32 = 0x141;
+pub const SCAUSE: u32 = 0x142;
+pub const STVAL: u32 = 0x143;
+pub const SIP: u32 = 0x144;
+pub const SATP: u32 = 0x180;
+pub const MSTATUS: u32 = 0x300;
+pub const MISA: u32 = 0x301;
+pub const MEDELEG: u32 = 0x302;
+pub const MIDELEG: u32 = 0x303;
+pub const MIE: u32 = 0x304;
+pub const MTVEC: u32 = 0x305;
+pub const MCOUNTEREN: u32 = 0x306;
+pub const MENVCFG: u32 = 0x30a;
+pub const MSTATUSH: u32 = 0x310;
+pub const MENVCFGH: u32 = 0x31a;
+pub const MCOUNTINHIBIT: u32 = 0x320;
+pub const MSCRATCH: u32 = 0x340;
+pub const MEPC: u32 = 0x341;
+pub const MCAUSE: u32 = 0x342;
+pub const MTVAL: u32 = 0x343;
+pub const MIP: u32 = 0x344;
+pub const PMPCFG0: u32 = 0x3a0;
+pub const PMPADDR0: u32 = 0x3b0;
+pub const MCYCLE: u32 = 0xb00;
+pub const MINSTRET: u32 = 0xb02;
+pub const MCYCLEH: u32 = 0xb80;
+pub const MINSTRETH: u32 = 0xb82;
+pub const CYCLE: u32 = 0xc00;
+pub const TIME: u32 = 0xc01;
+pub const INSTRET: u32 = 0xc02;
+pub const CYCLEH: u32 = 0xc80;
+pub const TIMEH: u32 = 0xc81;
+pub const INSTRETH: u32 = 0xc82;
+pub const MVENDORID: u32 = 0xf11;
+pub const MARCHID: u32 = 0xf12;
+pub const MIMPID: u32 = 0xf13;
+pub const MHARTID: u32 = 0xf14;
+pub const MCONFIGPTR: u32 = 0xf15;
+
+// mstatus fields
+pub const MSTATUS_SIE: u32 = 1 << 1;
+pub const MSTATUS_MIE: u32 = 1 << 3;
+pub const MSTATUS_SPIE: u32 = 1 << 5;
+pub const MSTATUS_MPIE: u32 = 1 << 7;
+pub const MSTATUS_SPP: u32 = 1 << 8;
+pub const MSTATUS_MPP: u32 = 0b11 << 11;
+pub const MSTATUS_MPRV: u32 = 1 << 17;
+pub const MSTATUS_SUM: u32 = 1 << 18;
+pub const MSTATUS_MXR: u32 = 1 << 19;
+pub const MSTATUS_TVM: u32 = 1 << 20;
+pub const MSTATUS_TW: u32 = 1 << 21;
+pub const MSTATUS_TSR: u32 = 1 << 22;
+
+const MSTATUS_WRITABLE: u32 = MSTATUS_SIE
+    | MSTATUS_MIE
+    | MSTATUS_SPIE
+    | MSTATUS_MPIE
+    | MSTATUS_SPP
+    | MSTATUS_MPP
+    | MSTATUS_MPRV
+    | MSTATUS_SUM
+    | MSTATUS_MXR
+    | MSTATUS_TVM
+    | MSTATUS_TW
+    | MSTATUS_TSR;
+/// The fields of `mstatus` visible in `sstatus`.
+const SSTATUS_MASK: u32 = MSTATUS_SIE | MSTATUS_SPIE | MSTATUS_SPP | MSTATUS_SUM | MSTATUS_MXR;
+
+/// RV32 with the I base, supervisor and user modes.
+pub(crate) const MISA_VALUE: u32 =
+    1 << 30 | 1 << (b'I' - b'A') | 1 << (b'S' - b'A') | 1 << (b'U' - b'A');
+/// Every exception but the environment call from M-mode can be delegated.
+const MEDELEG_WRITABLE: u32 = 0xb3ff;
+/// The supervisor software, timer and external interrupts.
+const SUPERVISOR_INTERRUPTS: u32 = 0x222;
+/// The software, timer and external interrupts of both modes.
+const INTERRUPTS: u32 = 0xaaa;
+
+/// The counters of the machine, which the counter CSRs read.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Counters {
+    pub cycle: u64,
+    pub instret: u64,
+    /// `mtime` of the CLINT.
+    pub time: u64,
+}
+
+/// The privilege mode and the CSRs of a hart. Starts in machine mode with everything zeroed, like
+/// after a reset.
+///
+/// The fields hold the raw register values, [`Csrs::read`] and [`Csrs::write`] implement the
+/// access checks and the WARL behavior of CSR instructions.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Csrs {
+    pub privilege: Privilege,
+    pub mstatus: u32,
+    pub medeleg: u32,
+    pub mideleg: u32,
+    pub mie: u32,
+    pub mip: u32,
+    pub mtvec: u32,
+    pub mcounteren: u32,
+    pub mscratch: u32,
+    pub mepc: u32,
+    pub mcause: u32,
+    pub mtval: u32,
+    pub stvec: u32,
+    pub scounteren: u32,
+    pub sscratch: u32,
+    pub sepc: u32,
+    pub scause: u32,
+    pub stval: u32,
+    pub satp: u32,
+    /// The configurations of the PMP entries, four per register.
+    pub pmpcfg: [u32; PMP_ENTRIES / 4],
+    pub pmpaddr: [u32; PMP_ENTRIES],
+    /// Added to the machine's cycles by `mcycle` and `cycle`, so they can be written.
+    pub cycle_offset: u64,
+    /// Added to the machine's retired instructions by `minstret` and `instret`.
+    pub instret_offset: u64,
+}
+
+/// Replaces the bits of `target` selected by `mask` with the ones of `value`.
+fn set(target: &mut u32, mask: u32, value: u32) {
+    *target = *target & !mask | value & mask;
+}
+
+/// Replaces the low or high half of `counter`, depending on whether `csr` is a `...h` CSR.
+fn set_half(counter: u64, csr: u32, value: u32) -> u64 {
+    if csr & 0x80 == 0 {
+        counter & !0xffff_ffff | value as u64
+    } else {
+        counter & 0xffff_ffff | (value as u64) << 32
+    }
+}
+
+impl Csrs {
+    /// Whether the current mode may access `csr`, for a write if `write`. Doesn't check whether
+    /// the CSR exists.
+    fn accessible(&self, csr: u32, write: bool) -> bool {
+        // csr[9:8] is the lowest mode which may access it, csr[11:10] == 0b11 is read-only
+        if (csr >> 8 & 0b11) > self.privilege as u32 || write && csr >> 10 == 0b11 {
+            return false;
+        }
+        match csr {
+            // the hardware performance monitor, enabled per counter for the less privileged modes
+            0xc00..=0xc1f | 0xc80..=0xc9f => {
+                let enabled = |counteren: u32| counteren >> (csr & 0x1f) & 1 == 1;
+                match self.privilege {
+                    Privilege::Machine => true,
+                    Privilege::Supervisor => enabled(self.mcounteren),
+                    Privilege::User => enabled(self.mcounteren) && enabled(self.scounteren),
+                }
+            }
+            SATP => !(self.privilege == Privilege::Supervisor && self.mstatus & MSTATUS_TVM != 0),
+            _ => true,
+        }
+    }
+
+    /// Reads `csr` for a CSR instruction. Returns `None` if it doesn't exist or the current mode
+    /// may not access it, which is an illegal instruction.
+    pub fn read(&self, csr: u32, counters: Counters) -> Option<u32> {
+        if !self.accessible(csr, false) {
+            return None;
+        }
+        let cycle = counters.cycle.wrapping_add(self.cycle_offset);
+        let instret = counters.instret.wrapping_add(self.instret_offset);
+        Some(match csr {
+            SSTATUS => self.mstatus & SSTATUS_MASK,
+            SIE => self.mie & self.mideleg,
+            STVEC => self.stvec,
+            SCOUNTEREN => self.scounteren,
+            SSCRATCH => self.sscratch,
+            SEPC => self.sepc,
+            SCAUSE => self.scause,
+            STVAL => self.stval,
+            SIP => self.mip & self.mideleg,
+            SATP => self.satp,
+            MSTATUS => self.mstatus,
+            MISA => MISA_VALUE,
+            MEDELEG => self.medeleg,
+            MIDELEG => self.mideleg,
+            MIE => self.mie,
+            MTVEC => self.mtvec,
+            MCOUNTEREN => self.mcounteren,
+            MSCRATCH => self.mscratch,
+            MEPC => self.mepc,
+            MCAUSE => self.mcause,
+            MTVAL => self.mtval,
+            MIP => self.mip,
+            PMPCFG0..=0x3a3 => self.pmpcfg[(csr - PMPCFG0) as usize],
+            PMPADDR0..=0x3bf => self.pmpaddr[(csr - PMPADDR0) as usize],
+            MCYCLE | CYCLE => cycle as u32,
+            MCYCLEH | CYCLEH => (cycle >> 32) as u32,
+            MINSTRET | INSTRET => instret as u32,
+            MINSTRETH | INSTRETH => (instret >> 32) as u32,
+            TIME => counters.time as u32,
+            TIMEH => (counters.time >> 32) as u32,
+            // hardwired to zero: the environment configuration, the counter inhibit, the event
+            // selectors and the other counters, the other PMP entries and the machine information
+            SENVCFG | MENVCFG | MSTATUSH | MENVCFGH => 0,
+            MCOUNTINHIBIT..=0x33f => 0,
+            0x3a4..=0x3af | 0x3c0..=0x3ef => 0,
+            0xb03..=0xb1f | 0xb83..=0xb9f | 0xc03..=0xc1f | 0xc83..=0xc9f => 0,
+            MVENDORID | MARCHID | MIMPID | MHARTID | MCONFIGPTR => 0,
+            _ => return None,
+        })
+    }
+
+    /// Writes `value` to `csr` for a CSR instruction, which keeps read-only fields and ignores
+    /// unsupported values. Returns `false` if it doesn't exist, is read-only or the current mode
+    /// may not access it, which is an illegal instruction. Then nothing is changed.
+    pub fn write(&mut self, csr: u32, value: u32, counters: Counters) -> bool {
+        if self.read(csr, counters).is_none() || !self.accessible(csr, true) {
+            return false;
+        }
+        match csr {
+            SSTATUS => set(&mut self.mstatus, SSTATUS_MASK, value),
+            SIE => set(&mut self.mie, self.mideleg & SUPERVISOR_INTERRUPTS, value),
+            STVEC => self.stvec = value & !0b10,
+            SCOUNTEREN => self.scounteren = value,
+            SSCRATCH => self.sscratch = value,
+            SEPC => self.sepc = value & !0b11,
+            SCAUSE => self.scause = value,
+            STVAL => self.stval = value,
+            // only supervisor software interrupts can be requested
+            SIP => set(&mut self.mip, self.mideleg & 0b10, value),
+            // the bare mode or Sv32, with all 9 bits of the ASID
+            SATP => self.satp = value,
+            MSTATUS => {
+                let mut value = value;
+                // MPP is WARL, the reserved mode keeps the previous one
+                if value & MSTATUS_MPP == 0b10 << 11 {
+                    value = value & !MSTATUS_MPP | self.mstatus & MSTATUS_MPP;
+                }
+                set(&mut self.mstatus, MSTATUS_WRITABLE, value)
+            }
+            MEDELEG => self.medeleg = value & MEDELEG_WRITABLE,
+            MIDELEG => self.mideleg = value & SUPERVISOR_INTERRUPTS,
+            MIE => self.mie = value & INTERRUPTS,
+            // only direct and vectored modes, a reserved mode selects direct mode
+            MTVEC => self.mtvec = value & !0b10,
+            MCOUNTEREN => self.mcounteren = value,
+            MSCRATCH => self.mscratch = value,
+            MEPC => self.mepc = value & !0b11,
+            MCAUSE => self.mcause = value,
+            MTVAL => self.mtval = value,
+            MIP => set(
+                &mut self.mip,
+                SUPERVISOR_INTERRUPTS & !DEVICE_INTERRUPTS,
+                value,
+            ),
+            // locked entries keep their configuration, the reserved W without R clears W
+            PMPCFG0..=0x3a3 => {
+                let index = (csr - PMPCFG0) as usize;
+                for byte in 0..4 {
+                    let mut config = (value >> (8 * byte)) as u8 & !0b0110_0000;
+                    if config & (PMP_R | PMP_W) == PMP_W {
+                        config &= !PMP_W;
+                    }
+                    if !pmp::locked(self, 4 * index + byte) {
+                        let mask = 0xff << (8 * byte);
+                        set(&mut self.pmpcfg[index], mask, (config as u32) << (8 * byte));
+                    }
+                }
+            }
+            // locked entries keep their address, and so do the ones below locked TOR entries
+            PMPADDR0..=0x3bf => {
+                let entry = (csr - PMPADDR0) as usize;
+                let top = entry + 1 < PMP_ENTRIES
+                    && pmp::config(self, entry + 1) & (PMP_L | PMP_A) == PMP_L | PMP_TOR;
+                if !pmp::locked(self, entry) && !top {
+                    self.pmpaddr[entry] = value;
+                }
+            }
+            MCYCLE | MCYCLEH => {
+                let cycle = counters.cycle.wrapping_add(self.cycle_offset);
+                let cycle = set_half(cycle, csr, value);
+                self.cycle_offset = cycle.wrapping_sub(counters.cycle);
+            }
+            MINSTRET | MINSTRETH => {
+                let instret = counters.instret.wrapping_add(self.instret_offset);
+                let instret = set_half(instret, csr, value);
+                self.instret_offset = instret.wrapping_sub(counters.instret);
+            }
+            // hardwired to zero
+            _ => {}
+        }
+        true
+    }
+
+    /// The interrupt the hart takes before executing the next instruction: the one with the
+    /// highest priority which is pending, enabled in `mie` and not masked. Interrupts for a more
+    /// privileged mode than the current one are never masked, interrupts for the current mode are
+    /// masked if `mstatus.MIE` or `mstatus.SIE` is clear, and interrupts delegated by `mideleg` to
+    /// supervisor mode are always masked in machine mode.
+    pub fn pending_interrupt(&self) -> Option<Interrupt> {
+        let pending = self.mip & self.mie;
+        if pending == 0 {
+            return None;
+        }
+        let machine = self.privilege < Privilege::Machine || self.mstatus & MSTATUS_MIE != 0;
+        let supervisor = self.privilege < Privilege::Supervisor
+            || self.privilege == Privilege::Supervisor && self.mstatus & MSTATUS_SIE != 0;
+        let enabled = match (machine, supervisor) {
+            (true, true) => pending,
+            (true, false) => pending & !self.mideleg,
+            (false, true) => pending & self.mideleg,
+            (false, false) => 0,
+        };
+        Interrupt::PRIORITY
+            .into_iter()
+            .find(|interrupt| enabled & interrupt.mask() != 0)
+    }
+
+    /// Takes a trap for `interrupt` before the instruction at `pc` and returns the address of the
+    /// handler, which is offset by 4 times the cause in vectored mode. Goes to supervisor mode if
+    /// `mideleg` delegates the interrupt.
+    pub fn interrupt(&mut self, pc: u32, interrupt: Interrupt) -> u32 {
+        let delegated = self.mideleg & interrupt.mask() != 0;
+        let tvec = self.enter(pc, interrupt.cause(), 0, delegated);
+        match tvec & 0b11 {
+            1 => (tvec & !0b11) + 4 * interrupt as u32,
+            _ => tvec & !0b11,
+        }
+    }
+
+    /// Takes a trap for `exception`, raised by the instruction at `pc`, and returns the address
+    /// of the handler. Traps from supervisor and user mode go to supervisor mode if `medeleg`
+    /// delegates the exception.
+    pub fn trap(&mut self, pc: u32, exception: Exception, tval: u32) -> u32 {
+        let cause = exception.cause();
+        let delegated = self.privilege <= Privilege::Supervisor && self.medeleg >> cause & 1 == 1;
+        self.enter(pc, cause, tval, delegated) & !0b11
+    }
+
+    /// Enters the trap handler of machine mode, or of supervisor mode if `delegated`, and returns
+    /// its `mtvec` or `stvec`.
+    fn enter(&mut self, pc: u32, cause: u32, tval: u32, delegated: bool) -> u32 {
+        let previous = self.privilege as u32;
+        if delegated {
+            self.scause = cause;
+            self.sepc = pc;
+            self.stval = tval;
+            let sie = self.mstatus & MSTATUS_SIE != 0;
+            set(&mut self.mstatus, MSTATUS_SPP, previous << 8);
+            set(&mut self.mstatus, MSTATUS_SPIE, (sie as u32) << 5);
+            self.mstatus &= !MSTATUS_SIE;
+            self.privilege = Privilege::Supervisor;
+            self.stvec
+        } else {
+            self.mcause = cause;
+            self.mepc = pc;
+            self.mtval = tval;
+            let mie = self.mstatus & MSTATUS_MIE != 0;
+            set(&mut self.mstatus, MSTATUS_MPP, previous << 11);
+            set(&mut self.mstatus, MSTATUS_MPIE, (mie as u32) << 7);
+            self.mstatus &= !MSTATUS_MIE;
+            self.privilege = Privilege::Machine;
+            self.mtvec
+        }
+    }
+
+    /// The mode whose permissions apply to `access`: the current one, or `mstatus.MPP` for loads
+    /// and stores of machine mode with `mstatus.MPRV` set.
+    pub fn access_privilege(&self, access: Access) -> Privilege {
+        match self.privilege {
+            Privilege::Machine if access != Access::Fetch && self.mstatus & MSTATUS_MPRV != 0 => {
+                Privilege::from_bits(self.mstatus >> 11)
+            }
+            privilege => privilege,
+        }
+    }
+
+    /// Returns from a trap to machine mode and returns `mepc`.
+    pub fn mret(&mut self) -> u32 {
+        let privilege = Privilege::from_bits(self.mstatus >> 11);
+        let mpie = self.mstatus & MSTATUS_MPIE != 0;
+        set(&mut self.mstatus, MSTATUS_MIE, (mpie as u32) << 3);
+        self.mstatus |= MSTATUS_MPIE;
+        self.mstatus &= !MSTATUS_MPP;
+        if privilege != Privilege::Machine {
+            self.mstatus &= !MSTATUS_MPRV;
+        }
+        self.privilege = privilege;
+        self.mepc
+    }
+
+    /// Returns from a trap to supervisor mode and returns `sepc`.
+    pub fn sret(&mut self) -> u32 {
+        let privilege = Privilege::from_bits(self.mstatus >> 8 & 1);
+        let spie = self.mstatus & MSTATUS_SPIE != 0;
+        set(&mut self.mstatus, MSTATUS_SIE, (spie as u32) << 1);
+        self.mstatus |= MSTATUS_SPIE;
+        self.mstatus &= !MSTATUS_SPP;
+        self.mstatus &= !MSTATUS_MPRV;
+        self.privilege = privilege;
+        self.sepc
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{csr::*, pmp::*, test_utils, Error, History, Machine, MEMORY_START, PC};
+
+    const COUNTERS: Counters = Counters {
+        cycle: 0x1_0000_0005,
+        instret: 3,
+        time: 0x2_0000_0007,
+    };
+
+    #[test]
+    fn access_checks() {
+        let mut csrs = Csrs::default();
+        assert_eq!(csrs.read(MHARTID, COUNTERS), Some(0));
+        assert_eq!(csrs.read(TIMEH, COUNTERS), Some(2));
+        assert!(!csrs.write(TIME, 0, COUNTERS));
+        assert!(!csrs.write(MHARTID, 1, COUNTERS));
+        assert_eq!(csrs.read(0x7ff, COUNTERS), None);
+
+        csrs.privilege = Privilege::Supervisor;
+        assert_eq!(csrs.read(MSTATUS, COUNTERS), None);
+        assert!(csrs.write(SSCRATCH, 1, COUNTERS));
+        assert_eq!(csrs.read(CYCLE, COUNTERS), None);
+        csrs.mcounteren = 1;
+        assert_eq!(csrs.read(CYCLE, COUNTERS), Some(5));
+        assert_eq!(csrs.read(CYCLEH, COUNTERS), Some(1));
+        assert_eq!(csrs.read(INSTRET, COUNTERS), None);
+
+        csrs.privilege = Privilege::User;
+        assert_eq!(csrs.read(SSCRATCH, COUNTERS), None);
+        assert_eq!(csrs.read(CYCLE, COUNTERS), None);
+        csrs.scounteren = 1;
+        assert_eq!(csrs.read(CYCLE, COUNTERS), Some(5));
+
+        csrs.privilege = Privilege::Supervisor;
+        csrs.mstatus |= MSTATUS_TVM;
+        assert_eq!(csrs.read(SATP, COUNTERS), None);
+    }
+
+    #[test]
+    fn warl_fields() {
+        let mut csrs = Csrs::default();
+        assert!(csrs.write(MSTATUS, u32::MAX, COUNTERS));
+        assert_eq!(csrs.mstatus, MSTATUS_WRITABLE);
+        assert_eq!(csrs.read(SSTATUS, COUNTERS), Some(SSTATUS_MASK));
+        // the reserved MPP keeps the previous mode
+        assert!(csrs.write(MSTATUS, 0b10 << 11, COUNTERS));
+        assert_eq!(csrs.mstatus, MSTATUS_MPP);
+
+        assert!(csrs.write(MEDELEG, u32::MAX, COUNTERS));
+        assert_eq!(csrs.medeleg & 1 << 11, 0);
+        assert!(csrs.write(MEPC, 0x8000_0003, COUNTERS));
+        assert_eq!(csrs.mepc, 0x8000_0000);
+        assert!(csrs.write(MISA, 0, COUNTERS));
+        assert_eq!(csrs.read(MISA, COUNTERS), Some(0x4014_0100));
+        assert!(csrs.write(SATP, u32::MAX, COUNTERS));
+        assert_eq!(csrs.satp, u32::MAX);
+
+        assert!(csrs.write(MCYCLE, 7, COUNTERS));
+        assert_eq!(csrs.read(MCYCLE, COUNTERS), Some(7));
+        assert_eq!(csrs.read(MCYCLEH, COUNTERS), Some(1));
+        assert!(csrs.write(MINSTRETH, 2, COUNTERS));
+        assert_eq!(csrs.read(INSTRETH, COUNTERS), Some(2));
+        assert_eq!(csrs.read(INSTRET, COUNTERS), Some(3));
+    }
+
+    #[test]
+    fn trap_and_return() {
+        let mut csrs = Csrs {
+            privilege: Privilege::User,
+            mtvec: 0x8000_0100,
+            stvec: 0x8000_0200,
+            medeleg: 1 << Exception::UserEnvironmentCall.cause(),
+            mstatus: MSTATUS_SIE,
+            ..Csrs::default()
+        };
+
+        // delegated to supervisor mode
+        let handler = csrs.trap(0x8000_0010, Exception::UserEnvironmentCall, 0);
+        assert_eq!(handler, 0x8000_0200);
+        assert_eq!(csrs.privilege, Privilege::Supervisor);
+        assert_eq!((csrs.scause, csrs.sepc), (8, 0x8000_0010));
+        assert_eq!(csrs.mstatus, MSTATUS_SPIE);
+
+        // never delegated from supervisor mode
+        let handler = csrs.trap(0x8000_0204, Exception::SupervisorEnvironmentCall, 0);
+        assert_eq!(handler, 0x8000_0100);
+        assert_eq!(csrs.privilege, Privilege::Machine);
+        assert_eq!((csrs.mcause, csrs.mepc), (9, 0x8000_0204));
+        assert_eq!(csrs.mstatus & MSTATUS_MPP, 1 << 11);
+
+        assert_eq!(csrs.mret(), 0x8000_0204);
+        assert_eq!(csrs.privilege, Privilege::Supervisor);
+        assert_eq!(csrs.mstatus & (MSTATUS_MPP | MSTATUS_MPIE), MSTATUS_MPIE);
+        assert_eq!(csrs.sret(), 0x8000_0010);
+        assert_eq!(csrs.privilege, Privilege::User);
+        assert_eq!(
+            csrs.mstatus & (MSTATUS_SIE | MSTATUS_SPIE),
+            MSTATUS_SIE | MSTATUS_SPIE
+        );
+    }
+
+    #[test]
+    fn interrupts() {
+        let mut csrs = Csrs {
+            mip: Interrupt::MachineTimer.mask()
+                | Interrupt::SupervisorSoftware.mask()
+                | Interrupt::MachineExternal.mask(),
+            mie: Interrupt::MachineTimer.mask() | Interrupt::SupervisorSoftware.mask(),
+            mideleg: Interrupt::SupervisorSoftware.mask(),
+            mtvec: 0x8000_0101,
+            stvec: 0x8000_0200,
+            ..Csrs::default()
+        };
+        // masked in machine mode until mstatus.MIE is set, delegated ones always
+        assert_eq!(csrs.pending_interrupt(), None);
+        csrs.mstatus = MSTATUS_MIE;
+        assert_eq!(csrs.pending_interrupt(), Some(Interrupt::MachineTimer));
+
+        // never masked for a more privileged mode
+        csrs.privilege = Privilege::Supervisor;
+        csrs.mstatus = 0;
+        assert_eq!(csrs.pending_interrupt(), Some(Interrupt::MachineTimer));
+        csrs.mie = Interrupt::SupervisorSoftware.mask();
+        assert_eq!(csrs.pending_interrupt(), None);
+        csrs.privilege = Privilege::User;
+        assert_eq!(
+            csrs.pending_interrupt(),
+            Some(Interrupt::SupervisorSoftware)
+        );
+
+        // delegated in direct mode
+        let handler = csrs.interrupt(0x8000_0010, Interrupt::SupervisorSoftware);
+        assert_eq!(handler, 0x8000_0200);
+        assert_eq!(csrs.privilege, Privilege::Supervisor);
+        assert_eq!((csrs.scause, csrs.sepc), (1 << 31 | 1, 0x8000_0010));
+
+        // vectored
+        let handler = csrs.interrupt(0x8000_0204, Interrupt::MachineTimer);
+        assert_eq!(handler, 0x8000_0100 + 4 * 7);
+        assert_eq!(csrs.privilege, Privilege::Machine);
+        assert_eq!((csrs.mcause, csrs.mepc), (1 << 31 | 7, 0x8000_0204));
+        assert_eq!(csrs.mstatus & MSTATUS_MPP, 1 << 11);
+        // exceptions ignore the mode
+        assert_eq!(csrs.trap(0, Exception::Breakpoint, 0), 0x8000_0100);
+    }
+
+    // M-mode delegates U-mode ECALLs and drops to S-mode, which drops to U-mode. U-mode calls S,
+    // which calls M, then reads `mstatus`, which traps to M and skips the instruction.
+    const KERNEL: &[u32] = &[
+        0x00000297, // la t0, mtrap
+        0x07828293, //
+        0x30529073, // csrw mtvec, t0
+        0x00000297, // la t0, strap
+        0x05428293, //
+        0x10529073, // csrw stvec, t0
+        0x10000293, // li t0, 0x100
+        0x30229073, // csrw medeleg, t0
+        0x000012b7, // li t0, 0x800
+        0x80028293, //
+        0x3002a073, // csrs mstatus, t0
+        0x00000297, // la t0, super
+        0x01028293, //
+        0x34129073, // csrw mepc, t0
+        0x30200073, // mret
+        // super
+        0x00000297, // la t0, user
+        0x01028293, //
+        0x14129073, // csrw sepc, t0
+        0x10200073, // sret
+        // user
+        0x00100513, // li a0, 1
+        0x00000073, // ecall
+        0x00300513, // li a0, 3
+        0x300025f3, // csrr a1, mstatus
+        0xc0001073, // unimp
+        // strap
+        0x00150513, // addi a0, a0, 1
+        0x141025f3, // csrr a1, sepc
+        0x00458593, // addi a1, a1, 4
+        0x14159073, // csrw sepc, a1
+        0x00000073, // ecall
+        0x10200073, // sret
+        // mtrap
+        0x34202673, // csrr a2, mcause
+        0x341026f3, // csrr a3, mepc
+        0x00468693, // addi a3, a3, 4
+        0x34169073, // csrw mepc, a3
+        0x30200073, // mret
+    ];
+
+    fn kernel() -> Machine {
+        let mut machine = test_utils::machine(KERNEL);
+        // a PMP entry granting S and U-mode access to everything, like firmware sets up
+        machine.csrs.pmpaddr[0] = u32::MAX;
+        machine.csrs.pmpcfg[0] = (PMP_NAPOT | PMP_R | PMP_W | PMP_X) as u32;
+        machine
+    }
+
+    #[test]
+    fn privilege_modes() {
+        let mut machine = kernel();
+        assert!(machine.run(100).unwrap());
+        let start = MEMORY_START as u32;
+        assert_eq!(
+            machine.registers[10..14],
+            [3, start + 0x54, 2, start + 0x5c]
+        );
+        assert_eq!(machine.csrs.privilege, Privilege::User);
+        assert_eq!((machine.csrs.scause, machine.csrs.sepc), (8, start + 0x54));
+        assert_eq!((machine.csrs.mcause, machine.csrs.mtval), (2, 0x300025f3));
+        // 22 instructions of M, S and U-mode, strap once and mtrap twice, the traps don't retire
+        assert_eq!(machine.instret, 22 + 5 + 2 * 5);
+    }
+
+    #[test]
+    fn step_back_over_traps() {
+        let mut machine = kernel();
+        machine.history = History::new(usize::MAX);
+        let mut states = vec![];
+        while {
+            states.push((machine.registers, machine.csrs, machine.instret));
+            !machine.step().unwrap()
+        } {}
+        while let Some((registers, csrs, instret)) = states.pop() {
+            assert!(machine.step_back());
+            assert_eq!(
+                (machine.registers, machine.csrs, machine.instret),
+                (registers, csrs, instret)
+            );
+        }
+        assert!(!machine.step_back());
+    }
+
+    #[test]
+    fn unhandled_exception() {
+        let mut machine = Machine::new();
+        machine.memory[..4].copy_from_slice(&0x00000073u32.to_le_bytes()); // ecall
+        let before = machine.clone();
+        assert_eq!(
+            machine.step(),
+            Err(Error::Exception {
+                pc: MEMORY_START as u32,
+                code: Some(0x00000073),
+                exception: Exception::MachineEnvironmentCall,
+                tval: 0,
+            })
+        );
+        assert!(machine == before);
+
+        // with a handler, the same ECALL traps
+        machine.csrs.mtvec = MEMORY_START as u32 + 0x100;
+        assert!(!machine.step().unwrap());
+        assert_eq!(machine.registers[PC], MEMORY_START as u32 + 0x100);
+        assert_eq!(machine.csrs.mcause, 11);
+    }
+}
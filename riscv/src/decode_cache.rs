@@ -0,0 +1,192 @@
+use crate::{fetch_physical, Error, Instruction, Memory, Retired, MEMORY_SIZE, MEMORY_START};
+
+pub const PAGE_SIZE: usize = 0x1000;
+
+/// Caches decoded instructions of RAM, so executing an instruction again skips fetching and
+/// decoding it.
+///
+/// Stores to a page from which instructions were fetched invalidate that page and FENCE.I
+/// invalidates all of them, so self-modifying code always executes the latest instructions.
+/// Writes to memory which don't go through [`DecodeCache::retire`], e.g., when loading a program,
+/// require a [`DecodeCache::clear`].
+#[derive(Debug, Clone)]
+pub struct DecodeCache {
+    /// One slot per word of RAM, empty if disabled.
+    slots: Vec<Option<(u32, Instruction)>>,
+    /// Whether any slot of a page is filled.
+    code_pages: Vec<bool>,
+}
+
+impl DecodeCache {
+    /// A cache of `memory_size` bytes of RAM, the size of the machine's `memory`.
+    pub fn new(memory_size: usize) -> DecodeCache {
+        DecodeCache {
+            slots: vec![None; memory_size / 4],
+            code_pages: vec![false; memory_size.div_ceil(PAGE_SIZE)],
+        }
+    }
+
+    /// A cache which always fetches and decodes, like [`fetch`](crate::fetch).
+    pub fn disabled() -> DecodeCache {
+        DecodeCache {
+            slots: Vec::new(),
+            code_pages: Vec::new(),
+        }
+    }
+
+    pub fn enabled(&self) -> bool {
+        !self.slots.is_empty()
+    }
+
+    /// Like [`fetch`](crate::fetch), but fetches the instruction at `pc` from the physical
+    /// `address` it was translated to, and returns the cached instruction if there is one.
+    pub fn fetch(
+        &mut self,
+        pc: u32,
+        address: u32,
+        memory: &Memory,
+    ) -> Result<(u32, Instruction), Error> {
+        let offset = address.wrapping_sub(MEMORY_START as u32) as usize;
+        // misaligned or invalid fetches are left to `fetch_physical` to report
+        if offset % 4 != 0 {
+            return fetch_physical(pc, address, memory);
+        }
+        let Some(slot) = self.slots.get_mut(offset / 4) else {
+            return fetch_physical(pc, address, memory);
+        };
+        if let Some(cached) = *slot {
+            return Ok(cached);
+        }
+        let fetched = fetch_physical(pc, address, memory)?;
+        *slot = Some(fetched);
+        self.code_pages[offset / PAGE_SIZE] = true;
+        Ok(fetched)
+    }
+
+    /// Invalidates what the retired instruction made stale.
+    pub fn retire(&mut self, retired: &Retired) {
+        if let Instruction::FENCE_I(_) = retired.instruction {
+            self.clear();
+        }
+        if let Some(store) = retired.store {
+            self.invalidate(store.address, store.size);
+        }
+    }
+
+    /// Invalidates the pages overlapping the `size` bytes at `address`.
+    pub fn invalidate(&mut self, address: u32, size: u32) {
+        let offset = address.wrapping_sub(MEMORY_START as u32) as usize;
+        for page in
+            [offset, offset.saturating_add(size as usize - 1)].map(|offset| offset / PAGE_SIZE)
+        {
+            if let Some(code_page) = self.code_pages.get_mut(page) {
+                if std::mem::take(code_page) {
+                    self.slots[page * PAGE_SIZE / 4..(page + 1) * PAGE_SIZE / 4].fill(None);
+                }
+            }
+        }
+    }
+
+    pub fn clear(&mut self) {
+        for page in 0..self.code_pages.len() {
+            self.invalidate((MEMORY_START + page * PAGE_SIZE) as u32, 1);
+        }
+    }
+}
+
+impl Default for DecodeCache {
+    fn default() -> DecodeCache {
+        DecodeCache::new(MEMORY_SIZE)
+    }
+}
+
+/// The cache holds no architectural state, so machines compare equal regardless of what they
+/// have cached.
+impl PartialEq for DecodeCache {
+    fn eq(&self, other: &DecodeCache) -> bool {
+        self.enabled() == other.enabled()
+    }
+}
+
+impl Eq for DecodeCache {}
+
+#[cfg(test)]
+mod tests {
+    use crate::{test_utils::machine, DecodeCache, History, MEMORY_SIZE, MEMORY_START, PC};
+
+    const ADDI_1: u32 = 0x00150513; // addi a0, a0, 1
+    const ADDI_2: u32 = 0x00250513; // addi a0, a0, 2
+
+    #[test]
+    fn store_invalidates_code_page() {
+        let mut machine = machine(&[
+            0x00000297, // auipc t0, 0
+            0x01050337, // lui t1, 0x1050
+            0x51330313, // addi t1, t1, 0x513 (t1 = `addi a0, a0, 16`)
+            ADDI_1,     // 0x0c
+            0x0062a623, // sw t1, 12(t0)
+            0xff9ff06f, // j 0x0c
+        ]);
+        for _ in 0..7 {
+            machine.step().unwrap();
+        }
+        assert_eq!(machine.registers[PC], MEMORY_START as u32 + 0x10);
+        assert_eq!(machine.registers[10], 1 + 16);
+    }
+
+    #[test]
+    fn fence_i() {
+        let mut machine = machine(&[
+            ADDI_1, 0x0000100f, // fence.i
+            0xff9ff06f, // j 0
+        ]);
+        machine.step().unwrap();
+        machine.memory[..4].copy_from_slice(&ADDI_2.to_le_bytes());
+        for _ in 0..3 {
+            machine.step().unwrap();
+        }
+        assert_eq!(machine.registers[10], 1 + 2);
+    }
+
+    #[test]
+    fn direct_writes_require_clear() {
+        let program = [ADDI_1, 0xffdff06f]; // j 0
+        let run = |decode_cache: DecodeCache, clear: bool| {
+            let mut machine = machine(&program);
+            machine.decode_cache = decode_cache;
+            machine.step().unwrap();
+            machine.step().unwrap();
+            machine.memory[..4].copy_from_slice(&ADDI_2.to_le_bytes());
+            if clear {
+                machine.decode_cache.clear();
+            }
+            machine.step().unwrap();
+            machine.registers[10]
+        };
+        // the stale instruction is executed
+        assert_eq!(run(DecodeCache::new(MEMORY_SIZE), false), 1 + 1);
+        assert_eq!(run(DecodeCache::new(MEMORY_SIZE), true), 1 + 2);
+        assert_eq!(run(DecodeCache::disabled(), false), 1 + 2);
+    }
+
+    #[test]
+    fn step_back_invalidates() {
+        let mut machine = machine(&[
+            0x00000297, // auipc t0, 0
+            0x0062a423, // sw t1, 8(t0)
+            ADDI_1,
+        ]);
+        machine.registers[6] = ADDI_2;
+        machine.history = History::new(10);
+        for _ in 0..3 {
+            machine.step().unwrap();
+        }
+        assert_eq!(machine.registers[10], 2);
+        // revert the addi and the store, then execute the restored instruction
+        assert!(machine.step_back());
+        assert!(machine.step_back());
+        machine.registers[PC] = MEMORY_START as u32 + 8;
+        machine.step().unwrap();
+        assert_eq!(machine.registers[10], 1);
+    }
+}
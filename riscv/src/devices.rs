@@ -0,0 +1,222 @@
+//! The devices mapped into the physical address space next to RAM:
+//!
+//! | Device                                         | Address      |
+//! |------------------------------------------------|--------------|
+//! | [`bootrom`](crate::bootrom), if any            | `0x00001000` |
+//! | [`clint`](crate::clint)                        | `0x02000000` |
+//! | [`plic`](crate::plic)                          | `0x0c000000` |
+//! | [`uart`](crate::uart)                          | `0x10000000` |
+//! | [`virtio`](crate::virtio) block device, if any | `0x10001000` |
+//! | [`framebuffer`](crate::framebuffer), if any    | `0x50000000` |
+//!
+//! Loads and stores which miss RAM go to the device whose address range contains them, accesses of
+//! other addresses are access faults. The registers of the CLINT and the PLIC are 32 bits wide,
+//! the UART's are bytes, other sizes and misaligned accesses fault too. Only the framebuffer's
+//! pixels take accesses of any size and alignment, and the boot ROM aligned bytes, halfwords and
+//! words. Instructions can only be fetched from the boot ROM.
+//!
+//! The boot ROM, the virtio block device and the framebuffer are optional, the host adds them with
+//! the payload, the disk and the resolution it wants.
+//!
+//! The UART's interrupt line is [`UART_IRQ`] of the PLIC, the virtio block device's
+//! [`VIRTIO_IRQ`].
+//!
+//! Devices aren't recorded in the [`History`](crate::History), stepping back reverts neither their
+//! state nor stores to them, nor what the virtio block device wrote to RAM.
+
+use crate::{
+    bootrom::{BootRom, BOOT_ROM_SIZE},
+    clint::{Clint, CLINT_SIZE},
+    csr::Interrupt,
+    framebuffer::{Framebuffer, FRAMEBUFFER_SIZE},
+    plic::{Plic, PLIC_SIZE},
+    uart::{Uart, UART_FIFO_SIZE, UART_SIZE},
+    virtio::{VirtioBlock, VIRTIO_SIZE},
+    Memory,
+};
+
+pub const BOOT_ROM_START: u32 = 0x0000_1000;
+pub const CLINT_START: u32 = 0x0200_0000;
+pub const PLIC_START: u32 = 0x0c00_0000;
+pub const UART_START: u32 = 0x1000_0000;
+pub const VIRTIO_START: u32 = 0x1000_1000;
+pub const FRAMEBUFFER_START: u32 = 0x5000_0000;
+
+/// The PLIC source of the UART's interrupt.
+pub const UART_IRQ: u32 = 10;
+/// The PLIC source of the virtio block device's interrupt.
+pub const VIRTIO_IRQ: u32 = 1;
+
+/// The bits of `mip` driven by devices, which are read-only for CSR instructions.
+pub const DEVICE_INTERRUPTS: u32 = Interrupt::MachineSoftware.mask()
+    | Interrupt::MachineTimer.mask()
+    | Interrupt::MachineExternal.mask()
+    | Interrupt::SupervisorExternal.mask();
+
+/// The `offset` of a 32-bit register, `None` for other sizes and misaligned accesses.
+fn word(offset: u32, size: u32) -> Option<u32> {
+    (size == 4 && offset % 4 == 0).then_some(offset)
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Devices {
+    pub rom: Option<BootRom>,
+    pub clint: Clint,
+    pub plic: Plic,
+    pub uart: Uart,
+    pub virtio: Option<VirtioBlock>,
+    pub framebuffer: Option<Framebuffer>,
+}
+
+impl Devices {
+    /// Reads the `size` bytes at the physical `address`, `None` if no device register is there.
+    /// `cycle` is the machine's number of elapsed cycles.
+    pub fn load(&mut self, address: u32, size: u32, cycle: u64) -> Option<u32> {
+        match address {
+            _ if address.wrapping_sub(BOOT_ROM_START) < BOOT_ROM_SIZE => {
+                let rom = self.rom.as_ref()?;
+                rom.load(address - BOOT_ROM_START, size)
+            }
+            _ if address.wrapping_sub(CLINT_START) < CLINT_SIZE => {
+                self.clint.load(word(address - CLINT_START, size)?, cycle)
+            }
+            _ if address.wrapping_sub(PLIC_START) < PLIC_SIZE => {
+                self.plic.load(word(address - PLIC_START, size)?)
+            }
+            _ if address.wrapping_sub(UART_START) < UART_SIZE && size == 1 => {
+                let value = self.uart.load(address - UART_START);
+                self.update_uart_line();
+                value.map(u32::from)
+            }
+            _ if address.wrapping_sub(VIRTIO_START) < VIRTIO_SIZE => {
+                let virtio = self.virtio.as_ref()?;
+                virtio.load(address - VIRTIO_START, size)
+            }
+            _ if address.wrapping_sub(FRAMEBUFFER_START) < FRAMEBUFFER_SIZE => {
+                let framebuffer = self.framebuffer.as_ref()?;
+                framebuffer.load(address - FRAMEBUFFER_START, size)
+            }
+            _ => None,
+        }
+    }
+
+    /// Writes `value` to the `size` bytes at the physical `address`, `None` if no device register
+    /// is there.
+    pub fn store(&mut self, address: u32, size: u32, value: u32, cycle: u64) -> Option<()> {
+        match address {
+            _ if address.wrapping_sub(CLINT_START) < CLINT_SIZE => {
+                self.clint
+                    .store(word(address - CLINT_START, size)?, value, cycle)
+            }
+            _ if address.wrapping_sub(PLIC_START) < PLIC_SIZE => {
+                self.plic.store(word(address - PLIC_START, size)?, value)
+            }
+            _ if address.wrapping_sub(UART_START) < UART_SIZE && size == 1 => {
+                let stored = self.uart.store(address - UART_START, value as u8);
+                self.update_uart_line();
+                stored
+            }
+            _ if address.wrapping_sub(VIRTIO_START) < VIRTIO_SIZE => {
+                let virtio = self.virtio.as_mut()?;
+                let stored = virtio.store(address - VIRTIO_START, size, value);
+                self.update_virtio_line();
+                stored
+            }
+            _ if address.wrapping_sub(FRAMEBUFFER_START) < FRAMEBUFFER_SIZE => {
+                let framebuffer = self.framebuffer.as_mut()?;
+                framebuffer.store(address - FRAMEBUFFER_START, size, value)
+            }
+            _ => None,
+        }
+    }
+
+    /// Delivers `input` from the host to the UART, as much as fits into its receive FIFO without
+    /// an overrun. Returns the number of bytes delivered, the host should retry the rest later.
+    pub fn receive(&mut self, input: &[u8]) -> usize {
+        let received = input.len().min(UART_FIFO_SIZE - self.uart.rx.len());
+        for &byte in &input[..received] {
+            self.uart.receive(byte);
+        }
+        self.update_uart_line();
+        received
+    }
+
+    /// Raises or lowers the PLIC line of the UART's interrupt.
+    fn update_uart_line(&mut self) {
+        if self.uart.interrupt() {
+            self.plic.raise(UART_IRQ);
+        } else {
+            self.plic.lower(UART_IRQ);
+        }
+    }
+
+    /// Serves the requests the virtio block device was notified of, which read and write RAM
+    /// directly. Returns the ranges of RAM written, as addresses and sizes.
+    pub fn serve(&mut self, memory: &mut Memory) -> Vec<(u32, u32)> {
+        let Some(virtio) = &mut self.virtio else {
+            return Vec::new();
+        };
+        let written = virtio.serve(memory);
+        self.update_virtio_line();
+        written
+    }
+
+    /// Raises or lowers the PLIC line of the virtio block device's interrupt.
+    fn update_virtio_line(&mut self) {
+        if self.virtio.as_ref().is_some_and(VirtioBlock::interrupt) {
+            self.plic.raise(VIRTIO_IRQ);
+        } else {
+            self.plic.lower(VIRTIO_IRQ);
+        }
+    }
+
+    /// The bits of the interrupts devices raise in `mip`, a subset of [`DEVICE_INTERRUPTS`].
+    pub fn interrupts(&self, cycle: u64) -> u32 {
+        self.clint.interrupts(cycle) | self.plic.interrupts()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        super::*,
+        crate::{clint::MTIME, uart::*},
+    };
+
+    #[test]
+    fn accesses() {
+        let mut devices = Devices::default();
+        assert_eq!(devices.load(CLINT_START + MTIME, 4, 7), Some(7));
+        assert_eq!(devices.store(CLINT_START, 4, 1, 0), Some(()));
+        assert!(devices.clint.msip);
+        // registers are only accessible as aligned words
+        assert_eq!(devices.load(CLINT_START, 2, 0), None);
+        assert_eq!(devices.store(CLINT_START + 2, 4, 0, 0), None);
+        assert_eq!(devices.load(CLINT_START + CLINT_SIZE, 4, 0), None);
+        assert_eq!(devices.load(CLINT_START - 4, 4, 0), None);
+        // but the UART's are bytes
+        assert_eq!(devices.load(UART_START + LSR, 1, 0), Some(0x60));
+        assert_eq!(devices.load(UART_START, 4, 0), None);
+    }
+
+    #[test]
+    fn uart_interrupt() {
+        let mut devices = Devices::default();
+        devices.plic.priorities[UART_IRQ as usize] = 1;
+        devices.plic.enables[0] = 1 << UART_IRQ;
+        assert_eq!(
+            devices.store(UART_START + IER, 1, IER_RDA as u32, 0),
+            Some(())
+        );
+
+        // the host can deliver as much as fits into the receive FIFO
+        assert_eq!(devices.receive(&[b'a'; 20]), UART_FIFO_SIZE);
+        assert!(!devices.uart.overrun);
+        assert_eq!(devices.interrupts(0), Interrupt::MachineExternal.mask());
+        for _ in 0..UART_FIFO_SIZE {
+            assert_eq!(devices.load(UART_START, 1, 0), Some(b'a' as u32));
+        }
+        assert_eq!(devices.plic.lines, 0);
+        assert_eq!(devices.interrupts(0), 0);
+    }
+}
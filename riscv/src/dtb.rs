@@ -0,0 +1,419 @@
+//! Generates the flattened device tree (version 17) which describes a [`Machine`] to kernels and
+//! firmware: its RAM, its hart with the ISA string of the extensions in `misa`, and its devices.
+//! The optional virtio block device and framebuffer appear only if the machine has them, the
+//! framebuffer only with RGB565 pixels, since `simple-framebuffer` has no format with red in the
+//! first byte.
+
+use crate::{
+    clint::CLINT_SIZE,
+    csr::{Interrupt, MISA_VALUE},
+    devices::{
+        CLINT_START, FRAMEBUFFER_START, PLIC_START, UART_IRQ, UART_START, VIRTIO_IRQ, VIRTIO_START,
+    },
+    framebuffer::{PixelFormat, PIXELS},
+    plic::{PLIC_SIZE, PLIC_SOURCES},
+    uart::UART_SIZE,
+    virtio::VIRTIO_SIZE,
+    Machine, MEMORY_START,
+};
+
+/// The frequency of `mtime`, which ticks once per cycle, so the nominal clock of the hart.
+pub const TIMEBASE_FREQUENCY: u32 = 10_000_000;
+/// The frequency of the UART's clock, which only matters for the divisor latch.
+const UART_CLOCK_FREQUENCY: u32 = 3_686_400;
+
+const MAGIC: u32 = 0xd00d_feed;
+const VERSION: u32 = 17;
+/// The oldest version this tree is compatible with.
+const LAST_COMPATIBLE_VERSION: u32 = 16;
+const HEADER_SIZE: usize = 40;
+
+// structure block tokens
+const BEGIN_NODE: u32 = 1;
+const END_NODE: u32 = 2;
+const PROP: u32 = 3;
+const END: u32 = 9;
+
+// phandles
+const CPU_INTC: u32 = 1;
+const PLIC: u32 = 2;
+
+/// Builds the structure and strings blocks of a device tree.
+#[derive(Default)]
+struct Builder {
+    structure: Vec<u8>,
+    strings: Vec<u8>,
+}
+
+impl Builder {
+    fn token(&mut self, token: u32) {
+        self.structure.extend(token.to_be_bytes());
+    }
+
+    /// Appends `bytes` to the structure block, padded to a multiple of 4 bytes.
+    fn padded(&mut self, bytes: &[u8]) {
+        self.structure.extend(bytes);
+        let padding = bytes.len().next_multiple_of(4) - bytes.len();
+        self.structure.resize(self.structure.len() + padding, 0);
+    }
+
+    fn begin(&mut self, name: &str) {
+        self.token(BEGIN_NODE);
+        self.padded(&[name.as_bytes(), &[0]].concat());
+    }
+
+    fn end(&mut self) {
+        self.token(END_NODE);
+    }
+
+    fn property(&mut self, name: &str, value: &[u8]) {
+        // property names are shared, e.g., `compatible`
+        let name = [name.as_bytes(), &[0]].concat();
+        let offset = match self
+            .strings
+            .windows(name.len())
+            .position(|window| window == name)
+        {
+            Some(offset) => offset,
+            None => {
+                self.strings.extend(&name);
+                self.strings.len() - name.len()
+            }
+        };
+        self.token(PROP);
+        self.structure.extend((value.len() as u32).to_be_bytes());
+        self.structure.extend((offset as u32).to_be_bytes());
+        self.padded(value);
+    }
+
+    fn cells(&mut self, name: &str, cells: &[u32]) {
+        let value = cells
+            .iter()
+            .flat_map(|cell| cell.to_be_bytes())
+            .collect::<Vec<u8>>();
+        self.property(name, &value);
+    }
+
+    fn strings(&mut self, name: &str, strings: &[&str]) {
+        let value = strings
+            .iter()
+            .flat_map(|string| string.bytes().chain([0]))
+            .collect::<Vec<u8>>();
+        self.property(name, &value);
+    }
+
+    /// The blob, with an empty memory reservation block.
+    fn finish(mut self) -> Vec<u8> {
+        self.token(END);
+        let reservations = HEADER_SIZE;
+        let structure = reservations + 16;
+        let strings = structure + self.structure.len();
+        let size = strings + self.strings.len();
+        let mut blob = Vec::with_capacity(size);
+        for field in [
+            MAGIC,
+            size as u32,
+            structure as u32,
+            strings as u32,
+            reservations as u32,
+            VERSION,
+            LAST_COMPATIBLE_VERSION,
+            // the boot hart
+            0,
+            self.strings.len() as u32,
+            self.structure.len() as u32,
+        ] {
+            blob.extend(field.to_be_bytes());
+        }
+        blob.extend([0; 16]);
+        blob.extend(self.structure);
+        blob.extend(self.strings);
+        blob
+    }
+}
+
+/// The base ISA and the extensions in `misa`, without the privilege modes, and the extensions
+/// every hart has.
+fn extensions(misa: u32) -> Vec<String> {
+    let letters = (b'a'..=b'z')
+        .filter(|&letter| misa >> (letter - b'a') & 1 == 1 && !b"su".contains(&letter))
+        .map(|letter| (letter as char).to_string());
+    letters.chain(["zicsr".into(), "zifencei".into()]).collect()
+}
+
+/// The ISA string, e.g., `rv32i_zicsr_zifencei`.
+fn isa(misa: u32) -> String {
+    let mut isa = String::from("rv32");
+    for extension in extensions(misa) {
+        if extension.len() > 1 {
+            isa.push('_');
+        }
+        isa.push_str(&extension);
+    }
+    isa
+}
+
+/// The device tree of `machine`, with `bootargs` as the kernel's command line.
+pub fn device_tree(machine: &Machine, bootargs: &str) -> Vec<u8> {
+    let mut tree = Builder::default();
+    tree.begin("");
+    tree.cells("#address-cells", &[1]);
+    tree.cells("#size-cells", &[1]);
+    tree.strings("compatible", &["riscv-core"]);
+    tree.strings("model", &["riscv-core"]);
+
+    tree.begin("chosen");
+    tree.strings("bootargs", &[bootargs]);
+    tree.strings("stdout-path", &[&format!("/soc/serial@{UART_START:x}")]);
+    tree.end();
+
+    tree.begin(&format!("memory@{MEMORY_START:x}"));
+    tree.strings("device_type", &["memory"]);
+    tree.cells("reg", &[MEMORY_START as u32, machine.memory.len() as u32]);
+    tree.end();
+
+    tree.begin("cpus");
+    tree.cells("#address-cells", &[1]);
+    tree.cells("#size-cells", &[0]);
+    tree.cells("timebase-frequency", &[TIMEBASE_FREQUENCY]);
+    tree.begin("cpu@0");
+    tree.strings("device_type", &["cpu"]);
+    tree.cells("reg", &[0]);
+    tree.strings("status", &["okay"]);
+    tree.strings("compatible", &["riscv"]);
+    tree.strings("riscv,isa", &[&isa(MISA_VALUE)]);
+    tree.strings("riscv,isa-base", &["rv32i"]);
+    let extensions = extensions(MISA_VALUE);
+    tree.strings(
+        "riscv,isa-extensions",
+        &extensions.iter().map(String::as_str).collect::<Vec<_>>(),
+    );
+    tree.strings("mmu-type", &["riscv,sv32"]);
+    tree.begin("interrupt-controller");
+    tree.cells("#interrupt-cells", &[1]);
+    tree.property("interrupt-controller", &[]);
+    tree.strings("compatible", &["riscv,cpu-intc"]);
+    tree.cells("phandle", &[CPU_INTC]);
+    tree.end();
+    tree.end();
+    tree.end();
+
+    tree.begin("soc");
+    tree.cells("#address-cells", &[1]);
+    tree.cells("#size-cells", &[1]);
+    tree.strings("compatible", &["simple-bus"]);
+    tree.property("ranges", &[]);
+
+    tree.begin(&format!("clint@{CLINT_START:x}"));
+    tree.strings("compatible", &["sifive,clint0", "riscv,clint0"]);
+    tree.cells("reg", &[CLINT_START, CLINT_SIZE]);
+    tree.cells(
+        "interrupts-extended",
+        &[
+            CPU_INTC,
+            Interrupt::MachineSoftware.cause() & !(1 << 31),
+            CPU_INTC,
+            Interrupt::MachineTimer.cause() & !(1 << 31),
+        ],
+    );
+    tree.end();
+
+    tree.begin(&format!("plic@{PLIC_START:x}"));
+    tree.strings("compatible", &["sifive,plic-1.0.0", "riscv,plic0"]);
+    tree.cells("reg", &[PLIC_START, PLIC_SIZE]);
+    tree.cells("#address-cells", &[0]);
+    tree.cells("#interrupt-cells", &[1]);
+    tree.property("interrupt-controller", &[]);
+    // context 0 is machine mode, context 1 supervisor mode
+    tree.cells(
+        "interrupts-extended",
+        &[
+            CPU_INTC,
+            Interrupt::MachineExternal.cause() & !(1 << 31),
+            CPU_INTC,
+            Interrupt::SupervisorExternal.cause() & !(1 << 31),
+        ],
+    );
+    tree.cells("riscv,ndev", &[PLIC_SOURCES - 1]);
+    tree.cells("phandle", &[PLIC]);
+    tree.end();
+
+    tree.begin(&format!("serial@{UART_START:x}"));
+    tree.strings("compatible", &["ns16550a"]);
+    tree.cells("reg", &[UART_START, UART_SIZE]);
+    tree.cells("clock-frequency", &[UART_CLOCK_FREQUENCY]);
+    tree.cells("interrupt-parent", &[PLIC]);
+    tree.cells("interrupts", &[UART_IRQ]);
+    tree.end();
+
+    if machine.devices.virtio.is_some() {
+        tree.begin(&format!("virtio_mmio@{VIRTIO_START:x}"));
+        tree.strings("compatible", &["virtio,mmio"]);
+        tree.cells("reg", &[VIRTIO_START, VIRTIO_SIZE]);
+        tree.cells("interrupt-parent", &[PLIC]);
+        tree.cells("interrupts", &[VIRTIO_IRQ]);
+        tree.end();
+    }
+
+    if let Some(framebuffer) = machine
+        .devices
+        .framebuffer
+        .as_ref()
+        .filter(|framebuffer| framebuffer.format() == PixelFormat::Rgb565)
+    {
+        let start = FRAMEBUFFER_START + PIXELS;
+        tree.begin(&format!("framebuffer@{start:x}"));
+        tree.strings("compatible", &["simple-framebuffer"]);
+        tree.cells("reg", &[start, framebuffer.pixels().len() as u32]);
+        tree.cells("width", &[framebuffer.width()]);
+        tree.cells("height", &[framebuffer.height()]);
+        tree.cells("stride", &[framebuffer.stride()]);
+        tree.strings("format", &["r5g6b5"]);
+        tree.end();
+    }
+
+    tree.end();
+    tree.end();
+    tree.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        super::*,
+        crate::{
+            framebuffer::Framebuffer,
+            virtio::{Disk, DiskMode, VirtioBlock, SECTOR_SIZE},
+        },
+    };
+
+    fn be32(blob: &[u8], offset: usize) -> u32 {
+        u32::from_be_bytes(blob[offset..offset + 4].try_into().unwrap())
+    }
+
+    fn string(bytes: &[u8]) -> &str {
+        let end = bytes.iter().position(|&byte| byte == 0).unwrap();
+        std::str::from_utf8(&bytes[..end]).unwrap()
+    }
+
+    /// The properties of the blob as paths of nodes and property names, with their values.
+    fn properties(blob: &[u8]) -> Vec<(String, Vec<u8>)> {
+        assert_eq!(be32(blob, 0), MAGIC);
+        assert_eq!(be32(blob, 4) as usize, blob.len());
+        assert_eq!(be32(blob, 20), VERSION);
+        let structure = be32(blob, 8) as usize;
+        let strings = be32(blob, 12) as usize;
+        // the memory reservation block ends with an empty entry
+        assert_eq!(blob[be32(blob, 16) as usize..structure], [0; 16]);
+        assert_eq!(strings + be32(blob, 32) as usize, blob.len());
+        assert_eq!(structure + be32(blob, 36) as usize, strings);
+
+        let mut properties = Vec::new();
+        let mut path = Vec::new();
+        let mut offset = structure;
+        loop {
+            let token = be32(blob, offset);
+            offset += 4;
+            match token {
+                BEGIN_NODE => {
+                    let name = string(&blob[offset..]);
+                    offset += (name.len() + 1).next_multiple_of(4);
+                    path.push(name.to_string());
+                }
+                END_NODE => {
+                    path.pop().unwrap();
+                }
+                PROP => {
+                    let length = be32(blob, offset) as usize;
+                    let name = string(&blob[strings + be32(blob, offset + 4) as usize..]);
+                    let value = blob[offset + 8..offset + 8 + length].to_vec();
+                    offset += 8 + length.next_multiple_of(4);
+                    properties.push((format!("{}/{name}", path.join("/")), value));
+                }
+                END => break,
+                _ => panic!("unknown token {token}"),
+            }
+        }
+        assert!(path.is_empty());
+        properties
+    }
+
+    fn property<'a>(properties: &'a [(String, Vec<u8>)], name: &str) -> Option<&'a [u8]> {
+        properties
+            .iter()
+            .find(|(path, _)| path == name)
+            .map(|(_, value)| &value[..])
+    }
+
+    fn cells(value: &[u8]) -> Vec<u32> {
+        (0..value.len())
+            .step_by(4)
+            .map(|offset| be32(value, offset))
+            .collect()
+    }
+
+    #[test]
+    fn isa_string() {
+        assert_eq!(isa(MISA_VALUE), "rv32i_zicsr_zifencei");
+        assert_eq!(isa(MISA_VALUE | 1 << 12 | 1), "rv32aim_zicsr_zifencei");
+    }
+
+    #[test]
+    fn tree() {
+        let mut machine = Machine::with_memory_size(0x10_0000);
+        let blob = device_tree(&machine, "console=ttyS0");
+        let properties = properties(&blob);
+        assert_eq!(
+            property(&properties, "/chosen/bootargs"),
+            Some(&b"console=ttyS0\0"[..])
+        );
+        assert_eq!(
+            property(&properties, "/chosen/stdout-path"),
+            Some(&b"/soc/serial@10000000\0"[..])
+        );
+        let reg = property(&properties, "/memory@80000000/reg").unwrap();
+        assert_eq!(cells(reg), [0x8000_0000, 0x10_0000]);
+        assert_eq!(
+            property(&properties, "/cpus/cpu@0/riscv,isa"),
+            Some(&b"rv32i_zicsr_zifencei\0"[..])
+        );
+        assert_eq!(
+            property(&properties, "/cpus/cpu@0/riscv,isa-extensions"),
+            Some(&b"i\0zicsr\0zifencei\0"[..])
+        );
+        let clint = property(&properties, "/soc/clint@2000000/interrupts-extended").unwrap();
+        assert_eq!(cells(clint), [CPU_INTC, 3, CPU_INTC, 7]);
+        let plic = property(&properties, "/soc/plic@c000000/interrupts-extended").unwrap();
+        assert_eq!(cells(plic), [CPU_INTC, 11, CPU_INTC, 9]);
+        let uart = property(&properties, "/soc/serial@10000000/interrupts").unwrap();
+        assert_eq!(cells(uart), [UART_IRQ]);
+        assert_eq!(
+            property(&properties, "/soc/plic@c000000/interrupt-controller"),
+            Some(&[][..])
+        );
+        assert!(property(&properties, "/soc/virtio_mmio@10001000/reg").is_none());
+        // names are shared
+        assert_eq!(
+            blob.windows(11)
+                .filter(|window| window == b"compatible\0")
+                .count(),
+            1
+        );
+
+        let disk = Disk::new(vec![0; SECTOR_SIZE], DiskMode::ReadOnly).unwrap();
+        machine.devices.virtio = Some(VirtioBlock::new(disk));
+        machine.devices.framebuffer = Some(Framebuffer::new(4, 3, PixelFormat::Rgb565).unwrap());
+        let properties = self::properties(&device_tree(&machine, ""));
+        let virtio = property(&properties, "/soc/virtio_mmio@10001000/reg").unwrap();
+        assert_eq!(cells(virtio), [VIRTIO_START, VIRTIO_SIZE]);
+        let framebuffer = property(&properties, "/soc/framebuffer@50001000/reg").unwrap();
+        assert_eq!(cells(framebuffer), [0x5000_1000, 24]);
+        let stride = property(&properties, "/soc/framebuffer@50001000/stride").unwrap();
+        assert_eq!(cells(stride), [8]);
+
+        // RGB888 has red in the first byte
+        machine.devices.framebuffer = Some(Framebuffer::new(4, 3, PixelFormat::Rgb888).unwrap());
+        let properties = self::properties(&device_tree(&machine, ""));
+        assert!(property(&properties, "/soc/framebuffer@50001000/reg").is_none());
+    }
+}
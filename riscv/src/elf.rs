@@ -0,0 +1,293 @@
+//! Loading ELF files: their segments into RAM, the functions in their symbol table and the line
+//! table of their DWARF debug information.
+
+use {
+    crate::{profile::Symbol, Machine, MEMORY_START, PC},
+    gimli::{EndianSlice, LittleEndian},
+    xmas_elf::{
+        program::{SegmentData, Type},
+        sections::{SectionData, SHF_EXECINSTR},
+        symbol_table::{self, Binding, Entry},
+        ElfFile,
+    },
+};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ElfError {
+    /// Not a valid ELF file.
+    Invalid(&'static str),
+    /// A loadable segment starting at `address` doesn't fit into the `memory_size` bytes of RAM.
+    OutsideOfRam { address: u64, memory_size: usize },
+    /// The DWARF debug information is malformed.
+    Dwarf(gimli::Error),
+}
+
+impl std::fmt::Display for ElfError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            ElfError::Invalid(message) => write!(f, "Invalid ELF file: {message}"),
+            ElfError::OutsideOfRam {
+                address,
+                memory_size,
+            } => write!(
+                f,
+                "Segment at 0x{address:x} is outside of RAM (0x{MEMORY_START:x}-0x{:x})",
+                MEMORY_START + memory_size
+            ),
+            ElfError::Dwarf(error) => write!(f, "Invalid DWARF debug information: {error}"),
+        }
+    }
+}
+
+impl std::error::Error for ElfError {}
+
+impl From<&'static str> for ElfError {
+    fn from(message: &'static str) -> ElfError {
+        ElfError::Invalid(message)
+    }
+}
+
+impl From<gimli::Error> for ElfError {
+    fn from(error: gimli::Error) -> ElfError {
+        ElfError::Dwarf(error)
+    }
+}
+
+/// Copies the loadable segments of the ELF file into RAM and starts at its entry point. Programs
+/// of riscv-tests signal their end by writing to the `tohost` symbol, see [`Machine::tohost`].
+pub fn load(machine: &mut Machine, data: &[u8]) -> Result<(), ElfError> {
+    let elf_file = ElfFile::new(data)?;
+    for program_header in elf_file.program_iter() {
+        if program_header.get_type() != Ok(Type::Load) || program_header.file_size() == 0 {
+            continue;
+        }
+        let SegmentData::Undefined(data) = program_header.get_data(&elf_file)? else {
+            return Err(ElfError::Invalid("unexpected segment data"));
+        };
+        let address = program_header.physical_addr();
+        let offset = address.wrapping_sub(MEMORY_START as u64) as usize;
+        let memory_size = machine.memory.len();
+        machine
+            .memory
+            .get_mut(offset..offset.saturating_add(data.len()))
+            .ok_or(ElfError::OutsideOfRam {
+                address,
+                memory_size,
+            })?
+            .copy_from_slice(data);
+    }
+    machine.registers[PC] = elf_file.header.pt2.entry_point() as u32;
+    machine.tohost = None;
+    for section in elf_file.section_iter() {
+        let Ok(SectionData::SymbolTable32(entries)) = section.get_data(&elf_file) else {
+            continue;
+        };
+        for entry in entries {
+            if entry.shndx() != 0 && entry.get_name(&elf_file)? == "tohost" {
+                machine.tohost = Some(entry.value() as u32);
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Returns the functions in the symbol table. Global symbols without a type in executable sections
+/// count as functions too, e.g., `_start` in assembly, but not `tohost`.
+pub fn symbols(data: &[u8]) -> Result<Vec<Symbol>, ElfError> {
+    let elf_file = ElfFile::new(data)?;
+    let mut symbols = Vec::new();
+    for section in elf_file.section_iter() {
+        let Ok(SectionData::SymbolTable32(entries)) = section.get_data(&elf_file) else {
+            continue;
+        };
+        for entry in entries {
+            let function = match entry.get_type()? {
+                symbol_table::Type::Func => true,
+                symbol_table::Type::NoType => {
+                    // absolute symbols have no section
+                    entry.get_binding()? == Binding::Global
+                        && elf_file
+                            .section_header(entry.shndx())
+                            .is_ok_and(|section| section.flags() & SHF_EXECINSTR != 0)
+                }
+                _ => false,
+            };
+            if function && entry.shndx() != 0 {
+                symbols.push(Symbol {
+                    name: entry.get_name(&elf_file)?.into(),
+                    address: entry.value() as u32,
+                    size: entry.size() as u32,
+                });
+            }
+        }
+    }
+    Ok(symbols)
+}
+
+/// The instructions at `start..end` were compiled from `line` of `file`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LineRange {
+    pub start: u32,
+    pub end: u32,
+    pub file: String,
+    pub line: u32,
+}
+
+/// Returns the line table of the DWARF debug information, sorted by address. Empty without debug
+/// information.
+pub fn lines(data: &[u8]) -> Result<Vec<LineRange>, ElfError> {
+    let elf_file = ElfFile::new(data)?;
+    let dwarf = gimli::Dwarf::load(|id| {
+        let data = elf_file
+            .find_section_by_name(id.name())
+            .map_or(&[][..], |section| section.raw_data(&elf_file));
+        Ok::<_, gimli::Error>(EndianSlice::new(data, LittleEndian))
+    })?;
+    let string = |unit: &gimli::Unit<_>, value| -> Result<String, gimli::Error> {
+        Ok(dwarf
+            .attr_string(unit, value)?
+            .to_string_lossy()
+            .into_owned())
+    };
+
+    let mut lines = Vec::new();
+    let mut units = dwarf.units();
+    while let Some(header) = units.next()? {
+        let unit = dwarf.unit(header)?;
+        let Some(program) = unit.line_program.clone() else {
+            continue;
+        };
+        let comp_dir = unit
+            .comp_dir
+            .map(|dir| dir.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        let mut rows = program.rows();
+        // the row starting the current range
+        let mut start: Option<(u32, String, u32)> = None;
+        while let Some((header, row)) = rows.next_row()? {
+            let address = row.address() as u32;
+            if let Some((start, file, line)) = start.take() {
+                if address > start {
+                    lines.push(LineRange {
+                        start,
+                        end: address,
+                        file,
+                        line,
+                    });
+                }
+            }
+            if row.end_sequence() {
+                continue;
+            }
+            let (Some(file), Some(line)) = (row.file(header), row.line()) else {
+                continue;
+            };
+            let mut path = std::path::PathBuf::from(&comp_dir);
+            // directory 0 is the compilation directory itself
+            if let Some(directory) = file
+                .directory(header)
+                .filter(|_| file.directory_index() != 0)
+            {
+                path.push(string(&unit, directory)?);
+            }
+            path.push(string(&unit, file.path_name())?);
+            start = Some((
+                address,
+                path.to_string_lossy().into_owned(),
+                line.get() as u32,
+            ));
+        }
+    }
+    lines.sort_by_key(|range| range.start);
+    Ok(lines)
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        super::*,
+        crate::{coverage::Coverage, load_word},
+    };
+
+    /// xmas-elf reads the symbol table in place, which needs the alignment of its entries.
+    #[repr(C, align(4))]
+    struct Aligned<T: ?Sized>(T);
+
+    /// Built from `fixtures/coverage.s`, which has the commands to rebuild it.
+    const FIXTURE: &[u8] = &Aligned(*include_bytes!("../fixtures/coverage.elf")).0;
+
+    #[test]
+    fn load_fixture() {
+        let mut machine = Machine::new();
+        load(&mut machine, FIXTURE).unwrap();
+        assert_eq!(machine.registers[PC], 0x8000_0000);
+        assert_eq!(machine.tohost, Some(0x8000_0030));
+        // li a0, 3 and ret
+        assert_eq!(load_word(&machine.memory, 0x8000_0000), Ok(0x0030_0513));
+        assert_eq!(load_word(&machine.memory, 0x8000_002c), Ok(0x0000_8067));
+
+        assert!(load(&mut machine, &FIXTURE[..16]).is_err());
+        assert_eq!(
+            load(&mut Machine::with_memory_size(0), FIXTURE),
+            Err(ElfError::OutsideOfRam {
+                address: 0x8000_0000,
+                memory_size: 0
+            })
+        );
+    }
+
+    #[test]
+    fn symbols_of_fixture() {
+        let symbols = symbols(FIXTURE).unwrap();
+        let symbol = |name: &str, address, size| Symbol {
+            name: name.into(),
+            address,
+            size,
+        };
+        assert_eq!(
+            symbols,
+            [
+                symbol("count", 0x8000_001c, 20),
+                symbol("_start", 0x8000_0000, 0)
+            ]
+        );
+    }
+
+    #[test]
+    fn lines_of_fixture() {
+        let lines = lines(FIXTURE).unwrap();
+        let range = |start, end, line| LineRange {
+            start,
+            end,
+            file: "./coverage.s".into(),
+            line,
+        };
+        assert_eq!(lines.len(), 10);
+        assert_eq!(lines[0], range(0x8000_0000, 0x8000_0004, 8));
+        // the call expands to auipc and jalr
+        assert_eq!(lines[1], range(0x8000_0004, 0x8000_000c, 9));
+        assert_eq!(lines[5], range(0x8000_001c, 0x8000_0020, 16));
+        assert_eq!(lines[9], range(0x8000_002c, 0x8000_0030, 21));
+    }
+
+    #[test]
+    fn lcov_of_fixture() {
+        let mut machine = Machine::new();
+        load(&mut machine, FIXTURE).unwrap();
+        machine.coverage = Some(Coverage::default());
+        assert_eq!(machine.run(100), Ok(true));
+        let lcov = machine
+            .coverage
+            .as_ref()
+            .unwrap()
+            .lcov(&lines(FIXTURE).unwrap(), &machine.memory);
+        // the loop on lines 16 and 17 runs three times, the nop on line 19 never
+        assert_eq!(
+            lcov,
+            "TN:\nSF:./coverage.s\n\
+             BRDA:17,0,0,2\nBRDA:17,0,1,1\nBRDA:18,0,0,1\nBRDA:18,0,1,0\nBRF:4\nBRH:3\n\
+             DA:8,1\nDA:9,1\nDA:10,1\nDA:11,1\nDA:12,1\nDA:16,3\nDA:17,3\nDA:18,1\nDA:19,0\n\
+             DA:21,1\nLF:10\nLH:9\nend_of_record\n"
+        );
+    }
+}
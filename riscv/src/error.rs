@@ -0,0 +1,173 @@
+use crate::{csr::Exception, MEMORY_START};
+
+/// The kind of memory access which caused a [`Error::MemoryError`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Access {
+    Fetch,
+    Load,
+    Store,
+}
+
+impl std::fmt::Display for Access {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.write_str(match self {
+            Access::Fetch => "fetch",
+            Access::Load => "load",
+            Access::Store => "store",
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Error {
+    /// The instruction at `pc` is not a valid RV32I instruction.
+    DecodeError { pc: u32, code: u32 },
+    /// `size` bytes at `address` are not backed by memory. `code` is `None` for instruction
+    /// fetches, since there is no instruction yet.
+    MemoryError {
+        pc: u32,
+        code: Option<u32>,
+        access: Access,
+        address: u32,
+        size: u32,
+    },
+    /// The instruction at `pc` raised an exception which no handler could take, e.g., an ECALL
+    /// before `mtvec` was set up. `tval` is the value a handler would find in `mtval`. `code` is
+    /// `None` if fetching the instruction raised it.
+    Exception {
+        pc: u32,
+        code: Option<u32>,
+        exception: Exception,
+        tval: u32,
+    },
+}
+
+impl Error {
+    pub fn pc(&self) -> u32 {
+        match *self {
+            Error::DecodeError { pc, .. }
+            | Error::MemoryError { pc, .. }
+            | Error::Exception { pc, .. } => pc,
+        }
+    }
+
+    /// The exception the error raises in the emulated hart and its trap value.
+    pub fn exception(&self) -> (Exception, u32) {
+        match *self {
+            Error::DecodeError { code, .. } => (Exception::IllegalInstruction, code),
+            Error::MemoryError {
+                access, address, ..
+            } => {
+                let exception = match access {
+                    Access::Fetch => Exception::InstructionAccessFault,
+                    Access::Load => Exception::LoadAccessFault,
+                    Access::Store => Exception::StoreAccessFault,
+                };
+                (exception, address)
+            }
+            Error::Exception {
+                exception, tval, ..
+            } => (exception, tval),
+        }
+    }
+}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match *self {
+            Error::DecodeError { pc, code } => {
+                write!(f, "Failed to decode instruction 0x{code:08x} at 0x{pc:08x}")
+            }
+            Error::MemoryError {
+                pc,
+                code,
+                access,
+                address,
+                size,
+            } => {
+                write!(
+                    f,
+                    "Memory Error: {size}-byte {access} at 0x{pc:08x}{} from address 0x{address:08x} \
+                    is neither RAM (from 0x{MEMORY_START:08x}) nor a device",
+                    code.map(|code| format!(" (instruction 0x{code:08x})"))
+                        .unwrap_or_default(),
+                )
+            }
+            Error::Exception {
+                pc,
+                code,
+                exception,
+                tval,
+            } => write!(
+                f,
+                "{exception} at 0x{pc:08x}{}, trap value 0x{tval:08x}",
+                code.map(|code| format!(" (instruction 0x{code:08x})"))
+                    .unwrap_or_default(),
+            ),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn display() {
+        assert_eq!(
+            Error::DecodeError {
+                pc: 0x8000_0010,
+                code: 0xffff_ffff
+            }
+            .to_string(),
+            "Failed to decode instruction 0xffffffff at 0x80000010"
+        );
+        assert_eq!(
+            Error::MemoryError {
+                pc: 0x8000_0004,
+                code: Some(0x0005_2283),
+                access: Access::Load,
+                address: 0x10,
+                size: 4
+            }
+            .to_string(),
+            "Memory Error: 4-byte load at 0x80000004 (instruction 0x00052283) from address \
+            0x00000010 is neither RAM (from 0x80000000) nor a device"
+        );
+        assert_eq!(
+            Error::MemoryError {
+                pc: 0,
+                code: None,
+                access: Access::Fetch,
+                address: 0,
+                size: 4
+            }
+            .to_string(),
+            "Memory Error: 4-byte fetch at 0x00000000 from address 0x00000000 \
+            is neither RAM (from 0x80000000) nor a device"
+        );
+        assert_eq!(
+            Error::Exception {
+                pc: 0x8000_0008,
+                code: Some(0x0000_0073),
+                exception: Exception::MachineEnvironmentCall,
+                tval: 0
+            }
+            .to_string(),
+            "Environment call from M-mode at 0x80000008 (instruction 0x00000073), trap value \
+            0x00000000"
+        );
+        assert_eq!(
+            Error::Exception {
+                pc: 0x0000_1000,
+                code: None,
+                exception: Exception::InstructionPageFault,
+                tval: 0x0000_1000
+            }
+            .to_string(),
+            "Instruction page fault at 0x00001000, trap value 0x00001000"
+        );
+    }
+}
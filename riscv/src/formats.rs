@@ -1,6 +1,6 @@
 use crate::utils::{sign_extend, REGISTER_NAMES};
 
-#[derive(Clone, Copy)]
+#[derive(Clone, Copy, PartialEq, Eq)]
 pub struct RType(pub u32);
 impl RType {
     pub fn rd(&self) -> u32 {
@@ -26,7 +26,7 @@ impl std::fmt::Debug for RType {
     }
 }
 
-#[derive(Clone, Copy)]
+#[derive(Clone, Copy, PartialEq, Eq)]
 pub struct IType(pub u32);
 impl IType {
     pub fn rd(&self) -> u32 {
@@ -52,7 +52,7 @@ impl std::fmt::Debug for IType {
     }
 }
 
-#[derive(Clone, Copy)]
+#[derive(Clone, Copy, PartialEq, Eq)]
 pub struct SType(pub u32);
 impl SType {
     pub fn rs1(&self) -> u32 {
@@ -81,7 +81,7 @@ impl std::fmt::Debug for SType {
     }
 }
 
-#[derive(Clone, Copy)]
+#[derive(Clone, Copy, PartialEq, Eq)]
 pub struct BType(pub u32);
 impl BType {
     pub fn rs1(&self) -> u32 {
@@ -113,7 +113,7 @@ impl std::fmt::Debug for BType {
     }
 }
 
-#[derive(Clone, Copy)]
+#[derive(Clone, Copy, PartialEq, Eq)]
 pub struct UType(pub u32);
 impl UType {
     pub fn rd(&self) -> u32 {
@@ -136,7 +136,7 @@ impl std::fmt::Debug for UType {
     }
 }
 
-#[derive(Clone, Copy)]
+#[derive(Clone, Copy, PartialEq, Eq)]
 pub struct JType(pub u32);
 impl JType {
     pub fn rd(&self) -> u32 {
@@ -163,3 +163,91 @@ impl std::fmt::Debug for JType {
         )
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // all bits outside of the immediate set, so leaking fields show up in the result
+    const I_MASK: u32 = 0x000f_ffff;
+    const S_MASK: u32 = 0x01ff_f07f;
+    const B_MASK: u32 = 0x01ff_f07f;
+    const U_MASK: u32 = 0x0000_0fff;
+    const J_MASK: u32 = 0x0000_0fff;
+
+    fn sext(value: u32, bits: u32) -> u32 {
+        ((value << (32 - bits)) as i32 >> (32 - bits)) as u32
+    }
+
+    #[test]
+    fn i_type_imm() {
+        for imm in 0..1 << 12 {
+            let code = imm << 20;
+            assert_eq!(IType(code).imm(), sext(imm, 12), "imm: {imm:012b}");
+            assert_eq!(IType(code | I_MASK).imm(), sext(imm, 12), "imm: {imm:012b}");
+        }
+        assert_eq!(IType(0x7ff0_0000).imm(), 2047);
+        assert_eq!(IType(0x8000_0000).imm(), -2048i32 as u32);
+        assert_eq!(IType(0xfff0_0000).imm(), -1i32 as u32);
+    }
+
+    #[test]
+    fn s_type_imm() {
+        for imm in 0..1 << 12 {
+            let code = (imm >> 5) << 25 | (imm & 0b1_1111) << 7;
+            assert_eq!(SType(code).imm(), sext(imm, 12), "imm: {imm:012b}");
+            assert_eq!(SType(code | S_MASK).imm(), sext(imm, 12), "imm: {imm:012b}");
+        }
+        assert_eq!(SType(0x7e00_0f80).imm(), 2047);
+        assert_eq!(SType(0x8000_0000).imm(), -2048i32 as u32);
+    }
+
+    #[test]
+    fn b_type_imm() {
+        // imm[0] is always zero
+        for imm in (0..1 << 13).step_by(2) {
+            let code = (imm >> 12 & 1) << 31
+                | (imm >> 5 & 0b11_1111) << 25
+                | (imm >> 1 & 0b1111) << 8
+                | (imm >> 11 & 1) << 7;
+            assert_eq!(BType(code).imm(), sext(imm, 13), "imm: {imm:013b}");
+            assert_eq!(BType(code | B_MASK).imm(), sext(imm, 13), "imm: {imm:013b}");
+        }
+        assert_eq!(BType(0x7e00_0f80).imm(), 4094);
+        assert_eq!(BType(0x8000_0000).imm(), -4096i32 as u32);
+    }
+
+    #[test]
+    fn u_type_imm() {
+        for imm in 0..1 << 20 {
+            let code = imm << 12;
+            assert_eq!(UType(code).imm(), imm << 12, "imm: {imm:020b}");
+            assert_eq!(UType(code | U_MASK).imm(), imm << 12, "imm: {imm:020b}");
+        }
+    }
+
+    #[test]
+    fn j_type_imm() {
+        // imm[0] is always zero
+        for imm in (0..1 << 21).step_by(2) {
+            let code = (imm >> 20 & 1) << 31
+                | (imm >> 1 & 0b11_1111_1111) << 21
+                | (imm >> 11 & 1) << 20
+                | (imm >> 12 & 0b1111_1111) << 12;
+            assert_eq!(JType(code).imm(), sext(imm, 21), "imm: {imm:021b}");
+            assert_eq!(JType(code | J_MASK).imm(), sext(imm, 21), "imm: {imm:021b}");
+        }
+        assert_eq!(JType(0x7fff_f000).imm(), 0x000f_fffe);
+        assert_eq!(JType(0x8000_0000).imm(), -0x10_0000i32 as u32);
+    }
+
+    #[test]
+    fn register_fields() {
+        // add t6, t5, t4
+        let r_type = RType(0x01df_0fb3);
+        assert_eq!((r_type.rd(), r_type.rs1(), r_type.rs2()), (31, 30, 29));
+        // sw t6, 0(t5)
+        let s_type = SType(0x01ff_2023);
+        assert_eq!((s_type.rs1(), s_type.rs2()), (30, 31));
+    }
+}
@@ -0,0 +1,317 @@
+//! A linear framebuffer for programs which draw. Its pixels are rows of RGB565 (a little-endian
+//! `u16` with red in the top 5 bits) or RGB888 (3 bytes, red first), starting at the top left,
+//! with `stride` bytes per row. The host chooses the resolution and the format, which programs
+//! read from the registers in front of the pixels:
+//!
+//! | Offset   | Register                                            |
+//! |----------|-----------------------------------------------------|
+//! | `0x0`    | width in pixels                                     |
+//! | `0x4`    | height in pixels                                    |
+//! | `0x8`    | format, 0 for RGB565 and 1 for RGB888               |
+//! | `0xc`    | stride in bytes                                     |
+//! | `0x1000` | the pixels, accessible as bytes, halfwords or words |
+//!
+//! The registers are read-only words.
+//!
+//! The host reads the picture with [`Framebuffer::rgb`], or encoded with [`Framebuffer::ppm`] and
+//! [`Framebuffer::png`].
+
+/// The size of the framebuffer's address range, enough for the largest resolution.
+pub const FRAMEBUFFER_SIZE: u32 = 0x200_0000;
+/// The offset of the pixels.
+pub const PIXELS: u32 = 0x1000;
+/// The largest width and height.
+pub const MAX_RESOLUTION: u32 = 2048;
+
+// register offsets
+pub const WIDTH: u32 = 0x0;
+pub const HEIGHT: u32 = 0x4;
+pub const FORMAT: u32 = 0x8;
+pub const STRIDE: u32 = 0xc;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum PixelFormat {
+    #[default]
+    Rgb565 = 0,
+    Rgb888 = 1,
+}
+
+impl PixelFormat {
+    pub fn bytes_per_pixel(self) -> u32 {
+        match self {
+            PixelFormat::Rgb565 => 2,
+            PixelFormat::Rgb888 => 3,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ConfigError {
+    /// The width or the height is 0.
+    Empty,
+    /// The width or the height is larger than [`MAX_RESOLUTION`].
+    TooLarge,
+}
+
+impl std::fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            ConfigError::Empty => f.write_str("The framebuffer has no pixels"),
+            ConfigError::TooLarge => write!(
+                f,
+                "The framebuffer is larger than {MAX_RESOLUTION}x{MAX_RESOLUTION} pixels"
+            ),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Framebuffer {
+    width: u32,
+    height: u32,
+    format: PixelFormat,
+    /// `stride * height` bytes, black after reset.
+    pixels: Vec<u8>,
+}
+
+impl Framebuffer {
+    pub fn new(width: u32, height: u32, format: PixelFormat) -> Result<Framebuffer, ConfigError> {
+        if width == 0 || height == 0 {
+            return Err(ConfigError::Empty);
+        }
+        if width > MAX_RESOLUTION || height > MAX_RESOLUTION {
+            return Err(ConfigError::TooLarge);
+        }
+        let length = (width * format.bytes_per_pixel() * height) as usize;
+        Ok(Framebuffer {
+            width,
+            height,
+            format,
+            pixels: vec![0; length],
+        })
+    }
+
+    pub fn width(&self) -> u32 {
+        self.width
+    }
+
+    pub fn height(&self) -> u32 {
+        self.height
+    }
+
+    pub fn format(&self) -> PixelFormat {
+        self.format
+    }
+
+    pub fn pixels(&self) -> &[u8] {
+        &self.pixels
+    }
+
+    pub fn pixels_mut(&mut self) -> &mut [u8] {
+        &mut self.pixels
+    }
+
+    /// The number of bytes per row.
+    pub fn stride(&self) -> u32 {
+        self.width * self.format.bytes_per_pixel()
+    }
+
+    /// Reads the `size` bytes at `offset`, `None` if they are neither a register nor pixels.
+    pub fn load(&self, offset: u32, size: u32) -> Option<u32> {
+        if offset < PIXELS {
+            if size != 4 {
+                return None;
+            }
+            return Some(match offset {
+                WIDTH => self.width,
+                HEIGHT => self.height,
+                FORMAT => self.format as u32,
+                STRIDE => self.stride(),
+                _ => return None,
+            });
+        }
+        let start = (offset - PIXELS) as usize;
+        let bytes = self.pixels.get(start..start + size as usize)?;
+        Some(
+            bytes
+                .iter()
+                .rev()
+                .fold(0, |value, &byte| value << 8 | byte as u32),
+        )
+    }
+
+    /// Writes the `size` bytes of `value` at `offset`, `None` if they aren't pixels. The
+    /// registers are read-only.
+    pub fn store(&mut self, offset: u32, size: u32, value: u32) -> Option<()> {
+        let start = offset.checked_sub(PIXELS)? as usize;
+        let bytes = self.pixels.get_mut(start..start + size as usize)?;
+        bytes.copy_from_slice(&value.to_le_bytes()[..size as usize]);
+        Some(())
+    }
+
+    /// The picture as rows of 3 bytes per pixel, red, green and blue.
+    pub fn rgb(&self) -> Vec<u8> {
+        match self.format {
+            PixelFormat::Rgb888 => self.pixels.clone(),
+            PixelFormat::Rgb565 => self
+                .pixels
+                .chunks(2)
+                .flat_map(|bytes| {
+                    let pixel = u16::from_le_bytes([bytes[0], bytes[1]]);
+                    // scales 5 and 6 bits to 8, so white stays white
+                    let scale = |value: u16, bits: u32| {
+                        let value = value & ((1 << bits) - 1);
+                        (value << (8 - bits) | value >> (2 * bits - 8)) as u8
+                    };
+                    [scale(pixel >> 11, 5), scale(pixel >> 5, 6), scale(pixel, 5)]
+                })
+                .collect(),
+        }
+    }
+
+    /// The picture as a binary PPM (P6) image.
+    pub fn ppm(&self) -> Vec<u8> {
+        let mut output = format!("P6\n{} {}\n255\n", self.width, self.height).into_bytes();
+        output.extend(self.rgb());
+        output
+    }
+
+    /// The picture as an 8-bit RGB PNG image. The image data is stored without compression.
+    pub fn png(&self) -> Vec<u8> {
+        let mut header = Vec::new();
+        header.extend(self.width.to_be_bytes());
+        header.extend(self.height.to_be_bytes());
+        // 8 bits per channel, truecolor, deflate, adaptive filtering, no interlacing
+        header.extend([8, 2, 0, 0, 0]);
+
+        // each row starts with filter type 0, none
+        let rgb = self.rgb();
+        let rows = rgb
+            .chunks(3 * self.width as usize)
+            .flat_map(|row| std::iter::once(0).chain(row.iter().copied()))
+            .collect::<Vec<u8>>();
+        // a zlib stream of stored deflate blocks, which hold at most 65535 bytes each
+        let mut data = vec![0x78, 0x01];
+        let blocks = rows.chunks(0xffff);
+        let count = blocks.len();
+        for (i, block) in blocks.enumerate() {
+            data.push((i + 1 == count) as u8);
+            data.extend((block.len() as u16).to_le_bytes());
+            data.extend((!(block.len() as u16)).to_le_bytes());
+            data.extend(block);
+        }
+        data.extend(adler32(&rows).to_be_bytes());
+
+        let mut output = b"\x89PNG\r\n\x1a\n".to_vec();
+        for (kind, payload) in [(b"IHDR", &header), (b"IDAT", &data), (b"IEND", &Vec::new())] {
+            output.extend((payload.len() as u32).to_be_bytes());
+            let start = output.len();
+            output.extend(kind);
+            output.extend(payload);
+            let crc = crc32(&output[start..]);
+            output.extend(crc.to_be_bytes());
+        }
+        output
+    }
+}
+
+/// The checksum of zlib streams.
+fn adler32(data: &[u8]) -> u32 {
+    let (a, b) = data.iter().fold((1u32, 0u32), |(a, b), &byte| {
+        let a = (a + byte as u32) % 65521;
+        (a, (b + a) % 65521)
+    });
+    b << 16 | a
+}
+
+/// The CRC-32 of PNG chunks, computed bit by bit.
+fn crc32(data: &[u8]) -> u32 {
+    !data.iter().fold(!0u32, |crc, &byte| {
+        (0..8).fold(crc ^ byte as u32, |crc, _| {
+            (crc >> 1) ^ (0xedb8_8320 & (crc & 1).wrapping_neg())
+        })
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        super::*,
+        crate::{devices::FRAMEBUFFER_START, Machine},
+    };
+
+    #[test]
+    fn config() {
+        assert_eq!(
+            Framebuffer::new(0, 1, PixelFormat::Rgb565),
+            Err(ConfigError::Empty)
+        );
+        assert_eq!(
+            Framebuffer::new(1, 4096, PixelFormat::Rgb888),
+            Err(ConfigError::TooLarge)
+        );
+        let framebuffer = Framebuffer::new(MAX_RESOLUTION, MAX_RESOLUTION, PixelFormat::Rgb888);
+        assert!(PIXELS as usize + framebuffer.unwrap().pixels().len() <= FRAMEBUFFER_SIZE as usize);
+    }
+
+    #[test]
+    fn accesses() {
+        let mut machine = Machine::new();
+        let framebuffer = Framebuffer::new(4, 2, PixelFormat::Rgb888).unwrap();
+        machine.devices.framebuffer = Some(framebuffer);
+        let devices = &mut machine.devices;
+        let load = |devices: &mut crate::devices::Devices, offset, size| {
+            devices.load(FRAMEBUFFER_START + offset, size, 0)
+        };
+        assert_eq!(load(devices, WIDTH, 4), Some(4));
+        assert_eq!(load(devices, FORMAT, 4), Some(1));
+        assert_eq!(load(devices, STRIDE, 4), Some(12));
+        assert_eq!(load(devices, STRIDE, 2), None);
+        assert_eq!(load(devices, 0x10, 4), None);
+
+        assert_eq!(
+            devices.store(FRAMEBUFFER_START + PIXELS + 3, 4, 0x4433_2211, 0),
+            Some(())
+        );
+        assert_eq!(
+            devices.store(FRAMEBUFFER_START + PIXELS + 22, 1, 0xff, 0),
+            Some(())
+        );
+        assert_eq!(load(devices, PIXELS + 4, 2), Some(0x3322));
+        assert_eq!(load(devices, PIXELS + 23, 1), Some(0));
+        // past the last pixel
+        assert_eq!(load(devices, PIXELS + 22, 4), None);
+        assert_eq!(
+            devices.store(FRAMEBUFFER_START + PIXELS + 24, 1, 0, 0),
+            None
+        );
+        assert_eq!(devices.store(FRAMEBUFFER_START + WIDTH, 4, 1, 0), None);
+
+        // without a framebuffer, its addresses fault
+        devices.framebuffer = None;
+        assert_eq!(load(devices, WIDTH, 4), None);
+    }
+
+    #[test]
+    fn images() {
+        let mut framebuffer = Framebuffer::new(2, 1, PixelFormat::Rgb565).unwrap();
+        // white and pure green
+        framebuffer
+            .pixels_mut()
+            .copy_from_slice(&[0xff, 0xff, 0xe0, 0x07]);
+        assert_eq!(framebuffer.rgb(), [0xff, 0xff, 0xff, 0, 0xff, 0]);
+        assert_eq!(framebuffer.ppm(), b"P6\n2 1\n255\n\xff\xff\xff\x00\xff\x00");
+
+        let png = framebuffer.png();
+        assert_eq!(png[..8], *b"\x89PNG\r\n\x1a\n");
+        assert_eq!(png[12..16], *b"IHDR");
+        // a single stored block with the filter byte and the pixels
+        assert_eq!(png[37..41], *b"IDAT");
+        assert_eq!(png[41..48], [0x78, 0x01, 1, 7, 0, 0xf8, 0xff]);
+        assert_eq!(adler32(&png[48..55]).to_be_bytes(), png[55..59]);
+        // the well-known CRC of an empty IEND chunk
+        assert_eq!(png[png.len() - 12..], *b"\0\0\0\0IEND\xae\x42\x60\x82");
+    }
+}
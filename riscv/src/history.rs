@@ -0,0 +1,237 @@
+use {
+    crate::{
+        csr::Csrs, store_byte, store_half_word, store_word, timing::Cost, Memory, MemoryWrite,
+        RegisterWrite, Registers, Retired, PC,
+    },
+    std::collections::VecDeque,
+};
+
+/// Everything needed to revert a single retired instruction or trap.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Undo {
+    pc: u32,
+    rd: Option<RegisterWrite>,
+    store: Option<MemoryWrite>,
+    /// The CSRs before, if they changed.
+    csrs: Option<Box<Csrs>>,
+    /// `false` for a trap, which didn't retire an instruction.
+    retired: bool,
+    cost: Cost,
+}
+
+/// A bounded undo log of retired instructions. Once `capacity` instructions are recorded, the
+/// oldest ones are dropped. A capacity of 0 disables recording.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct History {
+    entries: VecDeque<Undo>,
+    capacity: usize,
+}
+
+impl History {
+    pub fn new(capacity: usize) -> History {
+        History {
+            entries: VecDeque::new(),
+            capacity,
+        }
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    /// The number of instructions which can be undone.
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn clear(&mut self) {
+        self.entries.clear();
+    }
+
+    fn push(&mut self, undo: Undo) {
+        if self.capacity == 0 {
+            return;
+        }
+        if self.entries.len() == self.capacity {
+            self.entries.pop_front();
+        }
+        self.entries.push_back(undo);
+    }
+
+    pub fn record(&mut self, retired: &Retired, cost: Cost) {
+        self.push(Undo {
+            pc: retired.pc,
+            rd: retired.rd,
+            store: retired.store,
+            csrs: None,
+            retired: true,
+            cost,
+        });
+    }
+
+    /// Records the CSRs before the most recently recorded instruction, which changed them.
+    pub fn record_csrs(&mut self, previous: Csrs) {
+        if let Some(undo) = self.entries.back_mut() {
+            undo.csrs = Some(Box::new(previous));
+        }
+    }
+
+    /// Adds the `cycles` the most recently recorded instruction waited for an interrupt to its
+    /// cost.
+    pub fn record_wait(&mut self, cycles: u64) {
+        if let Some(undo) = self.entries.back_mut() {
+            undo.cost.cycles += cycles;
+        }
+    }
+
+    /// Records a trap taken by the instruction at `pc`, with the CSRs before.
+    pub fn record_trap(&mut self, pc: u32, previous: Csrs, cost: Cost) {
+        self.push(Undo {
+            pc,
+            rd: None,
+            store: None,
+            csrs: Some(Box::new(previous)),
+            retired: false,
+            cost,
+        });
+    }
+
+    /// The store of the most recently recorded instruction, which [`History::undo`] reverts next.
+    pub fn last_store(&self) -> Option<MemoryWrite> {
+        self.entries.back().and_then(|undo| undo.store)
+    }
+
+    /// The timing of the most recently recorded instruction, which [`History::undo`] reverts next.
+    pub fn last_cost(&self) -> Option<Cost> {
+        self.entries.back().map(|undo| undo.cost)
+    }
+
+    /// Whether the most recently recorded entry is a retired instruction rather than a trap.
+    pub fn last_retired(&self) -> bool {
+        self.entries.back().is_some_and(|undo| undo.retired)
+    }
+
+    /// Reverts the most recently recorded instruction or trap. Returns `false` if there is none.
+    pub fn undo(
+        &mut self,
+        registers: &mut Registers,
+        memory: &mut Memory,
+        csrs: &mut Csrs,
+    ) -> bool {
+        let Some(undo) = self.entries.pop_back() else {
+            return false;
+        };
+        registers[PC] = undo.pc;
+        if let Some(previous) = undo.csrs {
+            *csrs = *previous;
+        }
+        if let Some(RegisterWrite { register, old, .. }) = undo.rd {
+            registers[register as usize] = old;
+        }
+        if let Some(MemoryWrite {
+            address, size, old, ..
+        }) = undo.store
+        {
+            match size {
+                1 => store_byte(memory, address, old as u8),
+                2 => store_half_word(memory, address, old as u16),
+                _ => store_word(memory, address, old),
+            }
+            .expect("the store succeeded when it was recorded");
+        }
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{programs::BUBBLE_SORT, test_utils, History, Machine, MEMORY_START, PC};
+
+    fn machine(capacity: usize) -> Machine {
+        let mut machine = test_utils::machine(BUBBLE_SORT);
+        machine.registers[2] = MEMORY_START as u32 + 0xa0;
+        machine.history = History::new(capacity);
+        machine
+    }
+
+    #[test]
+    fn step_back_to_start() {
+        let mut machine = machine(usize::MAX);
+        let mut states = vec![];
+        // the program returns to `ra` = 0, which fails to fetch
+        while {
+            states.push((machine.registers, machine.memory.clone()));
+            machine.step().is_ok()
+        } {}
+        states.pop();
+        assert_eq!(machine.history.len(), states.len());
+        assert_eq!(
+            machine.memory[0x90..0xa0],
+            [1, 0, 0, 0, 2, 0, 0, 0, 4, 0, 0, 0, 5, 0, 0, 0]
+        );
+
+        while let Some((registers, memory)) = states.pop() {
+            assert!(machine.step_back());
+            assert_eq!(machine.registers, registers);
+            assert!(machine.memory == memory);
+        }
+        assert!(!machine.step_back());
+    }
+
+    #[test]
+    fn bounded_capacity() {
+        let mut machine = machine(10);
+        for _ in 0..20 {
+            machine.step().unwrap();
+        }
+        let registers = machine.registers;
+        assert_eq!(machine.history.len(), 10);
+        for _ in 0..10 {
+            assert!(machine.step_back());
+        }
+        assert!(!machine.step_back());
+        for _ in 0..10 {
+            machine.step().unwrap();
+        }
+        assert_eq!(machine.registers, registers);
+    }
+
+    #[test]
+    fn disabled_by_default() {
+        assert_eq!(Machine::new().history.capacity(), 0);
+        let mut machine = machine(0);
+        machine.step().unwrap();
+        assert!(machine.history.is_empty());
+        assert!(!machine.step_back());
+    }
+
+    #[test]
+    fn run_back_to() {
+        let mut machine = machine(1000);
+        // the inner loop starts at 0x38
+        let inner_loop = MEMORY_START as u32 + 0x38;
+        for _ in 0..50 {
+            machine.step().unwrap();
+        }
+        let visits = |machine: &Machine| {
+            let mut clone = machine.clone();
+            let mut visits = 0;
+            while clone.run_back_to(inner_loop) {
+                assert_eq!(clone.registers[PC], inner_loop);
+                visits += 1;
+            }
+            visits
+        };
+        let before = visits(&machine);
+        assert!(before > 1);
+        assert!(machine.run_back_to(inner_loop));
+        assert_eq!(machine.registers[PC], inner_loop);
+        assert_eq!(visits(&machine), before - 1);
+        assert!(!machine.run_back_to(0));
+        assert!(machine.history.is_empty());
+    }
+}
@@ -2,7 +2,7 @@
 
 use crate::formats::{BType, IType, JType, RType, SType, UType};
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum Instruction {
     // LUI 0110111
     LUI(UType),
@@ -60,7 +60,10 @@ pub enum Instruction {
     AND(RType),
 
     // FENCE 0001111
-    FENCE,
+    FENCE(IType),
+    // Instruction-Fetch Fence (Zifencei Standard Extension)
+    #[allow(non_camel_case_types)]
+    FENCE_I(IType),
 
     // SYSTEM 1110011
     ECALL,
@@ -71,11 +74,190 @@ pub enum Instruction {
     MRET,
     // Interrupt-Management Instructions
     WFI,
+    // Supervisor Memory-Management Instructions
+    #[allow(non_camel_case_types)]
+    SFENCE_VMA(RType),
     // CSR Instructions (Zicsr Standard Extension)
-    CSRRW,
-    CSRRS,
-    CSRRC,
-    CSRRWI,
-    CSRRSI,
-    CSRRCI,
+    CSRRW(IType),
+    CSRRS(IType),
+    CSRRC(IType),
+    CSRRWI(IType),
+    CSRRSI(IType),
+    CSRRCI(IType),
+}
+
+impl Instruction {
+    /// Returns the machine code of the instruction, i.e., the inverse of [`decode`](crate::decode).
+    pub fn encode(&self) -> u32 {
+        match *self {
+            Instruction::LUI(UType(code)) | Instruction::AUIPC(UType(code)) => code,
+            Instruction::JAL(JType(code)) => code,
+            Instruction::BEQ(BType(code))
+            | Instruction::BNE(BType(code))
+            | Instruction::BLT(BType(code))
+            | Instruction::BGE(BType(code))
+            | Instruction::BLTU(BType(code))
+            | Instruction::BGEU(BType(code)) => code,
+            Instruction::SB(SType(code))
+            | Instruction::SH(SType(code))
+            | Instruction::SW(SType(code)) => code,
+            Instruction::JALR(IType(code))
+            | Instruction::LB(IType(code))
+            | Instruction::LH(IType(code))
+            | Instruction::LW(IType(code))
+            | Instruction::LBU(IType(code))
+            | Instruction::LHU(IType(code))
+            | Instruction::ADDI(IType(code))
+            | Instruction::SLTI(IType(code))
+            | Instruction::SLTIU(IType(code))
+            | Instruction::XORI(IType(code))
+            | Instruction::ORI(IType(code))
+            | Instruction::ANDI(IType(code))
+            | Instruction::SLLI(IType(code))
+            | Instruction::SRLI(IType(code))
+            | Instruction::SRAI(IType(code))
+            | Instruction::FENCE(IType(code))
+            | Instruction::FENCE_I(IType(code))
+            | Instruction::CSRRW(IType(code))
+            | Instruction::CSRRS(IType(code))
+            | Instruction::CSRRC(IType(code))
+            | Instruction::CSRRWI(IType(code))
+            | Instruction::CSRRSI(IType(code))
+            | Instruction::CSRRCI(IType(code)) => code,
+            Instruction::ADD(RType(code))
+            | Instruction::SUB(RType(code))
+            | Instruction::SLL(RType(code))
+            | Instruction::SLT(RType(code))
+            | Instruction::SLTU(RType(code))
+            | Instruction::XOR(RType(code))
+            | Instruction::SRL(RType(code))
+            | Instruction::SRA(RType(code))
+            | Instruction::OR(RType(code))
+            | Instruction::AND(RType(code))
+            | Instruction::SFENCE_VMA(RType(code)) => code,
+            Instruction::ECALL => 0x0000_0073,
+            Instruction::EBREAK => 0x0010_0073,
+            Instruction::URET => 0x0020_0073,
+            Instruction::SRET => 0x1020_0073,
+            Instruction::MRET => 0x3020_0073,
+            Instruction::WFI => 0x1050_0073,
+        }
+    }
+
+    /// Returns the registers the instruction reads, `0` for unused operands (reading `x0` never
+    /// depends on another instruction).
+    pub fn sources(&self) -> [u32; 2] {
+        match *self {
+            Instruction::BEQ(b_type)
+            | Instruction::BNE(b_type)
+            | Instruction::BLT(b_type)
+            | Instruction::BGE(b_type)
+            | Instruction::BLTU(b_type)
+            | Instruction::BGEU(b_type) => [b_type.rs1(), b_type.rs2()],
+            Instruction::SB(s_type) | Instruction::SH(s_type) | Instruction::SW(s_type) => {
+                [s_type.rs1(), s_type.rs2()]
+            }
+            Instruction::JALR(i_type)
+            | Instruction::LB(i_type)
+            | Instruction::LH(i_type)
+            | Instruction::LW(i_type)
+            | Instruction::LBU(i_type)
+            | Instruction::LHU(i_type)
+            | Instruction::ADDI(i_type)
+            | Instruction::SLTI(i_type)
+            | Instruction::SLTIU(i_type)
+            | Instruction::XORI(i_type)
+            | Instruction::ORI(i_type)
+            | Instruction::ANDI(i_type)
+            | Instruction::SLLI(i_type)
+            | Instruction::SRLI(i_type)
+            | Instruction::SRAI(i_type)
+            | Instruction::CSRRW(i_type)
+            | Instruction::CSRRS(i_type)
+            | Instruction::CSRRC(i_type) => [i_type.rs1(), 0],
+            Instruction::ADD(r_type)
+            | Instruction::SUB(r_type)
+            | Instruction::SLL(r_type)
+            | Instruction::SLT(r_type)
+            | Instruction::SLTU(r_type)
+            | Instruction::XOR(r_type)
+            | Instruction::SRL(r_type)
+            | Instruction::SRA(r_type)
+            | Instruction::OR(r_type)
+            | Instruction::AND(r_type)
+            | Instruction::SFENCE_VMA(r_type) => [r_type.rs1(), r_type.rs2()],
+            Instruction::LUI(_)
+            | Instruction::AUIPC(_)
+            | Instruction::JAL(_)
+            | Instruction::FENCE(_)
+            | Instruction::FENCE_I(_)
+            | Instruction::CSRRWI(_)
+            | Instruction::CSRRSI(_)
+            | Instruction::CSRRCI(_)
+            | Instruction::ECALL
+            | Instruction::EBREAK
+            | Instruction::URET
+            | Instruction::SRET
+            | Instruction::MRET
+            | Instruction::WFI => [0, 0],
+        }
+    }
+
+    /// The mnemonic of the instruction, e.g., `"ADDI"` or `"FENCE.I"`.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Instruction::LUI(_) => "LUI",
+            Instruction::AUIPC(_) => "AUIPC",
+            Instruction::JAL(_) => "JAL",
+            Instruction::JALR(_) => "JALR",
+            Instruction::BEQ(_) => "BEQ",
+            Instruction::BNE(_) => "BNE",
+            Instruction::BLT(_) => "BLT",
+            Instruction::BGE(_) => "BGE",
+            Instruction::BLTU(_) => "BLTU",
+            Instruction::BGEU(_) => "BGEU",
+            Instruction::LB(_) => "LB",
+            Instruction::LH(_) => "LH",
+            Instruction::LW(_) => "LW",
+            Instruction::LBU(_) => "LBU",
+            Instruction::LHU(_) => "LHU",
+            Instruction::SB(_) => "SB",
+            Instruction::SH(_) => "SH",
+            Instruction::SW(_) => "SW",
+            Instruction::ADDI(_) => "ADDI",
+            Instruction::SLTI(_) => "SLTI",
+            Instruction::SLTIU(_) => "SLTIU",
+            Instruction::XORI(_) => "XORI",
+            Instruction::ORI(_) => "ORI",
+            Instruction::ANDI(_) => "ANDI",
+            Instruction::SLLI(_) => "SLLI",
+            Instruction::SRLI(_) => "SRLI",
+            Instruction::SRAI(_) => "SRAI",
+            Instruction::ADD(_) => "ADD",
+            Instruction::SUB(_) => "SUB",
+            Instruction::SLL(_) => "SLL",
+            Instruction::SLT(_) => "SLT",
+            Instruction::SLTU(_) => "SLTU",
+            Instruction::XOR(_) => "XOR",
+            Instruction::SRL(_) => "SRL",
+            Instruction::SRA(_) => "SRA",
+            Instruction::OR(_) => "OR",
+            Instruction::AND(_) => "AND",
+            Instruction::FENCE(_) => "FENCE",
+            Instruction::FENCE_I(_) => "FENCE.I",
+            Instruction::ECALL => "ECALL",
+            Instruction::EBREAK => "EBREAK",
+            Instruction::URET => "URET",
+            Instruction::SRET => "SRET",
+            Instruction::MRET => "MRET",
+            Instruction::WFI => "WFI",
+            Instruction::SFENCE_VMA(_) => "SFENCE.VMA",
+            Instruction::CSRRW(_) => "CSRRW",
+            Instruction::CSRRS(_) => "CSRRS",
+            Instruction::CSRRC(_) => "CSRRC",
+            Instruction::CSRRWI(_) => "CSRRWI",
+            Instruction::CSRRSI(_) => "CSRRSI",
+            Instruction::CSRRCI(_) => "CSRRCI",
+        }
+    }
 }
@@ -1,22 +1,64 @@
+mod blocks;
+pub mod bootrom;
+pub mod cache;
+pub mod clint;
+pub mod coverage;
+pub mod csr;
+mod decode_cache;
+pub mod devices;
+pub mod dtb;
+pub mod elf;
+mod error;
 mod formats;
+pub mod framebuffer;
+mod history;
 mod instructions;
+mod machine;
+pub mod mmu;
+pub mod pipeline;
+pub mod plic;
+pub mod pmp;
+pub mod predictor;
+pub mod profile;
+#[doc(hidden)]
+pub mod programs;
+pub mod replay;
+pub mod snapshot;
+#[cfg(test)]
+mod test_utils;
+pub mod timing;
+pub mod uart;
 mod utils;
+pub mod virtio;
 
 pub use {
+    blocks::{BlockCache, Engine},
+    decode_cache::{DecodeCache, PAGE_SIZE},
+    error::{Access, Error},
     formats::{BType, IType, JType, RType, SType, UType},
+    history::History,
     instructions::Instruction,
+    machine::Machine,
+    snapshot::SnapshotError,
     utils::{
-        dump_registers, load_word, sign_extend, store_byte, store_half_word, store_word,
-        MemoryError, REGISTER_NAMES,
+        dump_registers, load_byte, load_half_word, load_word, sign_extend, store_byte,
+        store_half_word, store_word, MemoryError, REGISTER_NAMES,
     },
 };
+use {
+    csr::{Counters, Csrs, Exception, Privilege, MSTATUS_TSR, MSTATUS_TVM, MSTATUS_TW},
+    devices::Devices,
+    mmu::Tlb,
+};
 
 pub const PC: usize = 32;
+/// The size of RAM of [`Machine::new`], see [`Machine::with_memory_size`] for others.
 pub const MEMORY_SIZE: usize = 0x10000;
 pub const MEMORY_START: usize = 0x80000000;
 
 pub type Registers = [u32; 33];
-pub type Memory = [u8; MEMORY_SIZE];
+/// RAM, which starts at [`MEMORY_START`].
+pub type Memory = [u8];
 
 pub fn decode(code: u32) -> Option<Instruction> {
     let opcode = code & 0b111_1111;
@@ -30,7 +72,10 @@ pub fn decode(code: u32) -> Option<Instruction> {
         // JAL
         0b1101111 => Instruction::JAL(JType(code)),
         // JALR
-        0b1100111 => Instruction::JALR(IType(code)),
+        0b1100111 => match funct3 {
+            0b000 => Instruction::JALR(IType(code)),
+            _ => return None,
+        },
         // BRANCH
         0b1100011 => match funct3 {
             0b000 => Instruction::BEQ(BType(code)),
@@ -65,7 +110,10 @@ pub fn decode(code: u32) -> Option<Instruction> {
             0b100 => Instruction::XORI(IType(code)),
             0b110 => Instruction::ORI(IType(code)),
             0b111 => Instruction::ANDI(IType(code)),
-            0b001 => Instruction::SLLI(IType(code)),
+            0b001 => match funct7 {
+                0b0000000 => Instruction::SLLI(IType(code)),
+                _ => return None,
+            },
             0b101 => match funct7 {
                 0b0000000 => Instruction::SRLI(IType(code)),
                 0b0100000 => Instruction::SRAI(IType(code)),
@@ -74,29 +122,33 @@ pub fn decode(code: u32) -> Option<Instruction> {
             _ => return None,
         },
         // OP
-        0b0110011 => match funct3 {
-            0b000 => match funct7 {
-                0b0000000 => Instruction::ADD(RType(code)),
-                0b0100000 => Instruction::SUB(RType(code)),
-                _ => return None,
-            },
-            0b001 => Instruction::SLL(RType(code)),
-            0b010 => Instruction::SLT(RType(code)),
-            0b011 => Instruction::SLTU(RType(code)),
-            0b100 => Instruction::XOR(RType(code)),
-            0b101 => match funct7 {
-                0b0000000 => Instruction::SRL(RType(code)),
-                0b0100000 => Instruction::SRA(RType(code)),
-                _ => return None,
-            },
-            0b110 => Instruction::OR(RType(code)),
-            0b111 => Instruction::AND(RType(code)),
+        0b0110011 => match (funct7, funct3) {
+            (0b0000000, 0b000) => Instruction::ADD(RType(code)),
+            (0b0100000, 0b000) => Instruction::SUB(RType(code)),
+            (0b0000000, 0b001) => Instruction::SLL(RType(code)),
+            (0b0000000, 0b010) => Instruction::SLT(RType(code)),
+            (0b0000000, 0b011) => Instruction::SLTU(RType(code)),
+            (0b0000000, 0b100) => Instruction::XOR(RType(code)),
+            (0b0000000, 0b101) => Instruction::SRL(RType(code)),
+            (0b0100000, 0b101) => Instruction::SRA(RType(code)),
+            (0b0000000, 0b110) => Instruction::OR(RType(code)),
+            (0b0000000, 0b111) => Instruction::AND(RType(code)),
             _ => return None,
         },
         // FENCE
-        0b0001111 => Instruction::FENCE,
+        0b0001111 => match funct3 {
+            0b000 => Instruction::FENCE(IType(code)),
+            0b001 => Instruction::FENCE_I(IType(code)),
+            _ => return None,
+        },
         // SYSTEM
         0b1110011 => match funct3 {
+            // Supervisor Memory-Management Instructions
+            0b000 if funct7 == 0b0001001 && code & 0b1111_1000_0000 == 0 => {
+                Instruction::SFENCE_VMA(RType(code))
+            }
+            // rd and rs1 are reserved and must be zero
+            0b000 if code & 0b1111_1111_1111_1000_0000 != 0 => return None,
             0b000 => match code >> 20 & 0xffff {
                 0b0000_0000_0000 => Instruction::ECALL,
                 0b0000_0000_0001 => Instruction::EBREAK,
@@ -108,40 +160,188 @@ pub fn decode(code: u32) -> Option<Instruction> {
                 0b0001_0000_0101 => Instruction::WFI,
                 _ => return None,
             },
-            0b001 => Instruction::CSRRW,
-            0b010 => Instruction::CSRRS,
-            0b011 => Instruction::CSRRC,
-            0b101 => Instruction::CSRRWI,
-            0b110 => Instruction::CSRRSI,
-            0b111 => Instruction::CSRRCI,
+            0b001 => Instruction::CSRRW(IType(code)),
+            0b010 => Instruction::CSRRS(IType(code)),
+            0b011 => Instruction::CSRRC(IType(code)),
+            0b101 => Instruction::CSRRWI(IType(code)),
+            0b110 => Instruction::CSRRSI(IType(code)),
+            0b111 => Instruction::CSRRCI(IType(code)),
             _ => return None,
         },
         _ => return None,
     })
 }
 
-#[derive(Debug, Clone)]
-pub enum Error {
-    DecodeError { code: u32 },
-    MemoryError { address: u32 },
+/// A register written by a retired instruction.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RegisterWrite {
+    pub register: u32,
+    pub old: u32,
+    pub new: u32,
 }
 
-impl From<MemoryError> for Error {
-    fn from(MemoryError { address }: MemoryError) -> Error {
-        Error::MemoryError { address }
-    }
+/// `size` bytes at the physical `address` written by a retired instruction, as little-endian
+/// values.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MemoryWrite {
+    pub address: u32,
+    pub size: u32,
+    pub old: u32,
+    pub new: u32,
+}
+
+/// The effects of an instruction executed by [`execute`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Retired {
+    pub pc: u32,
+    pub code: u32,
+    pub instruction: Instruction,
+    pub next_pc: u32,
+    /// `None` if the instruction has no destination register or it is `x0`.
+    pub rd: Option<RegisterWrite>,
+    /// `None` for stores to [`devices`], which aren't recorded.
+    pub store: Option<MemoryWrite>,
+    /// The physical address read by a load, from RAM or a device.
+    pub load: Option<u32>,
+    /// The riscv-tests program signaled its end.
+    pub done: bool,
 }
 
+/// Executes an instruction in machine mode with the CSRs of a reset hart, which are discarded
+/// afterwards. Exceptions are returned as errors, use [`Machine::step`] to take traps.
 pub fn step(registers: &mut Registers, memory: &mut Memory) -> Result<bool, Error> {
-    let mut done = false;
+    let (code, instruction) = fetch(registers, memory)?;
+    let mut csrs = Csrs::default();
+    Ok(execute(
+        registers,
+        memory,
+        &mut csrs,
+        &mut Tlb::default(),
+        &mut Devices::default(),
+        Counters::default(),
+        code,
+        instruction,
+    )?
+    .done)
+}
+
+/// Fetches and decodes the instruction at the pc.
+pub fn fetch(registers: &Registers, memory: &Memory) -> Result<(u32, Instruction), Error> {
+    fetch_physical(registers[PC], registers[PC], memory)
+}
 
+/// Like [`fetch`], but reads the instruction at `pc` from the physical `address` it was
+/// translated to.
+pub(crate) fn fetch_physical(
+    pc: u32,
+    address: u32,
+    memory: &Memory,
+) -> Result<(u32, Instruction), Error> {
     // Instruction Fetch
-    let pc = registers[PC];
-    let mut next_pc = pc + 4;
-    let code = load_word(memory, pc)?;
+    let code =
+        load_word(memory, address).map_err(|MemoryError { address, size }| Error::MemoryError {
+            pc,
+            code: None,
+            access: Access::Fetch,
+            address,
+            size,
+        })?;
 
     // Instruction Decode
-    let instruction = decode(code).ok_or(Error::DecodeError { code })?;
+    let instruction = decode(code).ok_or(Error::DecodeError { pc, code })?;
+    Ok((code, instruction))
+}
+
+/// Reads `size` bytes at the physical `address` from RAM or a device.
+#[inline(always)]
+fn load_physical(
+    memory: &Memory,
+    devices: &mut Devices,
+    address: u32,
+    size: u32,
+    counters: Counters,
+) -> Result<u32, MemoryError> {
+    match size {
+        1 => load_byte(memory, address).map(u32::from),
+        2 => load_half_word(memory, address).map(u32::from),
+        _ => load_word(memory, address),
+    }
+    .or_else(|error| devices.load(address, size, counters.cycle).ok_or(error))
+}
+
+/// Writes the low `size` bytes of `value` to the physical `address` in RAM or a device. Returns
+/// the previous value if it was RAM.
+#[inline(always)]
+fn store_physical(
+    memory: &mut Memory,
+    devices: &mut Devices,
+    address: u32,
+    size: u32,
+    value: u32,
+    counters: Counters,
+) -> Result<Option<MemoryWrite>, MemoryError> {
+    let old = match size {
+        1 => load_byte(memory, address).map(u32::from),
+        2 => load_half_word(memory, address).map(u32::from),
+        _ => load_word(memory, address),
+    };
+    let Ok(old) = old else {
+        return match devices.store(address, size, value, counters.cycle) {
+            Some(()) => Ok(None),
+            None => Err(MemoryError { address, size }),
+        };
+    };
+    match size {
+        1 => store_byte(memory, address, value as u8),
+        2 => store_half_word(memory, address, value as u16),
+        _ => store_word(memory, address, value),
+    }?;
+    Ok(Some(MemoryWrite {
+        address,
+        size,
+        old,
+        new: value & (u32::MAX >> (32 - 8 * size)),
+    }))
+}
+
+/// Executes `instruction`, which was fetched from the pc, in the privilege mode of `csrs`. Loads
+/// and stores are translated and checked against the PMP by `tlb`, and go to RAM or `devices`.
+/// `counters` are read by the counter CSRs and the devices. On error, including exceptions, no
+/// state is changed, except for the A and D bits set by page-table walks.
+// Not inlining it into the few callers halves the interpreter's speed.
+#[inline(always)]
+#[allow(clippy::too_many_arguments)]
+pub fn execute(
+    registers: &mut Registers,
+    memory: &mut Memory,
+    csrs: &mut Csrs,
+    tlb: &mut Tlb,
+    devices: &mut Devices,
+    counters: Counters,
+    code: u32,
+    instruction: Instruction,
+) -> Result<Retired, Error> {
+    let mut done = false;
+    let pc = registers[PC];
+    let mut next_pc = pc.wrapping_add(4);
+    let mut store = None;
+    let mut load = None;
+    let fault = |access| {
+        move |MemoryError { address, size }| Error::MemoryError {
+            pc,
+            code: Some(code),
+            access,
+            address,
+            size,
+        }
+    };
+    let raise = |exception, tval| Error::Exception {
+        pc,
+        code: Some(code),
+        exception,
+        tval,
+    };
+    let illegal = || raise(Exception::IllegalInstruction, code);
 
     // Execute
     let mut rd: Option<u32> = None;
@@ -161,7 +361,7 @@ pub fn step(registers: &mut Registers, memory: &mut Memory) -> Result<bool, Erro
         Instruction::JAL(j_type) => {
             next_pc = pc.overflowing_add(j_type.imm()).0;
             rd = Some(j_type.rd());
-            rd_value = pc + 4;
+            rd_value = pc.wrapping_add(4);
         }
         // JALR
         Instruction::JALR(i_type) => {
@@ -170,7 +370,7 @@ pub fn step(registers: &mut Registers, memory: &mut Memory) -> Result<bool, Erro
                 .0)
                 & !1;
             rd = Some(i_type.rd());
-            rd_value = pc + 4;
+            rd_value = pc.wrapping_add(4);
         }
         // BRANCH
         Instruction::BEQ(b_type) => {
@@ -211,55 +411,95 @@ pub fn step(registers: &mut Registers, memory: &mut Memory) -> Result<bool, Erro
             let address = registers[i_type.rs1() as usize]
                 .overflowing_add(i_type.imm())
                 .0;
+            let address = tlb
+                .translate(memory, csrs, address, 1, Access::Load)
+                .map_err(|exception| raise(exception, address))?;
             rd = Some(i_type.rd());
-            rd_value = sign_extend(load_word(memory, address)? & 0xFF, 7);
+            load = Some(address);
+            rd_value = load_physical(memory, devices, address, 1, counters)
+                .map_err(fault(Access::Load))? as i8 as u32;
         }
         Instruction::LH(i_type) => {
             let address = registers[i_type.rs1() as usize]
                 .overflowing_add(i_type.imm())
                 .0;
+            let address = tlb
+                .translate(memory, csrs, address, 2, Access::Load)
+                .map_err(|exception| raise(exception, address))?;
             rd = Some(i_type.rd());
-            rd_value = sign_extend(load_word(memory, address)? & 0xFFFF, 15);
+            load = Some(address);
+            rd_value = load_physical(memory, devices, address, 2, counters)
+                .map_err(fault(Access::Load))? as i16 as u32;
         }
         Instruction::LW(i_type) => {
             let address = registers[i_type.rs1() as usize]
                 .overflowing_add(i_type.imm())
                 .0;
+            let address = tlb
+                .translate(memory, csrs, address, 4, Access::Load)
+                .map_err(|exception| raise(exception, address))?;
             rd = Some(i_type.rd());
-            rd_value = load_word(memory, address)?;
+            load = Some(address);
+            rd_value = load_physical(memory, devices, address, 4, counters)
+                .map_err(fault(Access::Load))?;
         }
         Instruction::LBU(i_type) => {
             let address = registers[i_type.rs1() as usize]
                 .overflowing_add(i_type.imm())
                 .0;
+            let address = tlb
+                .translate(memory, csrs, address, 1, Access::Load)
+                .map_err(|exception| raise(exception, address))?;
             rd = Some(i_type.rd());
-            rd_value = load_word(memory, address)? & 0xFF;
+            load = Some(address);
+            rd_value = load_physical(memory, devices, address, 1, counters)
+                .map_err(fault(Access::Load))?;
         }
         Instruction::LHU(i_type) => {
             let address = registers[i_type.rs1() as usize]
                 .overflowing_add(i_type.imm())
                 .0;
+            let address = tlb
+                .translate(memory, csrs, address, 2, Access::Load)
+                .map_err(|exception| raise(exception, address))?;
             rd = Some(i_type.rd());
-            rd_value = load_word(memory, address)? & 0xFFFF;
+            load = Some(address);
+            rd_value = load_physical(memory, devices, address, 2, counters)
+                .map_err(fault(Access::Load))?;
         }
         // STORE
         Instruction::SB(s_type) => {
             let address = registers[s_type.rs1() as usize]
                 .overflowing_add(s_type.imm())
                 .0;
-            store_byte(memory, address, registers[s_type.rs2() as usize] as u8)?
+            let address = tlb
+                .translate(memory, csrs, address, 1, Access::Store)
+                .map_err(|exception| raise(exception, address))?;
+            let value = registers[s_type.rs2() as usize];
+            store = store_physical(memory, devices, address, 1, value, counters)
+                .map_err(fault(Access::Store))?;
         }
         Instruction::SH(s_type) => {
             let address = registers[s_type.rs1() as usize]
                 .overflowing_add(s_type.imm())
                 .0;
-            store_half_word(memory, address, registers[s_type.rs2() as usize] as u16)?
+            let address = tlb
+                .translate(memory, csrs, address, 2, Access::Store)
+                .map_err(|exception| raise(exception, address))?;
+            let value = registers[s_type.rs2() as usize];
+            store = store_physical(memory, devices, address, 2, value, counters)
+                .map_err(fault(Access::Store))?;
         }
         Instruction::SW(s_type) => {
             let address = registers[s_type.rs1() as usize]
                 .overflowing_add(s_type.imm())
                 .0;
-            store_word(memory, address, registers[s_type.rs2() as usize])?
+            let address = tlb
+                .translate(memory, csrs, address, 4, Access::Store)
+                .map_err(|exception| raise(exception, address))?;
+            let value = registers[s_type.rs2() as usize];
+            store = store_physical(memory, devices, address, 4, value, counters)
+                .map_err(fault(Access::Store))?;
         }
         // OP-IMM
         Instruction::ADDI(i_type) => {
@@ -353,44 +593,385 @@ pub fn step(registers: &mut Registers, memory: &mut Memory) -> Result<bool, Erro
             rd_value = registers[r_type.rs1() as usize] & registers[r_type.rs2() as usize]
         }
         // FENCE
-        Instruction::FENCE => {}
+        Instruction::FENCE(_) => {}
+        // instruction fetches always observe the latest stores, see `Machine::decode_cache`
+        Instruction::FENCE_I(_) => {}
         // SYSTEM
         Instruction::ECALL => {
-            let x3_value = registers[3];
-            if x3_value > 1 {
-                println!("{}", dump_registers(registers));
-                panic!("Test fails ECALL(x3: {:08x})", x3_value);
-            }
-        }
-        Instruction::EBREAK => {}
+            let exception = match csrs.privilege {
+                Privilege::User => Exception::UserEnvironmentCall,
+                Privilege::Supervisor => Exception::SupervisorEnvironmentCall,
+                Privilege::Machine => Exception::MachineEnvironmentCall,
+            };
+            return Err(raise(exception, 0));
+        }
+        Instruction::EBREAK => return Err(raise(Exception::Breakpoint, pc)),
         // Trap-Return Instructions
-        Instruction::URET | Instruction::SRET | Instruction::MRET => {}
+        Instruction::MRET if csrs.privilege == Privilege::Machine => next_pc = csrs.mret(),
+        Instruction::SRET
+            if csrs.privilege == Privilege::Machine
+                || csrs.privilege == Privilege::Supervisor && csrs.mstatus & MSTATUS_TSR == 0 =>
+        {
+            next_pc = csrs.sret()
+        }
+        // there are no user-level traps
+        Instruction::URET | Instruction::SRET | Instruction::MRET => return Err(illegal()),
         // Interrupt-Management Instructions
-        Instruction::WFI => {}
+        Instruction::WFI => {
+            // the Machine sleeps until an interrupt is pending after retiring it
+            if csrs.privilege == Privilege::User
+                || csrs.privilege == Privilege::Supervisor && csrs.mstatus & MSTATUS_TW != 0
+            {
+                return Err(illegal());
+            }
+        }
+        // Supervisor Memory-Management Instructions
+        Instruction::SFENCE_VMA(r_type) => {
+            if csrs.privilege == Privilege::User
+                || csrs.privilege == Privilege::Supervisor && csrs.mstatus & MSTATUS_TVM != 0
+            {
+                return Err(illegal());
+            }
+            // x0 selects all addresses or address spaces
+            let address = (r_type.rs1() != 0).then(|| registers[r_type.rs1() as usize]);
+            let asid = (r_type.rs2() != 0).then(|| registers[r_type.rs2() as usize] & 0x1ff);
+            tlb.flush(address, asid);
+        }
         // CSR Instructions (Zicsr Standard Extension)
-        Instruction::CSRRW => {
-            let csr = code >> 20 & 0xffff;
-            if csr == 3072 {
-                done = true;
+        // writing the read-only `cycle` (`unimp`) ends the program
+        Instruction::CSRRW(i_type) if i_type.imm() & 0xfff == csr::CYCLE => done = true,
+        Instruction::CSRRW(i_type)
+        | Instruction::CSRRS(i_type)
+        | Instruction::CSRRC(i_type)
+        | Instruction::CSRRWI(i_type)
+        | Instruction::CSRRSI(i_type)
+        | Instruction::CSRRCI(i_type) => {
+            let csr = i_type.imm() & 0xfff;
+            // the immediate variants use the rs1 field as an unsigned immediate
+            let source = match instruction {
+                Instruction::CSRRWI(_) | Instruction::CSRRSI(_) | Instruction::CSRRCI(_) => {
+                    i_type.rs1()
+                }
+                _ => registers[i_type.rs1() as usize],
+            };
+            let old = csrs.read(csr, counters).ok_or_else(illegal)?;
+            // setting or clearing with x0 or a zero immediate doesn't write
+            let new = match instruction {
+                Instruction::CSRRW(_) | Instruction::CSRRWI(_) => Some(source),
+                _ if i_type.rs1() == 0 => None,
+                Instruction::CSRRS(_) | Instruction::CSRRSI(_) => Some(old | source),
+                _ => Some(old & !source),
+            };
+            if new.is_some_and(|new| !csrs.write(csr, new, counters)) {
+                return Err(illegal());
             }
+            rd = Some(i_type.rd());
+            rd_value = old;
         }
-        Instruction::CSRRS
-        | Instruction::CSRRC
-        | Instruction::CSRRWI
-        | Instruction::CSRRSI
-        | Instruction::CSRRCI => {}
+    }
+
+    // jumps and taken branches
+    if next_pc & 0b11 != 0 && next_pc != pc.wrapping_add(4) {
+        return Err(raise(Exception::InstructionAddressMisaligned, next_pc));
     }
 
     // Memory Access
 
     // Register Write Back
     registers[PC] = next_pc;
-    if let Some(register) = rd {
-        // ignore writes to x0 register
-        if register != 0 {
-            registers[register as usize] = rd_value
+    // ignore writes to x0 register
+    let rd = rd.filter(|&register| register != 0).map(|register| {
+        let old = std::mem::replace(&mut registers[register as usize], rd_value);
+        RegisterWrite {
+            register,
+            old,
+            new: rd_value,
+        }
+    });
+
+    Ok(Retired {
+        pc,
+        code,
+        instruction,
+        next_pc,
+        rd,
+        store,
+        load,
+        done,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LUI: u32 = 0b0110111;
+    const AUIPC: u32 = 0b0010111;
+    const JAL: u32 = 0b1101111;
+    const JALR: u32 = 0b1100111;
+    const BRANCH: u32 = 0b1100011;
+    const LOAD: u32 = 0b0000011;
+    const STORE: u32 = 0b0100011;
+    const OP_IMM: u32 = 0b0010011;
+    const OP: u32 = 0b0110011;
+    const MISC_MEM: u32 = 0b0001111;
+    const SYSTEM: u32 = 0b1110011;
+
+    /// Every valid encoding as (name, opcode, funct3, funct7), `None` meaning "don't care".
+    const VALID: &[(&str, u32, Option<u32>, Option<u32>)] = &[
+        ("LUI", LUI, None, None),
+        ("AUIPC", AUIPC, None, None),
+        ("JAL", JAL, None, None),
+        ("JALR", JALR, Some(0b000), None),
+        ("BEQ", BRANCH, Some(0b000), None),
+        ("BNE", BRANCH, Some(0b001), None),
+        ("BLT", BRANCH, Some(0b100), None),
+        ("BGE", BRANCH, Some(0b101), None),
+        ("BLTU", BRANCH, Some(0b110), None),
+        ("BGEU", BRANCH, Some(0b111), None),
+        ("LB", LOAD, Some(0b000), None),
+        ("LH", LOAD, Some(0b001), None),
+        ("LW", LOAD, Some(0b010), None),
+        ("LBU", LOAD, Some(0b100), None),
+        ("LHU", LOAD, Some(0b101), None),
+        ("SB", STORE, Some(0b000), None),
+        ("SH", STORE, Some(0b001), None),
+        ("SW", STORE, Some(0b010), None),
+        ("ADDI", OP_IMM, Some(0b000), None),
+        ("SLTI", OP_IMM, Some(0b010), None),
+        ("SLTIU", OP_IMM, Some(0b011), None),
+        ("XORI", OP_IMM, Some(0b100), None),
+        ("ORI", OP_IMM, Some(0b110), None),
+        ("ANDI", OP_IMM, Some(0b111), None),
+        ("SLLI", OP_IMM, Some(0b001), Some(0b0000000)),
+        ("SRLI", OP_IMM, Some(0b101), Some(0b0000000)),
+        ("SRAI", OP_IMM, Some(0b101), Some(0b0100000)),
+        ("ADD", OP, Some(0b000), Some(0b0000000)),
+        ("SUB", OP, Some(0b000), Some(0b0100000)),
+        ("SLL", OP, Some(0b001), Some(0b0000000)),
+        ("SLT", OP, Some(0b010), Some(0b0000000)),
+        ("SLTU", OP, Some(0b011), Some(0b0000000)),
+        ("XOR", OP, Some(0b100), Some(0b0000000)),
+        ("SRL", OP, Some(0b101), Some(0b0000000)),
+        ("SRA", OP, Some(0b101), Some(0b0100000)),
+        ("OR", OP, Some(0b110), Some(0b0000000)),
+        ("AND", OP, Some(0b111), Some(0b0000000)),
+        ("FENCE", MISC_MEM, Some(0b000), None),
+        ("FENCE_I", MISC_MEM, Some(0b001), None),
+        ("CSRRW", SYSTEM, Some(0b001), None),
+        ("CSRRS", SYSTEM, Some(0b010), None),
+        ("CSRRC", SYSTEM, Some(0b011), None),
+        ("CSRRWI", SYSTEM, Some(0b101), None),
+        ("CSRRSI", SYSTEM, Some(0b110), None),
+        ("CSRRCI", SYSTEM, Some(0b111), None),
+    ];
+
+    fn name(instruction: Instruction) -> String {
+        let debug = format!("{instruction:?}");
+        debug.split('(').next().unwrap().to_string()
+    }
+
+    fn expected(opcode: u32, funct3: u32, funct7: u32) -> Option<&'static str> {
+        VALID
+            .iter()
+            .find(|(_, o, f3, f7)| {
+                *o == opcode
+                    && f3.map_or(true, |f3| f3 == funct3)
+                    && f7.map_or(true, |f7| f7 == funct7)
+            })
+            .map(|(name, ..)| *name)
+    }
+
+    #[test]
+    fn decode_every_opcode_funct3_funct7() {
+        for opcode in 0..0b1000_0000 {
+            for funct3 in 0..0b1000 {
+                // funct3 == 0 of SYSTEM is decoded by its immediate (see below)
+                if opcode == SYSTEM && funct3 == 0 {
+                    continue;
+                }
+                for funct7 in 0..0b1000_0000 {
+                    // rd = a0, rs1 = a1, rs2 = a2
+                    let code = funct7 << 25 | 12 << 20 | 11 << 15 | funct3 << 12 | 10 << 7 | opcode;
+                    assert_eq!(
+                        decode(code).map(name).as_deref(),
+                        expected(opcode, funct3, funct7),
+                        "code: {code:032b}",
+                    );
+                    if let Some(instruction) = decode(code) {
+                        assert_eq!(instruction.encode(), code, "code: {code:032b}");
+                    }
+                }
+            }
         }
-    };
+    }
 
-    Ok(done)
+    #[test]
+    fn decode_system_immediates() {
+        for funct12 in 0..0b1_0000_0000_0000 {
+            let code = funct12 << 20 | SYSTEM;
+            let expected = match funct12 {
+                0b0000_0000_0000 => Some("ECALL"),
+                0b0000_0000_0001 => Some("EBREAK"),
+                0b0000_0000_0010 => Some("URET"),
+                0b0001_0000_0010 => Some("SRET"),
+                0b0011_0000_0010 => Some("MRET"),
+                0b0001_0000_0101 => Some("WFI"),
+                // funct7 and rs2
+                _ if funct12 >> 5 == 0b0001001 => Some("SFENCE_VMA"),
+                _ => None,
+            };
+            assert_eq!(
+                decode(code).map(name).as_deref(),
+                expected,
+                "funct12: {funct12:012b}"
+            );
+            if let Some(instruction) = decode(code) {
+                assert_eq!(instruction.encode(), code, "funct12: {funct12:012b}");
+            }
+            // rd and rs1 must be zero, except for the address in rs1 of SFENCE.VMA
+            assert_eq!(decode(code | 1 << 7), None, "funct12: {funct12:012b}");
+            assert_eq!(
+                decode(code | 1 << 15).map(name).as_deref(),
+                expected.filter(|&name| name == "SFENCE_VMA"),
+                "funct12: {funct12:012b}"
+            );
+        }
+    }
+
+    #[test]
+    fn decode_known_encodings() {
+        // encodings taken from `llvm-mc -triple=riscv32 -show-encoding`
+        let cases: &[(u32, &str)] = &[
+            (0x12345537, "LUI"),                 // lui a0, 0x12345
+            (0x00000517, "AUIPC"),               // auipc a0, 0
+            (0xffdff0ef, "JAL"),                 // jal ra, -4
+            (0x00008067, "JALR"),                // ret
+            (0xfe0798e3, "BNE"),                 // bnez a5, -16
+            (0xfee6d8e3, "BGE"),                 // bge a3, a4, -16
+            (0xfec42783, "LW"),                  // lw a5, -20(s0)
+            (0x0047c703, "LBU"),                 // lbu a4, 4(a5)
+            (0x02812623, "SW"),                  // sw s0, 44(sp)
+            (0xfff78793, "ADDI"),                // addi a5, a5, -1
+            (0x41f55513, "SRAI"),                // srai a0, a0, 31
+            (0x00e50533, "ADD"),                 // add a0, a0, a4
+            (0x40b50533, "SUB"),                 // sub a0, a0, a1
+            (0x0ff0000f, "FENCE"),               // fence
+            (0x0000100f, "FENCE_I"),             // fence.i
+            (0x00000073, "ECALL"),               // ecall
+            (0x30200073, "MRET"),                // mret
+            (0xc0001073, "CSRRW"),               // unimp
+            (0xf1402573, "CSRRS"),               // csrr a0, mhartid
+            (0x00000000, "<invalid>"),           // all zeros
+            (0xffffffff, "<invalid>"),           // all ones
+            (0x02b50533, "<invalid>"),           // mul a0, a0, a1
+            (0x00a51513 | 1 << 30, "<invalid>"), // slli with funct7 = 0b0100000
+        ];
+        for &(code, expected) in cases {
+            assert_eq!(
+                decode(code).map_or("<invalid>".to_string(), name),
+                expected,
+                "code: {code:08x}"
+            );
+        }
+    }
+
+    /// Returns a machine with `program` at the start of RAM and `sp` at its end.
+    fn machine(program: &[u32]) -> (Registers, Box<Memory>) {
+        let mut registers: Registers = [0; 33];
+        let memory = test_utils::machine(program).memory;
+        registers[PC] = MEMORY_START as u32;
+        registers[2] = (MEMORY_START + MEMORY_SIZE) as u32;
+        (registers, memory)
+    }
+
+    #[test]
+    fn step_memory_access_at_address_space_edges() {
+        let loads_and_stores = [
+            0x00052283, // lw t0, 0(a0)
+            0x00051283, // lh t0, 0(a0)
+            0x00054283, // lbu t0, 0(a0)
+            0x00552023, // sw t0, 0(a0)
+            0x00551023, // sh t0, 0(a0)
+            0x00550023, // sb t0, 0(a0)
+        ];
+        for code in loads_and_stores {
+            for address in [0, 1, MEMORY_START as u32 - 1, u32::MAX - 1, u32::MAX] {
+                let (mut registers, mut memory) = machine(&[code]);
+                registers[10] = address;
+                let before = registers;
+                let access = if code & 0b010_0000 == 0 {
+                    Access::Load
+                } else {
+                    Access::Store
+                };
+                let size = [1, 2, 4][(code >> 12 & 0b11) as usize];
+                assert_eq!(
+                    step(&mut registers, &mut memory).unwrap_err(),
+                    Error::MemoryError {
+                        pc: MEMORY_START as u32,
+                        code: Some(code),
+                        access,
+                        address,
+                        size,
+                    },
+                );
+                assert_eq!(registers, before);
+            }
+        }
+    }
+
+    #[test]
+    fn step_loads_last_bytes_of_memory() {
+        let end = (MEMORY_START + MEMORY_SIZE) as u32;
+        let (mut registers, mut memory) = machine(&[
+            0xfff10283, // lb t0, -1(sp)
+            0xffe15303, // lhu t1, -2(sp)
+            0xffc12383, // lw t2, -4(sp)
+        ]);
+        store_word(&mut memory, end - 4, 0x8081_8283).unwrap();
+        for _ in 0..3 {
+            step(&mut registers, &mut memory).unwrap();
+        }
+        assert_eq!(registers[5..8], [0xffff_ff80, 0x8081, 0x8081_8283]);
+    }
+
+    #[test]
+    fn step_fetch_outside_of_memory() {
+        for target in [0, MEMORY_START as u32 - 4, u32::MAX - 3] {
+            // jalr zero, 0(a0)
+            let (mut registers, mut memory) = machine(&[0x00050067]);
+            registers[10] = target;
+            step(&mut registers, &mut memory).unwrap();
+            assert_eq!(registers[PC], target);
+            assert_eq!(
+                step(&mut registers, &mut memory).unwrap_err(),
+                Error::MemoryError {
+                    pc: target,
+                    code: None,
+                    access: Access::Fetch,
+                    address: target,
+                    size: 4,
+                },
+            );
+        }
+    }
+
+    #[test]
+    fn step_misaligned_jump() {
+        // jalr ra, 2(a0)
+        let (mut registers, mut memory) = machine(&[0x002500e7]);
+        registers[10] = MEMORY_START as u32;
+        let before = registers;
+        assert_eq!(
+            step(&mut registers, &mut memory).unwrap_err(),
+            Error::Exception {
+                pc: MEMORY_START as u32,
+                code: Some(0x002500e7),
+                exception: Exception::InstructionAddressMisaligned,
+                tval: MEMORY_START as u32 + 2,
+            },
+        );
+        assert_eq!(registers, before);
+    }
 }
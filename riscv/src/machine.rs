@@ -0,0 +1,452 @@
+use crate::{
+    bootrom::BOOT_ROM_SIZE,
+    cache::Caches,
+    coverage::Coverage,
+    csr::{Counters, Csrs, Interrupt},
+    decode,
+    devices::{Devices, BOOT_ROM_START, DEVICE_INTERRUPTS, UART_START},
+    execute, load_word,
+    mmu::{self, Tlb},
+    pmp,
+    predictor::Predictor,
+    profile::Profiler,
+    replay::{Mode, Replay},
+    timing::Timing,
+    Access, BlockCache, DecodeCache, Engine, Error, History, Instruction, Memory, Registers,
+    Retired, MEMORY_SIZE, MEMORY_START, PAGE_SIZE, PC,
+};
+
+/// The complete state of the emulated system.
+#[derive(Clone, PartialEq, Eq)]
+pub struct Machine {
+    pub registers: Registers,
+    /// The privilege mode and the CSRs, starting in machine mode.
+    pub csrs: Csrs,
+    /// Caches the translations of the [`mmu`]. Clear it after changing page tables or `satp`
+    /// directly.
+    pub tlb: Tlb,
+    pub memory: Box<Memory>,
+    pub devices: Devices,
+    /// The address of the `tohost` word of riscv-tests programs, set by
+    /// [`elf::load`](crate::elf::load). Storing a non-zero value to it signals the end of the
+    /// program.
+    pub tohost: Option<u32>,
+    /// The number of retired instructions.
+    pub instret: u64,
+    /// The number of elapsed cycles, as estimated by `timing`.
+    pub cycle: u64,
+    /// Disabled by default, which makes every instruction take a single cycle.
+    pub timing: Timing,
+    /// Disabled by default. Only simulate hits and misses, RAM is always up to date.
+    pub caches: Caches,
+    /// Disabled by default. With the timing model, only mispredicted branches and jumps pay a
+    /// penalty.
+    pub predictor: Option<Predictor>,
+    /// Disabled by default.
+    pub profiler: Option<Profiler>,
+    /// Disabled by default.
+    pub coverage: Option<Coverage>,
+    /// Disabled by default, set it to a [`History`] with non-zero capacity to enable
+    /// [`Machine::step_back`].
+    pub history: History,
+    pub replay: Replay,
+    /// Enabled by default. Clear it after writing instructions to `memory` directly.
+    pub decode_cache: DecodeCache,
+    /// The engine used by [`Machine::run`].
+    pub engine: Engine,
+    /// Filled by the [`Engine::Blocks`] engine. Clear it after writing instructions to `memory`
+    /// directly.
+    pub blocks: BlockCache,
+}
+
+impl Machine {
+    /// Returns a machine with zeroed registers and [`MEMORY_SIZE`] bytes of RAM, which starts
+    /// executing at the RAM base.
+    pub fn new() -> Machine {
+        Machine::with_memory_size(MEMORY_SIZE)
+    }
+
+    /// Like [`Machine::new`], but with `size` bytes of RAM.
+    ///
+    /// # Panics
+    ///
+    /// If `size` isn't a multiple of the page size or RAM would extend past the address space.
+    pub fn with_memory_size(size: usize) -> Machine {
+        assert!(
+            size % PAGE_SIZE == 0 && size <= u32::MAX as usize - MEMORY_START + 1,
+            "invalid RAM size 0x{size:x}"
+        );
+        let mut registers = [0; 33];
+        registers[PC] = MEMORY_START as u32;
+        Machine {
+            registers,
+            csrs: Csrs::default(),
+            tlb: Tlb::default(),
+            memory: vec![0; size].into_boxed_slice(),
+            devices: Devices::default(),
+            tohost: None,
+            instret: 0,
+            cycle: 0,
+            timing: Timing::default(),
+            caches: Caches::default(),
+            predictor: None,
+            profiler: None,
+            coverage: None,
+            history: History::default(),
+            replay: Replay::default(),
+            decode_cache: DecodeCache::new(size),
+            engine: Engine::default(),
+            blocks: BlockCache::new(),
+        }
+    }
+
+    /// Executes the next instruction or takes the trap it raised. Returns `true` once the program
+    /// signaled its end.
+    pub fn step(&mut self) -> Result<bool, Error> {
+        let fetched = self.fetch(self.registers[PC]);
+        Ok(self.execute(fetched)?.is_some_and(|retired| retired.done))
+    }
+
+    /// Fetches and decodes the instruction at `pc`, with the address translation of the current
+    /// mode.
+    pub(crate) fn fetch(&mut self, pc: u32) -> Result<(u32, Instruction), Error> {
+        let address = self
+            .tlb
+            .translate(&mut self.memory, &self.csrs, pc, 4, Access::Fetch)
+            .map_err(|exception| Error::Exception {
+                pc,
+                code: None,
+                exception,
+                tval: pc,
+            })?;
+        match self.decode_cache.fetch(pc, address, &self.memory) {
+            Err(error @ Error::MemoryError { .. }) if self.devices.rom.is_some() => {
+                self.fetch_rom(pc, address, error)
+            }
+            fetched => fetched,
+        }
+    }
+
+    /// Fetches and decodes the instruction at `pc` from the boot ROM, at the physical `address`.
+    /// Returns `error`, the failed fetch from RAM, if the ROM isn't there.
+    #[cold]
+    fn fetch_rom(&self, pc: u32, address: u32, error: Error) -> Result<(u32, Instruction), Error> {
+        let code = self
+            .devices
+            .rom
+            .as_ref()
+            .filter(|_| address.wrapping_sub(BOOT_ROM_START) < BOOT_ROM_SIZE)
+            .and_then(|rom| rom.load(address - BOOT_ROM_START, 4))
+            .ok_or(error)?;
+        let instruction = decode(code).ok_or(Error::DecodeError { pc, code })?;
+        Ok((code, instruction))
+    }
+
+    /// Executes the instruction fetched from the pc and retires it, or takes a pending interrupt
+    /// instead. Returns `None` if the hart trapped to a handler, also if the instruction raised an
+    /// exception.
+    ///
+    /// If the handler can't be fetched, e.g., because a bare-metal program never set up `mtvec`,
+    /// the exception is returned as an error and nothing is changed.
+    #[inline(always)]
+    pub(crate) fn execute(
+        &mut self,
+        fetched: Result<(u32, Instruction), Error>,
+    ) -> Result<Option<Retired>, Error> {
+        if self.replay.mode() != Mode::Off {
+            self.replay_inputs();
+        }
+        let counters = Counters {
+            cycle: self.cycle,
+            instret: self.instret,
+            time: self.devices.clint.mtime(self.cycle),
+        };
+        // devices raise interrupts between instructions, which only matter while `mie` enables
+        // some, or for CSR instructions reading `mip`
+        if self.csrs.mie != 0 {
+            self.update_mip();
+            if self.csrs.mip & self.csrs.mie != 0 {
+                if let Some(interrupt) = self.csrs.pending_interrupt() {
+                    self.interrupt(interrupt);
+                    return Ok(None);
+                }
+            }
+        }
+        // the history reverts changes of the CSRs
+        let previous = (self.history.capacity() > 0).then_some(self.csrs);
+        let (code, instruction) = match fetched {
+            Ok(fetched) => fetched,
+            Err(error) => return self.trap(error).map(|()| None),
+        };
+        // SYSTEM
+        if code & 0x7f == 0b1110011 {
+            self.update_mip();
+        }
+        let result = execute(
+            &mut self.registers,
+            &mut self.memory,
+            &mut self.csrs,
+            &mut self.tlb,
+            &mut self.devices,
+            counters,
+            code,
+            instruction,
+        );
+        let mut retired = match result {
+            Ok(retired) => retired,
+            Err(error) => return self.trap(error).map(|()| None),
+        };
+        if let (Some(tohost), Some(store)) = (self.tohost, retired.store) {
+            retired.done |= store.address == tohost && store.new != 0;
+        }
+        // STORE to a device, which may have been notified of requests
+        if code & 0x7f == 0b0100011 && retired.store.is_none() {
+            self.serve_devices();
+        }
+        self.retire(&retired);
+        if let Some(previous) = previous.filter(|previous| *previous != self.csrs) {
+            self.history.record_csrs(previous);
+        }
+        if matches!(retired.instruction, Instruction::WFI) {
+            self.wait();
+        }
+        Ok(Some(retired))
+    }
+
+    /// Delivers `input` from the host to the UART like [`Devices::receive`], unless recording or
+    /// replaying. While recording, the bytes are queued and reach the UART at the next
+    /// instruction boundary, as much as fits, while replaying they are discarded in favor of the
+    /// logged ones, see [`replay`](crate::replay). Returns the number of bytes taken, the host
+    /// should retry the rest later.
+    pub fn receive(&mut self, input: &[u8]) -> usize {
+        if self.replay.mode() == Mode::Off {
+            return self.devices.receive(input);
+        }
+        self.replay.queue(input);
+        input.len()
+    }
+
+    /// Delivers the recorded or replayed input from the host at this instruction boundary.
+    #[cold]
+    #[inline(never)]
+    fn replay_inputs(&mut self) {
+        let devices = &mut self.devices;
+        self.replay.receive(self.instret, UART_START, |byte| {
+            devices.receive(&[byte]) == 1
+        });
+    }
+
+    /// Sets the bits of `mip` driven by devices.
+    #[inline(always)]
+    fn update_mip(&mut self) {
+        self.csrs.mip = self.csrs.mip & !DEVICE_INTERRUPTS | self.devices.interrupts(self.cycle);
+    }
+
+    /// Sleeps until an interrupt is pending, after a WFI. Only the CLINT's timer wakes the hart up
+    /// by itself, so this fast-forwards the cycles until it fires if its interrupt is enabled in
+    /// `mie`. Otherwise, or if an interrupt is already pending, waiting ends immediately.
+    #[cold]
+    #[inline(never)]
+    fn wait(&mut self) {
+        let mip = self.csrs.mip & !DEVICE_INTERRUPTS | self.devices.interrupts(self.cycle);
+        if mip & self.csrs.mie != 0 || self.csrs.mie & Interrupt::MachineTimer.mask() == 0 {
+            return;
+        }
+        if let Some(cycles) = self.devices.clint.until_timer(self.cycle) {
+            // `mtimecmp` may be further away than the cycles can count, e.g., after `mtime` was
+            // written backwards
+            let cycles = cycles.min(u64::MAX - self.cycle);
+            self.cycle += cycles;
+            self.history.record_wait(cycles);
+        }
+    }
+
+    /// Serves the requests devices were notified of and discards the decoded instructions and
+    /// translated blocks in the RAM they wrote.
+    #[cold]
+    #[inline(never)]
+    fn serve_devices(&mut self) {
+        for (address, size) in self.devices.serve(&mut self.memory) {
+            // the first and last byte of a chunk of at most a page cover all pages it touches
+            for start in (address..address + size).step_by(PAGE_SIZE) {
+                let size = (address + size - start).min(PAGE_SIZE as u32);
+                self.decode_cache.invalidate(start, size);
+                self.blocks.invalidate(start, size);
+            }
+        }
+    }
+
+    /// Traps to the handler of the exception `error` raises, unless it can't be fetched, with the
+    /// translation of the mode it runs in.
+    #[cold]
+    #[inline(never)]
+    fn trap(&mut self, error: Error) -> Result<(), Error> {
+        let (exception, tval) = error.exception();
+        let mut csrs = self.csrs;
+        let handler = csrs.trap(error.pc(), exception, tval);
+        let stats = self.tlb.stats;
+        let fetchable = self
+            .tlb
+            .translate(&mut self.memory, &csrs, handler, 4, Access::Fetch)
+            .is_ok_and(|address| {
+                load_word(&self.memory, address).is_ok()
+                    || self.devices.rom.as_ref().is_some_and(|rom| {
+                        rom.load(address.wrapping_sub(BOOT_ROM_START), 4).is_some()
+                    })
+            });
+        if !fetchable {
+            // a failed walk doesn't fill the TLB
+            self.tlb.stats = stats;
+            return Err(error);
+        }
+        let previous = std::mem::replace(&mut self.csrs, csrs);
+        self.enter(error.pc(), previous, handler);
+        Ok(())
+    }
+
+    /// Traps to the handler of `interrupt` instead of executing the instruction at the pc.
+    #[cold]
+    #[inline(never)]
+    fn interrupt(&mut self, interrupt: Interrupt) {
+        let pc = self.registers[PC];
+        let previous = self.csrs;
+        let handler = self.csrs.interrupt(pc, interrupt);
+        self.enter(pc, previous, handler);
+    }
+
+    /// Jumps to the trap `handler`, which the instruction at `pc` trapped to with the CSRs
+    /// `previous`.
+    fn enter(&mut self, pc: u32, previous: Csrs, handler: u32) {
+        self.registers[PC] = handler;
+        let cost = self.timing.trap();
+        self.history.record_trap(pc, previous, cost);
+        self.cycle += cost.cycles;
+    }
+
+    /// Whether every retired instruction has to be observed, which rules out the blocks engine.
+    fn observed(&self) -> bool {
+        self.history.capacity() > 0
+            || self.timing.enabled()
+            || self.caches.enabled()
+            || self.predictor.is_some()
+            || self.profiler.is_some()
+            || self.coverage.is_some()
+    }
+
+    /// Updates the caches, the timing model, the history and the counters for an executed
+    /// instruction.
+    #[inline]
+    pub(crate) fn retire(&mut self, retired: &Retired) {
+        self.decode_cache.retire(retired);
+        self.blocks.retire(retired);
+        self.instret += 1;
+        // the interpreter's fast path
+        if !self.observed() {
+            self.cycle += 1;
+            return;
+        }
+        self.caches.retire(retired);
+        if let Some(profiler) = &mut self.profiler {
+            profiler.retire(retired);
+        }
+        if let Some(coverage) = &mut self.coverage {
+            coverage.retire(retired);
+        }
+        let mispredicted = self
+            .predictor
+            .as_mut()
+            .and_then(|predictor| predictor.retire(retired));
+        let cost = self.timing.retire(retired, mispredicted);
+        self.history.record(retired, cost);
+        self.cycle += cost.cycles;
+    }
+
+    /// Executes up to `limit` instructions with the selected [`Engine`], where a trap counts as
+    /// an instruction. Returns `true` once the program signaled its end. [`Engine::Blocks`] falls
+    /// back to single steps while addresses are translated or checked by the PMP, `mie` enables
+    /// interrupts, or anything observes every instruction: the history, the timing model, a
+    /// cache, the branch predictor, the profiler or the coverage.
+    pub fn run(&mut self, limit: u64) -> Result<bool, Error> {
+        let mut remaining = limit;
+        while remaining > 0 {
+            if self.engine == Engine::Blocks
+                && !self.observed()
+                && !mmu::enabled(&self.csrs)
+                && !pmp::enforced(&self.csrs)
+                && self.csrs.mie == 0
+            {
+                let mut limit = remaining;
+                if self.replay.mode() != Mode::Off {
+                    self.replay_inputs();
+                    limit = self
+                        .replay
+                        .until_next(self.instret)
+                        .unwrap_or(limit)
+                        .min(limit);
+                }
+                let (retired, done) = self.blocks.run(
+                    &mut self.registers,
+                    &mut self.memory,
+                    &mut self.decode_cache,
+                    limit,
+                    self.tohost,
+                );
+                self.instret += retired;
+                self.cycle += retired;
+                remaining -= retired;
+                if done {
+                    return Ok(true);
+                }
+                if remaining == 0 {
+                    break;
+                }
+            }
+            remaining -= 1;
+            if self.step()? {
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+
+    /// Reverts the last retired instruction or trap. Returns `false` if the history is exhausted.
+    ///
+    /// Stepping back doesn't rewind the [`Replay`] log, so a replayed run can't be stepped
+    /// forward again after stepping back, nor the [`devices`](crate::devices). It clears the TLB,
+    /// since the reverted instruction may have changed page tables or `satp`.
+    pub fn step_back(&mut self) -> bool {
+        if let Some(store) = self.history.last_store() {
+            self.decode_cache.invalidate(store.address, store.size);
+            self.blocks.invalidate(store.address, store.size);
+        }
+        let Some(cost) = self.history.last_cost() else {
+            return false;
+        };
+        if self.history.last_retired() {
+            self.instret -= 1;
+        }
+        self.history
+            .undo(&mut self.registers, &mut self.memory, &mut self.csrs);
+        self.tlb.clear();
+        self.timing.revert(cost);
+        self.cycle -= cost.cycles;
+        true
+    }
+
+    /// Steps backwards at least once, until the pc is `pc`. Returns `false` if the history is
+    /// exhausted before.
+    pub fn run_back_to(&mut self, pc: u32) -> bool {
+        while self.step_back() {
+            if self.registers[PC] == pc {
+                return true;
+            }
+        }
+        false
+    }
+}
+
+impl Default for Machine {
+    fn default() -> Machine {
+        Machine::new()
+    }
+}
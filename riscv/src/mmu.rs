@@ -0,0 +1,655 @@
+//! The Sv32 memory-management unit: two-level page-table walks with permission checks, and a TLB
+//! of recent translations.
+//!
+//! Fetches of supervisor and user mode are translated if `satp.MODE` selects Sv32, and so are
+//! loads and stores, also those of machine mode with `mstatus.MPRV` set and `MPP` below machine
+//! mode. Walks set the A and D bits of leaf PTEs in hardware. These writes go straight to RAM, they
+//! are neither recorded in the history nor reverted by [`Machine::step_back`](crate::Machine::step_back).
+//!
+//! The translated physical addresses, and those of the walks, are checked by the [`pmp`].
+
+use crate::{
+    csr::{Csrs, Exception, Privilege, MSTATUS_MXR, MSTATUS_SUM},
+    load_word, pmp, store_word, Access, Memory,
+};
+
+/// `satp.MODE` selecting Sv32, the bare mode if clear.
+pub const SATP_SV32: u32 = 1 << 31;
+
+// PTE fields
+pub const PTE_V: u32 = 1 << 0;
+pub const PTE_R: u32 = 1 << 1;
+pub const PTE_W: u32 = 1 << 2;
+pub const PTE_X: u32 = 1 << 3;
+pub const PTE_U: u32 = 1 << 4;
+pub const PTE_G: u32 = 1 << 5;
+pub const PTE_A: u32 = 1 << 6;
+pub const PTE_D: u32 = 1 << 7;
+
+const PAGE_SIZE: u32 = 0x1000;
+
+/// Whether accesses with the permissions of `privilege` mode are translated.
+fn translated(csrs: &Csrs, privilege: Privilege) -> bool {
+    csrs.satp & SATP_SV32 != 0 && privilege != Privilege::Machine
+}
+
+/// Whether any access of the current mode is translated.
+pub fn enabled(csrs: &Csrs) -> bool {
+    // loads use the mode of fetches, or MPP with MPRV
+    translated(csrs, csrs.privilege) || translated(csrs, csrs.access_privilege(Access::Load))
+}
+
+fn page_fault(access: Access) -> Exception {
+    match access {
+        Access::Fetch => Exception::InstructionPageFault,
+        Access::Load => Exception::LoadPageFault,
+        Access::Store => Exception::StorePageFault,
+    }
+}
+
+pub(crate) fn access_fault(access: Access) -> Exception {
+    match access {
+        Access::Fetch => Exception::InstructionAccessFault,
+        Access::Load => Exception::LoadAccessFault,
+        Access::Store => Exception::StoreAccessFault,
+    }
+}
+
+/// Whether a leaf PTE with `flags` permits `access` in `privilege` mode.
+fn permitted(flags: u32, access: Access, privilege: Privilege, mstatus: u32) -> bool {
+    let mode = match privilege {
+        Privilege::User => flags & PTE_U != 0,
+        // supervisor mode may only load and store user pages with SUM set, and never execute them
+        _ => flags & PTE_U == 0 || access != Access::Fetch && mstatus & MSTATUS_SUM != 0,
+    };
+    mode && match access {
+        Access::Fetch => flags & PTE_X != 0,
+        // MXR makes executable pages readable
+        Access::Load => flags & PTE_R != 0 || mstatus & MSTATUS_MXR != 0 && flags & PTE_X != 0,
+        Access::Store => flags & PTE_W != 0,
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct TlbStats {
+    /// Translations which found their page in the TLB.
+    pub hits: u64,
+    /// Translations which walked the page table.
+    pub misses: u64,
+    /// SFENCE.VMA instructions.
+    pub flushes: u64,
+    pub page_faults: u64,
+}
+
+impl TlbStats {
+    pub fn lookups(&self) -> u64 {
+        self.hits + self.misses
+    }
+
+    /// The share of lookups which hit, 0 if there were none.
+    pub fn hit_rate(&self) -> f64 {
+        match self.lookups() {
+            0 => 0.0,
+            lookups => self.hits as f64 / lookups as f64,
+        }
+    }
+}
+
+/// A translation of a 4 KiB page, also for the pages of a megapage.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Entry {
+    vpn: u32,
+    asid: u32,
+    ppn: u32,
+    /// The low byte of the leaf PTE.
+    flags: u32,
+    megapage: bool,
+}
+
+/// A direct-mapped TLB, indexed by the low bits of the virtual page number and tagged with the
+/// ASID of `satp`, except for global pages. It only holds translations whose PTEs had their A bit
+/// set, stores to pages whose D bit is clear walk again to set it.
+#[derive(Debug, Clone)]
+pub struct Tlb {
+    /// Allocated by the first walk, so a TLB which is never used is free.
+    entries: Vec<Option<Entry>>,
+    size: usize,
+    pub stats: TlbStats,
+}
+
+impl Tlb {
+    /// A TLB with `entries` entries, rounded up to a power of two.
+    pub fn new(entries: usize) -> Tlb {
+        Tlb {
+            entries: Vec::new(),
+            size: entries.max(1).next_power_of_two(),
+            stats: TlbStats::default(),
+        }
+    }
+
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    /// Translates the virtual `address` of an access of `size` bytes to a physical address and
+    /// checks it against the [`pmp`], or returns the exception it raises. Without translation, the
+    /// address is returned as is.
+    ///
+    /// Translated accesses crossing a page boundary raise an address-misaligned exception.
+    #[inline(always)]
+    pub fn translate(
+        &mut self,
+        memory: &mut Memory,
+        csrs: &Csrs,
+        address: u32,
+        size: u32,
+        access: Access,
+    ) -> Result<u32, Exception> {
+        let privilege = csrs.access_privilege(access);
+        // the interpreter's fast path, machine mode is neither translated nor checked unless an
+        // entry is locked
+        if privilege == Privilege::Machine && !pmp::any_locked(csrs) {
+            return Ok(address);
+        }
+        self.translate_checked(memory, csrs, privilege, address, size, access)
+    }
+
+    #[inline(never)]
+    fn translate_checked(
+        &mut self,
+        memory: &mut Memory,
+        csrs: &Csrs,
+        privilege: Privilege,
+        address: u32,
+        size: u32,
+        access: Access,
+    ) -> Result<u32, Exception> {
+        let address = if translated(csrs, privilege) {
+            self.lookup(memory, csrs, privilege, address, size, access)?
+        } else {
+            address
+        };
+        pmp::check(csrs, privilege, address, size, access)?;
+        Ok(address)
+    }
+
+    fn lookup(
+        &mut self,
+        memory: &mut Memory,
+        csrs: &Csrs,
+        privilege: Privilege,
+        address: u32,
+        size: u32,
+        access: Access,
+    ) -> Result<u32, Exception> {
+        if address % PAGE_SIZE + size > PAGE_SIZE {
+            return Err(match access {
+                Access::Fetch => Exception::InstructionAddressMisaligned,
+                Access::Load => Exception::LoadAddressMisaligned,
+                Access::Store => Exception::StoreAddressMisaligned,
+            });
+        }
+        let vpn = address / PAGE_SIZE;
+        let asid = csrs.satp >> 22 & 0x1ff;
+        let index = vpn as usize & (self.size - 1);
+        let cached = self.entries.get(index).copied().flatten().filter(|entry| {
+            entry.vpn == vpn
+                && (entry.asid == asid || entry.flags & PTE_G != 0)
+                && (access != Access::Store || entry.flags & PTE_D != 0)
+        });
+        let entry = match cached {
+            Some(entry) => {
+                self.stats.hits += 1;
+                entry
+            }
+            None => {
+                self.stats.misses += 1;
+                let entry = walk(memory, csrs, privilege, address, access).inspect_err(|_| {
+                    self.stats.page_faults += 1;
+                })?;
+                if self.entries.is_empty() {
+                    self.entries = vec![None; self.size];
+                }
+                self.entries[index] = Some(entry);
+                entry
+            }
+        };
+        if !permitted(entry.flags, access, privilege, csrs.mstatus) {
+            self.stats.page_faults += 1;
+            return Err(page_fault(access));
+        }
+        Ok(entry.ppn * PAGE_SIZE + address % PAGE_SIZE)
+    }
+
+    /// Invalidates the translations of the page of `address` and of the address space `asid`,
+    /// or of all pages or address spaces if `None`, like SFENCE.VMA. Global pages are kept if
+    /// `asid` is given.
+    pub fn flush(&mut self, address: Option<u32>, asid: Option<u32>) {
+        self.stats.flushes += 1;
+        for slot in &mut self.entries {
+            let Some(entry) = *slot else {
+                continue;
+            };
+            let page = address.map_or(true, |address| {
+                if entry.megapage {
+                    entry.vpn >> 10 == address >> 22
+                } else {
+                    entry.vpn == address / PAGE_SIZE
+                }
+            });
+            let space = asid.map_or(true, |asid| entry.asid == asid && entry.flags & PTE_G == 0);
+            if page && space {
+                *slot = None;
+            }
+        }
+    }
+
+    /// Invalidates all translations, e.g., after changing page tables or `satp` other than by
+    /// executing instructions.
+    pub fn clear(&mut self) {
+        self.entries.fill(None);
+    }
+}
+
+impl Default for Tlb {
+    /// A TLB with 64 entries.
+    fn default() -> Tlb {
+        Tlb::new(64)
+    }
+}
+
+/// The TLB holds no architectural state, so machines compare equal regardless of what it cached.
+impl PartialEq for Tlb {
+    fn eq(&self, other: &Tlb) -> bool {
+        self.size == other.size
+    }
+}
+
+impl Eq for Tlb {}
+
+/// Walks the page table of `satp` for the virtual `address`, checks the permissions of the leaf
+/// PTE and sets its A bit, and its D bit for stores.
+fn walk(
+    memory: &mut Memory,
+    csrs: &Csrs,
+    privilege: Privilege,
+    address: u32,
+    access: Access,
+) -> Result<Entry, Exception> {
+    // the physical address space of Sv32 has 34 bits, RAM only lies in the lower 4 GiB
+    let physical = |ppn: u32| ppn.checked_mul(PAGE_SIZE).ok_or(access_fault(access));
+    let mut table = physical(csrs.satp & 0x3f_ffff)?;
+    for level in [1, 0] {
+        let pte_address = table + (address >> (12 + 10 * level) & 0x3ff) * 4;
+        // the PMP checks walks like accesses of supervisor mode
+        pmp::check(csrs, Privilege::Supervisor, pte_address, 4, Access::Load)
+            .map_err(|_| access_fault(access))?;
+        let pte = load_word(memory, pte_address).map_err(|_| access_fault(access))?;
+        if pte & PTE_V == 0 || pte & (PTE_R | PTE_W) == PTE_W {
+            return Err(page_fault(access));
+        }
+        let ppn = pte >> 10;
+        if pte & (PTE_R | PTE_X) == 0 {
+            // a pointer to the next level
+            table = physical(ppn)?;
+            continue;
+        }
+        // megapages must be aligned to 4 MiB
+        if level == 1 && ppn & 0x3ff != 0 {
+            return Err(page_fault(access));
+        }
+        if !permitted(pte, access, privilege, csrs.mstatus) {
+            return Err(page_fault(access));
+        }
+        let updated = pte | PTE_A | if access == Access::Store { PTE_D } else { 0 };
+        if updated != pte {
+            pmp::check(csrs, Privilege::Supervisor, pte_address, 4, Access::Store)
+                .map_err(|_| access_fault(access))?;
+            store_word(memory, pte_address, updated).map_err(|_| access_fault(access))?;
+        }
+        let vpn = address / PAGE_SIZE;
+        let ppn = if level == 1 { ppn | vpn & 0x3ff } else { ppn };
+        physical(ppn)?;
+        return Ok(Entry {
+            vpn,
+            asid: csrs.satp >> 22 & 0x1ff,
+            ppn,
+            flags: updated & 0xff,
+            megapage: level == 1,
+        });
+    }
+    Err(page_fault(access))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        csr::*, load_word, mmu::*, pmp::*, store_word, test_utils, Access, Engine, Error, Machine,
+        Memory, MEMORY_START, PC,
+    };
+
+    const ROOT: u32 = 0x8000_8000;
+    const TABLE: u32 = 0x8000_9000;
+    const CODE: u32 = 0x8000_1000;
+    const DATA: u32 = 0x8000_2000;
+
+    /// Maps the virtual page 0 to `CODE` and the page 0x1000 to `DATA` with `flags`.
+    fn page_tables(memory: &mut Memory, flags: u32) {
+        store_word(memory, ROOT, TABLE >> 12 << 10 | PTE_V).unwrap();
+        store_word(memory, TABLE, CODE >> 12 << 10 | PTE_V | PTE_R | PTE_X).unwrap();
+        store_word(memory, TABLE + 4, DATA >> 12 << 10 | flags).unwrap();
+    }
+
+    /// With a PMP entry which permits everything.
+    fn csrs(privilege: Privilege, mstatus: u32) -> Csrs {
+        let mut csrs = Csrs {
+            privilege,
+            mstatus,
+            satp: SATP_SV32 | ROOT >> 12,
+            ..Csrs::default()
+        };
+        csrs.pmpaddr[0] = u32::MAX;
+        csrs.pmpcfg[0] = (PMP_NAPOT | PMP_R | PMP_W | PMP_X) as u32;
+        csrs
+    }
+
+    #[test]
+    fn walk_sets_accessed_and_dirty() {
+        let mut memory = Machine::new().memory;
+        page_tables(&mut memory, PTE_V | PTE_R | PTE_W);
+        let csrs = csrs(Privilege::Supervisor, 0);
+        let mut tlb = Tlb::default();
+        let mut translate =
+            |memory: &mut Memory, access| tlb.translate(memory, &csrs, 0x1234, 4, access);
+        assert_eq!(translate(&mut memory, Access::Load), Ok(DATA + 0x234));
+        let pte = load_word(&memory, TABLE + 4).unwrap();
+        assert_eq!(pte & (PTE_A | PTE_D), PTE_A);
+        assert_eq!(translate(&mut memory, Access::Load), Ok(DATA + 0x234));
+        // the cached translation can't set the D bit
+        assert_eq!(translate(&mut memory, Access::Store), Ok(DATA + 0x234));
+        let pte = load_word(&memory, TABLE + 4).unwrap();
+        assert_eq!(pte & (PTE_A | PTE_D), PTE_A | PTE_D);
+        assert_eq!(translate(&mut memory, Access::Store), Ok(DATA + 0x234));
+        assert_eq!(
+            tlb.stats,
+            TlbStats {
+                hits: 2,
+                misses: 2,
+                flushes: 0,
+                page_faults: 0
+            }
+        );
+
+        // without translation, addresses are physical
+        let bare = Csrs { satp: 0, ..csrs };
+        assert_eq!(
+            tlb.translate(&mut memory, &bare, 0x1234, 4, Access::Load),
+            Ok(0x1234)
+        );
+        assert_eq!(tlb.stats.lookups(), 4);
+    }
+
+    #[test]
+    fn permissions() {
+        let (r, w, x, u) = (PTE_R, PTE_W, PTE_X, PTE_U);
+        let (user, supervisor) = (Privilege::User, Privilege::Supervisor);
+        let (fetch, load, store) = (Access::Fetch, Access::Load, Access::Store);
+        #[rustfmt::skip]
+        let cases = [
+            (r | w, supervisor, 0, load, true),
+            (r | w, supervisor, 0, store, true),
+            (r | w, supervisor, 0, fetch, false),
+            (r, supervisor, 0, store, false),
+            (x, supervisor, 0, fetch, true),
+            (x, supervisor, 0, load, false),
+            // MXR makes executable pages readable
+            (x, supervisor, MSTATUS_MXR, load, true),
+            // user pages, which supervisor mode may only load and store with SUM
+            (r | w | x | u, user, 0, load, true),
+            (r | w | x | u, user, 0, fetch, true),
+            (r | w | x, user, 0, load, false),
+            (r | w | x | u, supervisor, 0, load, false),
+            (r | w | x | u, supervisor, MSTATUS_SUM, store, true),
+            (r | w | x | u, supervisor, MSTATUS_SUM, fetch, false),
+            // reserved encodings
+            (w, supervisor, 0, load, false),
+            (w | x, supervisor, 0, fetch, false),
+        ];
+        for (flags, privilege, mstatus, access, permitted) in cases {
+            let mut memory = Machine::new().memory;
+            page_tables(&mut memory, PTE_V | flags);
+            let result =
+                Tlb::default().translate(&mut memory, &csrs(privilege, mstatus), 0x1000, 4, access);
+            let expected = if permitted {
+                Ok(DATA)
+            } else {
+                Err(page_fault(access))
+            };
+            assert_eq!(
+                result, expected,
+                "{flags:04b} {privilege:?} {mstatus:x} {access}"
+            );
+            // faults leave the PTE untouched
+            let pte = load_word(&memory, TABLE + 4).unwrap();
+            assert_eq!(pte & PTE_A != 0, permitted);
+        }
+
+        // an invalid PTE, and a pointer at the last level
+        let mut memory = Machine::new().memory;
+        page_tables(&mut memory, PTE_R);
+        let mut tlb = Tlb::default();
+        let csrs = csrs(Privilege::Supervisor, 0);
+        assert_eq!(
+            tlb.translate(&mut memory, &csrs, 0x1000, 4, Access::Load),
+            Err(Exception::LoadPageFault)
+        );
+        page_tables(&mut memory, PTE_V);
+        assert_eq!(
+            tlb.translate(&mut memory, &csrs, 0x1000, 4, Access::Store),
+            Err(Exception::StorePageFault)
+        );
+        assert_eq!(tlb.stats.page_faults, 2);
+        // a table outside of RAM
+        store_word(&mut memory, ROOT, PTE_V).unwrap();
+        assert_eq!(
+            tlb.translate(&mut memory, &csrs, 0x1000, 4, Access::Fetch),
+            Err(Exception::InstructionAccessFault)
+        );
+    }
+
+    #[test]
+    fn megapages() {
+        let mut memory = Machine::new().memory;
+        let csrs = csrs(Privilege::Supervisor, 0);
+        // the second 4 MiB map to the start of RAM
+        let leaf = MEMORY_START as u32 >> 12 << 10 | PTE_V | PTE_R | PTE_W;
+        store_word(&mut memory, ROOT + 4, leaf).unwrap();
+        let mut tlb = Tlb::default();
+        assert_eq!(
+            tlb.translate(&mut memory, &csrs, 0x0040_5678, 4, Access::Load),
+            Ok(0x8000_5678)
+        );
+        assert_eq!(
+            tlb.translate(&mut memory, &csrs, 0x0047_fffc, 4, Access::Store),
+            Ok(0x8007_fffc)
+        );
+        // flushing an address invalidates all pages of its megapage
+        tlb.flush(Some(0x0040_0000), None);
+        assert_eq!(
+            tlb.translate(&mut memory, &csrs, 0x0040_5678, 4, Access::Load),
+            Ok(0x8000_5678)
+        );
+        assert_eq!(tlb.stats.misses, 3);
+
+        // megapages must be aligned
+        store_word(&mut memory, ROOT + 4, leaf | 1 << 10).unwrap();
+        tlb.clear();
+        assert_eq!(
+            tlb.translate(&mut memory, &csrs, 0x0040_5678, 4, Access::Load),
+            Err(Exception::LoadPageFault)
+        );
+        // accesses crossing a page boundary
+        store_word(&mut memory, ROOT + 4, leaf).unwrap();
+        assert_eq!(
+            tlb.translate(&mut memory, &csrs, 0x0040_0ffe, 4, Access::Store),
+            Err(Exception::StoreAddressMisaligned)
+        );
+        assert_eq!(
+            tlb.translate(&mut memory, &csrs, 0x0040_0ffe, 4, Access::Fetch),
+            Err(Exception::InstructionAddressMisaligned)
+        );
+        assert_eq!(
+            tlb.translate(&mut memory, &csrs, 0x0040_0ffe, 2, Access::Load),
+            Ok(0x8000_0ffe)
+        );
+    }
+
+    #[test]
+    fn flush() {
+        let mut memory = Machine::new().memory;
+        page_tables(&mut memory, PTE_V | PTE_R | PTE_G);
+        let mut csrs = csrs(Privilege::Supervisor, 0);
+        let mut tlb = Tlb::default();
+        tlb.translate(&mut memory, &csrs, 0x1000, 4, Access::Load)
+            .unwrap();
+        tlb.translate(&mut memory, &csrs, 0x0000, 4, Access::Fetch)
+            .unwrap();
+        // remapping the pages takes effect after a flush
+        store_word(&mut memory, TABLE, DATA >> 12 << 10 | PTE_V | PTE_X).unwrap();
+        store_word(&mut memory, TABLE + 4, CODE >> 12 << 10 | PTE_V | PTE_R).unwrap();
+        let mut translate = |tlb: &mut Tlb, csrs: &Csrs, address, access| {
+            tlb.translate(&mut memory, csrs, address, 4, access)
+        };
+        assert_eq!(translate(&mut tlb, &csrs, 0x1000, Access::Load), Ok(DATA));
+        assert_eq!(translate(&mut tlb, &csrs, 0x0000, Access::Fetch), Ok(CODE));
+        tlb.flush(Some(0x0000), None);
+        assert_eq!(translate(&mut tlb, &csrs, 0x1000, Access::Load), Ok(DATA));
+        assert_eq!(translate(&mut tlb, &csrs, 0x0000, Access::Fetch), Ok(DATA));
+        // other address spaces see the global page, but not the others
+        csrs.satp |= 1 << 22;
+        tlb.flush(None, Some(1));
+        assert_eq!(translate(&mut tlb, &csrs, 0x1000, Access::Load), Ok(DATA));
+        tlb.flush(Some(0x1000), None);
+        assert_eq!(translate(&mut tlb, &csrs, 0x1000, Access::Load), Ok(CODE));
+        assert_eq!(tlb.stats.flushes, 3);
+        assert_eq!(tlb.stats.misses, 4);
+    }
+
+    #[test]
+    fn modify_privilege() {
+        let mut memory = Machine::new().memory;
+        page_tables(&mut memory, PTE_V | PTE_R | PTE_W);
+        let mut tlb = Tlb::default();
+        // loads and stores of machine mode use the translation of MPP
+        let mprv = MSTATUS_MPRV | 1 << 11;
+        let csrs = csrs(Privilege::Machine, mprv);
+        assert!(enabled(&csrs));
+        assert_eq!(
+            tlb.translate(&mut memory, &csrs, 0x1000, 4, Access::Store),
+            Ok(DATA)
+        );
+        assert_eq!(
+            tlb.translate(&mut memory, &csrs, 0x1000, 4, Access::Fetch),
+            Ok(0x1000)
+        );
+        let csrs = Csrs {
+            mstatus: MSTATUS_MPRV | MSTATUS_MPP,
+            ..csrs
+        };
+        assert!(!enabled(&csrs));
+        assert_eq!(
+            tlb.translate(&mut memory, &csrs, 0x1000, 4, Access::Load),
+            Ok(0x1000)
+        );
+    }
+
+    // Enables Sv32 and returns to supervisor mode at the virtual address 0, the handler stores
+    // mcause, mtval and mepc in s0, s1 and s2, then ends the program
+    const KERNEL: &[u32] = &[
+        0x800802b7, // lui t0, 0x80080
+        0x00828293, // addi t0, t0, 8 (satp: Sv32, root table at 0x80008000)
+        0x18029073, // csrw satp, t0
+        0x00000297, // auipc t0, 0
+        0x02028293, // addi t0, t0, 32
+        0x30529073, // csrw mtvec, t0
+        0x000012b7, // lui t0, 1
+        0x80028293, // addi t0, t0, -2048 (MPP = S)
+        0x3002a073, // csrs mstatus, t0
+        0x34101073, // csrw mepc, zero
+        0x30200073, // mret
+        0x34202473, // handler: csrr s0, mcause
+        0x343024f3, // csrr s1, mtval
+        0x34102973, // csrr s2, mepc
+        0xc0001073, // unimp
+    ];
+    // At `CODE`, mapped to the virtual address 0
+    const SUPERVISOR: &[u32] = &[
+        0x00001537, // lui a0, 1
+        0x00052583, // lw a1, 0(a0)
+        0x00b52223, // sw a1, 4(a0)
+        0x12000073, // sfence.vma
+        0x00002637, // lui a2, 2
+        0x00062683, // lw a3, 0(a2) (unmapped)
+    ];
+
+    #[test]
+    fn supervisor_program() {
+        for engine in [Engine::Interpreter, Engine::Blocks] {
+            let mut machine = test_utils::machine(KERNEL);
+            machine.engine = engine;
+            machine.csrs = csrs(Privilege::Machine, 0);
+            machine.csrs.satp = 0;
+            test_utils::load(&mut machine.memory, CODE, SUPERVISOR);
+            page_tables(&mut machine.memory, PTE_V | PTE_R | PTE_W);
+            store_word(&mut machine.memory, DATA, 0x1234_5678).unwrap();
+
+            assert_eq!(machine.run(100), Ok(true));
+            assert_eq!(machine.registers[11], 0x1234_5678);
+            assert_eq!(load_word(&machine.memory, DATA + 4), Ok(0x1234_5678));
+            assert_eq!(machine.csrs.privilege, Privilege::Machine);
+            assert_eq!(machine.registers[8..=9], [13, 0x2000]);
+            assert_eq!(machine.registers[18], 0x14);
+            assert_eq!(machine.registers[PC], MEMORY_START as u32 + 0x3c);
+            // the walks set the A bits, and the D bit of the stored page
+            let code = load_word(&machine.memory, TABLE).unwrap();
+            let data = load_word(&machine.memory, TABLE + 4).unwrap();
+            assert_eq!((code & PTE_A, code & PTE_D), (PTE_A, 0));
+            assert_eq!(data & (PTE_A | PTE_D), PTE_A | PTE_D);
+            assert_eq!(machine.tlb.stats.flushes, 1);
+            assert_eq!(machine.tlb.stats.page_faults, 1);
+        }
+    }
+
+    #[test]
+    fn delegated_page_fault() {
+        let mut machine = Machine::new();
+        machine.csrs = csrs(Privilege::Supervisor, 0);
+        machine.csrs.medeleg = 1 << Exception::LoadPageFault.cause();
+        // the handler's virtual address is mapped to `CODE + 0x100` only
+        machine.csrs.stvec = 0x100;
+        machine.registers[PC] = 0x4;
+        store_word(&mut machine.memory, CODE + 0x4, 0x00062683).unwrap(); // lw a3, 0(a2)
+        store_word(&mut machine.memory, CODE + 0x100, 0x14202773).unwrap(); // csrr a4, scause
+        page_tables(&mut machine.memory, PTE_V | PTE_R);
+        machine.registers[12] = 0x2000;
+
+        assert_eq!(machine.step(), Ok(false));
+        assert_eq!(machine.csrs.privilege, Privilege::Supervisor);
+        assert_eq!(machine.registers[PC], 0x100);
+        assert_eq!((machine.csrs.sepc, machine.csrs.stval), (0x4, 0x2000));
+        assert_eq!(machine.step(), Ok(false));
+        assert_eq!(machine.registers[14], Exception::LoadPageFault.cause());
+
+        // an unmapped handler can't be fetched
+        machine.csrs.stvec = 0x3000;
+        machine.registers[PC] = 0x4;
+        assert!(matches!(
+            machine.step(),
+            Err(Error::Exception {
+                exception: Exception::LoadPageFault,
+                ..
+            })
+        ));
+        assert_eq!(machine.registers[PC], 0x4);
+    }
+}
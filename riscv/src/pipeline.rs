@@ -0,0 +1,339 @@
+use crate::{Error, Instruction, Machine, Retired, PC};
+
+/// The stages of the classic RISC pipeline.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Stage {
+    Fetch,
+    Decode,
+    Execute,
+    Memory,
+    WriteBack,
+}
+
+impl Stage {
+    pub const ALL: [Stage; 5] = [
+        Stage::Fetch,
+        Stage::Decode,
+        Stage::Execute,
+        Stage::Memory,
+        Stage::WriteBack,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Stage::Fetch => "IF",
+            Stage::Decode => "ID",
+            Stage::Execute => "EX",
+            Stage::Memory => "MEM",
+            Stage::WriteBack => "WB",
+        }
+    }
+}
+
+/// An instruction in flight.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Slot {
+    pub pc: u32,
+    /// Errors only surface once the instruction reaches the execute stage, so fetches on a wrong
+    /// path never fault.
+    pub fetched: Result<(u32, Instruction), Error>,
+    /// Set once the instruction was executed, stays `None` if it trapped.
+    pub retired: Option<Retired>,
+}
+
+impl Slot {
+    /// The register the instruction writes, if it was executed.
+    fn destination(&self) -> Option<u32> {
+        self.retired?
+            .rd
+            .map(|register_write| register_write.register)
+    }
+}
+
+/// A cycle-stepped IF/ID/EX/MEM/WB pipeline with full forwarding, which predicts branches as not
+/// taken and resolves them in the execute stage.
+///
+/// Instructions are executed on the [`Machine`] when they enter the execute stage, which updates
+/// its registers, memory and counters just like [`Machine::step`]. The forwarding paths supply the
+/// same values, so only the timing differs: a load followed by an instruction reading its result
+/// stalls a cycle, and taken branches, jumps, FENCE.I and SFENCE.VMA flush the two instructions
+/// behind them. Instructions are fetched without FENCE.I, so stores don't modify instructions
+/// already in flight, and with the address translation of the machine's current mode.
+///
+/// An instruction raising an exception traps when it reaches the execute stage and flushes the
+/// instructions behind it, it passes the remaining stages without being retired.
+///
+/// Call [`Pipeline::flush`] after changing the machine other than with [`Pipeline::clock`], e.g.,
+/// after [`Machine::step_back`].
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Pipeline {
+    /// The instruction in each stage, indexed by [`Stage`], `None` for bubbles.
+    pub stages: [Option<Slot>; 5],
+    /// The number of clock cycles.
+    pub cycle: u64,
+    /// The instruction in the decode stage waited for a load in the last cycle.
+    pub stalled: bool,
+    /// The instructions following a taken branch or jump were discarded in the last cycle.
+    pub flushed: bool,
+    /// Where the operands `rs1` and `rs2` of the instruction in the execute stage were forwarded
+    /// from, `None` if they were read from the register file.
+    pub forwarded: [Option<Stage>; 2],
+    /// The next pc to fetch from, the pc of the machine if `None`.
+    fetch_pc: Option<u32>,
+    /// The target of a branch resolved in the last cycle, the following instructions are wrong.
+    redirect: Option<u32>,
+}
+
+impl Pipeline {
+    pub fn stage(&self, stage: Stage) -> Option<&Slot> {
+        self.stages[stage as usize].as_ref()
+    }
+
+    /// Discards all instructions in flight, the next cycle fetches from the machine's pc.
+    pub fn flush(&mut self) {
+        *self = Pipeline {
+            cycle: self.cycle,
+            ..Pipeline::default()
+        };
+    }
+
+    /// Advances the pipeline by a cycle. Returns `true` once the instruction which signaled the
+    /// end of a riscv-tests program reached the write-back stage.
+    ///
+    /// On error, neither the pipeline nor the machine are changed.
+    pub fn clock(&mut self, machine: &mut Machine) -> Result<bool, Error> {
+        let [mut fetched, mut decoded, executed, memory, _] = self.stages.clone();
+        let mut fetch_pc = self.fetch_pc.unwrap_or(machine.registers[PC]);
+        let flushed = self.redirect.is_some();
+        if let Some(target) = self.redirect {
+            fetched = None;
+            decoded = None;
+            fetch_pc = target;
+        }
+
+        // the loaded value is only available after the memory stage
+        let stalled = executed.as_ref().is_some_and(|slot| {
+            let load = slot.retired.is_some_and(|retired| retired.load.is_some());
+            let sources = match &decoded {
+                Some(Slot {
+                    fetched: Ok((_, instruction)),
+                    ..
+                }) => instruction.sources(),
+                _ => [0; 2],
+            };
+            load && slot
+                .destination()
+                .is_some_and(|register| sources.contains(&register))
+        });
+        // nothing after the end of the program is executed
+        let halted = [&executed, &memory].iter().any(|slot| {
+            slot.as_ref()
+                .is_some_and(|slot| slot.retired.is_some_and(|retired| retired.done))
+        });
+
+        let mut forwarded = [None; 2];
+        let mut redirect = None;
+        let (next_executed, next_decoded, next_fetched) = match decoded {
+            _ if stalled || halted => (None, decoded, fetched),
+            None => (None, fetched, None),
+            Some(mut slot) => {
+                debug_assert_eq!(slot.pc, machine.registers[PC]);
+                if let Ok((_, instruction)) = &slot.fetched {
+                    forwarded = instruction.sources().map(|source| {
+                        if source == 0 {
+                            None
+                        } else if executed.as_ref().and_then(Slot::destination) == Some(source) {
+                            Some(Stage::Memory)
+                        } else if memory.as_ref().and_then(Slot::destination) == Some(source) {
+                            Some(Stage::WriteBack)
+                        } else {
+                            None
+                        }
+                    });
+                }
+                match machine.execute(slot.fetched.clone())? {
+                    Some(retired) => {
+                        let predicted = fetched.as_ref().map_or(fetch_pc, |slot| slot.pc);
+                        if retired.next_pc != predicted
+                            || matches!(
+                                retired.instruction,
+                                Instruction::JAL(_)
+                                    | Instruction::JALR(_)
+                                    | Instruction::FENCE_I(_)
+                                    | Instruction::SFENCE_VMA(_)
+                            )
+                        {
+                            redirect = Some(retired.next_pc);
+                        }
+                        slot.retired = Some(retired);
+                    }
+                    // the instruction trapped or an interrupt was taken instead, the handler is
+                    // fetched instead of the following instructions
+                    None => redirect = Some(machine.registers[PC]),
+                }
+                (Some(slot), fetched, None)
+            }
+        };
+        let next_fetched = match next_fetched {
+            Some(slot) => Some(slot),
+            None if halted => None,
+            None => {
+                let pc = fetch_pc;
+                fetch_pc = pc.wrapping_add(4);
+                Some(Slot {
+                    pc,
+                    fetched: machine.fetch(pc),
+                    retired: None,
+                })
+            }
+        };
+
+        let done = memory
+            .as_ref()
+            .is_some_and(|slot| slot.retired.is_some_and(|retired| retired.done));
+        *self = Pipeline {
+            stages: [next_fetched, next_decoded, next_executed, executed, memory],
+            cycle: self.cycle + 1,
+            stalled,
+            flushed,
+            forwarded,
+            fetch_pc: Some(fetch_pc),
+            redirect,
+        };
+        Ok(done)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        pipeline::{Pipeline, Stage},
+        programs::BUBBLE_SORT,
+        test_utils::call,
+        timing::{Latencies, Timing},
+        Machine, MEMORY_START,
+    };
+
+    /// Clocks the pipeline until the program ended, checking that it never takes longer than
+    /// `limit` cycles.
+    fn run(pipeline: &mut Pipeline, machine: &mut Machine, limit: u64) {
+        while !pipeline.clock(machine).unwrap() {
+            assert!(pipeline.cycle < limit);
+        }
+    }
+
+    #[test]
+    fn same_as_step() {
+        let mut expected = call(BUBBLE_SORT);
+        expected.timing = Timing::new(Latencies::default());
+        assert!(expected.run(u64::MAX).unwrap());
+
+        let mut machine = call(BUBBLE_SORT);
+        machine.timing = Timing::new(Latencies::default());
+        let mut pipeline = Pipeline::default();
+        run(&mut pipeline, &mut machine, 1000);
+        assert!(machine == expected);
+        // the timing model agrees, except for the 4 cycles it takes to fill the pipeline
+        assert_eq!(pipeline.cycle, expected.cycle + 4);
+    }
+
+    #[test]
+    fn load_use() {
+        let mut machine = call(&[
+            0x00012503, // lw a0, 0(sp)
+            0x00150593, // addi a1, a0, 1
+            0x00b58633, // add a2, a1, a1
+        ]);
+        machine.registers[2] -= 4;
+        let mut pipeline = Pipeline::default();
+        for _ in 0..3 {
+            pipeline.clock(&mut machine).unwrap();
+        }
+        // the lw is executed, the addi waits in the decode stage for a cycle
+        pipeline.clock(&mut machine).unwrap();
+        assert!(pipeline.stalled);
+        assert!(pipeline.stage(Stage::Execute).is_none());
+        assert_eq!(
+            pipeline.stage(Stage::Decode).unwrap().pc,
+            MEMORY_START as u32 + 4
+        );
+        pipeline.clock(&mut machine).unwrap();
+        assert!(!pipeline.stalled);
+        assert_eq!(pipeline.forwarded, [Some(Stage::WriteBack), None]);
+        pipeline.clock(&mut machine).unwrap();
+        assert_eq!(
+            pipeline.forwarded,
+            [Some(Stage::Memory), Some(Stage::Memory)]
+        );
+        run(&mut pipeline, &mut machine, 20);
+        assert_eq!(machine.registers[12], 2);
+    }
+
+    #[test]
+    fn flush() {
+        let mut machine = call(&[
+            0x0080006f, // j 8
+            0x00000000, // invalid, only fetched
+            0x00100513, // li a0, 1
+        ]);
+        let mut pipeline = Pipeline::default();
+        for _ in 0..3 {
+            pipeline.clock(&mut machine).unwrap();
+        }
+        assert_eq!(
+            pipeline.stage(Stage::Decode).unwrap().pc,
+            MEMORY_START as u32 + 4
+        );
+        assert!(pipeline.stage(Stage::Decode).unwrap().fetched.is_err());
+        pipeline.clock(&mut machine).unwrap();
+        assert!(pipeline.flushed);
+        assert!(pipeline.stage(Stage::Execute).is_none());
+        assert!(pipeline.stage(Stage::Decode).is_none());
+        assert_eq!(
+            pipeline.stage(Stage::Fetch).unwrap().pc,
+            MEMORY_START as u32 + 8
+        );
+        run(&mut pipeline, &mut machine, 20);
+        assert_eq!(machine.registers[10], 1);
+    }
+
+    #[test]
+    fn traps() {
+        let program = &[
+            0x00000297, // la t0, handler
+            0x02428293, //
+            0x30529073, // csrw mtvec, t0
+            0x00100513, // li a0, 1
+            0x00000073, // ecall
+            0x00150513, // addi a0, a0, 1
+            0x00100073, // ebreak
+            0x00150513, // addi a0, a0, 1
+            0x0140006f, // j end
+            // handler, returns behind the instruction which trapped
+            0x34102373, // csrr t1, mepc
+            0x00430313, // addi t1, t1, 4
+            0x34131073, // csrw mepc, t1
+            0x30200073, // mret
+        ];
+        let mut expected = call(program);
+        assert!(expected.run(u64::MAX).unwrap());
+
+        let mut machine = call(program);
+        let mut pipeline = Pipeline::default();
+        run(&mut pipeline, &mut machine, 100);
+        assert!(machine == expected);
+        assert_eq!(machine.registers[10], 3);
+    }
+
+    #[test]
+    fn errors_surface_in_execute() {
+        let mut machine = call(&[0x00000000]);
+        let mut pipeline = Pipeline::default();
+        pipeline.clock(&mut machine).unwrap();
+        let before = pipeline.clone();
+        assert!(pipeline.clock(&mut machine).is_ok());
+        assert!(pipeline.clock(&mut machine).is_err());
+        assert_eq!(pipeline.cycle, before.cycle + 1);
+        assert_eq!(machine.instret, 0);
+    }
+}
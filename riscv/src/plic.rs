@@ -0,0 +1,261 @@
+//! The platform-level interrupt controller, with the register layout of SiFive's PLIC, which
+//! routes the interrupt lines of devices to the external interrupts of the hart.
+//!
+//! Sources 1 to 31 have a priority from 0 to 7, where 0 never interrupts. Context 0 raises
+//! `mip.MEIP` and context 1 `mip.SEIP`, each with its own enables and priority threshold. A
+//! source is pending while its line is raised, until a context claims it. Once claimed, it isn't
+//! pending again before the handler completes it by writing its ID back.
+//!
+//! Devices drive their lines with [`Plic::raise`] and [`Plic::lower`]. The lines are level
+//! triggered, a source whose line is lowered before it is claimed is no longer pending.
+
+use crate::csr::Interrupt;
+
+/// The size of the PLIC's address range.
+pub const PLIC_SIZE: u32 = 0x400_0000;
+/// The number of interrupt sources including source 0, which doesn't exist.
+pub const PLIC_SOURCES: u32 = 32;
+/// The number of contexts, machine and supervisor mode of the hart.
+pub const PLIC_CONTEXTS: usize = 2;
+/// The highest priority.
+pub const PLIC_MAX_PRIORITY: u32 = 7;
+
+// register offsets
+pub const PRIORITY: u32 = 0x0;
+pub const PENDING: u32 = 0x1000;
+/// The enables of context `n` are at `ENABLE + 0x80 * n`.
+pub const ENABLE: u32 = 0x2000;
+/// The threshold of context `n` is at `THRESHOLD + 0x1000 * n`, its claim/complete register
+/// right after it.
+pub const THRESHOLD: u32 = 0x20_0000;
+pub const CLAIM: u32 = 0x20_0004;
+
+/// The interrupts the contexts raise.
+const CONTEXT_INTERRUPTS: [Interrupt; PLIC_CONTEXTS] =
+    [Interrupt::MachineExternal, Interrupt::SupervisorExternal];
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Plic {
+    /// The priority of each source, source 0's is always 0.
+    pub priorities: [u32; PLIC_SOURCES as usize],
+    /// The levels of the interrupt lines, one bit per source.
+    pub lines: u32,
+    /// The sources claimed but not completed yet.
+    pub claimed: u32,
+    /// The sources each context is interested in.
+    pub enables: [u32; PLIC_CONTEXTS],
+    /// Only sources with a higher priority interrupt a context.
+    pub thresholds: [u32; PLIC_CONTEXTS],
+}
+
+impl Plic {
+    /// Raises the interrupt line of `source`, which must be between 1 and 31.
+    pub fn raise(&mut self, source: u32) {
+        debug_assert!((1..PLIC_SOURCES).contains(&source));
+        self.lines |= 1 << source;
+    }
+
+    /// Lowers the interrupt line of `source`.
+    pub fn lower(&mut self, source: u32) {
+        self.lines &= !(1 << source);
+    }
+
+    /// The bits of the pending sources.
+    pub fn pending(&self) -> u32 {
+        self.lines & !self.claimed & !1
+    }
+
+    /// The source `context` claims next: the pending and enabled one with the highest priority
+    /// above the threshold, the lowest ID among equal priorities. 0 if there is none.
+    fn best(&self, context: usize) -> u32 {
+        let candidates = self.pending() & self.enables[context];
+        let mut best = 0;
+        for source in 1..PLIC_SOURCES {
+            if candidates >> source & 1 == 1
+                && self.priorities[source as usize] > self.thresholds[context]
+                && self.priorities[source as usize] > self.priorities[best as usize]
+            {
+                best = source;
+            }
+        }
+        best
+    }
+
+    /// The bits of the external interrupts the contexts raise in `mip`.
+    pub fn interrupts(&self) -> u32 {
+        if self.pending() == 0 {
+            return 0;
+        }
+        (0..PLIC_CONTEXTS)
+            .filter(|&context| self.best(context) != 0)
+            .fold(0, |mip, context| mip | CONTEXT_INTERRUPTS[context].mask())
+    }
+
+    /// The context whose register `offset` is the `base` of, with a register every `stride` bytes.
+    fn context(offset: u32, base: u32, stride: u32) -> Option<usize> {
+        let relative = offset.checked_sub(base)?;
+        let context = (relative / stride) as usize;
+        (relative % stride == 0 && context < PLIC_CONTEXTS).then_some(context)
+    }
+
+    /// Reads the 32-bit register at `offset`, `None` if there is none. Reading a claim/complete
+    /// register claims the source it returns.
+    pub fn load(&mut self, offset: u32) -> Option<u32> {
+        Some(match offset {
+            PRIORITY..PENDING => *self.priorities.get((offset / 4) as usize)?,
+            PENDING => self.pending(),
+            _ => {
+                if let Some(context) = Plic::context(offset, ENABLE, 0x80) {
+                    self.enables[context]
+                } else if let Some(context) = Plic::context(offset, THRESHOLD, 0x1000) {
+                    self.thresholds[context]
+                } else {
+                    let context = Plic::context(offset, CLAIM, 0x1000)?;
+                    let source = self.best(context);
+                    self.claimed |= (1 << source) & !1;
+                    source
+                }
+            }
+        })
+    }
+
+    /// Writes the 32-bit register at `offset`, `None` if there is none. Writing a source's ID to
+    /// a claim/complete register completes it, unless the context doesn't enable the source.
+    pub fn store(&mut self, offset: u32, value: u32) -> Option<()> {
+        match offset {
+            PRIORITY..PENDING => {
+                let priority = self.priorities.get_mut((offset / 4) as usize)?;
+                // source 0 doesn't exist
+                if offset != PRIORITY {
+                    *priority = value & PLIC_MAX_PRIORITY;
+                }
+            }
+            // pending bits are read-only
+            PENDING => {}
+            _ => {
+                if let Some(context) = Plic::context(offset, ENABLE, 0x80) {
+                    self.enables[context] = value & !1;
+                } else if let Some(context) = Plic::context(offset, THRESHOLD, 0x1000) {
+                    self.thresholds[context] = value & PLIC_MAX_PRIORITY;
+                } else {
+                    let context = Plic::context(offset, CLAIM, 0x1000)?;
+                    if value < PLIC_SOURCES && self.enables[context] >> value & 1 == 1 {
+                        self.claimed &= !(1 << value);
+                    }
+                }
+            }
+        }
+        Some(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        super::*,
+        crate::{blocks::Engine, test_utils, MEMORY_START},
+    };
+
+    #[test]
+    fn claim_and_complete() {
+        let mut plic = Plic::default();
+        for (source, priority) in [(1, 1), (2, 3), (3, 3), (4, 0)] {
+            assert_eq!(plic.store(PRIORITY + 4 * source, priority), Some(()));
+            plic.raise(source);
+        }
+        assert_eq!(plic.load(PENDING), Some(0b11110));
+        // nothing is enabled
+        assert_eq!(plic.interrupts(), 0);
+        assert_eq!(plic.load(CLAIM), Some(0));
+
+        assert_eq!(plic.store(ENABLE, u32::MAX), Some(()));
+        assert_eq!(plic.load(ENABLE), Some(u32::MAX - 1));
+        assert_eq!(plic.interrupts(), Interrupt::MachineExternal.mask());
+        // the highest priority wins, then the lowest ID
+        assert_eq!(plic.load(CLAIM), Some(2));
+        assert_eq!(plic.load(CLAIM), Some(3));
+        assert_eq!(plic.load(PENDING), Some(0b10010));
+        // only sources with a priority above the threshold interrupt
+        assert_eq!(plic.store(THRESHOLD, 1), Some(()));
+        assert_eq!(plic.interrupts(), 0);
+        assert_eq!(plic.load(CLAIM), Some(0));
+
+        // completing makes the source pending again while its line is raised
+        assert_eq!(plic.store(CLAIM, 2), Some(()));
+        plic.lower(3);
+        assert_eq!(plic.store(CLAIM, 3), Some(()));
+        assert_eq!(plic.load(PENDING), Some(0b10110));
+        assert_eq!(plic.interrupts(), Interrupt::MachineExternal.mask());
+
+        // the supervisor context has its own enables, completions of others are ignored
+        assert_eq!(plic.store(ENABLE + 0x80, 1 << 1), Some(()));
+        assert_eq!(plic.load(CLAIM + 0x1000), Some(1));
+        assert_eq!(plic.store(CLAIM + 0x1000, 2), Some(()));
+        assert_eq!(plic.load(PENDING), Some(0b10100));
+        assert_eq!(plic.store(CLAIM + 0x1000, 1), Some(()));
+        assert_eq!(
+            plic.interrupts(),
+            Interrupt::MachineExternal.mask() | Interrupt::SupervisorExternal.mask()
+        );
+
+        // source 0 doesn't exist, priorities and thresholds have 3 bits
+        assert_eq!(plic.store(PRIORITY, 1), Some(()));
+        assert_eq!(plic.load(PRIORITY), Some(0));
+        assert_eq!(plic.store(THRESHOLD + 0x1000, 0xf), Some(()));
+        assert_eq!(plic.load(THRESHOLD + 0x1000), Some(7));
+        assert_eq!(plic.load(PRIORITY + 4 * PLIC_SOURCES), None);
+        assert_eq!(plic.load(ENABLE + 0x100), None);
+        assert_eq!(plic.load(CLAIM + 0x2000), None);
+    }
+
+    // Enables source 1 for machine mode and waits for it. The handler claims it, masks it with
+    // the threshold and completes it.
+    const EXTERNAL: &[u32] = &[
+        0x00000297, // la t0, handler
+        0x04028293, //
+        0x30529073, // csrw mtvec, t0
+        0x0c0002b7, // li t0, 0x0c000004
+        0x00428293, //
+        0x00200313, // li t1, 2
+        0x0062a023, // sw t1, 0(t0)
+        0x0c0022b7, // li t0, 0x0c002000
+        0x0062a023, // sw t1, 0(t0)
+        0x000012b7, // li t0, 0x800
+        0x80028293, //
+        0x3042a073, // csrs mie, t0
+        0x30046073, // csrsi mstatus, 8
+        0x10500073, // wfi
+        0x00100513, // li a0, 1
+        0xc0001073, // unimp
+        // handler
+        0x34202673, // csrr a2, mcause
+        0x0c2002b7, // li t0, 0x0c200004
+        0x00428293, //
+        0x0002a583, // lw a1, 0(t0)
+        0x00700313, // li t1, 7
+        0xfe62ae23, // sw t1, -4(t0)
+        0x00b2a023, // sw a1, 0(t0)
+        0x30200073, // mret
+    ];
+
+    #[test]
+    fn external_interrupt() {
+        for engine in [Engine::Interpreter, Engine::Blocks] {
+            let mut machine = test_utils::machine(EXTERNAL);
+            machine.engine = engine;
+            let start = MEMORY_START as u32;
+            machine.devices.plic.raise(1);
+
+            assert_eq!(machine.run(100), Ok(true));
+            assert_eq!(
+                machine.registers[10..13],
+                [1, 1, Interrupt::MachineExternal.cause()]
+            );
+            // taken as soon as mstatus.MIE is set, WFI then ends immediately
+            assert_eq!(machine.csrs.mepc, start + 0x34);
+            let plic = &machine.devices.plic;
+            assert_eq!((plic.claimed, plic.thresholds[0]), (0, 7));
+            assert_eq!(plic.pending(), 1 << 1);
+        }
+    }
+}
@@ -0,0 +1,299 @@
+//! Physical memory protection: the 16 entries of `pmpcfg0`–`pmpcfg3` and `pmpaddr0`–`pmpaddr15`,
+//! which grant fetches, loads and stores to ranges of physical addresses.
+//!
+//! The entry with the lowest number which matches any byte of an access decides it, and fails it
+//! unless it matches all of its bytes. Supervisor and user mode may only access what a matching
+//! entry permits, machine mode may access anything but what locked entries deny. Locked entries
+//! can't be changed until reset. The page-table walks of the [`mmu`](crate::mmu) are checked like
+//! loads and stores of supervisor mode.
+
+use crate::{
+    csr::{Csrs, Exception, Privilege},
+    mmu::access_fault,
+    Access,
+};
+
+/// The number of entries.
+pub const PMP_ENTRIES: usize = 16;
+
+// pmpcfg fields of an entry
+pub const PMP_R: u8 = 1 << 0;
+pub const PMP_W: u8 = 1 << 1;
+pub const PMP_X: u8 = 1 << 2;
+pub const PMP_A: u8 = 0b11 << 3;
+pub const PMP_L: u8 = 1 << 7;
+
+// address-matching modes of PMP_A
+/// Disabled.
+pub const PMP_OFF: u8 = 0;
+/// Top of range, from the address of the previous entry, or 0 for the first, up to the entry's.
+pub const PMP_TOR: u8 = 1 << 3;
+/// The naturally aligned 4 bytes at the entry's address.
+pub const PMP_NA4: u8 = 2 << 3;
+/// A naturally aligned power of two of at least 8 bytes, whose size is encoded by the trailing ones
+/// of `pmpaddr`.
+pub const PMP_NAPOT: u8 = 3 << 3;
+
+/// The `pmpcfg` byte of `entry`.
+pub fn config(csrs: &Csrs, entry: usize) -> u8 {
+    (csrs.pmpcfg[entry / 4] >> (8 * (entry % 4))) as u8
+}
+
+/// The physical addresses `entry` matches, `None` if it is disabled. `pmpaddr` holds bits 33 to 2
+/// of an address, so ranges may extend beyond 4 GiB.
+pub fn range(csrs: &Csrs, entry: usize) -> Option<std::ops::Range<u64>> {
+    let address = (csrs.pmpaddr[entry] as u64) << 2;
+    match config(csrs, entry) & PMP_A {
+        PMP_TOR => {
+            let start = match entry {
+                0 => 0,
+                _ => (csrs.pmpaddr[entry - 1] as u64) << 2,
+            };
+            Some(start..address)
+        }
+        PMP_NA4 => Some(address..address + 4),
+        PMP_NAPOT => {
+            let size = 8u64 << csrs.pmpaddr[entry].trailing_ones();
+            let start = address & !(size - 1);
+            Some(start..start + size)
+        }
+        _ => None,
+    }
+}
+
+/// Whether `entry` is locked, and so are its configuration and address.
+pub fn locked(csrs: &Csrs, entry: usize) -> bool {
+    config(csrs, entry) & PMP_L != 0
+}
+
+/// Whether any entry is locked, which restricts machine mode.
+#[inline(always)]
+pub(crate) fn any_locked(csrs: &Csrs) -> bool {
+    csrs.pmpcfg.iter().fold(0, |all, config| all | config) & 0x8080_8080 != 0
+}
+
+/// Whether accesses of the current mode are checked, i.e., the mode is below machine mode, loads
+/// and stores use the permissions of `mstatus.MPP` or an entry is locked.
+pub fn enforced(csrs: &Csrs) -> bool {
+    csrs.access_privilege(Access::Load) != Privilege::Machine || any_locked(csrs)
+}
+
+/// Checks an access of `size` bytes at the physical `address` with the permissions of
+/// `privilege` mode, and returns the access fault it raises.
+#[inline(always)]
+pub fn check(
+    csrs: &Csrs,
+    privilege: Privilege,
+    address: u32,
+    size: u32,
+    access: Access,
+) -> Result<(), Exception> {
+    if privilege == Privilege::Machine && !any_locked(csrs)
+        || permitted(csrs, privilege, address, size, access)
+    {
+        return Ok(());
+    }
+    Err(access_fault(access))
+}
+
+#[inline(never)]
+fn permitted(csrs: &Csrs, privilege: Privilege, address: u32, size: u32, access: Access) -> bool {
+    let start = address as u64;
+    let end = start + size as u64;
+    for entry in 0..PMP_ENTRIES {
+        let Some(range) = range(csrs, entry).filter(|range| !range.is_empty()) else {
+            continue;
+        };
+        if end <= range.start || range.end <= start {
+            continue;
+        }
+        if start < range.start || range.end < end {
+            return false;
+        }
+        let config = config(csrs, entry);
+        if privilege == Privilege::Machine && config & PMP_L == 0 {
+            return true;
+        }
+        let permission = match access {
+            Access::Fetch => PMP_X,
+            Access::Load => PMP_R,
+            Access::Store => PMP_W,
+        };
+        return config & permission != 0;
+    }
+    // supervisor and user mode fail if no entry matches
+    privilege == Privilege::Machine
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        csr::*,
+        pmp::*,
+        store_word,
+        test_utils::{self, UNIMP},
+        Access, Engine, MEMORY_START, PC,
+    };
+
+    const COUNTERS: Counters = Counters {
+        cycle: 0,
+        instret: 0,
+        time: 0,
+    };
+
+    fn entry(csrs: &mut Csrs, entry: usize, config: u8, address: u32) {
+        csrs.pmpaddr[entry] = address;
+        csrs.pmpcfg[entry / 4] |= (config as u32) << (8 * (entry % 4));
+    }
+
+    #[test]
+    fn address_matching() {
+        let mut csrs = Csrs::default();
+        entry(&mut csrs, 0, PMP_TOR, 0x2000_0000);
+        entry(&mut csrs, 1, PMP_NA4, 0x2000_0400);
+        entry(&mut csrs, 2, PMP_NAPOT, 0x2000_01ff);
+        entry(&mut csrs, 3, PMP_TOR, 0x2000_0000);
+        entry(&mut csrs, 4, PMP_NAPOT, u32::MAX);
+        entry(&mut csrs, 5, PMP_OFF, 0x2000_0000);
+        assert_eq!(range(&csrs, 0), Some(0..0x8000_0000));
+        assert_eq!(range(&csrs, 1), Some(0x8000_1000..0x8000_1004));
+        assert_eq!(range(&csrs, 2), Some(0x8000_0000..0x8000_1000));
+        // a TOR entry below the address of its previous entry matches nothing
+        assert!(range(&csrs, 3).unwrap().is_empty());
+        assert_eq!(range(&csrs, 4), Some(0..1 << 35));
+        assert_eq!(range(&csrs, 5), None);
+    }
+
+    #[test]
+    fn priority() {
+        let mut csrs = Csrs {
+            privilege: Privilege::Supervisor,
+            ..Csrs::default()
+        };
+        // a read-only word in front of an executable page, and a writable page
+        entry(&mut csrs, 0, PMP_NA4 | PMP_R, 0x2000_0000);
+        entry(&mut csrs, 1, PMP_NAPOT | PMP_R | PMP_X, 0x2000_01ff);
+        entry(&mut csrs, 2, PMP_TOR | PMP_R | PMP_W, 0x2000_0800);
+        let check = |csrs: &Csrs, address, size, access| {
+            check(csrs, csrs.access_privilege(access), address, size, access)
+        };
+        assert_eq!(check(&csrs, 0x8000_0000, 4, Access::Load), Ok(()));
+        assert_eq!(
+            check(&csrs, 0x8000_0000, 4, Access::Fetch),
+            Err(Exception::InstructionAccessFault)
+        );
+        assert_eq!(check(&csrs, 0x8000_0004, 4, Access::Fetch), Ok(()));
+        // the page of entry 1 covers the start of entry 2's range
+        assert_eq!(
+            check(&csrs, 0x8000_0ffc, 4, Access::Store),
+            Err(Exception::StoreAccessFault)
+        );
+        assert_eq!(check(&csrs, 0x8000_1000, 4, Access::Store), Ok(()));
+        // accesses only partially matching an entry fail
+        assert_eq!(
+            check(&csrs, 0x8000_0002, 4, Access::Load),
+            Err(Exception::LoadAccessFault)
+        );
+        assert_eq!(
+            check(&csrs, 0x8000_1ffe, 4, Access::Load),
+            Err(Exception::LoadAccessFault)
+        );
+        // nothing matches
+        csrs.privilege = Privilege::User;
+        assert_eq!(
+            check(&csrs, 0x1000_0000, 1, Access::Load),
+            Err(Exception::LoadAccessFault)
+        );
+        assert!(enforced(&csrs));
+
+        // machine mode ignores unlocked entries
+        csrs.privilege = Privilege::Machine;
+        assert!(!enforced(&csrs));
+        assert_eq!(check(&csrs, 0x8000_0000, 4, Access::Store), Ok(()));
+        // unless MPRV applies the permissions of MPP to loads and stores
+        csrs.mstatus = MSTATUS_MPRV;
+        assert!(enforced(&csrs));
+        assert_eq!(
+            check(&csrs, 0x8000_0000, 4, Access::Store),
+            Err(Exception::StoreAccessFault)
+        );
+        assert_eq!(check(&csrs, 0x8000_0000, 4, Access::Fetch), Ok(()));
+        csrs.mstatus = 0;
+        // locked entries apply to machine mode too, which may access what nothing matches
+        csrs.pmpcfg[0] |= PMP_L as u32;
+        assert!(enforced(&csrs));
+        assert_eq!(
+            check(&csrs, 0x8000_0000, 4, Access::Store),
+            Err(Exception::StoreAccessFault)
+        );
+        assert_eq!(check(&csrs, 0x8000_0004, 4, Access::Store), Ok(()));
+        assert_eq!(check(&csrs, 0x1000_0000, 4, Access::Store), Ok(()));
+    }
+
+    #[test]
+    fn csr_writes() {
+        let mut csrs = Csrs::default();
+        // reserved bits and W without R are cleared
+        let configs = [
+            0,
+            PMP_NA4 | PMP_W | PMP_X | 0x60,
+            PMP_NAPOT | PMP_R | PMP_W,
+            0,
+        ];
+        assert!(csrs.write(PMPCFG0 + 3, u32::from_le_bytes(configs), COUNTERS));
+        assert_eq!(csrs.read(PMPCFG0 + 3, COUNTERS), Some(0x1b_14_00));
+        assert_eq!(config(&csrs, 13), PMP_NA4 | PMP_X);
+        assert!(csrs.write(PMPADDR0 + 15, 0x2000_0000, COUNTERS));
+        assert_eq!(csrs.pmpaddr[15], 0x2000_0000);
+        // the higher entries are hardwired to zero
+        assert!(csrs.write(PMPADDR0 + 16, 1, COUNTERS));
+        assert_eq!(csrs.read(PMPADDR0 + 16, COUNTERS), Some(0));
+
+        // locking a TOR entry also locks the address below it
+        let locked = (PMP_TOR | PMP_L | PMP_R) as u32;
+        assert!(csrs.write(PMPCFG0, locked << 8 | PMP_R as u32, COUNTERS));
+        assert!(csrs.write(PMPADDR0 + 1, 0x2000_1000, COUNTERS));
+        assert!(csrs.write(PMPADDR0, 0x2000_0400, COUNTERS));
+        assert_eq!(csrs.pmpaddr[..2], [0, 0]);
+        // and the lock can't be cleared, while the other entries stay writable
+        assert!(csrs.write(PMPCFG0, (PMP_NA4 as u32) << 16, COUNTERS));
+        assert_eq!(csrs.pmpcfg[0], (PMP_NA4 as u32) << 16 | locked << 8);
+        assert!(csrs.write(PMPADDR0 + 2, 0x2000_0400, COUNTERS));
+        assert_eq!(csrs.pmpaddr[2], 0x2000_0400);
+
+        // only machine mode may access them
+        csrs.privilege = Privilege::Supervisor;
+        assert_eq!(csrs.read(PMPCFG0, COUNTERS), None);
+        assert!(!csrs.write(PMPADDR0, 0, COUNTERS));
+    }
+
+    // Loads a word from a read-only region and stores it back, which faults
+    const USER: &[u32] = &[
+        0x00052583, // lw a1, 0(a0)
+        0x00b52223, // sw a1, 4(a0)
+    ];
+
+    #[test]
+    fn user_program() {
+        for engine in [Engine::Interpreter, Engine::Blocks] {
+            let mut machine = test_utils::machine(USER);
+            machine.engine = engine;
+            let start = MEMORY_START as u32;
+            store_word(&mut machine.memory, start + 0x100, UNIMP).unwrap();
+            store_word(&mut machine.memory, start + 0x1000, 0x1234_5678).unwrap();
+            let csrs = &mut machine.csrs;
+            csrs.privilege = Privilege::User;
+            csrs.mtvec = start + 0x100;
+            entry(csrs, 0, PMP_NAPOT | PMP_X, (start >> 2) | 0x1ff);
+            entry(csrs, 1, PMP_NAPOT | PMP_R, ((start + 0x1000) >> 2) | 0x1ff);
+            machine.registers[10] = start + 0x1000;
+
+            assert_eq!(machine.run(10), Ok(true));
+            assert_eq!(machine.registers[11], 0x1234_5678);
+            assert_eq!(machine.csrs.mcause, Exception::StoreAccessFault.cause());
+            assert_eq!(machine.csrs.mtval, start + 0x1004);
+            assert_eq!(machine.csrs.mepc, start + 4);
+            assert_eq!(machine.registers[PC], start + 0x104);
+        }
+    }
+}
@@ -0,0 +1,377 @@
+//! Models of branch predictors, which observe the branches and jumps retired by the machine and
+//! count how often the instruction fetched after them would have been the wrong one.
+//!
+//! Predictions are made in the fetch stage, which knows the pc and whether the instruction is a
+//! branch, a call, a return or another jump, but not its target: the direction predictor decides
+//! whether a conditional branch is taken, the branch target buffer (BTB) supplies the target of
+//! taken branches and jumps, and the return address stack (RAS) that of returns. A taken
+//! prediction without a BTB entry falls through to `pc + 4`.
+
+use crate::{Instruction, Retired};
+
+/// How the direction of conditional branches is predicted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Scheme {
+    /// Never taken.
+    NotTaken,
+    /// Backward branches (loops) are taken, forward branches aren't.
+    Btfn,
+    /// A table of 2-bit saturating counters indexed by the pc.
+    Bimodal { entries: usize },
+    /// A table of 2-bit saturating counters indexed by the pc xor the directions of the last
+    /// `history_bits` branches.
+    Gshare { entries: usize, history_bits: usize },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PredictorConfig {
+    pub scheme: Scheme,
+    /// The number of entries of the direct-mapped BTB.
+    pub btb_entries: usize,
+    /// The number of return addresses kept, the oldest is dropped on overflow. 0 disables the
+    /// RAS, returns are predicted by the BTB.
+    pub ras_depth: usize,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ConfigError {
+    /// The number of counters or BTB entries is not a power of two.
+    NotPowerOfTwo,
+    /// The global history is longer than 32 bits.
+    HistoryTooLong,
+}
+
+impl std::fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            ConfigError::NotPowerOfTwo => f.write_str(
+                "The number of counters and BTB entries of a predictor must be powers of two",
+            ),
+            ConfigError::HistoryTooLong => f.write_str("The global history is at most 32 bits"),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Stats {
+    /// Conditional branches.
+    pub branches: u64,
+    pub mispredicted_branches: u64,
+    /// JAL and JALR.
+    pub jumps: u64,
+    pub mispredicted_jumps: u64,
+}
+
+impl Stats {
+    pub fn predictions(&self) -> u64 {
+        self.branches + self.jumps
+    }
+
+    pub fn mispredictions(&self) -> u64 {
+        self.mispredicted_branches + self.mispredicted_jumps
+    }
+
+    /// The share of correct predictions, 0 if there were none.
+    pub fn accuracy(&self) -> f64 {
+        match self.predictions() {
+            0 => 0.0,
+            predictions => 1.0 - self.mispredictions() as f64 / predictions as f64,
+        }
+    }
+}
+
+/// The prediction for a single branch or jump, e.g., for rendering it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Prediction {
+    pub pc: u32,
+    /// The pc the fetch stage continued with.
+    pub predicted: u32,
+    /// The pc the instruction actually continued with.
+    pub actual: u32,
+}
+
+impl Prediction {
+    pub fn mispredicted(&self) -> bool {
+        self.predicted != self.actual
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Predictor {
+    config: PredictorConfig,
+    /// 2-bit saturating counters, taken if at least 2.
+    counters: Vec<u8>,
+    /// The directions of the last branches, the latest in the lowest bit.
+    history: u32,
+    /// The pc and target of the last taken branch or jump mapping to each entry.
+    btb: Vec<Option<(u32, u32)>>,
+    ras: Vec<u32>,
+    pub stats: Stats,
+    /// The most recent prediction.
+    pub last_prediction: Option<Prediction>,
+}
+
+impl Predictor {
+    pub fn new(config: PredictorConfig) -> Result<Predictor, ConfigError> {
+        let counters = match config.scheme {
+            Scheme::NotTaken | Scheme::Btfn => 0,
+            Scheme::Bimodal { entries } => entries,
+            Scheme::Gshare {
+                entries,
+                history_bits,
+            } => {
+                if history_bits > 32 {
+                    return Err(ConfigError::HistoryTooLong);
+                }
+                entries
+            }
+        };
+        let static_scheme = matches!(config.scheme, Scheme::NotTaken | Scheme::Btfn);
+        if !(static_scheme || counters.is_power_of_two()) || !config.btb_entries.is_power_of_two() {
+            return Err(ConfigError::NotPowerOfTwo);
+        }
+        Ok(Predictor {
+            config,
+            // weakly not taken
+            counters: vec![1; counters],
+            history: 0,
+            btb: vec![None; config.btb_entries],
+            ras: Vec::with_capacity(config.ras_depth),
+            stats: Stats::default(),
+            last_prediction: None,
+        })
+    }
+
+    pub fn config(&self) -> &PredictorConfig {
+        &self.config
+    }
+
+    /// The return addresses on the RAS, the next one to be used last.
+    pub fn ras(&self) -> &[u32] {
+        &self.ras
+    }
+
+    fn counter(&self, pc: u32) -> Option<usize> {
+        let index = match self.config.scheme {
+            Scheme::NotTaken | Scheme::Btfn => return None,
+            Scheme::Bimodal { .. } => pc >> 2,
+            Scheme::Gshare { history_bits, .. } => {
+                (pc >> 2) ^ (self.history & ((1u64 << history_bits) - 1) as u32)
+            }
+        };
+        Some(index as usize & (self.counters.len() - 1))
+    }
+
+    fn btb_entry(&self, pc: u32) -> usize {
+        (pc >> 2) as usize & (self.btb.len() - 1)
+    }
+
+    /// Predicts the pc following a retired branch or jump and trains the predictor with its
+    /// actual outcome. Returns whether it was mispredicted, `None` for other instructions.
+    pub fn retire(&mut self, retired: &Retired) -> Option<bool> {
+        let pc = retired.pc;
+        let fall_through = pc.wrapping_add(4);
+        let taken = retired.next_pc != fall_through;
+        let btb_entry = self.btb_entry(pc);
+        let btb_target = match self.btb[btb_entry] {
+            Some((tag, target)) if tag == pc => Some(target),
+            _ => None,
+        };
+        // x1 and x5 are link registers
+        let link = |register: u32| register == 1 || register == 5;
+        let predicted = match retired.instruction {
+            Instruction::BEQ(b_type)
+            | Instruction::BNE(b_type)
+            | Instruction::BLT(b_type)
+            | Instruction::BGE(b_type)
+            | Instruction::BLTU(b_type)
+            | Instruction::BGEU(b_type) => {
+                let counter = self.counter(pc);
+                let predict_taken = match self.config.scheme {
+                    Scheme::NotTaken => false,
+                    // the offset is negative
+                    Scheme::Btfn => (b_type.imm() as i32) < 0,
+                    _ => self.counters[counter.unwrap()] >= 2,
+                };
+                if let Some(counter) = counter {
+                    let counter = &mut self.counters[counter];
+                    *counter = if taken {
+                        (*counter + 1).min(3)
+                    } else {
+                        counter.saturating_sub(1)
+                    };
+                }
+                self.history = self.history << 1 | taken as u32;
+                self.stats.branches += 1;
+                match btb_target {
+                    Some(target) if predict_taken => target,
+                    _ => fall_through,
+                }
+            }
+            Instruction::JAL(j_type) => {
+                if link(j_type.rd()) {
+                    self.push(fall_through);
+                }
+                self.stats.jumps += 1;
+                btb_target.unwrap_or(fall_through)
+            }
+            Instruction::JALR(i_type) => {
+                let returns = i_type.rd() == 0 && link(i_type.rs1()) && self.config.ras_depth > 0;
+                let predicted = match returns {
+                    true => self.ras.pop(),
+                    false => None,
+                };
+                if link(i_type.rd()) {
+                    self.push(fall_through);
+                }
+                self.stats.jumps += 1;
+                predicted.or(btb_target).unwrap_or(fall_through)
+            }
+            _ => return None,
+        };
+        if taken {
+            self.btb[btb_entry] = Some((pc, retired.next_pc));
+        }
+        let prediction = Prediction {
+            pc,
+            predicted,
+            actual: retired.next_pc,
+        };
+        let mispredicted = prediction.mispredicted();
+        if mispredicted {
+            match retired.instruction {
+                Instruction::JAL(_) | Instruction::JALR(_) => self.stats.mispredicted_jumps += 1,
+                _ => self.stats.mispredicted_branches += 1,
+            }
+        }
+        self.last_prediction = Some(prediction);
+        Some(mispredicted)
+    }
+
+    fn push(&mut self, address: u32) {
+        if self.config.ras_depth == 0 {
+            return;
+        }
+        if self.ras.len() == self.config.ras_depth {
+            self.ras.remove(0);
+        }
+        self.ras.push(address);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        predictor::{ConfigError, Predictor, PredictorConfig, Scheme},
+        test_utils::call,
+        timing::{Latencies, Timing},
+    };
+
+    // Counts down from 10 in a0
+    const COUNTDOWN: &[u32] = &[
+        0x00a00513, // li a0, 10
+        0xfff50513, // addi a0, a0, -1
+        0xfe051ee3, // bnez a0, -4
+    ];
+
+    // Counts down from 10 in a0, calling a leaf function in every iteration
+    const CALLS: &[u32] = &[
+        0x00a00513, // li a0, 10
+        0x010000ef, // jal ra, 16
+        0xfff50513, // addi a0, a0, -1
+        0xfe051ce3, // bnez a0, -8
+        0x0080006f, // j 8
+        0x00008067, // ret
+    ];
+
+    fn predictor(scheme: Scheme) -> Predictor {
+        Predictor::new(PredictorConfig {
+            scheme,
+            btb_entries: 16,
+            ras_depth: 4,
+        })
+        .unwrap()
+    }
+
+    /// Runs `program` to its end with `predictor` and returns the predictor.
+    fn run(program: &[u32], predictor: Predictor) -> Predictor {
+        let mut machine = call(program);
+        machine.predictor = Some(predictor);
+        assert!(machine.run(1000).unwrap());
+        machine.predictor.unwrap()
+    }
+
+    #[test]
+    fn direction() {
+        // the first taken branch misses the BTB, the last one isn't taken
+        for (scheme, mispredicted) in [
+            (Scheme::NotTaken, 9),
+            (Scheme::Btfn, 2),
+            (Scheme::Bimodal { entries: 16 }, 2),
+            // the counters of the first histories have to be trained separately
+            (
+                Scheme::Gshare {
+                    entries: 16,
+                    history_bits: 2,
+                },
+                4,
+            ),
+        ] {
+            let stats = run(COUNTDOWN, predictor(scheme)).stats;
+            assert_eq!(stats.branches, 10);
+            assert_eq!(stats.mispredicted_branches, mispredicted, "{scheme:?}");
+            assert_eq!(stats.jumps, 0);
+        }
+    }
+
+    #[test]
+    fn return_address_stack() {
+        let predictor = run(CALLS, predictor(Scheme::Btfn));
+        // the first call and the final j miss the BTB, all returns hit the RAS
+        assert_eq!(predictor.stats.jumps, 2 * 10 + 1);
+        assert_eq!(predictor.stats.mispredicted_jumps, 2);
+        assert!(predictor.ras().is_empty());
+        assert!(predictor.last_prediction.unwrap().mispredicted());
+
+        // without a RAS, the BTB predicts returns, which always have the same target here
+        let config = PredictorConfig {
+            ras_depth: 0,
+            ..*predictor.config()
+        };
+        let predictor = run(CALLS, Predictor::new(config).unwrap());
+        assert_eq!(predictor.stats.mispredicted_jumps, 3);
+    }
+
+    #[test]
+    fn timing() {
+        let mut machine = call(COUNTDOWN);
+        machine.timing = Timing::new(Latencies::default());
+        let mut predicted = machine.clone();
+        predicted.predictor = Some(predictor(Scheme::Btfn));
+        machine.run(1000).unwrap();
+        predicted.run(1000).unwrap();
+        // 9 taken branches, but only 2 mispredictions
+        assert_eq!(machine.cycle, machine.instret + 9 * 2);
+        assert_eq!(predicted.cycle, predicted.instret + 2 * 2);
+    }
+
+    #[test]
+    fn invalid_config() {
+        let config = PredictorConfig {
+            scheme: Scheme::Bimodal { entries: 12 },
+            btb_entries: 16,
+            ras_depth: 0,
+        };
+        assert_eq!(Predictor::new(config), Err(ConfigError::NotPowerOfTwo));
+        let config = PredictorConfig {
+            scheme: Scheme::Gshare {
+                entries: 16,
+                history_bits: 33,
+            },
+            ..config
+        };
+        assert_eq!(Predictor::new(config), Err(ConfigError::HistoryTooLong));
+    }
+}
@@ -0,0 +1,318 @@
+//! A profiler counting how often each instruction is executed, which attributes the executions
+//! to functions and call stacks with the symbols of the program.
+//!
+//! Calls and returns are detected like the RISC-V calling convention defines them: JAL and JALR
+//! with a link register (`ra` or `t0`) as destination call their target, JALR to the address in a
+//! link register without a destination returns. Tail calls replace the current frame.
+
+use {
+    crate::{Instruction, Retired},
+    std::collections::HashMap,
+};
+
+/// A function of the profiled program, e.g., from the symbol table of an ELF file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Symbol {
+    pub name: String,
+    pub address: u32,
+    /// The size in bytes, 0 if unknown, then the symbol extends to the next one.
+    pub size: u32,
+}
+
+/// A row of the flat profile.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FunctionProfile {
+    pub name: String,
+    /// Instructions executed in the function itself.
+    pub instructions: u64,
+    /// Instructions executed in the function and the functions it called.
+    pub total: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Frame {
+    /// The entry point of the function.
+    function: u32,
+    /// Where the function returns to.
+    return_address: u32,
+}
+
+/// Collects the profile of the instructions retired by a [`Machine`](crate::Machine). Stepping back
+/// doesn't revert the counts.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Profiler {
+    /// Sorted by address.
+    symbols: Vec<Symbol>,
+    /// The instruction at each pc and how often it was executed.
+    instructions: HashMap<u32, (Instruction, u64)>,
+    /// The call stack, empty until the first instruction.
+    frames: Vec<Frame>,
+    /// The entry points of the functions on the call stack.
+    stack: Vec<u32>,
+    /// Executed instructions per call stack.
+    stacks: HashMap<Vec<u32>, u64>,
+}
+
+impl Profiler {
+    pub fn new(mut symbols: Vec<Symbol>) -> Profiler {
+        symbols.sort_by_key(|symbol| symbol.address);
+        Profiler {
+            symbols,
+            ..Profiler::default()
+        }
+    }
+
+    /// Counts an executed instruction and follows calls and returns.
+    pub fn retire(&mut self, retired: &Retired) {
+        if self.frames.is_empty() {
+            self.push(retired.pc, 0);
+        }
+        self.instructions
+            .entry(retired.pc)
+            .or_insert((retired.instruction, 0))
+            .1 += 1;
+        match self.stacks.get_mut(self.stack.as_slice()) {
+            Some(count) => *count += 1,
+            None => {
+                self.stacks.insert(self.stack.clone(), 1);
+            }
+        }
+
+        // x1 and x5 are link registers
+        let link = |register: u32| register == 1 || register == 5;
+        let return_address = retired.pc.wrapping_add(4);
+        match retired.instruction {
+            Instruction::JAL(j_type) if link(j_type.rd()) => {
+                self.push(retired.next_pc, return_address)
+            }
+            Instruction::JALR(i_type) if link(i_type.rd()) => {
+                self.push(retired.next_pc, return_address)
+            }
+            Instruction::JALR(i_type) if i_type.rd() == 0 && link(i_type.rs1()) => {
+                // unwinds several frames at once if functions returned without a JALR
+                if let Some(depth) = self
+                    .frames
+                    .iter()
+                    .rposition(|frame| frame.return_address == retired.next_pc)
+                {
+                    self.frames.truncate(depth.max(1));
+                    self.stack.truncate(depth.max(1));
+                }
+            }
+            Instruction::JAL(_) | Instruction::JALR(_)
+                if self.function(retired.next_pc) != self.function(retired.pc) =>
+            {
+                // a tail call
+                let function = self.function_start(retired.next_pc);
+                self.frames.last_mut().unwrap().function = function;
+                *self.stack.last_mut().unwrap() = function;
+            }
+            _ => {}
+        }
+    }
+
+    fn push(&mut self, address: u32, return_address: u32) {
+        let function = self.function_start(address);
+        self.frames.push(Frame {
+            function,
+            return_address,
+        });
+        self.stack.push(function);
+    }
+
+    /// Forgets the call stack, e.g., after restoring a snapshot. The counts are kept.
+    pub fn unwind(&mut self) {
+        self.frames.clear();
+        self.stack.clear();
+    }
+
+    /// The symbol containing `address`.
+    fn function(&self, address: u32) -> Option<&Symbol> {
+        let index = self
+            .symbols
+            .partition_point(|symbol| symbol.address <= address)
+            .checked_sub(1)?;
+        let symbol = &self.symbols[index];
+        (symbol.size == 0 || address - symbol.address < symbol.size).then_some(symbol)
+    }
+
+    /// The entry point of the function containing `address`, `address` itself without a symbol.
+    fn function_start(&self, address: u32) -> u32 {
+        self.function(address)
+            .map_or(address, |symbol| symbol.address)
+    }
+
+    fn function_name(&self, address: u32) -> String {
+        match self.function(address) {
+            Some(symbol) => symbol.name.clone(),
+            None => format!("0x{address:08x}"),
+        }
+    }
+
+    /// The number of executed instructions.
+    pub fn instret(&self) -> u64 {
+        self.stacks.values().sum()
+    }
+
+    /// How often the instruction at each pc was executed, the most frequent first.
+    pub fn hotspots(&self) -> Vec<(u32, Instruction, u64)> {
+        let mut hotspots: Vec<_> = self
+            .instructions
+            .iter()
+            .map(|(&pc, &(instruction, count))| (pc, instruction, count))
+            .collect();
+        hotspots.sort_by_key(|&(pc, _, count)| (std::cmp::Reverse(count), pc));
+        hotspots
+    }
+
+    /// How often each kind of instruction was executed, the most frequent first.
+    pub fn instruction_mix(&self) -> Vec<(&'static str, u64)> {
+        let mut mix = HashMap::new();
+        for (instruction, count) in self.instructions.values() {
+            *mix.entry(instruction.name()).or_default() += count;
+        }
+        let mut mix: Vec<_> = mix.into_iter().collect();
+        mix.sort_by_key(|&(name, count)| (std::cmp::Reverse(count), name));
+        mix
+    }
+
+    /// The instructions executed per function, the most expensive first.
+    pub fn flat(&self) -> Vec<FunctionProfile> {
+        let mut functions: HashMap<String, FunctionProfile> = HashMap::new();
+        for (stack, &count) in &self.stacks {
+            let mut names: Vec<_> = stack
+                .iter()
+                .map(|&function| self.function_name(function))
+                .collect();
+            let leaf = names.pop().unwrap();
+            // recursive functions count once
+            names.sort();
+            names.dedup();
+            names.retain(|name| *name != leaf);
+            for name in names.into_iter().chain([leaf.clone()]) {
+                let function = functions
+                    .entry(name.clone())
+                    .or_insert_with(|| FunctionProfile {
+                        name,
+                        instructions: 0,
+                        total: 0,
+                    });
+                function.total += count;
+            }
+            functions.get_mut(&leaf).unwrap().instructions += count;
+        }
+        let mut functions: Vec<_> = functions.into_values().collect();
+        functions.sort_by(|a, b| {
+            (b.instructions, b.total)
+                .cmp(&(a.instructions, a.total))
+                .then_with(|| a.name.cmp(&b.name))
+        });
+        functions
+    }
+
+    /// The executed instructions per call stack in the folded format of flamegraph tools: a line
+    /// per stack with the functions from the outermost one separated by `;`, a space and the
+    /// count.
+    pub fn folded(&self) -> String {
+        let mut lines: Vec<_> = self
+            .stacks
+            .iter()
+            .map(|(stack, count)| {
+                let names: Vec<_> = stack
+                    .iter()
+                    .map(|&function| self.function_name(function))
+                    .collect();
+                format!("{} {count}\n", names.join(";"))
+            })
+            .collect();
+        lines.sort();
+        lines.concat()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        profile::{FunctionProfile, Profiler, Symbol},
+        test_utils, MEMORY_START,
+    };
+
+    // main calls square twice, which calls mul with a loop
+    const PROGRAM: &[u32] = &[
+        // main
+        0x00300513, // li a0, 3
+        0x010000ef, // jal ra, square
+        0x00400513, // li a0, 4
+        0x008000ef, // jal ra, square
+        0xc0001073, // unimp
+        // square
+        0x00008293, // mv t0, ra
+        0x00050593, // mv a1, a0
+        0x008000ef, // jal ra, mul
+        0x00028067, // jr t0
+        // mul
+        0x00000613, // li a2, 0
+        0x00a60633, // add a2, a2, a0
+        0xfff58593, // addi a1, a1, -1
+        0xfe059ce3, // bnez a1, -8
+        0x00008067, // ret
+    ];
+
+    fn symbol(name: &str, offset: u32) -> Symbol {
+        Symbol {
+            name: name.into(),
+            address: MEMORY_START as u32 + offset,
+            size: 0,
+        }
+    }
+
+    fn profile() -> Profiler {
+        let mut machine = test_utils::machine(PROGRAM);
+        machine.profiler = Some(Profiler::new(vec![
+            symbol("square", 0x14),
+            symbol("main", 0x00),
+            symbol("mul", 0x24),
+        ]));
+        assert!(machine.run(1000).unwrap());
+        machine.profiler.unwrap()
+    }
+
+    #[test]
+    fn flat() {
+        let profile = profile();
+        // mul runs 2 + 3 * 3 and 2 + 4 * 3 instructions
+        assert_eq!(profile.instret(), 5 + 2 * 4 + 11 + 14);
+        let row = |name: &str, instructions, total| FunctionProfile {
+            name: name.into(),
+            instructions,
+            total,
+        };
+        assert_eq!(
+            profile.flat(),
+            [row("mul", 25, 25), row("square", 8, 33), row("main", 5, 38),]
+        );
+    }
+
+    #[test]
+    fn instructions() {
+        let profile = profile();
+        let (pc, instruction, count) = profile.hotspots()[0];
+        assert_eq!(
+            (pc, instruction.name(), count),
+            (MEMORY_START as u32 + 0x28, "ADD", 7)
+        );
+        // li and mv are ADDIs
+        assert_eq!(
+            profile.instruction_mix()[..2],
+            [("ADDI", 8 + 7), ("ADD", 7)]
+        );
+    }
+
+    #[test]
+    fn folded() {
+        assert_eq!(
+            profile().folded(),
+            "main 5\nmain;square 8\nmain;square;mul 25\n"
+        );
+    }
+}
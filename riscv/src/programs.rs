@@ -0,0 +1,32 @@
+//! Programs shared by the tests and the benchmarks.
+
+/// Bubble sort of four words on the stack (from the web app's examples), which returns to `ra`.
+pub const BUBBLE_SORT: &[u32] = &[
+    0xff010113, 0x00500793, 0x00f12023, 0x00100793, 0x00f12223, 0x00200793, 0x00f12423, 0x00400793,
+    0x00f12623, 0x00010513, 0x00c10613, 0x00000893, 0x00100813, 0x0340006f, 0x00478793, 0x02c78063,
+    0x0007a703, 0x0047a683, 0xfee6d8e3, 0x00d7a023, 0x00e7a223, 0x00080593, 0xfe1ff06f, 0x00058c63,
+    0xffc60613, 0x00a60863, 0x00050793, 0x00088593, 0xfd1ff06f, 0x01010113, 0x00008067,
+];
+
+/// An optimized Fibonacci (from the web app's examples), which leaves the 10th number in `a0` and
+/// returns to `ra`.
+pub const FIBONACCI: &[u32] = &[
+    0x00900793, 0x00100513, 0x00000713, 0x00050693, 0x00e50533, 0xfff78793, 0x00068713, 0xfe0798e3,
+    0x00008067,
+];
+
+/// A loop of 1000 iterations with a store and a load to the stack, which is on a different page
+/// than the code, ending with an `unimp`.
+pub const LOOP: &[u32] = &[
+    0x00000513, // li a0, 0
+    0x3e800593, // li a1, 1000
+    0x80010137, // lui sp, 0x80010
+    0xfeb12e23, // sw a1, -4(sp)
+    0xffc12603, // lw a2, -4(sp)
+    0x00c50533, // add a0, a0, a2
+    0x00b546b3, // xor a3, a0, a1
+    0x00169693, // slli a3, a3, 1
+    0xfff58593, // addi a1, a1, -1
+    0xfe0594e3, // bnez a1, -24
+    0xc0001073, // unimp
+];
@@ -0,0 +1,473 @@
+//! Deterministic record and replay of externally sourced values.
+//!
+//! The devices derive almost everything from the machine state: `mtime` counts cycles, and
+//! interrupt lines follow the devices' registers. What the host delivers doesn't, so
+//! [`Machine::receive`](crate::Machine::receive) passes the bytes for the UART through the
+//! [`Replay`]. When recording, they reach the UART at the next instruction boundary and are logged
+//! together with the number of retired instructions. When replaying, the host's bytes are
+//! discarded and the logged ones delivered at the same points instead, so a run is bit-identical
+//! to the recorded one. Replaying has to start from the same state as the recording, e.g., the
+//! same [snapshot](crate::snapshot).
+//!
+//! Input is only delivered at the first instruction boundary with a given number of retired
+//! instructions, since a trap retires none, which would make the point ambiguous. Devices which
+//! read a value from the host, e.g., a real-time clock, go through [`Replay::input`] instead.
+//!
+//! A log serialized by [`Replay::log`] starts with the magic `RVRP` and a `u32` version, followed
+//! by events of 21 bytes each: `instret: u64`, `kind: u8`, `argument: u32` and `value: u64`, all
+//! little-endian.
+
+use std::collections::VecDeque;
+
+const MAGIC: [u8; 4] = *b"RVRP";
+pub const LOG_VERSION: u32 = 1;
+
+/// Where an externally sourced value comes from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Input {
+    /// A read of a memory-mapped register of an input device.
+    Mmio { address: u32 },
+    /// The level of an interrupt line. Lines are sampled at every instruction boundary, so only
+    /// changes of the level are logged.
+    Interrupt { line: u32 },
+    /// A read of the real-time counter.
+    Time,
+    /// A byte the host delivered to the receiver of the device at `address`, e.g., the UART.
+    Receive { address: u32 },
+}
+
+impl Input {
+    fn encode(&self) -> (u8, u32) {
+        match *self {
+            Input::Mmio { address } => (0, address),
+            Input::Interrupt { line } => (1, line),
+            Input::Time => (2, 0),
+            Input::Receive { address } => (3, address),
+        }
+    }
+
+    fn decode(kind: u8, argument: u32) -> Option<Input> {
+        Some(match kind {
+            0 => Input::Mmio { address: argument },
+            1 => Input::Interrupt { line: argument },
+            2 => Input::Time,
+            3 => Input::Receive { address: argument },
+            _ => return None,
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Event {
+    /// The number of instructions retired before the value was read.
+    pub instret: u64,
+    pub input: Input,
+    pub value: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ReplayError {
+    /// The replayed run requested a different input than the recorded one at this point, so it
+    /// didn't start from the same state or something is nondeterministic.
+    Divergence {
+        instret: u64,
+        input: Input,
+        expected: Option<Event>,
+    },
+    BadMagic,
+    UnsupportedVersion(u32),
+    Truncated,
+    UnknownInput(u8),
+}
+
+impl std::fmt::Display for ReplayError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            ReplayError::Divergence {
+                instret,
+                input,
+                expected: Some(expected),
+            } => write!(
+                f,
+                "Replay diverged after {instret} instructions: read {input:?}, \
+                but recorded {:?} after {} instructions",
+                expected.input, expected.instret
+            ),
+            ReplayError::Divergence {
+                instret,
+                input,
+                expected: None,
+            } => write!(
+                f,
+                "Replay diverged after {instret} instructions: read {input:?} past the end of the log"
+            ),
+            ReplayError::BadMagic => write!(f, "Not a replay log"),
+            ReplayError::UnsupportedVersion(version) => write!(
+                f,
+                "Unsupported replay log version {version} (expected {LOG_VERSION})"
+            ),
+            ReplayError::Truncated => write!(f, "Replay log is truncated"),
+            ReplayError::UnknownInput(kind) => write!(f, "Unknown input kind {kind} in replay log"),
+        }
+    }
+}
+
+impl std::error::Error for ReplayError {}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Mode {
+    /// Values come straight from the devices.
+    #[default]
+    Off,
+    Record,
+    Replay,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Replay {
+    mode: Mode,
+    events: Vec<Event>,
+    /// The index of the next event to replay.
+    next: usize,
+    /// The current level of every interrupt line which has been logged, lines start out low.
+    levels: Vec<(Input, u64)>,
+    /// The bytes from the host which haven't been delivered yet while recording.
+    queue: VecDeque<u8>,
+    /// The number of retired instructions at the last boundary where input was delivered.
+    boundary: Option<u64>,
+}
+
+impl Replay {
+    pub fn record() -> Replay {
+        Replay {
+            mode: Mode::Record,
+            ..Default::default()
+        }
+    }
+
+    /// Replays a log serialized by [`Replay::log`].
+    pub fn from_log(log: &[u8]) -> Result<Replay, ReplayError> {
+        let (header, mut rest) = log.split_at_checked(8).ok_or(ReplayError::BadMagic)?;
+        if header[..4] != MAGIC {
+            return Err(ReplayError::BadMagic);
+        }
+        let version = u32::from_le_bytes(header[4..].try_into().unwrap());
+        if version != LOG_VERSION {
+            return Err(ReplayError::UnsupportedVersion(version));
+        }
+        let mut events = Vec::new();
+        while !rest.is_empty() {
+            let (event, tail) = rest.split_at_checked(21).ok_or(ReplayError::Truncated)?;
+            let kind = event[8];
+            events.push(Event {
+                instret: u64::from_le_bytes(event[..8].try_into().unwrap()),
+                input: Input::decode(kind, u32::from_le_bytes(event[9..13].try_into().unwrap()))
+                    .ok_or(ReplayError::UnknownInput(kind))?,
+                value: u64::from_le_bytes(event[13..].try_into().unwrap()),
+            });
+            rest = tail;
+        }
+        Ok(Replay {
+            mode: Mode::Replay,
+            events,
+            ..Default::default()
+        })
+    }
+
+    pub fn mode(&self) -> Mode {
+        self.mode
+    }
+
+    /// The recorded events, or the events being replayed.
+    pub fn events(&self) -> &[Event] {
+        &self.events
+    }
+
+    /// Serializes the recorded (or replayed) events, see the [module documentation](self).
+    pub fn log(&self) -> Vec<u8> {
+        let mut output = Vec::with_capacity(8 + 21 * self.events.len());
+        output.extend(MAGIC);
+        output.extend(LOG_VERSION.to_le_bytes());
+        for event in &self.events {
+            let (kind, argument) = event.input.encode();
+            output.extend(event.instret.to_le_bytes());
+            output.push(kind);
+            output.extend(argument.to_le_bytes());
+            output.extend(event.value.to_le_bytes());
+        }
+        output
+    }
+
+    /// Returns `true` once all recorded events have been replayed.
+    pub fn finished(&self) -> bool {
+        self.mode == Mode::Replay && self.next == self.events.len()
+    }
+
+    /// Returns the value of `input`, which `live` reads from the device unless replaying.
+    pub fn input(
+        &mut self,
+        instret: u64,
+        input: Input,
+        live: impl FnOnce() -> u64,
+    ) -> Result<u64, ReplayError> {
+        let event = match self.mode {
+            Mode::Off => return Ok(live()),
+            Mode::Record => {
+                let value = live();
+                if matches!(input, Input::Interrupt { .. }) && self.level(input) == value {
+                    return Ok(value);
+                }
+                let event = Event {
+                    instret,
+                    input,
+                    value,
+                };
+                self.events.push(event);
+                event
+            }
+            Mode::Replay => match self.events.get(self.next) {
+                Some(&event) if event.instret == instret && event.input == input => {
+                    self.next += 1;
+                    event
+                }
+                _ if matches!(input, Input::Interrupt { .. }) => return Ok(self.level(input)),
+                expected => {
+                    return Err(ReplayError::Divergence {
+                        instret,
+                        input,
+                        expected: expected.copied(),
+                    })
+                }
+            },
+        };
+        if matches!(input, Input::Interrupt { .. }) {
+            match self.levels.iter_mut().find(|(line, _)| *line == input) {
+                Some((_, level)) => *level = event.value,
+                None => self.levels.push((input, event.value)),
+            }
+        }
+        Ok(event.value)
+    }
+
+    /// Queues `input` from the host while recording, it's discarded while replaying.
+    pub fn queue(&mut self, input: &[u8]) {
+        if self.mode == Mode::Record {
+            self.queue.extend(input);
+        }
+    }
+
+    /// Delivers the host's input to the receiver at `address` with `deliver`, which returns
+    /// whether the byte fit, at the instruction boundary after `instret` retired instructions.
+    pub fn receive(&mut self, instret: u64, address: u32, mut deliver: impl FnMut(u8) -> bool) {
+        if self.boundary == Some(instret) {
+            return;
+        }
+        self.boundary = Some(instret);
+        let input = Input::Receive { address };
+        match self.mode {
+            Mode::Off => {}
+            Mode::Record => {
+                while let Some(&byte) = self.queue.front() {
+                    if !deliver(byte) {
+                        break;
+                    }
+                    self.queue.pop_front();
+                    self.events.push(Event {
+                        instret,
+                        input,
+                        value: byte.into(),
+                    });
+                }
+            }
+            Mode::Replay => {
+                while let Some(&event) = self.events.get(self.next) {
+                    if event.instret != instret || event.input != input {
+                        break;
+                    }
+                    deliver(event.value as u8);
+                    self.next += 1;
+                }
+            }
+        }
+    }
+
+    /// The number of instructions which can retire before the next logged input is due while
+    /// replaying.
+    pub fn until_next(&self, instret: u64) -> Option<u64> {
+        let event = self.events.get(self.next)?;
+        (self.mode == Mode::Replay && event.instret > instret).then(|| event.instret - instret)
+    }
+
+    fn level(&self, input: Input) -> u64 {
+        self.levels
+            .iter()
+            .find(|(line, _)| *line == input)
+            .map_or(0, |&(_, level)| level)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        super::*,
+        crate::{test_utils, Engine},
+    };
+
+    const UART: Input = Input::Mmio {
+        address: 0x1000_0000,
+    };
+    const LINE: Input = Input::Interrupt { line: 10 };
+
+    /// Reads inputs like a device would, with `live` providing the values of this run.
+    fn run(replay: &mut Replay, live: u64) -> Result<Vec<u64>, ReplayError> {
+        let mut values = vec![];
+        for instret in 0..100 {
+            let level = if (20..40).contains(&instret) { 1 } else { 0 };
+            values.push(replay.input(instret, LINE, || level)?);
+            if instret % 10 == 0 {
+                values.push(replay.input(instret, UART, || live + instret)?);
+            }
+            if instret % 25 == 0 {
+                values.push(replay.input(instret, Input::Time, || live * instret)?);
+            }
+        }
+        Ok(values)
+    }
+
+    #[test]
+    fn record_and_replay() {
+        let mut recording = Replay::record();
+        let recorded = run(&mut recording, 7).unwrap();
+        // 10 UART reads, 4 time reads and 2 level changes
+        assert_eq!(recording.events().len(), 16);
+
+        let mut replay = Replay::from_log(&recording.log()).unwrap();
+        assert_eq!(run(&mut replay, 1000).unwrap(), recorded);
+        assert!(replay.finished());
+
+        let mut off = Replay::default();
+        assert_ne!(run(&mut off, 1000).unwrap(), recorded);
+    }
+
+    #[test]
+    fn divergence() {
+        let mut recording = Replay::record();
+        recording.input(5, UART, || 1).unwrap();
+        let mut replay = Replay::from_log(&recording.log()).unwrap();
+        assert_eq!(
+            replay.input(6, UART, || 1),
+            Err(ReplayError::Divergence {
+                instret: 6,
+                input: UART,
+                expected: Some(Event {
+                    instret: 5,
+                    input: UART,
+                    value: 1
+                }),
+            })
+        );
+        assert_eq!(replay.input(5, UART, || 2), Ok(1));
+        assert!(matches!(
+            replay.input(6, Input::Time, || 0),
+            Err(ReplayError::Divergence { expected: None, .. })
+        ));
+    }
+
+    #[test]
+    fn invalid_logs() {
+        assert_eq!(Replay::from_log(b"RVR"), Err(ReplayError::BadMagic));
+        assert_eq!(
+            Replay::from_log(b"RVSN\x01\x00\x00\x00"),
+            Err(ReplayError::BadMagic)
+        );
+        assert_eq!(
+            Replay::from_log(b"RVRP\x02\x00\x00\x00"),
+            Err(ReplayError::UnsupportedVersion(2))
+        );
+        let mut recording = Replay::record();
+        recording.input(0, Input::Time, || 0).unwrap();
+        let mut log = recording.log();
+        log.pop();
+        assert_eq!(Replay::from_log(&log), Err(ReplayError::Truncated));
+        log.push(0);
+        log[8 + 8] = 9;
+        assert_eq!(Replay::from_log(&log), Err(ReplayError::UnknownInput(9)));
+    }
+
+    #[test]
+    fn queue_and_receive() {
+        const RECEIVER: u32 = 0x1000_0000;
+        let mut recording = Replay::record();
+        recording.queue(b"abc");
+        // the receiver takes a byte per boundary, the rest waits for the next one, and the second
+        // boundary after 3 instructions doesn't count
+        let mut received = vec![];
+        for instret in [3, 3, 5, 8] {
+            let mut room = true;
+            recording.receive(instret, RECEIVER, |byte| {
+                if room {
+                    received.push((instret, byte));
+                }
+                std::mem::take(&mut room)
+            });
+        }
+        assert_eq!(received, [(3, b'a'), (5, b'b'), (8, b'c')]);
+
+        let mut replay = Replay::from_log(&recording.log()).unwrap();
+        replay.queue(b"xyz");
+        assert_eq!(replay.until_next(1), Some(2));
+        let mut replayed = vec![];
+        for instret in 0..10 {
+            replay.receive(instret, RECEIVER, |byte| {
+                replayed.push((instret, byte));
+                true
+            });
+        }
+        assert_eq!(replayed, received);
+        assert!(replay.finished());
+    }
+
+    // Reads 4 bytes from the UART into s1, counting the polls of LSR in s0
+    const RECEIVE: &[u32] = &[
+        0x100002b7, // li t0, 0x10000000
+        0x00400313, // li t1, 4
+        0x00140413, // loop: addi s0, s0, 1
+        0x0052ce03, // lbu t3, 5(t0)
+        0x001e7e13, // andi t3, t3, 1
+        0xfe0e0ae3, // beqz t3, loop
+        0x0002c383, // lbu t2, 0(t0)
+        0x00849493, // slli s1, s1, 8
+        0x0074e4b3, // or s1, s1, t2
+        0xfff30313, // addi t1, t1, -1
+        0xfe0310e3, // bnez t1, loop
+        0xc0001073, // unimp
+    ];
+
+    #[test]
+    fn receive() {
+        let mut recorded = test_utils::machine(RECEIVE);
+        recorded.replay = Replay::record();
+        for (slice, input) in [(37, &b"a"[..]), (50, b""), (3, b"bc"), (101, b"d")] {
+            assert_eq!(recorded.run(slice), Ok(false));
+            assert_eq!(recorded.receive(input), input.len());
+        }
+        assert_eq!(recorded.run(1000), Ok(true));
+        assert_eq!(recorded.registers[9], u32::from_be_bytes(*b"abcd"));
+        assert_eq!(recorded.replay.events().len(), 4);
+
+        // other slices and input from the host change nothing
+        for engine in [Engine::Interpreter, Engine::Blocks] {
+            let mut machine = test_utils::machine(RECEIVE);
+            machine.engine = engine;
+            machine.replay = Replay::from_log(&recorded.replay.log()).unwrap();
+            for slice in [10, 100] {
+                assert_eq!(machine.run(slice), Ok(false));
+                assert_eq!(machine.receive(b"x"), 1);
+            }
+            assert_eq!(machine.run(1000), Ok(true));
+            assert_eq!(machine.registers, recorded.registers);
+            assert_eq!(machine.instret, recorded.instret);
+            assert!(machine.replay.finished());
+        }
+    }
+}
@@ -0,0 +1,1007 @@
+//! Binary snapshots of a [`Machine`].
+//!
+//! A snapshot starts with the magic `RVSN` and a little-endian `u32` format version, followed by
+//! sections. Each section is a 4-byte tag, the `u32` length of its payload and the payload:
+//!
+//! * `REGS`: the 32 general purpose registers followed by the pc, as `u32`s.
+//! * `RAM `: the base address and size of RAM as `u32`s, followed by its contents as runs of
+//!   `[zeros: u32][length: u32][length bytes]`, so untouched memory costs almost nothing.
+//! * `CNTR` (since version 2): the number of retired instructions as `u64`, followed by the number
+//!   of elapsed cycles as `u64` since version 3. Otherwise, the cycles equal the instructions.
+//! * `CSRS` (since version 4): the privilege mode (0, 1 or 3) and the CSRs `mstatus`, `medeleg`,
+//!   `mideleg`, `mie`, `mip`, `mtvec`, `mcounteren`, `mscratch`, `mepc`, `mcause`, `mtval`,
+//!   `stvec`, `scounteren`, `sscratch`, `sepc`, `scause`, `stval` and `satp` as `u32`s, followed
+//!   by the offsets of the cycle and instret counters as `u64`s, and since version 5 by `pmpcfg0`
+//!   to `pmpcfg3` and `pmpaddr0` to `pmpaddr15` as `u32`s. Otherwise, the hart is in machine mode
+//!   with zeroed CSRs.
+//! * `CLNT` (since version 6): the [`Clint`]'s `msip` (0 or 1) as `u32`, followed by `mtimecmp` and
+//!   the offset of `mtime` from the cycles as `u64`s. Otherwise, the CLINT is reset.
+//! * `PLIC` (since version 7): the [`Plic`]'s priorities of sources 0 to 31, its lines, its claimed
+//!   sources and the enables and thresholds of both contexts as `u32`s. Otherwise, the PLIC is
+//!   reset.
+//! * `UART` (since version 8): the [`Uart`]'s `IER`, `LCR`, `MCR` and `SCR` as bytes, its divisor
+//!   latch as `u16`, whether its FIFOs are enabled, an overrun happened and the empty transmitter
+//!   interrupt is pending as bytes (0 or 1), followed by the contents of its receive FIFO.
+//!   Transmitted bytes which the host hasn't taken yet are not part of the snapshot. Otherwise,
+//!   the UART is reset.
+//! * `FBUF` (since version 9, only if the machine has a [`Framebuffer`]): its width, height and
+//!   pixel format (0 for RGB565, 1 for RGB888) as `u32`s, followed by its pixels. Restoring a
+//!   snapshot without it removes the framebuffer.
+//! * `VIRT` (since version 10, only if the machine has a [`VirtioBlock`]): the [`DiskMode`] (0 to
+//!   2), the device status and the selected device feature word as `u32`s, the driver features as
+//!   `u64`, the selected driver feature word, the selected queue, the queue size and whether it's
+//!   ready (0 or 1) as `u32`s, the addresses of the descriptor table, the available and the used
+//!   ring as `u64`s, the index of the next available request and the interrupt status as `u32`s,
+//!   followed by the size of the disk as `u32` and its contents as runs like RAM's. Restoring it
+//!   marks all sectors dirty in [`DiskMode::ReadWrite`], restoring a snapshot without it removes
+//!   the device.
+//! * `BROM` (since version 11, only if the machine has a [`BootRom`]): its contents, the code, the
+//!   entry point and the device tree. Restoring a snapshot without it removes the ROM.
+//!
+//! All integers are little-endian. Every section must appear exactly once, sections added in a
+//! later version are optional in snapshots of earlier versions. The [`History`] is not part of the
+//! snapshot, restoring one clears it. The [`Replay`](crate::replay::Replay) log and the
+//! [`Timing`](crate::timing::Timing) model are kept, the latter without its pipeline state. So are
+//! the [`Caches`](crate::cache::Caches) and their statistics, but all lines are invalidated, and the
+//! trained [`Predictor`](crate::predictor::Predictor) and the counts of the
+//! [`Profiler`](crate::profile::Profiler) and the [`Coverage`](crate::coverage::Coverage). The
+//! address of `tohost` is kept too.
+
+use crate::{
+    bootrom::BootRom,
+    clint::Clint,
+    csr::{Csrs, Privilege},
+    framebuffer::{Framebuffer, PixelFormat},
+    plic::{Plic, PLIC_CONTEXTS, PLIC_MAX_PRIORITY, PLIC_SOURCES},
+    uart::{Uart, UART_FIFO_SIZE},
+    virtio::{Disk, DiskMode, Queue, VirtioBlock, SECTOR_SIZE},
+    DecodeCache, History, Machine, MEMORY_START,
+};
+
+const MAGIC: [u8; 4] = *b"RVSN";
+pub const SNAPSHOT_VERSION: u32 = 11;
+
+const REGISTERS: [u8; 4] = *b"REGS";
+const RAM: [u8; 4] = *b"RAM ";
+const COUNTERS: [u8; 4] = *b"CNTR";
+const CSRS: [u8; 4] = *b"CSRS";
+const CLINT: [u8; 4] = *b"CLNT";
+const PLIC: [u8; 4] = *b"PLIC";
+const UART: [u8; 4] = *b"UART";
+const FRAMEBUFFER: [u8; 4] = *b"FBUF";
+const VIRTIO: [u8; 4] = *b"VIRT";
+const BOOT_ROM: [u8; 4] = *b"BROM";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SnapshotError {
+    BadMagic,
+    UnsupportedVersion(u32),
+    /// The snapshot ended in the middle of a field.
+    Truncated,
+    UnknownSection([u8; 4]),
+    DuplicateSection([u8; 4]),
+    MissingSection([u8; 4]),
+    /// The RAM in the snapshot has a different base address or size than this machine's.
+    MemoryMismatch {
+        start: u32,
+        size: u32,
+        /// The size of this machine's RAM.
+        memory_size: u32,
+    },
+    /// A section's payload doesn't match its length or layout.
+    Malformed([u8; 4]),
+}
+
+impl std::fmt::Display for SnapshotError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let tag = |tag: &[u8; 4]| String::from_utf8_lossy(tag).into_owned();
+        match self {
+            SnapshotError::BadMagic => write!(f, "Not a snapshot"),
+            SnapshotError::UnsupportedVersion(version) => write!(
+                f,
+                "Unsupported snapshot version {version} (expected {SNAPSHOT_VERSION})"
+            ),
+            SnapshotError::Truncated => write!(f, "Snapshot is truncated"),
+            SnapshotError::UnknownSection(section) => {
+                write!(f, "Unknown snapshot section {:?}", tag(section))
+            }
+            SnapshotError::DuplicateSection(section) => {
+                write!(f, "Duplicate snapshot section {:?}", tag(section))
+            }
+            SnapshotError::MissingSection(section) => {
+                write!(f, "Missing snapshot section {:?}", tag(section))
+            }
+            SnapshotError::MemoryMismatch {
+                start,
+                size,
+                memory_size,
+            } => write!(
+                f,
+                "Snapshot RAM 0x{start:08x}+0x{size:x} doesn't match 0x{MEMORY_START:08x}+0x{memory_size:x}"
+            ),
+            SnapshotError::Malformed(section) => {
+                write!(f, "Malformed snapshot section {:?}", tag(section))
+            }
+        }
+    }
+}
+
+impl std::error::Error for SnapshotError {}
+
+#[derive(Clone, Copy)]
+struct Reader<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn bytes(&mut self, n: usize) -> Result<&'a [u8], SnapshotError> {
+        if self.data.len() < n {
+            return Err(SnapshotError::Truncated);
+        }
+        let (bytes, rest) = self.data.split_at(n);
+        self.data = rest;
+        Ok(bytes)
+    }
+
+    fn tag(&mut self) -> Result<[u8; 4], SnapshotError> {
+        Ok(self.bytes(4)?.try_into().unwrap())
+    }
+
+    fn u32(&mut self) -> Result<u32, SnapshotError> {
+        Ok(u32::from_le_bytes(self.tag()?))
+    }
+
+    fn u64(&mut self) -> Result<u64, SnapshotError> {
+        Ok(u64::from_le_bytes(self.bytes(8)?.try_into().unwrap()))
+    }
+}
+
+fn section(output: &mut Vec<u8>, tag: [u8; 4], payload: &[u8]) {
+    output.extend(tag);
+    output.extend((payload.len() as u32).to_le_bytes());
+    output.extend(payload);
+}
+
+fn encode_memory(memory: &[u8]) -> Vec<u8> {
+    let mut output = Vec::new();
+    output.extend((MEMORY_START as u32).to_le_bytes());
+    output.extend((memory.len() as u32).to_le_bytes());
+    encode_runs(&mut output, memory);
+    output
+}
+
+/// Appends `data` as runs of `[zeros: u32][length: u32][length bytes]`.
+fn encode_runs(output: &mut Vec<u8>, data: &[u8]) {
+    let mut rest = data;
+    while !rest.is_empty() {
+        let zeros = rest.iter().take_while(|&&byte| byte == 0).count();
+        rest = &rest[zeros..];
+        let length = rest
+            .iter()
+            .position(|&byte| byte == 0)
+            .unwrap_or(rest.len());
+        output.extend((zeros as u32).to_le_bytes());
+        output.extend((length as u32).to_le_bytes());
+        output.extend(&rest[..length]);
+        rest = &rest[length..];
+    }
+}
+
+fn decode_memory(payload: &[u8], memory: &mut [u8]) -> Result<(), SnapshotError> {
+    let malformed = |_| SnapshotError::Malformed(RAM);
+    let mut reader = Reader { data: payload };
+    let start = reader.u32().map_err(malformed)?;
+    let size = reader.u32().map_err(malformed)?;
+    if start as usize != MEMORY_START || size as usize != memory.len() {
+        return Err(SnapshotError::MemoryMismatch {
+            start,
+            size,
+            memory_size: memory.len() as u32,
+        });
+    }
+    decode_runs(reader, memory).ok_or(SnapshotError::Malformed(RAM))
+}
+
+/// Fills `data` with the runs of the rest of `reader`, `None` if they don't cover it exactly.
+fn decode_runs(mut reader: Reader, data: &mut [u8]) -> Option<()> {
+    data.fill(0);
+    let mut offset = 0usize;
+    while !reader.data.is_empty() {
+        let zeros = reader.u32().ok()? as usize;
+        let length = reader.u32().ok()? as usize;
+        let bytes = reader.bytes(length).ok()?;
+        // the sizes come from the snapshot, so they may overflow on 32-bit hosts
+        offset = offset.checked_add(zeros)?;
+        data.get_mut(offset..offset.checked_add(length)?)?
+            .copy_from_slice(bytes);
+        offset += length;
+    }
+    (offset == data.len()).then_some(())
+}
+
+/// The number of bytes the runs of the rest of `reader` cover, `None` if they are truncated.
+fn runs_length(mut reader: Reader) -> Option<usize> {
+    let mut offset = 0usize;
+    while !reader.data.is_empty() {
+        let zeros = reader.u32().ok()? as usize;
+        let length = reader.u32().ok()? as usize;
+        reader.bytes(length).ok()?;
+        offset = offset.checked_add(zeros)?.checked_add(length)?;
+    }
+    Some(offset)
+}
+
+/// The CSRs in the order of the `CSRS` section.
+fn csr_fields(csrs: &mut Csrs) -> [&mut u32; 18] {
+    [
+        &mut csrs.mstatus,
+        &mut csrs.medeleg,
+        &mut csrs.mideleg,
+        &mut csrs.mie,
+        &mut csrs.mip,
+        &mut csrs.mtvec,
+        &mut csrs.mcounteren,
+        &mut csrs.mscratch,
+        &mut csrs.mepc,
+        &mut csrs.mcause,
+        &mut csrs.mtval,
+        &mut csrs.stvec,
+        &mut csrs.scounteren,
+        &mut csrs.sscratch,
+        &mut csrs.sepc,
+        &mut csrs.scause,
+        &mut csrs.stval,
+        &mut csrs.satp,
+    ]
+}
+
+fn encode_csrs(mut csrs: Csrs) -> Vec<u8> {
+    let mut output = Vec::new();
+    output.extend((csrs.privilege as u32).to_le_bytes());
+    for field in csr_fields(&mut csrs) {
+        output.extend(field.to_le_bytes());
+    }
+    output.extend(csrs.cycle_offset.to_le_bytes());
+    output.extend(csrs.instret_offset.to_le_bytes());
+    for field in csrs.pmpcfg.iter().chain(&csrs.pmpaddr) {
+        output.extend(field.to_le_bytes());
+    }
+    output
+}
+
+fn decode_csrs(payload: &[u8], version: u32) -> Result<Csrs, SnapshotError> {
+    let malformed = |_| SnapshotError::Malformed(CSRS);
+    let mut reader = Reader { data: payload };
+    let mut csrs = Csrs {
+        privilege: match reader.u32().map_err(malformed)? {
+            0 => Privilege::User,
+            1 => Privilege::Supervisor,
+            3 => Privilege::Machine,
+            _ => return Err(SnapshotError::Malformed(CSRS)),
+        },
+        ..Csrs::default()
+    };
+    for field in csr_fields(&mut csrs) {
+        *field = reader.u32().map_err(malformed)?;
+    }
+    let mut u64 = || -> Result<u64, SnapshotError> {
+        let bytes = reader.bytes(8).map_err(malformed)?;
+        Ok(u64::from_le_bytes(bytes.try_into().unwrap()))
+    };
+    csrs.cycle_offset = u64()?;
+    csrs.instret_offset = u64()?;
+    if version >= 5 {
+        for field in csrs.pmpcfg.iter_mut().chain(&mut csrs.pmpaddr) {
+            *field = reader.u32().map_err(malformed)?;
+        }
+    }
+    if !reader.data.is_empty() {
+        return Err(SnapshotError::Malformed(CSRS));
+    }
+    Ok(csrs)
+}
+
+fn encode_clint(clint: &Clint) -> Vec<u8> {
+    let mut output = Vec::new();
+    output.extend((clint.msip as u32).to_le_bytes());
+    output.extend(clint.mtimecmp.to_le_bytes());
+    output.extend(clint.time_offset.to_le_bytes());
+    output
+}
+
+fn decode_clint(payload: &[u8]) -> Result<Clint, SnapshotError> {
+    if payload.len() != 4 + 8 + 8 {
+        return Err(SnapshotError::Malformed(CLINT));
+    }
+    let u64 = |bytes: &[u8]| u64::from_le_bytes(bytes.try_into().unwrap());
+    Ok(Clint {
+        msip: match u32::from_le_bytes(payload[..4].try_into().unwrap()) {
+            0 => false,
+            1 => true,
+            _ => return Err(SnapshotError::Malformed(CLINT)),
+        },
+        mtimecmp: u64(&payload[4..12]),
+        time_offset: u64(&payload[12..]),
+    })
+}
+
+fn encode_plic(plic: &Plic) -> Vec<u8> {
+    let fields = [plic.lines, plic.claimed];
+    plic.priorities
+        .iter()
+        .chain(&fields)
+        .chain(&plic.enables)
+        .chain(&plic.thresholds)
+        .flat_map(|field| field.to_le_bytes())
+        .collect()
+}
+
+fn decode_plic(payload: &[u8]) -> Result<Plic, SnapshotError> {
+    if payload.len() != 4 * (PLIC_SOURCES as usize + 2 + 2 * PLIC_CONTEXTS) {
+        return Err(SnapshotError::Malformed(PLIC));
+    }
+    let mut plic = Plic::default();
+    let mut fields = payload
+        .chunks(4)
+        .map(|bytes| u32::from_le_bytes(bytes.try_into().unwrap()));
+    for priority in &mut plic.priorities {
+        *priority = fields.next().unwrap();
+    }
+    plic.lines = fields.next().unwrap();
+    plic.claimed = fields.next().unwrap();
+    for field in plic.enables.iter_mut().chain(&mut plic.thresholds) {
+        *field = fields.next().unwrap();
+    }
+    // source 0 doesn't exist
+    let valid = |priority: &u32| *priority <= PLIC_MAX_PRIORITY;
+    if plic.priorities[0] != 0
+        || (plic.lines | plic.claimed) & 1 != 0
+        || plic.enables.iter().any(|enable| enable & 1 != 0)
+        || !plic.priorities.iter().chain(&plic.thresholds).all(valid)
+    {
+        return Err(SnapshotError::Malformed(PLIC));
+    }
+    Ok(plic)
+}
+
+fn encode_uart(uart: &Uart) -> Vec<u8> {
+    let mut output = vec![uart.ier, uart.lcr, uart.mcr, uart.scr];
+    output.extend(uart.divisor.to_le_bytes());
+    output.extend([uart.fifo_enabled, uart.overrun, uart.thre_pending].map(u8::from));
+    output.extend(&uart.rx);
+    output
+}
+
+fn decode_uart(payload: &[u8]) -> Result<Uart, SnapshotError> {
+    if !(9..=9 + UART_FIFO_SIZE).contains(&payload.len())
+        || payload[6..9].iter().any(|&flag| flag > 1)
+    {
+        return Err(SnapshotError::Malformed(UART));
+    }
+    Ok(Uart {
+        ier: payload[0],
+        lcr: payload[1],
+        mcr: payload[2],
+        scr: payload[3],
+        divisor: u16::from_le_bytes([payload[4], payload[5]]),
+        fifo_enabled: payload[6] == 1,
+        overrun: payload[7] == 1,
+        thre_pending: payload[8] == 1,
+        rx: payload[9..].iter().copied().collect(),
+        ..Uart::default()
+    })
+}
+
+fn encode_framebuffer(framebuffer: &Framebuffer) -> Vec<u8> {
+    let mut output = Vec::new();
+    for field in [
+        framebuffer.width(),
+        framebuffer.height(),
+        framebuffer.format() as u32,
+    ] {
+        output.extend(field.to_le_bytes());
+    }
+    output.extend(framebuffer.pixels());
+    output
+}
+
+fn decode_framebuffer(payload: &[u8]) -> Result<Framebuffer, SnapshotError> {
+    let malformed = |_| SnapshotError::Malformed(FRAMEBUFFER);
+    let mut reader = Reader { data: payload };
+    let width = reader.u32().map_err(malformed)?;
+    let height = reader.u32().map_err(malformed)?;
+    let format = match reader.u32().map_err(malformed)? {
+        0 => PixelFormat::Rgb565,
+        1 => PixelFormat::Rgb888,
+        _ => return Err(SnapshotError::Malformed(FRAMEBUFFER)),
+    };
+    let mut framebuffer = Framebuffer::new(width, height, format)
+        .map_err(|_| SnapshotError::Malformed(FRAMEBUFFER))?;
+    if reader.data.len() != framebuffer.pixels().len() {
+        return Err(SnapshotError::Malformed(FRAMEBUFFER));
+    }
+    framebuffer.pixels_mut().copy_from_slice(reader.data);
+    Ok(framebuffer)
+}
+
+fn encode_virtio(virtio: &VirtioBlock) -> Vec<u8> {
+    let mut output = Vec::new();
+    let queue = virtio.queue;
+    for field in [
+        virtio.disk.mode() as u32,
+        virtio.status,
+        virtio.device_features_sel,
+    ] {
+        output.extend(field.to_le_bytes());
+    }
+    output.extend(virtio.driver_features.to_le_bytes());
+    for field in [
+        virtio.driver_features_sel,
+        virtio.queue_sel,
+        queue.size,
+        queue.ready as u32,
+    ] {
+        output.extend(field.to_le_bytes());
+    }
+    for address in [queue.desc, queue.driver, queue.device] {
+        output.extend(address.to_le_bytes());
+    }
+    for field in [
+        queue.last_avail as u32,
+        virtio.interrupt_status,
+        virtio.disk.data().len() as u32,
+    ] {
+        output.extend(field.to_le_bytes());
+    }
+    encode_runs(&mut output, virtio.disk.data());
+    output
+}
+
+fn decode_virtio(payload: &[u8]) -> Result<VirtioBlock, SnapshotError> {
+    let malformed = |_| SnapshotError::Malformed(VIRTIO);
+    let mut reader = Reader { data: payload };
+    let mode = match reader.u32().map_err(malformed)? {
+        0 => DiskMode::ReadWrite,
+        1 => DiskMode::CopyOnWrite,
+        2 => DiskMode::ReadOnly,
+        _ => return Err(SnapshotError::Malformed(VIRTIO)),
+    };
+    let status = reader.u32().map_err(malformed)?;
+    let device_features_sel = reader.u32().map_err(malformed)?;
+    let driver_features = reader.u64().map_err(malformed)?;
+    let driver_features_sel = reader.u32().map_err(malformed)?;
+    let queue_sel = reader.u32().map_err(malformed)?;
+    let size = reader.u32().map_err(malformed)?;
+    let ready = reader.u32().map_err(malformed)?;
+    let desc = reader.u64().map_err(malformed)?;
+    let driver = reader.u64().map_err(malformed)?;
+    let device = reader.u64().map_err(malformed)?;
+    let last_avail = reader.u32().map_err(malformed)?;
+    let interrupt_status = reader.u32().map_err(malformed)?;
+    let length = reader.u32().map_err(malformed)?;
+    if ready > 1 || last_avail > u16::MAX as u32 {
+        return Err(SnapshotError::Malformed(VIRTIO));
+    }
+    // the size comes from the snapshot, so it's checked before allocating the disk
+    let length = length as usize;
+    if length % SECTOR_SIZE != 0 || runs_length(reader) != Some(length) {
+        return Err(SnapshotError::Malformed(VIRTIO));
+    }
+    let mut data = Vec::new();
+    data.try_reserve_exact(length)
+        .map_err(|_| SnapshotError::Malformed(VIRTIO))?;
+    data.resize(length, 0);
+    decode_runs(reader, &mut data).ok_or(SnapshotError::Malformed(VIRTIO))?;
+    let mut disk = Disk::new(data, mode).map_err(|_| SnapshotError::Malformed(VIRTIO))?;
+    // the image no longer matches the disk
+    disk.touch();
+    Ok(VirtioBlock {
+        disk,
+        status,
+        device_features_sel,
+        driver_features,
+        driver_features_sel,
+        queue_sel,
+        queue: Queue {
+            size,
+            ready: ready == 1,
+            desc,
+            driver,
+            device,
+            last_avail: last_avail as u16,
+        },
+        interrupt_status,
+        notified: false,
+    })
+}
+
+impl Machine {
+    /// Serializes the complete machine state, see the [module documentation](self) for the format.
+    pub fn snapshot(&self) -> Vec<u8> {
+        let mut output = Vec::new();
+        output.extend(MAGIC);
+        output.extend(SNAPSHOT_VERSION.to_le_bytes());
+        let registers = self
+            .registers
+            .iter()
+            .flat_map(|register| register.to_le_bytes())
+            .collect::<Vec<_>>();
+        section(&mut output, REGISTERS, &registers);
+        section(&mut output, RAM, &encode_memory(&self.memory[..]));
+        let counters = [self.instret, self.cycle].map(u64::to_le_bytes).concat();
+        section(&mut output, COUNTERS, &counters);
+        section(&mut output, CSRS, &encode_csrs(self.csrs));
+        section(&mut output, CLINT, &encode_clint(&self.devices.clint));
+        section(&mut output, PLIC, &encode_plic(&self.devices.plic));
+        section(&mut output, UART, &encode_uart(&self.devices.uart));
+        if let Some(framebuffer) = &self.devices.framebuffer {
+            section(&mut output, FRAMEBUFFER, &encode_framebuffer(framebuffer));
+        }
+        if let Some(virtio) = &self.devices.virtio {
+            section(&mut output, VIRTIO, &encode_virtio(virtio));
+        }
+        if let Some(rom) = &self.devices.rom {
+            section(&mut output, BOOT_ROM, rom.data());
+        }
+        output
+    }
+
+    /// Restores a state serialized by [`Machine::snapshot`], whose RAM must have the size of this
+    /// machine's. On error, the machine is unchanged.
+    pub fn restore(&mut self, snapshot: &[u8]) -> Result<(), SnapshotError> {
+        let mut reader = Reader { data: snapshot };
+        if reader.tag().map_err(|_| SnapshotError::BadMagic)? != MAGIC {
+            return Err(SnapshotError::BadMagic);
+        }
+        let version = reader.u32()?;
+        if !(1..=SNAPSHOT_VERSION).contains(&version) {
+            return Err(SnapshotError::UnsupportedVersion(version));
+        }
+
+        let mut machine = Machine::with_memory_size(self.memory.len());
+        let mut seen = Vec::new();
+        while !reader.data.is_empty() {
+            let tag = reader.tag()?;
+            let length = reader.u32()? as usize;
+            let payload = reader.bytes(length)?;
+            if seen.contains(&tag) {
+                return Err(SnapshotError::DuplicateSection(tag));
+            }
+            seen.push(tag);
+            match tag {
+                REGISTERS => {
+                    if payload.len() != 4 * machine.registers.len() {
+                        return Err(SnapshotError::Malformed(tag));
+                    }
+                    for (register, bytes) in machine.registers.iter_mut().zip(payload.chunks(4)) {
+                        *register = u32::from_le_bytes(bytes.try_into().unwrap());
+                    }
+                }
+                RAM => decode_memory(payload, &mut machine.memory[..])?,
+                COUNTERS => {
+                    let length = match version {
+                        2 => 8,
+                        _ => 16,
+                    };
+                    if payload.len() != length {
+                        return Err(SnapshotError::Malformed(tag));
+                    }
+                    let counters = payload
+                        .chunks(8)
+                        .map(|bytes| u64::from_le_bytes(bytes.try_into().unwrap()))
+                        .collect::<Vec<_>>();
+                    machine.instret = counters[0];
+                    machine.cycle = *counters.last().unwrap();
+                }
+                CSRS if version >= 4 => machine.csrs = decode_csrs(payload, version)?,
+                CLINT if version >= 6 => machine.devices.clint = decode_clint(payload)?,
+                PLIC if version >= 7 => machine.devices.plic = decode_plic(payload)?,
+                UART if version >= 8 => machine.devices.uart = decode_uart(payload)?,
+                FRAMEBUFFER if version >= 9 => {
+                    machine.devices.framebuffer = Some(decode_framebuffer(payload)?)
+                }
+                VIRTIO if version >= 10 => machine.devices.virtio = Some(decode_virtio(payload)?),
+                BOOT_ROM if version >= 11 => {
+                    let rom = BootRom::from_data(payload.to_vec())
+                        .map_err(|_| SnapshotError::Malformed(tag))?;
+                    machine.devices.rom = Some(rom);
+                }
+                _ => return Err(SnapshotError::UnknownSection(tag)),
+            }
+        }
+        let required: &[[u8; 4]] = match version {
+            1 => &[REGISTERS, RAM],
+            2 | 3 => &[REGISTERS, RAM, COUNTERS],
+            4 | 5 => &[REGISTERS, RAM, COUNTERS, CSRS],
+            6 => &[REGISTERS, RAM, COUNTERS, CSRS, CLINT],
+            7 => &[REGISTERS, RAM, COUNTERS, CSRS, CLINT, PLIC],
+            _ => &[REGISTERS, RAM, COUNTERS, CSRS, CLINT, PLIC, UART],
+        };
+        for &tag in required {
+            if !seen.contains(&tag) {
+                return Err(SnapshotError::MissingSection(tag));
+            }
+        }
+
+        // the recorded history doesn't apply to the restored state
+        machine.history = History::new(self.history.capacity());
+        machine.replay = std::mem::take(&mut self.replay);
+        if !self.decode_cache.enabled() {
+            machine.decode_cache = DecodeCache::disabled();
+        }
+        machine.engine = self.engine;
+        machine.tohost = self.tohost;
+        machine.tlb = std::mem::take(&mut self.tlb);
+        machine.tlb.clear();
+        machine.timing = std::mem::take(&mut self.timing);
+        machine.timing.flush();
+        machine.caches = std::mem::take(&mut self.caches);
+        machine.caches.invalidate();
+        machine.predictor = self.predictor.take();
+        machine.profiler = self.profiler.take();
+        if let Some(profiler) = &mut machine.profiler {
+            profiler.unwind();
+        }
+        machine.coverage = self.coverage.take();
+        *self = machine;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        super::*,
+        crate::{virtio::SECTOR_SIZE, MEMORY_SIZE, PC},
+    };
+
+    /// The length of the `CSRS` section, followed by `CLNT`, `PLIC` and `UART`.
+    const CSRS_LENGTH: usize = 8 + 4 * 19 + 16 + 4 * 20;
+    const CLINT_LENGTH: usize = 8 + 4 + 8 + 8;
+    const PLIC_LENGTH: usize = 8 + 4 * (32 + 2 + 4);
+    /// The length of the `UART` section, the last one, with two received bytes.
+    const UART_LENGTH: usize = 8 + 9 + 2;
+
+    fn machine() -> Machine {
+        let mut machine = Machine::new();
+        for (i, register) in machine.registers.iter_mut().enumerate() {
+            *register = 0x0101_0101 * i as u32;
+        }
+        machine.memory[..4].copy_from_slice(&[0x13, 0x05, 0xf5, 0xff]);
+        machine.memory[0x100..0x103].copy_from_slice(&[1, 0, 2]);
+        machine.memory[MEMORY_SIZE - 1] = 0xff;
+        machine.instret = 1234;
+        machine.cycle = 2345;
+        machine.csrs.privilege = Privilege::Supervisor;
+        machine.csrs.mepc = 0x8000_0010;
+        machine.csrs.instret_offset = u64::MAX;
+        machine.csrs.pmpcfg[3] = 0x1f00_0000;
+        machine.csrs.pmpaddr[15] = u32::MAX;
+        machine.devices.clint.msip = true;
+        machine.devices.clint.mtimecmp = 3456;
+        machine.devices.clint.time_offset = u64::MAX;
+        machine.devices.plic.priorities[31] = 7;
+        machine.devices.plic.lines = 0x8000_0002;
+        machine.devices.plic.claimed = 0x8000_0000;
+        machine.devices.plic.enables[1] = 0x8000_0000;
+        machine.devices.plic.thresholds[0] = 3;
+        machine.devices.uart.rx.extend(b"hi");
+        machine.devices.uart.divisor = 3;
+        machine.devices.uart.thre_pending = true;
+        machine
+    }
+
+    #[test]
+    fn round_trip() {
+        let machine = machine();
+        let snapshot = machine.snapshot();
+        let mut restored = Machine::new();
+        restored.restore(&snapshot).unwrap();
+        assert!(restored == machine);
+        assert_eq!(restored.snapshot(), snapshot);
+    }
+
+    #[test]
+    fn memory_size() {
+        let mut machine = Machine::with_memory_size(2 * MEMORY_SIZE);
+        machine.memory[2 * MEMORY_SIZE - 1] = 0xff;
+        let snapshot = machine.snapshot();
+        let mut restored = Machine::with_memory_size(2 * MEMORY_SIZE);
+        restored.restore(&snapshot).unwrap();
+        assert!(restored == machine);
+        assert_eq!(
+            Machine::new().restore(&snapshot),
+            Err(SnapshotError::MemoryMismatch {
+                start: MEMORY_START as u32,
+                size: 2 * MEMORY_SIZE as u32,
+                memory_size: MEMORY_SIZE as u32,
+            })
+        );
+    }
+
+    #[test]
+    fn version_1() {
+        let mut snapshot = machine().snapshot();
+        snapshot[4..8].copy_from_slice(&1u32.to_le_bytes());
+        snapshot.truncate(snapshot.len() - UART_LENGTH - PLIC_LENGTH - CLINT_LENGTH);
+        snapshot.truncate(snapshot.len() - CSRS_LENGTH - (8 + 16));
+        let mut restored = Machine::new();
+        restored.instret = 1;
+        restored.restore(&snapshot).unwrap();
+        assert_eq!(restored.registers, machine().registers);
+        assert_eq!(restored.instret, 0);
+    }
+
+    #[test]
+    fn version_2() {
+        let mut snapshot = machine().snapshot();
+        snapshot[4..8].copy_from_slice(&2u32.to_le_bytes());
+        snapshot.truncate(snapshot.len() - UART_LENGTH - PLIC_LENGTH - CLINT_LENGTH);
+        snapshot.truncate(snapshot.len() - CSRS_LENGTH - (8 + 16));
+        snapshot.extend(b"CNTR\x08\x00\x00\x00");
+        snapshot.extend(1234u64.to_le_bytes());
+        let mut restored = Machine::new();
+        restored.restore(&snapshot).unwrap();
+        assert_eq!(restored.instret, 1234);
+        assert_eq!(restored.cycle, 1234);
+    }
+
+    #[test]
+    fn version_3() {
+        let mut snapshot = machine().snapshot();
+        snapshot[4..8].copy_from_slice(&3u32.to_le_bytes());
+        snapshot.truncate(snapshot.len() - UART_LENGTH - PLIC_LENGTH - CLINT_LENGTH);
+        snapshot.truncate(snapshot.len() - CSRS_LENGTH);
+        let mut restored = Machine::new();
+        restored.csrs.mepc = 4;
+        restored.restore(&snapshot).unwrap();
+        assert_eq!(restored.cycle, 2345);
+        assert_eq!(restored.csrs, Csrs::default());
+    }
+
+    #[test]
+    fn version_4() {
+        let mut snapshot = machine().snapshot();
+        snapshot[4..8].copy_from_slice(&4u32.to_le_bytes());
+        snapshot.truncate(snapshot.len() - UART_LENGTH - PLIC_LENGTH - CLINT_LENGTH);
+        snapshot.truncate(snapshot.len() - 4 * 20);
+        let length = snapshot.len() - CSRS_LENGTH + 4 * 20 + 4;
+        snapshot[length..length + 4].copy_from_slice(&(4 * 19 + 16u32).to_le_bytes());
+        let mut restored = Machine::new();
+        restored.restore(&snapshot).unwrap();
+        assert_eq!(restored.csrs.mepc, 0x8000_0010);
+        assert_eq!(restored.csrs.pmpaddr, [0; 16]);
+    }
+
+    #[test]
+    fn version_5() {
+        let mut snapshot = machine().snapshot();
+        snapshot[4..8].copy_from_slice(&5u32.to_le_bytes());
+        snapshot.truncate(snapshot.len() - UART_LENGTH - PLIC_LENGTH - CLINT_LENGTH);
+        let mut restored = Machine::new();
+        restored.devices.clint.msip = true;
+        restored.restore(&snapshot).unwrap();
+        assert_eq!(restored.csrs, machine().csrs);
+        assert_eq!(restored.devices.clint, Clint::default());
+    }
+
+    #[test]
+    fn version_6() {
+        let mut snapshot = machine().snapshot();
+        snapshot[4..8].copy_from_slice(&6u32.to_le_bytes());
+        snapshot.truncate(snapshot.len() - UART_LENGTH - PLIC_LENGTH);
+        let mut restored = Machine::new();
+        restored.devices.plic.lines = 2;
+        restored.restore(&snapshot).unwrap();
+        assert_eq!(restored.devices.clint, machine().devices.clint);
+        assert_eq!(restored.devices.plic, Plic::default());
+    }
+
+    #[test]
+    fn version_7() {
+        let mut snapshot = machine().snapshot();
+        snapshot[4..8].copy_from_slice(&7u32.to_le_bytes());
+        snapshot.truncate(snapshot.len() - UART_LENGTH);
+        let mut restored = Machine::new();
+        restored.devices.uart.ier = 1;
+        restored.restore(&snapshot).unwrap();
+        assert_eq!(restored.devices.plic, machine().devices.plic);
+        assert_eq!(restored.devices.uart, Uart::default());
+    }
+
+    #[test]
+    fn untaken_output() {
+        let mut machine = machine();
+        machine.devices.uart.output.extend(b"lost");
+        let mut restored = Machine::new();
+        restored.restore(&machine.snapshot()).unwrap();
+        assert!(restored.devices.uart.output.is_empty());
+        assert_eq!(restored.devices.uart.rx, b"hi");
+    }
+
+    #[test]
+    fn framebuffer() {
+        let mut machine = machine();
+        let mut framebuffer = Framebuffer::new(3, 2, PixelFormat::Rgb888).unwrap();
+        framebuffer.pixels_mut()[17] = 0xff;
+        machine.devices.framebuffer = Some(framebuffer);
+        let snapshot = machine.snapshot();
+        let mut restored = Machine::new();
+        restored.restore(&snapshot).unwrap();
+        assert!(restored == machine);
+
+        // restoring a snapshot without one removes it
+        restored.restore(&self::machine().snapshot()).unwrap();
+        assert_eq!(restored.devices.framebuffer, None);
+
+        // the pixels must match the resolution
+        let mut truncated = snapshot.clone();
+        truncated.pop();
+        let length = snapshot.len() - (8 + 12 + 18) + 4;
+        truncated[length..length + 4].copy_from_slice(&(12 + 17u32).to_le_bytes());
+        assert_eq!(
+            restored.restore(&truncated),
+            Err(SnapshotError::Malformed(FRAMEBUFFER))
+        );
+    }
+
+    #[test]
+    fn virtio() {
+        let mut machine = machine();
+        let mut data = vec![0; 4 * SECTOR_SIZE];
+        data[SECTOR_SIZE + 1] = 0xff;
+        let mut virtio = VirtioBlock::new(Disk::new(data, DiskMode::CopyOnWrite).unwrap());
+        virtio.status = 0xf;
+        virtio.driver_features = 1 << 32;
+        virtio.queue = Queue {
+            size: 8,
+            ready: true,
+            desc: 0x8000_1000,
+            driver: 0x8000_1080,
+            device: 0x8000_2000,
+            last_avail: 0xffff,
+        };
+        virtio.interrupt_status = 1;
+        machine.devices.virtio = Some(virtio);
+        let snapshot = machine.snapshot();
+        let mut restored = Machine::new();
+        restored.restore(&snapshot).unwrap();
+        assert!(restored == machine);
+
+        // a read-write disk no longer matches its image
+        let virtio = machine.devices.virtio.as_mut().unwrap();
+        virtio.disk = Disk::new(vec![0; 4 * SECTOR_SIZE], DiskMode::ReadWrite).unwrap();
+        restored.restore(&machine.snapshot()).unwrap();
+        let disk = &mut restored.devices.virtio.as_mut().unwrap().disk;
+        let dirty = disk.take_dirty();
+        assert_eq!((dirty.len(), &dirty[0]), (1, &(0..4)));
+
+        restored.restore(&self::machine().snapshot()).unwrap();
+        assert_eq!(restored.devices.virtio, None);
+
+        // `ready` is a boolean
+        let mut invalid = snapshot.clone();
+        let virtio = snapshot.len() - (8 + 72 + 8 + 1 + 8);
+        invalid[virtio + 8 + 32] = 2;
+        assert_eq!(
+            restored.restore(&invalid),
+            Err(SnapshotError::Malformed(VIRTIO))
+        );
+
+        // the size of the disk has to match its runs before it's allocated
+        let mut huge = snapshot.clone();
+        huge[virtio + 8 + 68..virtio + 8 + 72].copy_from_slice(&0xffff_fe00u32.to_le_bytes());
+        assert_eq!(
+            restored.restore(&huge),
+            Err(SnapshotError::Malformed(VIRTIO))
+        );
+    }
+
+    #[test]
+    fn boot_rom() {
+        let mut machine = machine();
+        machine.boot("").unwrap();
+        let snapshot = machine.snapshot();
+        let mut restored = Machine::new();
+        restored.restore(&snapshot).unwrap();
+        assert!(restored == machine);
+
+        restored.restore(&self::machine().snapshot()).unwrap();
+        assert_eq!(restored.devices.rom, None);
+    }
+
+    #[test]
+    fn compact() {
+        // header, REGS, RAM with a single run of zeros, CNTR, CSRS and the devices
+        let devices = CLINT_LENGTH + PLIC_LENGTH + UART_LENGTH;
+        assert_eq!(
+            Machine::new().snapshot().len(),
+            8 + (8 + 4 * 33) + (8 + 8 + 8) + (8 + 16) + CSRS_LENGTH + devices - 2
+        );
+        assert!(machine().snapshot().len() < 256 + CSRS_LENGTH + devices);
+    }
+
+    #[test]
+    fn rejects_invalid_snapshots() {
+        let snapshot = machine().snapshot();
+        let mut target = Machine::new();
+        target.registers[PC] = 0x1234;
+        let mut check = |data: &[u8], expected| {
+            assert_eq!(target.restore(data), Err(expected));
+            assert_eq!(target.registers[PC], 0x1234);
+        };
+
+        check(b"", SnapshotError::BadMagic);
+        check(b"ELF\x7f\x01\x00\x00\x00", SnapshotError::BadMagic);
+        check(
+            b"RVSN\x0c\x00\x00\x00",
+            SnapshotError::UnsupportedVersion(12),
+        );
+        check(&snapshot[..snapshot.len() - 1], SnapshotError::Truncated);
+        check(
+            b"RVSN\x01\x00\x00\x00",
+            SnapshotError::MissingSection(REGISTERS),
+        );
+
+        let mut unknown = snapshot.clone();
+        unknown.extend(b"ZZZZ\x00\x00\x00\x00");
+        check(&unknown, SnapshotError::UnknownSection(*b"ZZZZ"));
+
+        let mut duplicate = snapshot.clone();
+        duplicate.extend_from_slice(&snapshot[8..8 + 8 + 4 * 33]);
+        check(&duplicate, SnapshotError::DuplicateSection(REGISTERS));
+
+        let mut mismatch = snapshot.clone();
+        let ram = 8 + 8 + 4 * 33 + 8;
+        mismatch[ram + 4..ram + 8].copy_from_slice(&0x20000u32.to_le_bytes());
+        check(
+            &mismatch,
+            SnapshotError::MemoryMismatch {
+                start: MEMORY_START as u32,
+                size: 0x20000,
+                memory_size: MEMORY_SIZE as u32,
+            },
+        );
+
+        let uart = snapshot.len() - UART_LENGTH;
+        let plic = uart - PLIC_LENGTH;
+        let clint = plic - CLINT_LENGTH;
+        let csrs = clint - CSRS_LENGTH;
+        let mut truncated_counters = snapshot[..csrs - (8 + 16)].to_vec();
+        truncated_counters.extend(b"CNTR\x04\x00\x00\x00\x00\x00\x00\x00");
+        truncated_counters.extend(&snapshot[csrs..]);
+        check(&truncated_counters, SnapshotError::Malformed(COUNTERS));
+
+        let mut missing_csrs = snapshot[..csrs].to_vec();
+        missing_csrs.extend(&snapshot[clint..]);
+        check(&missing_csrs, SnapshotError::MissingSection(CSRS));
+
+        check(&snapshot[..uart], SnapshotError::MissingSection(UART));
+
+        // privilege mode 2 is reserved
+        let mut reserved = snapshot.clone();
+        reserved[csrs + 8..csrs + 12].copy_from_slice(&2u32.to_le_bytes());
+        check(&reserved, SnapshotError::Malformed(CSRS));
+
+        // msip is a single bit
+        let mut msip = snapshot.clone();
+        msip[clint + 8..clint + 12].copy_from_slice(&2u32.to_le_bytes());
+        check(&msip, SnapshotError::Malformed(CLINT));
+
+        // priorities have 3 bits
+        let mut priority = snapshot.clone();
+        priority[plic + 12..plic + 16].copy_from_slice(&8u32.to_le_bytes());
+        check(&priority, SnapshotError::Malformed(PLIC));
+
+        // the receive FIFO holds 16 bytes
+        let mut full = snapshot[..uart].to_vec();
+        full.extend(b"UART\x1a\x00\x00\x00");
+        full.extend([0; 9 + 17]);
+        check(&full, SnapshotError::Malformed(UART));
+
+        // the first run of zeros extends past the end of RAM
+        let mut overflow = snapshot.clone();
+        overflow[ram + 8..ram + 12].copy_from_slice(&u32::MAX.to_le_bytes());
+        check(&overflow, SnapshotError::Malformed(RAM));
+    }
+}
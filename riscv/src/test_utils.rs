@@ -0,0 +1,29 @@
+//! Fixtures shared by the unit tests.
+
+use crate::{store_word, Machine, Memory, MEMORY_SIZE, MEMORY_START};
+
+/// Ends the program, see [`Machine::run`].
+pub const UNIMP: u32 = 0xc0001073;
+
+/// Writes the instructions of `program` to `memory` at `address`.
+pub fn load(memory: &mut Memory, address: u32, program: &[u32]) {
+    for (i, &code) in program.iter().enumerate() {
+        store_word(memory, address + 4 * i as u32, code).unwrap();
+    }
+}
+
+/// Returns a machine with `program` at the start of RAM, where it starts executing.
+pub fn machine(program: &[u32]) -> Machine {
+    let mut machine = Machine::new();
+    load(&mut machine.memory, MEMORY_START as u32, program);
+    machine
+}
+
+/// Returns a machine which calls `program` at the start of RAM: `ra` points to an `unimp` behind
+/// it and the stack is at the end of RAM.
+pub fn call(program: &[u32]) -> Machine {
+    let mut machine = machine(&[program, &[UNIMP]].concat());
+    machine.registers[1] = MEMORY_START as u32 + 4 * program.len() as u32;
+    machine.registers[2] = (MEMORY_START + MEMORY_SIZE) as u32;
+    machine
+}
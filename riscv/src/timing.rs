@@ -0,0 +1,260 @@
+use crate::{Instruction, Retired};
+
+/// Additional cycles of each access to the addresses `start..end`, e.g., a flash behind a slow
+/// bus.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WaitStates {
+    pub start: u32,
+    pub end: u32,
+    pub cycles: u64,
+}
+
+/// The latencies in cycles of an in-order core, by instruction class.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Latencies {
+    /// The cycles of every instruction without stalls.
+    pub base: u64,
+    /// The stall of an instruction which reads the destination of the load right before it.
+    pub load_use: u64,
+    /// The penalty of a taken branch.
+    pub taken_branch: u64,
+    /// The penalty of JAL, JALR, FENCE.I and SFENCE.VMA, which refetch the following instructions.
+    pub jump: u64,
+    /// The penalty of a mispredicted branch or jump with a branch predictor, which replaces
+    /// `taken_branch` and the penalty of JAL and JALR.
+    pub mispredict: u64,
+    /// Apply to instruction fetches, loads and stores. The first region containing the address
+    /// applies.
+    pub wait_states: Vec<WaitStates>,
+}
+
+impl Latencies {
+    fn wait_states(&self, address: u32) -> u64 {
+        self.wait_states
+            .iter()
+            .find(|region| (region.start..region.end).contains(&address))
+            .map_or(0, |region| region.cycles)
+    }
+}
+
+impl Default for Latencies {
+    /// A classic 5-stage pipeline with forwarding, which resolves branches and jumps in the
+    /// execute stage.
+    fn default() -> Latencies {
+        Latencies {
+            base: 1,
+            load_use: 1,
+            taken_branch: 2,
+            jump: 2,
+            mispredict: 2,
+            wait_states: Vec::new(),
+        }
+    }
+}
+
+/// The cycles a retired instruction took and what [`Timing::revert`] needs to undo it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Cost {
+    pub cycles: u64,
+    previous_load: Option<u32>,
+}
+
+/// Estimates the cycles retired instructions take. Disabled by default, then every instruction
+/// takes a single cycle, so the cycle count equals the number of retired instructions.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Timing {
+    latencies: Option<Latencies>,
+    /// The destination of the previously retired instruction if it was a load.
+    pending_load: Option<u32>,
+}
+
+impl Timing {
+    pub fn new(latencies: Latencies) -> Timing {
+        Timing {
+            latencies: Some(latencies),
+            pending_load: None,
+        }
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.latencies.is_some()
+    }
+
+    pub fn latencies(&self) -> Option<&Latencies> {
+        self.latencies.as_ref()
+    }
+
+    /// Returns the cycles `retired` took, which depend on the instruction retired before it.
+    /// `mispredicted` is the outcome of a branch predictor for branches and jumps, `None` without
+    /// one.
+    #[inline]
+    pub fn retire(&mut self, retired: &Retired, mispredicted: Option<bool>) -> Cost {
+        let previous_load = self.pending_load;
+        let Some(latencies) = &self.latencies else {
+            return Cost {
+                cycles: 1,
+                previous_load,
+            };
+        };
+        let mut cycles = latencies.base + latencies.wait_states(retired.pc);
+        if let Some(address) = retired.load.or(retired.store.map(|store| store.address)) {
+            cycles += latencies.wait_states(address);
+        }
+        if previous_load.is_some_and(|load| retired.instruction.sources().contains(&load)) {
+            cycles += latencies.load_use;
+        }
+        match retired.instruction {
+            _ if mispredicted == Some(true) => cycles += latencies.mispredict,
+            _ if mispredicted == Some(false) => {}
+            Instruction::JAL(_)
+            | Instruction::JALR(_)
+            | Instruction::FENCE_I(_)
+            | Instruction::SFENCE_VMA(_) => cycles += latencies.jump,
+            Instruction::BEQ(_)
+            | Instruction::BNE(_)
+            | Instruction::BLT(_)
+            | Instruction::BGE(_)
+            | Instruction::BLTU(_)
+            | Instruction::BGEU(_)
+                if retired.next_pc != retired.pc.wrapping_add(4) =>
+            {
+                cycles += latencies.taken_branch
+            }
+            _ => {}
+        }
+        self.pending_load = retired
+            .load
+            .and(retired.rd)
+            .map(|register_write| register_write.register);
+        Cost {
+            cycles,
+            previous_load,
+        }
+    }
+
+    /// Returns the cycles of a trap, which refetches from the handler like a jump and takes no
+    /// cycles without latencies.
+    pub fn trap(&mut self) -> Cost {
+        let previous_load = self.pending_load.take();
+        Cost {
+            cycles: self
+                .latencies
+                .as_ref()
+                .map_or(0, |latencies| latencies.jump),
+            previous_load,
+        }
+    }
+
+    /// Forgets the previously retired instruction, e.g., when execution continues elsewhere.
+    pub fn flush(&mut self) {
+        self.pending_load = None;
+    }
+
+    /// Reverts the [`Timing::retire`] which returned `cost`.
+    pub fn revert(&mut self, cost: Cost) {
+        self.pending_load = cost.previous_load;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        test_utils::{self, UNIMP},
+        timing::{Latencies, Timing, WaitStates},
+        History, Machine, MEMORY_START,
+    };
+
+    /// Runs the program to its end and returns the cycles of each instruction.
+    fn cycles(machine: &mut Machine) -> Vec<u64> {
+        let mut cycles = Vec::new();
+        loop {
+            let cycle = machine.cycle;
+            let done = machine.step().unwrap();
+            cycles.push(machine.cycle - cycle);
+            if done {
+                return cycles;
+            }
+        }
+    }
+
+    #[test]
+    fn disabled() {
+        let mut machine = test_utils::machine(&[0x00000013, 0x0000006f]); // nop, j 4
+        machine.run(10).unwrap();
+        assert_eq!(machine.cycle, 10);
+        assert_eq!(machine.instret, 10);
+    }
+
+    #[test]
+    fn stalls() {
+        let mut machine = test_utils::machine(&[
+            0x00000013, // nop
+            0x00012503, // lw a0, 0(sp)
+            0x00150593, // addi a1, a0, 1 (load-use)
+            0x00012503, // lw a0, 0(sp)
+            0x00000013, // nop
+            0x00150593, // addi a1, a0, 1
+            0x00000463, // beqz zero, 8 (taken)
+            0x00000013, // nop
+            0x00001463, // bnez zero, 8 (not taken)
+            0x008000ef, // jal ra, 8
+            0x00000013, // nop
+            UNIMP,
+        ]);
+        machine.registers[2] = MEMORY_START as u32 + 0x1000;
+        machine.timing = Timing::new(Latencies::default());
+        assert_eq!(cycles(&mut machine), [1, 1, 2, 1, 1, 1, 3, 1, 3, 1]);
+    }
+
+    #[test]
+    fn wait_states() {
+        let start = MEMORY_START as u32;
+        let mut machine = test_utils::machine(&[
+            0x00000013, // nop
+            0x00012503, // lw a0, 0(sp)
+            0x00212223, // sw sp, 4(sp)
+            UNIMP,
+        ]);
+        machine.registers[2] = MEMORY_START as u32 + 0x1000;
+        machine.timing = Timing::new(Latencies {
+            wait_states: vec![
+                WaitStates {
+                    start,
+                    end: start + 8,
+                    cycles: 3,
+                },
+                WaitStates {
+                    start,
+                    end: start + 0x2000,
+                    cycles: 1,
+                },
+            ],
+            ..Latencies::default()
+        });
+        // fetches from the first 8 bytes wait 3 cycles, all other accesses wait a cycle
+        assert_eq!(cycles(&mut machine), [1 + 3, 1 + 3 + 1, 1 + 1 + 1, 1 + 1]);
+    }
+
+    #[test]
+    fn step_back() {
+        let mut machine = test_utils::machine(&[
+            0x00012503, // lw a0, 0(sp)
+            0x00150593, // addi a1, a0, 1 (load-use)
+            UNIMP,
+        ]);
+        machine.registers[2] = MEMORY_START as u32 + 0x1000;
+        machine.timing = Timing::new(Latencies::default());
+        machine.history = History::new(10);
+        machine.step().unwrap();
+        machine.step().unwrap();
+        assert_eq!(machine.cycle, 1 + 2);
+        assert!(machine.step_back());
+        assert_eq!(machine.cycle, 1);
+        // the load is still pending
+        machine.step().unwrap();
+        assert_eq!(machine.cycle, 1 + 2);
+        assert!(machine.step_back());
+        assert!(machine.step_back());
+        assert_eq!(machine.cycle, 0);
+    }
+}
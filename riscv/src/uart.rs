@@ -0,0 +1,296 @@
+//! A 16550-compatible UART, the serial port most RISC-V firmware and kernels have a driver for.
+//! Its registers are bytes, the divisor latch replaces the data and interrupt enable registers
+//! while `LCR.DLAB` is set.
+//!
+//! The host reads the transmitted bytes from [`Uart::output`] and delivers input with
+//! [`Uart::receive`] into the 16-byte receive FIFO. Transmitting takes no time, the transmitter is
+//! always empty, and the baud rate and line settings are only stored. The UART raises its
+//! interrupt line while received data is available, a receive FIFO overrun happened or the
+//! transmitter became empty, as enabled in `IER`.
+
+use std::collections::VecDeque;
+
+/// The size of the UART's address range.
+pub const UART_SIZE: u32 = 0x100;
+/// The depth of the receive FIFO.
+pub const UART_FIFO_SIZE: usize = 16;
+
+// register offsets
+/// The receive buffer for loads, the transmit holding register for stores.
+pub const RBR_THR: u32 = 0;
+pub const IER: u32 = 1;
+/// The interrupt identification for loads, the FIFO control for stores.
+pub const IIR_FCR: u32 = 2;
+pub const LCR: u32 = 3;
+pub const MCR: u32 = 4;
+pub const LSR: u32 = 5;
+pub const MSR: u32 = 6;
+pub const SCR: u32 = 7;
+
+// interrupt enables
+pub const IER_RDA: u8 = 1 << 0;
+pub const IER_THRE: u8 = 1 << 1;
+pub const IER_RLS: u8 = 1 << 2;
+const IER_MASK: u8 = 0x0f;
+
+// interrupt identifications, bit 0 is clear while one is pending
+pub const IIR_NONE: u8 = 0x01;
+pub const IIR_RLS: u8 = 0x06;
+pub const IIR_RDA: u8 = 0x04;
+pub const IIR_THRE: u8 = 0x02;
+const IIR_FIFO: u8 = 0xc0;
+
+const FCR_ENABLE: u8 = 1 << 0;
+const FCR_CLEAR_RX: u8 = 1 << 1;
+
+pub const LCR_DLAB: u8 = 1 << 7;
+const MCR_LOOP: u8 = 1 << 4;
+
+// line status
+pub const LSR_DR: u8 = 1 << 0;
+pub const LSR_OE: u8 = 1 << 1;
+pub const LSR_THRE: u8 = 1 << 5;
+pub const LSR_TEMT: u8 = 1 << 6;
+
+/// Clear to send, data set ready and data carrier detect, the modem is always ready.
+const MSR_READY: u8 = 0xb0;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Uart {
+    pub rx: VecDeque<u8>,
+    /// The transmitted bytes the host hasn't taken yet.
+    pub output: Vec<u8>,
+    pub ier: u8,
+    pub lcr: u8,
+    pub mcr: u8,
+    pub scr: u8,
+    /// The divisor latch, low and high byte.
+    pub divisor: u16,
+    pub fifo_enabled: bool,
+    /// Set when the receive FIFO overflowed, until `LSR` is read.
+    pub overrun: bool,
+    /// Set when the transmitter became empty, until `IIR` reports it or `THR` is written.
+    pub thre_pending: bool,
+}
+
+impl Default for Uart {
+    fn default() -> Uart {
+        Uart {
+            rx: VecDeque::with_capacity(UART_FIFO_SIZE),
+            output: Vec::new(),
+            ier: 0,
+            lcr: 0,
+            mcr: 0,
+            scr: 0,
+            divisor: 0,
+            fifo_enabled: false,
+            overrun: false,
+            thre_pending: false,
+        }
+    }
+}
+
+impl Uart {
+    /// Puts a byte from the host into the receive FIFO. Returns `false` and flags an overrun if
+    /// the FIFO is full.
+    pub fn receive(&mut self, byte: u8) -> bool {
+        if self.rx.len() == UART_FIFO_SIZE {
+            self.overrun = true;
+            return false;
+        }
+        self.rx.push_back(byte);
+        true
+    }
+
+    /// Takes the bytes transmitted since the last call.
+    pub fn take_output(&mut self) -> Vec<u8> {
+        std::mem::take(&mut self.output)
+    }
+
+    /// The pending interrupt with the highest priority, as reported by `IIR`.
+    fn identification(&self) -> u8 {
+        if self.ier & IER_RLS != 0 && self.overrun {
+            IIR_RLS
+        } else if self.ier & IER_RDA != 0 && !self.rx.is_empty() {
+            IIR_RDA
+        } else if self.ier & IER_THRE != 0 && self.thre_pending {
+            IIR_THRE
+        } else {
+            IIR_NONE
+        }
+    }
+
+    /// Whether the interrupt line is raised.
+    pub fn interrupt(&self) -> bool {
+        self.identification() != IIR_NONE
+    }
+
+    fn line_status(&self) -> u8 {
+        let ready = if self.rx.is_empty() { 0 } else { LSR_DR };
+        let overrun = if self.overrun { LSR_OE } else { 0 };
+        ready | overrun | LSR_THRE | LSR_TEMT
+    }
+
+    /// Reads the register at `offset`, `None` if there is none. Reading the receive buffer pops
+    /// the FIFO, and reading `LSR` and `IIR` clears the conditions they report.
+    pub fn load(&mut self, offset: u32) -> Option<u8> {
+        let dlab = self.lcr & LCR_DLAB != 0;
+        Some(match offset {
+            RBR_THR if dlab => self.divisor as u8,
+            IER if dlab => (self.divisor >> 8) as u8,
+            RBR_THR => self.rx.pop_front().unwrap_or(0),
+            IER => self.ier,
+            IIR_FCR => {
+                let identification = self.identification();
+                if identification == IIR_THRE {
+                    self.thre_pending = false;
+                }
+                let fifo = if self.fifo_enabled { IIR_FIFO } else { 0 };
+                identification | fifo
+            }
+            LCR => self.lcr,
+            MCR => self.mcr,
+            LSR => {
+                let status = self.line_status();
+                self.overrun = false;
+                status
+            }
+            // in loopback mode, the modem control outputs are wired to the status inputs
+            MSR if self.mcr & MCR_LOOP != 0 => {
+                let mcr = self.mcr;
+                (mcr & 0b10) << 3 | (mcr & 0b01) << 5 | (mcr & 0b1100) << 4
+            }
+            MSR => MSR_READY,
+            SCR => self.scr,
+            _ => return None,
+        })
+    }
+
+    /// Writes `value` to the register at `offset`, `None` if there is none.
+    pub fn store(&mut self, offset: u32, value: u8) -> Option<()> {
+        let dlab = self.lcr & LCR_DLAB != 0;
+        match offset {
+            RBR_THR if dlab => self.divisor = self.divisor & 0xff00 | value as u16,
+            IER if dlab => self.divisor = self.divisor & 0x00ff | (value as u16) << 8,
+            RBR_THR => {
+                if self.mcr & MCR_LOOP != 0 {
+                    self.receive(value);
+                } else {
+                    self.output.push(value);
+                }
+                // the byte is sent right away
+                self.thre_pending = true;
+            }
+            IER => {
+                // enabling the interrupt reports the empty transmitter
+                if value & IER_THRE != 0 && self.ier & IER_THRE == 0 {
+                    self.thre_pending = true;
+                }
+                self.ier = value & IER_MASK;
+            }
+            IIR_FCR => {
+                self.fifo_enabled = value & FCR_ENABLE != 0;
+                if value & FCR_CLEAR_RX != 0 {
+                    self.rx.clear();
+                }
+            }
+            LCR => self.lcr = value,
+            MCR => self.mcr = value & 0x1f,
+            // the line and modem status are read-only
+            LSR | MSR => {}
+            SCR => self.scr = value,
+            _ => return None,
+        }
+        Some(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        super::*,
+        crate::{blocks::Engine, test_utils},
+    };
+
+    #[test]
+    fn registers() {
+        let mut uart = Uart::default();
+        assert_eq!(uart.load(LSR), Some(LSR_THRE | LSR_TEMT));
+        assert_eq!(uart.load(IIR_FCR), Some(IIR_NONE));
+        assert_eq!(uart.load(MSR), Some(MSR_READY));
+
+        // the divisor latch shadows the data and interrupt enable registers
+        assert_eq!(uart.store(LCR, LCR_DLAB | 0b11), Some(()));
+        assert_eq!(uart.store(RBR_THR, 0x34), Some(()));
+        assert_eq!(uart.store(IER, 0x12), Some(()));
+        assert_eq!((uart.divisor, uart.ier), (0x1234, 0));
+        assert_eq!(uart.store(LCR, 0b11), Some(()));
+
+        assert_eq!(uart.store(RBR_THR, b'a'), Some(()));
+        assert_eq!(uart.take_output(), b"a");
+        assert!(uart.output.is_empty());
+
+        // received data, overruns and the empty transmitter interrupt by priority
+        assert_eq!(uart.store(IIR_FCR, FCR_ENABLE), Some(()));
+        assert_eq!(uart.store(IER, IER_RDA | IER_THRE | IER_RLS), Some(()));
+        for byte in 0..UART_FIFO_SIZE as u8 {
+            assert!(uart.receive(byte));
+        }
+        assert!(!uart.receive(0xff));
+        assert_eq!(uart.load(IIR_FCR), Some(IIR_FIFO | IIR_RLS));
+        assert_eq!(uart.load(LSR), Some(LSR_DR | LSR_OE | LSR_THRE | LSR_TEMT));
+        assert_eq!(uart.load(IIR_FCR), Some(IIR_FIFO | IIR_RDA));
+        assert_eq!(uart.load(RBR_THR), Some(0));
+        assert_eq!(uart.store(IIR_FCR, FCR_ENABLE | FCR_CLEAR_RX), Some(()));
+        assert_eq!(uart.load(RBR_THR), Some(0));
+        // reading IIR acknowledges the empty transmitter
+        assert!(uart.interrupt());
+        assert_eq!(uart.load(IIR_FCR), Some(IIR_FIFO | IIR_THRE));
+        assert!(!uart.interrupt());
+        assert_eq!(uart.load(IIR_FCR), Some(IIR_FIFO | IIR_NONE));
+
+        // loopback
+        assert_eq!(uart.store(MCR, MCR_LOOP | 0b1010), Some(()));
+        assert_eq!(uart.store(RBR_THR, b'b'), Some(()));
+        assert_eq!(uart.load(RBR_THR), Some(b'b'));
+        assert_eq!(uart.load(MSR), Some(0x90));
+        assert!(uart.output.is_empty());
+        assert_eq!(uart.load(8), None);
+    }
+
+    // Prints "hi\n" while polling LSR.THRE, then echoes the first received byte.
+    const ECHO: &[u32] = &[
+        0x100002b7, // li t0, 0x10000000
+        0x00000317, // la t1, message
+        0x04030313, //
+        0x00034383, // loop: lbu t2, 0(t1)
+        0x00038e63, // beqz t2, echo
+        0x0052ce03, // wait: lbu t3, 5(t0)
+        0x020e7e13, // andi t3, t3, 0x20
+        0xfe0e0ce3, // beqz t3, wait
+        0x00728023, // sb t2, 0(t0)
+        0x00130313, // addi t1, t1, 1
+        0xfe5ff06f, // j loop
+        0x0052ce03, // echo: lbu t3, 5(t0)
+        0x001e7e13, // andi t3, t3, 1
+        0xfe0e0ce3, // beqz t3, echo
+        0x0002c383, // lbu t2, 0(t0)
+        0x00728023, // sb t2, 0(t0)
+        0xc0001073, // unimp
+        0x000a6968, // message: .asciz "hi\n"
+    ];
+
+    #[test]
+    fn echo() {
+        for engine in [Engine::Interpreter, Engine::Blocks] {
+            let mut machine = test_utils::machine(ECHO);
+            machine.engine = engine;
+
+            assert_eq!(machine.run(1000), Ok(false));
+            assert_eq!(machine.devices.uart.take_output(), b"hi\n");
+            assert_eq!(machine.devices.receive(b"x"), 1);
+            assert_eq!(machine.run(1000), Ok(true));
+            assert_eq!(machine.devices.uart.take_output(), b"x");
+        }
+    }
+}
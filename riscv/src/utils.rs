@@ -1,6 +1,7 @@
-use std::convert::TryInto;
-
-use crate::{Memory, Registers, MEMORY_START, PC};
+use {
+    crate::{Memory, Registers, MEMORY_START, PC},
+    std::{convert::TryInto, ops::Range},
+};
 
 pub const REGISTER_NAMES: [&str; 33] = [
     "zero", "ra", "sp", "gp", "tp", "t0", "t1", "t2", "s0", "s1", "a0", "a1", "a2", "a3", "a4",
@@ -12,48 +13,58 @@ pub fn sign_extend(number: u32, bit: u8) -> u32 {
     (number ^ (1 << bit)).overflowing_sub(1 << bit).0
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub struct MemoryError {
     pub address: u32,
+    pub size: u32,
+}
+
+/// Returns the range of `memory` backing `size` bytes at `address`, or an error if any of them
+/// lies outside of RAM.
+fn range(memory: &Memory, address: u32, size: usize) -> Result<Range<usize>, MemoryError> {
+    let error = MemoryError {
+        address,
+        size: size as u32,
+    };
+    let start = (address as usize).checked_sub(MEMORY_START).ok_or(error)?;
+    let end = start
+        .checked_add(size)
+        .filter(|&end| end <= memory.len())
+        .ok_or(error)?;
+    Ok(start..end)
+}
+
+pub fn load_byte(memory: &Memory, address: u32) -> Result<u8, MemoryError> {
+    Ok(memory[range(memory, address, 1)?][0])
+}
+
+pub fn load_half_word(memory: &Memory, address: u32) -> Result<u16, MemoryError> {
+    Ok(u16::from_le_bytes(
+        memory[range(memory, address, 2)?].try_into().unwrap(),
+    ))
 }
 
 pub fn load_word(memory: &Memory, address: u32) -> Result<u32, MemoryError> {
-    let index = address as usize - MEMORY_START;
     Ok(u32::from_le_bytes(
-        memory
-            .get(index..index + 4)
-            .ok_or(MemoryError { address })?
-            .try_into()
-            .unwrap(),
+        memory[range(memory, address, 4)?].try_into().unwrap(),
     ))
 }
 
 pub fn store_word(memory: &mut Memory, address: u32, value: u32) -> Result<(), MemoryError> {
-    let index = address as usize - MEMORY_START;
-    memory
-        .get_mut(index..index + 4)
-        .ok_or(MemoryError { address })?
-        .copy_from_slice(&[
-            value as u8,
-            (value >> 8) as u8,
-            (value >> 16) as u8,
-            (value >> 24) as u8,
-        ]);
+    let range = range(memory, address, 4)?;
+    memory[range].copy_from_slice(&value.to_le_bytes());
     Ok(())
 }
 
 pub fn store_half_word(memory: &mut Memory, address: u32, value: u16) -> Result<(), MemoryError> {
-    let index = address as usize - MEMORY_START;
-    memory
-        .get_mut(index..index + 2)
-        .ok_or(MemoryError { address })?
-        .copy_from_slice(&[value as u8, (value >> 8) as u8]);
+    let range = range(memory, address, 2)?;
+    memory[range].copy_from_slice(&value.to_le_bytes());
     Ok(())
 }
 
 pub fn store_byte(memory: &mut Memory, address: u32, value: u8) -> Result<(), MemoryError> {
-    let index = address as usize - MEMORY_START;
-    *memory.get_mut(index).ok_or(MemoryError { address })? = value;
+    let range = range(memory, address, 1)?;
+    memory[range][0] = value;
     Ok(())
 }
 
@@ -76,3 +87,91 @@ pub fn dump_registers(registers: &Registers) -> String {
     result += &format!("╰{0:}┴{0:}┴{0:}┴{0:}╯\n", filler);
     result
 }
+
+#[cfg(test)]
+mod tests {
+    use {super::*, crate::MEMORY_SIZE};
+
+    #[test]
+    fn sign_extend_boundaries() {
+        for bit in 0..32u8 {
+            let sign = 1u32 << bit;
+            let max = sign - 1;
+            let min = sign;
+            let all = sign | max;
+            // positive values are unchanged
+            assert_eq!(sign_extend(0, bit), 0, "bit: {bit}");
+            assert_eq!(sign_extend(max, bit), max, "bit: {bit}");
+            // negative values get all upper bits set
+            assert_eq!(sign_extend(min, bit), !max, "bit: {bit}");
+            assert_eq!(sign_extend(all, bit), u32::MAX, "bit: {bit}");
+            if bit > 0 {
+                assert_eq!(sign_extend(1, bit), 1, "bit: {bit}");
+                assert_eq!(sign_extend(min | 1, bit), !max | 1, "bit: {bit}");
+            }
+        }
+        assert_eq!(sign_extend(0x80, 7), 0xffff_ff80);
+        assert_eq!(sign_extend(0x7fff, 15), 0x0000_7fff);
+        assert_eq!(sign_extend(0x8000, 15), 0xffff_8000);
+    }
+
+    const END: u32 = (MEMORY_START + MEMORY_SIZE) as u32;
+
+    fn in_memory(address: u32, size: u32) -> bool {
+        address >= MEMORY_START as u32 && address as u64 + size as u64 <= END as u64
+    }
+
+    #[test]
+    fn memory_address_space_edges() {
+        let mut memory = crate::Machine::new().memory;
+        let addresses = [
+            0,
+            1,
+            0x7fff_ffff,
+            MEMORY_START as u32 - 4,
+            MEMORY_START as u32 - 1,
+            MEMORY_START as u32,
+            MEMORY_START as u32 + 1,
+            END - 4,
+            END - 3,
+            END - 2,
+            END - 1,
+            END,
+            END + 1,
+            u32::MAX - 3,
+            u32::MAX - 1,
+            u32::MAX,
+        ];
+        for address in addresses {
+            let result = store_byte(&mut memory, address, 0xab);
+            assert_eq!(result.is_ok(), in_memory(address, 1), "{address:08x}");
+            let result = store_half_word(&mut memory, address, 0xabcd);
+            assert_eq!(result.is_ok(), in_memory(address, 2), "{address:08x}");
+            let result = store_word(&mut memory, address, 0xabcd_ef01);
+            assert_eq!(result.is_ok(), in_memory(address, 4), "{address:08x}");
+
+            let result = load_byte(&memory, address);
+            assert_eq!(result.is_ok(), in_memory(address, 1), "{address:08x}");
+            let result = load_half_word(&memory, address);
+            assert_eq!(result.is_ok(), in_memory(address, 2), "{address:08x}");
+            let result = load_word(&memory, address);
+            assert_eq!(result.is_ok(), in_memory(address, 4), "{address:08x}");
+            if let Err(error) = result {
+                assert_eq!(error.address, address);
+            }
+        }
+    }
+
+    #[test]
+    fn memory_little_endian() {
+        let mut memory = crate::Machine::new().memory;
+        store_word(&mut memory, END - 4, 0x1234_5678).unwrap();
+        assert_eq!(memory[MEMORY_SIZE - 4..], [0x78, 0x56, 0x34, 0x12]);
+        assert_eq!(load_byte(&memory, END - 1).unwrap(), 0x12);
+        assert_eq!(load_half_word(&memory, END - 2).unwrap(), 0x1234);
+        assert_eq!(load_half_word(&memory, END - 3).unwrap(), 0x3456);
+        store_half_word(&mut memory, END - 3, 0xabcd).unwrap();
+        store_byte(&mut memory, END - 4, 0xef).unwrap();
+        assert_eq!(load_word(&memory, END - 4).unwrap(), 0x12ab_cdef);
+    }
+}
@@ -0,0 +1,842 @@
+//! A virtio block device with the MMIO transport of virtio 1.1 (version 2) and a single split
+//! virtqueue, the disk of Linux and xv6.
+//!
+//! The driver negotiates the features, places the descriptor table, the available (driver) ring
+//! and the used (device) ring of the request queue in RAM and notifies the device of new requests
+//! by writing the queue's index to `QueueNotify`. Requests are served right after the store which
+//! notified the device, by [`Devices::serve`](crate::devices::Devices::serve), which reads and
+//! writes RAM directly. Reads and writes take no time, the device then raises its interrupt line
+//! with the used buffer notification in `InterruptStatus`. Malformed requests, e.g., with
+//! descriptors outside of RAM, set `DEVICE_NEEDS_RESET` and stop the queue.
+//!
+//! The [`Disk`] is a buffer of whole sectors. The host fills it, e.g., from an image file, and
+//! writes the sectors back which [`Disk::take_dirty`] reports.
+
+use {
+    crate::{Memory, MEMORY_START},
+    std::ops::Range,
+};
+
+/// The size of the device's address range.
+pub const VIRTIO_SIZE: u32 = 0x1000;
+/// The number of descriptors the request queue can have at most.
+pub const VIRTIO_QUEUE_SIZE: u32 = 128;
+/// The size of a sector, the unit of addresses and lengths of requests.
+pub const SECTOR_SIZE: usize = 512;
+
+// register offsets, all registers are 32 bits wide
+pub const MAGIC_VALUE: u32 = 0x000;
+pub const VERSION: u32 = 0x004;
+pub const DEVICE_ID: u32 = 0x008;
+pub const VENDOR_ID: u32 = 0x00c;
+pub const DEVICE_FEATURES: u32 = 0x010;
+pub const DEVICE_FEATURES_SEL: u32 = 0x014;
+pub const DRIVER_FEATURES: u32 = 0x020;
+pub const DRIVER_FEATURES_SEL: u32 = 0x024;
+pub const QUEUE_SEL: u32 = 0x030;
+pub const QUEUE_NUM_MAX: u32 = 0x034;
+pub const QUEUE_NUM: u32 = 0x038;
+pub const QUEUE_READY: u32 = 0x044;
+pub const QUEUE_NOTIFY: u32 = 0x050;
+pub const INTERRUPT_STATUS: u32 = 0x060;
+pub const INTERRUPT_ACK: u32 = 0x064;
+pub const STATUS: u32 = 0x070;
+pub const QUEUE_DESC_LOW: u32 = 0x080;
+pub const QUEUE_DESC_HIGH: u32 = 0x084;
+pub const QUEUE_DRIVER_LOW: u32 = 0x090;
+pub const QUEUE_DRIVER_HIGH: u32 = 0x094;
+pub const QUEUE_DEVICE_LOW: u32 = 0x0a0;
+pub const QUEUE_DEVICE_HIGH: u32 = 0x0a4;
+pub const CONFIG_GENERATION: u32 = 0x0fc;
+/// The configuration of the block device, its capacity in sectors as `u64`.
+pub const CONFIG: u32 = 0x100;
+const CONFIG_SIZE: u32 = 8;
+
+/// "virt"
+const MAGIC: u32 = 0x7472_6976;
+const BLOCK_DEVICE: u32 = 2;
+/// "QEMU", which drivers don't care about.
+const VENDOR: u32 = 0x554d_4551;
+
+// feature bits
+pub const VIRTIO_BLK_F_RO: u64 = 1 << 5;
+pub const VIRTIO_BLK_F_FLUSH: u64 = 1 << 9;
+pub const VIRTIO_F_VERSION_1: u64 = 1 << 32;
+
+// device status bits
+pub const STATUS_ACKNOWLEDGE: u32 = 1;
+pub const STATUS_DRIVER: u32 = 2;
+pub const STATUS_DRIVER_OK: u32 = 4;
+pub const STATUS_FEATURES_OK: u32 = 8;
+pub const STATUS_NEEDS_RESET: u32 = 0x40;
+pub const STATUS_FAILED: u32 = 0x80;
+
+// interrupt status bits
+pub const INTERRUPT_USED_BUFFER: u32 = 1;
+pub const INTERRUPT_CONFIG_CHANGE: u32 = 2;
+
+// descriptor flags
+const DESC_F_NEXT: u16 = 1;
+const DESC_F_WRITE: u16 = 2;
+/// Set in the flags of the available ring if the driver doesn't want interrupts.
+const AVAIL_F_NO_INTERRUPT: u16 = 1;
+
+// request types
+pub const VIRTIO_BLK_T_IN: u32 = 0;
+pub const VIRTIO_BLK_T_OUT: u32 = 1;
+pub const VIRTIO_BLK_T_FLUSH: u32 = 4;
+pub const VIRTIO_BLK_T_GET_ID: u32 = 8;
+
+// request status
+pub const VIRTIO_BLK_S_OK: u8 = 0;
+pub const VIRTIO_BLK_S_IOERR: u8 = 1;
+pub const VIRTIO_BLK_S_UNSUPP: u8 = 2;
+
+/// The device ID requests of type `VIRTIO_BLK_T_GET_ID` return, at most 20 bytes.
+const ID: &[u8] = b"riscv-core";
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum DiskMode {
+    /// Writes change the disk and are reported by [`Disk::take_dirty`].
+    #[default]
+    ReadWrite = 0,
+    /// Writes only change the disk in memory, the host keeps the image as it was.
+    CopyOnWrite = 1,
+    /// The device offers `VIRTIO_BLK_F_RO` and fails writes.
+    ReadOnly = 2,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ConfigError {
+    /// The disk has no sectors.
+    Empty,
+    /// The size of the disk isn't a multiple of [`SECTOR_SIZE`].
+    PartialSector,
+    /// The disk is 4 GiB or larger.
+    TooLarge,
+}
+
+impl std::fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            ConfigError::Empty => f.write_str("The disk is empty"),
+            ConfigError::PartialSector => {
+                write!(f, "The disk size is not a multiple of {SECTOR_SIZE} bytes")
+            }
+            ConfigError::TooLarge => f.write_str("The disk is 4 GiB or larger"),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Disk {
+    data: Vec<u8>,
+    mode: DiskMode,
+    /// Whether each sector was written since the last [`Disk::take_dirty`], only in
+    /// [`DiskMode::ReadWrite`].
+    dirty: Vec<bool>,
+}
+
+impl Disk {
+    pub fn new(data: Vec<u8>, mode: DiskMode) -> Result<Disk, ConfigError> {
+        if data.is_empty() {
+            return Err(ConfigError::Empty);
+        }
+        if data.len() % SECTOR_SIZE != 0 {
+            return Err(ConfigError::PartialSector);
+        }
+        if data.len() > u32::MAX as usize {
+            return Err(ConfigError::TooLarge);
+        }
+        let dirty = vec![false; data.len() / SECTOR_SIZE];
+        Ok(Disk { data, mode, dirty })
+    }
+
+    pub fn data(&self) -> &[u8] {
+        &self.data
+    }
+
+    pub fn mode(&self) -> DiskMode {
+        self.mode
+    }
+
+    /// The number of sectors.
+    pub fn sectors(&self) -> u64 {
+        (self.data.len() / SECTOR_SIZE) as u64
+    }
+
+    /// The bytes of `length` sectors starting at `sector`, `None` if they are past the end.
+    fn sectors_range(&self, sector: u64, length: usize) -> Option<Range<usize>> {
+        let start = usize::try_from(sector).ok()?.checked_mul(SECTOR_SIZE)?;
+        let end = start.checked_add(length * SECTOR_SIZE)?;
+        (end <= self.data.len()).then_some(start..end)
+    }
+
+    /// Writes `data`, whole sectors, at `sector`. Returns `false` if the disk is read-only or
+    /// they are past the end.
+    fn write(&mut self, sector: u64, data: &[u8]) -> bool {
+        let Some(range) = self.sectors_range(sector, data.len() / SECTOR_SIZE) else {
+            return false;
+        };
+        match self.mode {
+            DiskMode::ReadOnly => return false,
+            DiskMode::ReadWrite => {
+                self.dirty[range.start / SECTOR_SIZE..range.end / SECTOR_SIZE].fill(true)
+            }
+            DiskMode::CopyOnWrite => {}
+        }
+        self.data[range].copy_from_slice(data);
+        true
+    }
+
+    /// Marks every sector dirty in [`DiskMode::ReadWrite`], after the contents changed as a whole.
+    pub(crate) fn touch(&mut self) {
+        if self.mode == DiskMode::ReadWrite {
+            self.dirty.fill(true);
+        }
+    }
+
+    /// Takes the runs of sectors written since the last call, which the host should write back
+    /// to the image. Always empty unless the disk is in [`DiskMode::ReadWrite`].
+    pub fn take_dirty(&mut self) -> Vec<Range<u64>> {
+        let mut runs: Vec<Range<u64>> = Vec::new();
+        for (sector, dirty) in self.dirty.iter_mut().enumerate() {
+            if !std::mem::take(dirty) {
+                continue;
+            }
+            let sector = sector as u64;
+            match runs.last_mut() {
+                Some(run) if run.end == sector => run.end += 1,
+                _ => runs.push(sector..sector + 1),
+            }
+        }
+        runs
+    }
+}
+
+/// The request queue, as configured by the driver.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Queue {
+    /// The number of descriptors, a power of 2 up to [`VIRTIO_QUEUE_SIZE`].
+    pub size: u32,
+    pub ready: bool,
+    /// The guest physical addresses of the descriptor table, the available and the used ring.
+    pub desc: u64,
+    pub driver: u64,
+    pub device: u64,
+    /// The index of the next request in the available ring.
+    pub last_avail: u16,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VirtioBlock {
+    pub disk: Disk,
+    pub status: u32,
+    pub device_features_sel: u32,
+    pub driver_features: u64,
+    pub driver_features_sel: u32,
+    pub queue_sel: u32,
+    pub queue: Queue,
+    pub interrupt_status: u32,
+    /// Set by `QueueNotify` until the requests are served.
+    pub notified: bool,
+}
+
+impl VirtioBlock {
+    pub fn new(disk: Disk) -> VirtioBlock {
+        VirtioBlock {
+            disk,
+            status: 0,
+            device_features_sel: 0,
+            driver_features: 0,
+            driver_features_sel: 0,
+            queue_sel: 0,
+            queue: Queue::default(),
+            interrupt_status: 0,
+            notified: false,
+        }
+    }
+
+    /// The features the device offers.
+    pub fn device_features(&self) -> u64 {
+        let read_only = match self.disk.mode {
+            DiskMode::ReadOnly => VIRTIO_BLK_F_RO,
+            _ => 0,
+        };
+        VIRTIO_F_VERSION_1 | VIRTIO_BLK_F_FLUSH | read_only
+    }
+
+    /// Resets the device after the driver wrote 0 to `Status`. The disk is kept.
+    fn reset(&mut self) {
+        self.status = 0;
+        self.device_features_sel = 0;
+        self.driver_features = 0;
+        self.driver_features_sel = 0;
+        self.queue_sel = 0;
+        self.queue = Queue::default();
+        self.interrupt_status = 0;
+        self.notified = false;
+    }
+
+    /// Whether the interrupt line is raised.
+    pub fn interrupt(&self) -> bool {
+        self.interrupt_status != 0
+    }
+
+    /// Reads the `size` bytes at `offset`, `None` if there is no register. The configuration can
+    /// be read with any aligned access, the other registers are words.
+    pub fn load(&self, offset: u32, size: u32) -> Option<u32> {
+        if offset >= CONFIG {
+            let start = (offset - CONFIG) as usize;
+            let config = self.disk.sectors().to_le_bytes();
+            let bytes = config.get(start..start + size as usize)?;
+            return (offset % size == 0).then(|| {
+                bytes
+                    .iter()
+                    .rev()
+                    .fold(0, |value, &byte| value << 8 | byte as u32)
+            });
+        }
+        if size != 4 || offset % 4 != 0 {
+            return None;
+        }
+        let selected = self.queue_sel == 0;
+        Some(match offset {
+            MAGIC_VALUE => MAGIC,
+            VERSION => 2,
+            DEVICE_ID => BLOCK_DEVICE,
+            VENDOR_ID => VENDOR,
+            DEVICE_FEATURES => match self.device_features_sel {
+                0 => self.device_features() as u32,
+                1 => (self.device_features() >> 32) as u32,
+                _ => 0,
+            },
+            QUEUE_NUM_MAX if selected => VIRTIO_QUEUE_SIZE,
+            QUEUE_READY if selected => self.queue.ready as u32,
+            INTERRUPT_STATUS => self.interrupt_status,
+            STATUS => self.status,
+            CONFIG_GENERATION => 0,
+            // write-only registers and those of queues which don't exist
+            DEVICE_FEATURES_SEL | DRIVER_FEATURES | DRIVER_FEATURES_SEL | QUEUE_SEL
+            | QUEUE_NUM_MAX | QUEUE_NUM | QUEUE_READY | QUEUE_NOTIFY | INTERRUPT_ACK
+            | QUEUE_DESC_LOW | QUEUE_DESC_HIGH | QUEUE_DRIVER_LOW | QUEUE_DRIVER_HIGH
+            | QUEUE_DEVICE_LOW | QUEUE_DEVICE_HIGH => 0,
+            _ => return None,
+        })
+    }
+
+    /// Writes the `size` bytes of `value` at `offset`, `None` if there is no register. The
+    /// configuration is read-only.
+    pub fn store(&mut self, offset: u32, size: u32, value: u32) -> Option<()> {
+        if offset >= CONFIG {
+            return (offset < CONFIG + CONFIG_SIZE && offset % size == 0).then_some(());
+        }
+        if size != 4 || offset % 4 != 0 {
+            return None;
+        }
+        let selected = self.queue_sel == 0;
+        let set_low = |address: &mut u64| *address = *address & !0xffff_ffff | value as u64;
+        let set_high = |address: &mut u64| *address = *address & 0xffff_ffff | (value as u64) << 32;
+        match offset {
+            DEVICE_FEATURES_SEL => self.device_features_sel = value,
+            DRIVER_FEATURES => {
+                let shift = match self.driver_features_sel {
+                    0 => 0,
+                    1 => 32,
+                    _ => return Some(()),
+                };
+                // drivers can only accept offered features
+                let features = (value as u64) << shift & self.device_features();
+                self.driver_features = self.driver_features & !(0xffff_ffff << shift) | features;
+            }
+            DRIVER_FEATURES_SEL => self.driver_features_sel = value,
+            QUEUE_SEL => self.queue_sel = value,
+            QUEUE_NUM if selected => self.queue.size = value,
+            QUEUE_READY if selected => self.queue.ready = value & 1 == 1,
+            QUEUE_NOTIFY => self.notified |= value == 0,
+            INTERRUPT_ACK => self.interrupt_status &= !value,
+            STATUS if value == 0 => self.reset(),
+            STATUS => {
+                // the device can't work with legacy drivers
+                let rejected = value & STATUS_FEATURES_OK != 0
+                    && self.driver_features & VIRTIO_F_VERSION_1 == 0;
+                self.status = if rejected {
+                    value & !STATUS_FEATURES_OK
+                } else {
+                    value
+                } & 0xff;
+            }
+            QUEUE_DESC_LOW if selected => set_low(&mut self.queue.desc),
+            QUEUE_DESC_HIGH if selected => set_high(&mut self.queue.desc),
+            QUEUE_DRIVER_LOW if selected => set_low(&mut self.queue.driver),
+            QUEUE_DRIVER_HIGH if selected => set_high(&mut self.queue.driver),
+            QUEUE_DEVICE_LOW if selected => set_low(&mut self.queue.device),
+            QUEUE_DEVICE_HIGH if selected => set_high(&mut self.queue.device),
+            // read-only registers and those of queues which don't exist
+            MAGIC_VALUE | VERSION | DEVICE_ID | VENDOR_ID | DEVICE_FEATURES | QUEUE_NUM_MAX
+            | QUEUE_NUM | QUEUE_READY | INTERRUPT_STATUS | CONFIG_GENERATION | QUEUE_DESC_LOW
+            | QUEUE_DESC_HIGH | QUEUE_DRIVER_LOW | QUEUE_DRIVER_HIGH | QUEUE_DEVICE_LOW
+            | QUEUE_DEVICE_HIGH => {}
+            _ => return None,
+        }
+        Some(())
+    }
+
+    /// Serves the requests in the available ring if the device was notified, and raises the used
+    /// buffer interrupt unless the driver suppressed it. Returns the ranges of RAM it wrote, as
+    /// addresses and sizes.
+    pub fn serve(&mut self, memory: &mut Memory) -> Vec<(u32, u32)> {
+        let mut written = Vec::new();
+        if !std::mem::take(&mut self.notified)
+            || !self.queue.ready
+            || self.status & STATUS_NEEDS_RESET != 0
+        {
+            return written;
+        }
+        if self.serve_queue(memory, &mut written).is_none() {
+            self.status |= STATUS_NEEDS_RESET;
+            self.interrupt_status |= INTERRUPT_CONFIG_CHANGE;
+        }
+        written
+    }
+
+    /// Serves the available requests, `None` if the queue is malformed.
+    fn serve_queue(&mut self, memory: &mut Memory, written: &mut Vec<(u32, u32)>) -> Option<()> {
+        let Queue {
+            size,
+            driver,
+            device,
+            ..
+        } = self.queue;
+        if !size.is_power_of_two() || size > VIRTIO_QUEUE_SIZE {
+            return None;
+        }
+        let avail = ram(memory, driver, 4 + 2 * size as usize)?;
+        let used = ram(memory, device, 4 + 8 * size as usize)?;
+        let flags = read_u16(memory, avail.start);
+        let mut served = false;
+        while self.queue.last_avail != read_u16(memory, avail.start + 2) {
+            let slot = self.queue.last_avail as usize % size as usize;
+            let head = read_u16(memory, avail.start + 4 + 2 * slot);
+            let length = self.request(memory, head, written)?;
+
+            let index = read_u16(memory, used.start + 2);
+            let element = used.start + 4 + 8 * (index as usize % size as usize);
+            memory[element..element + 4].copy_from_slice(&(head as u32).to_le_bytes());
+            memory[element + 4..element + 8].copy_from_slice(&length.to_le_bytes());
+            written.push(address(element..element + 8));
+            memory[used.start + 2..used.start + 4]
+                .copy_from_slice(&index.wrapping_add(1).to_le_bytes());
+            written.push(address(used.start + 2..used.start + 4));
+            self.queue.last_avail = self.queue.last_avail.wrapping_add(1);
+            served = true;
+        }
+        if served && flags & AVAIL_F_NO_INTERRUPT == 0 {
+            self.interrupt_status |= INTERRUPT_USED_BUFFER;
+        }
+        Some(())
+    }
+
+    /// Serves the request whose descriptor chain starts at `head`. Returns the number of bytes
+    /// written to its buffers, `None` if the chain is malformed.
+    fn request(
+        &mut self,
+        memory: &mut Memory,
+        head: u16,
+        written: &mut Vec<(u32, u32)>,
+    ) -> Option<u32> {
+        // the device-readable buffers come first, then the device-writable ones
+        let mut readable = Vec::new();
+        let mut writable: Vec<Range<usize>> = Vec::new();
+        let mut index = head as u32;
+        // a chain without an end would loop forever
+        for count in 1.. {
+            if index >= self.queue.size || count > self.queue.size {
+                return None;
+            }
+            let descriptor = &memory[ram(memory, self.queue.desc + 16 * index as u64, 16)?];
+            let address = u64::from_le_bytes(descriptor[..8].try_into().unwrap());
+            let length = u32::from_le_bytes(descriptor[8..12].try_into().unwrap());
+            let flags = u16::from_le_bytes(descriptor[12..14].try_into().unwrap());
+            let next = u16::from_le_bytes(descriptor[14..16].try_into().unwrap());
+            let buffer = ram(memory, address, length as usize)?;
+            if flags & DESC_F_WRITE != 0 {
+                writable.push(buffer);
+            } else if writable.is_empty() {
+                readable.extend_from_slice(&memory[buffer]);
+            } else {
+                return None;
+            }
+            if flags & DESC_F_NEXT == 0 {
+                break;
+            }
+            index = next as u32;
+        }
+
+        // the last writable byte is the status
+        let capacity = writable.iter().map(Range::len).sum::<usize>();
+        let status = writable.last().filter(|buffer| !buffer.is_empty())?.end - 1;
+        let header = readable.get(..16);
+        let kind = header.map(|header| u32::from_le_bytes(header[..4].try_into().unwrap()));
+        let sector = header.map_or(0, |header| {
+            u64::from_le_bytes(header[8..].try_into().unwrap())
+        });
+        let data = capacity - 1;
+        let (result, length) = match kind {
+            None => (VIRTIO_BLK_S_IOERR, 0),
+            Some(VIRTIO_BLK_T_IN) => match self.disk.sectors_range(sector, data / SECTOR_SIZE) {
+                Some(range) if data % SECTOR_SIZE == 0 => {
+                    scatter(memory, &writable, &self.disk.data[range], written);
+                    (VIRTIO_BLK_S_OK, data)
+                }
+                _ => (VIRTIO_BLK_S_IOERR, 0),
+            },
+            Some(VIRTIO_BLK_T_OUT) => {
+                let data = &readable[16..];
+                if data.len() % SECTOR_SIZE == 0 && self.disk.write(sector, data) {
+                    (VIRTIO_BLK_S_OK, 0)
+                } else {
+                    (VIRTIO_BLK_S_IOERR, 0)
+                }
+            }
+            // the disk is in memory, the host writes it back
+            Some(VIRTIO_BLK_T_FLUSH) => (VIRTIO_BLK_S_OK, 0),
+            Some(VIRTIO_BLK_T_GET_ID) => {
+                let id = &ID[..ID.len().min(data)];
+                scatter(memory, &writable, id, written);
+                (VIRTIO_BLK_S_OK, id.len())
+            }
+            Some(_) => (VIRTIO_BLK_S_UNSUPP, 0),
+        };
+        memory[status] = result;
+        written.push(address(status..status + 1));
+        Some(length as u32 + 1)
+    }
+}
+
+/// The offsets of the `length` bytes at the guest physical `address` in `memory`, `None` if they
+/// aren't all RAM.
+fn ram(memory: &Memory, address: u64, length: usize) -> Option<Range<usize>> {
+    let start = usize::try_from(address.checked_sub(MEMORY_START as u64)?).ok()?;
+    let end = start.checked_add(length)?;
+    (end <= memory.len()).then_some(start..end)
+}
+
+/// The physical address and size of `range` of RAM.
+fn address(range: Range<usize>) -> (u32, u32) {
+    ((MEMORY_START + range.start) as u32, range.len() as u32)
+}
+
+fn read_u16(memory: &Memory, offset: usize) -> u16 {
+    u16::from_le_bytes([memory[offset], memory[offset + 1]])
+}
+
+/// Copies `data` to the consecutive `buffers`, which have room for it.
+fn scatter(
+    memory: &mut Memory,
+    buffers: &[Range<usize>],
+    mut data: &[u8],
+    written: &mut Vec<(u32, u32)>,
+) {
+    for buffer in buffers {
+        if data.is_empty() {
+            break;
+        }
+        let length = buffer.len().min(data.len());
+        let target = buffer.start..buffer.start + length;
+        memory[target.clone()].copy_from_slice(&data[..length]);
+        written.push(address(target));
+        data = &data[length..];
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        super::*,
+        crate::{
+            blocks::Engine,
+            devices::{Devices, VIRTIO_IRQ, VIRTIO_START},
+            store_half_word, store_word, test_utils, Machine, PC,
+        },
+    };
+
+    const DESC: u32 = MEMORY_START as u32 + 0x1000;
+    const DRIVER: u32 = MEMORY_START as u32 + 0x1800;
+    const DEVICE: u32 = MEMORY_START as u32 + 0x2000;
+    const BUFFER: u32 = MEMORY_START as u32 + 0x3000;
+
+    fn disk(mode: DiskMode) -> VirtioBlock {
+        VirtioBlock::new(Disk::new(vec![0; 4 * SECTOR_SIZE], mode).unwrap())
+    }
+
+    #[test]
+    fn registers() {
+        let mut virtio = disk(DiskMode::ReadOnly);
+        assert_eq!(virtio.load(MAGIC_VALUE, 4), Some(MAGIC));
+        assert_eq!(virtio.load(VERSION, 4), Some(2));
+        assert_eq!(virtio.load(DEVICE_ID, 4), Some(BLOCK_DEVICE));
+        assert_eq!(virtio.load(QUEUE_NUM_MAX, 4), Some(VIRTIO_QUEUE_SIZE));
+        // the capacity in sectors, also as bytes
+        assert_eq!(virtio.load(CONFIG, 4), Some(4));
+        assert_eq!(virtio.load(CONFIG, 1), Some(4));
+        assert_eq!(virtio.load(CONFIG + 4, 4), Some(0));
+        assert_eq!(virtio.load(CONFIG + 2, 4), None);
+        assert_eq!(virtio.load(CONFIG + CONFIG_SIZE, 4), None);
+        assert_eq!(virtio.load(STATUS, 2), None);
+        assert_eq!(virtio.load(0x0f8, 4), None);
+
+        assert_eq!(virtio.load(DEVICE_FEATURES, 4), Some(1 << 9 | 1 << 5));
+        assert_eq!(virtio.store(DEVICE_FEATURES_SEL, 4, 1), Some(()));
+        assert_eq!(virtio.load(DEVICE_FEATURES, 4), Some(1));
+        // drivers can only accept offered features, and must accept VIRTIO_F_VERSION_1
+        assert_eq!(virtio.store(DRIVER_FEATURES, 4, u32::MAX), Some(()));
+        assert_eq!(virtio.driver_features, VIRTIO_BLK_F_RO | VIRTIO_BLK_F_FLUSH);
+        let status = STATUS_ACKNOWLEDGE | STATUS_DRIVER | STATUS_FEATURES_OK;
+        assert_eq!(virtio.store(STATUS, 4, status), Some(()));
+        assert_eq!(virtio.load(STATUS, 4), Some(status & !STATUS_FEATURES_OK));
+        assert_eq!(virtio.store(DRIVER_FEATURES_SEL, 4, 1), Some(()));
+        assert_eq!(virtio.store(DRIVER_FEATURES, 4, 1), Some(()));
+        assert_eq!(virtio.store(STATUS, 4, status), Some(()));
+        assert_eq!(virtio.load(STATUS, 4), Some(status));
+
+        // there is only queue 0
+        assert_eq!(virtio.store(QUEUE_DESC_HIGH, 4, 1), Some(()));
+        assert_eq!(virtio.store(QUEUE_DESC_LOW, 4, 2), Some(()));
+        assert_eq!(virtio.queue.desc, 1 << 32 | 2);
+        assert_eq!(virtio.store(QUEUE_SEL, 4, 1), Some(()));
+        assert_eq!(virtio.load(QUEUE_NUM_MAX, 4), Some(0));
+        assert_eq!(virtio.store(QUEUE_READY, 4, 1), Some(()));
+        assert_eq!(virtio.store(QUEUE_NOTIFY, 4, 1), Some(()));
+        assert!(!virtio.queue.ready && !virtio.notified);
+
+        // writing 0 to the status resets everything but the disk
+        virtio.interrupt_status = INTERRUPT_USED_BUFFER;
+        assert!(virtio.interrupt());
+        assert_eq!(virtio.store(STATUS, 4, 0), Some(()));
+        assert_eq!(virtio, disk(DiskMode::ReadOnly));
+        assert_eq!(virtio.store(CONFIG, 4, 0), Some(()));
+        assert_eq!(virtio.store(CONFIG + CONFIG_SIZE, 4, 0), None);
+    }
+
+    /// Initializes the device like a driver would, with a queue of 8 descriptors.
+    fn initialize(devices: &mut Devices) {
+        devices.plic.priorities[VIRTIO_IRQ as usize] = 1;
+        devices.plic.enables[0] = 1 << VIRTIO_IRQ;
+        for (register, value) in [
+            (STATUS, STATUS_ACKNOWLEDGE | STATUS_DRIVER),
+            (DRIVER_FEATURES_SEL, 1),
+            (DRIVER_FEATURES, 1),
+            (
+                STATUS,
+                STATUS_ACKNOWLEDGE | STATUS_DRIVER | STATUS_FEATURES_OK,
+            ),
+            (QUEUE_NUM, 8),
+            (QUEUE_DESC_LOW, DESC),
+            (QUEUE_DRIVER_LOW, DRIVER),
+            (QUEUE_DEVICE_LOW, DEVICE),
+            (QUEUE_READY, 1),
+            (STATUS, 0xf),
+        ] {
+            assert_eq!(
+                devices.store(VIRTIO_START + register, 4, value, 0),
+                Some(())
+            );
+        }
+    }
+
+    /// Puts a request with the `buffers`, addresses, lengths and whether they are writable, into
+    /// the descriptors starting at `head` and the next slot of the available ring.
+    fn submit(memory: &mut Memory, head: u16, buffers: &[(u32, u32, bool)]) {
+        for (i, &(address, length, writable)) in buffers.iter().enumerate() {
+            let descriptor = DESC + 16 * (head as u32 + i as u32);
+            let next = i + 1 < buffers.len();
+            let flags =
+                if next { DESC_F_NEXT } else { 0 } | if writable { DESC_F_WRITE } else { 0 };
+            store_word(memory, descriptor, address).unwrap();
+            store_word(memory, descriptor + 4, 0).unwrap();
+            store_word(memory, descriptor + 8, length).unwrap();
+            store_half_word(memory, descriptor + 12, flags).unwrap();
+            store_half_word(memory, descriptor + 14, head + i as u16 + 1).unwrap();
+        }
+        let index = read_u16(memory, DRIVER as usize - MEMORY_START + 2);
+        let slot = DRIVER + 4 + 2 * (index as u32 % 8);
+        store_half_word(memory, slot, head).unwrap();
+        store_half_word(memory, DRIVER + 2, index.wrapping_add(1)).unwrap();
+    }
+
+    /// Writes the header of a request of type `kind` for `sector` to `address`.
+    fn header(memory: &mut Memory, address: u32, kind: u32, sector: u32) {
+        store_word(memory, address, kind).unwrap();
+        store_word(memory, address + 4, 0).unwrap();
+        store_word(memory, address + 8, sector).unwrap();
+        store_word(memory, address + 12, 0).unwrap();
+    }
+
+    /// The status byte, the request's buffers are at `address`, the status right after them.
+    fn status(memory: &Memory, address: u32) -> u8 {
+        memory[address as usize - MEMORY_START]
+    }
+
+    /// The used ring's index and the ID and length of the element at `index`.
+    fn used(memory: &Memory, index: usize) -> (u16, u32, u32) {
+        let ring = DEVICE as usize - MEMORY_START;
+        let element = ring + 4 + 8 * index;
+        let word =
+            |offset: usize| u32::from_le_bytes(memory[offset..offset + 4].try_into().unwrap());
+        (read_u16(memory, ring + 2), word(element), word(element + 4))
+    }
+
+    #[test]
+    fn requests() {
+        let mut machine = Machine::new();
+        machine.devices.virtio = Some(disk(DiskMode::ReadWrite));
+        initialize(&mut machine.devices);
+        let (memory, devices) = (&mut machine.memory, &mut machine.devices);
+
+        // write sector 1, then read it back into two buffers
+        let data = BUFFER + 0x10;
+        memory[data as usize - MEMORY_START..][..SECTOR_SIZE].fill(0xab);
+        header(memory, BUFFER, VIRTIO_BLK_T_OUT, 1);
+        let out = BUFFER + 0x400;
+        submit(
+            memory,
+            0,
+            &[(BUFFER, 16, false), (data, 512, false), (out, 1, true)],
+        );
+        header(memory, BUFFER + 0x800, VIRTIO_BLK_T_IN, 1);
+        let input = BUFFER + 0x810;
+        submit(
+            memory,
+            3,
+            &[
+                (BUFFER + 0x800, 16, false),
+                (input, 256, true),
+                (input + 256, 257, true),
+            ],
+        );
+        assert_eq!(
+            devices.store(VIRTIO_START + QUEUE_NOTIFY, 4, 0, 0),
+            Some(())
+        );
+        let written = devices.serve(memory);
+        assert_eq!(status(memory, out), VIRTIO_BLK_S_OK);
+        assert_eq!(status(memory, input + 512), VIRTIO_BLK_S_OK);
+        assert_eq!(memory[input as usize - MEMORY_START..][..512], [0xab; 512]);
+        assert_eq!(used(memory, 0), (2, 0, 1));
+        assert_eq!(used(memory, 1), (2, 3, 513));
+        assert!(written.contains(&(input + 256, 256)));
+        assert!(written.contains(&(DEVICE + 2, 2)));
+
+        let virtio = devices.virtio.as_mut().unwrap();
+        assert_eq!(
+            virtio.disk.data()[SECTOR_SIZE..2 * SECTOR_SIZE],
+            [0xab; 512]
+        );
+        let dirty = virtio.disk.take_dirty();
+        assert_eq!((dirty.len(), &dirty[0]), (1, &(1..2)));
+        assert_eq!(virtio.disk.take_dirty(), []);
+        // the used buffer interrupt, until it's acknowledged
+        assert_eq!(devices.plic.lines, 1 << VIRTIO_IRQ);
+        assert_eq!(devices.load(VIRTIO_START + INTERRUPT_STATUS, 4, 0), Some(1));
+        assert_eq!(
+            devices.store(VIRTIO_START + INTERRUPT_ACK, 4, 1, 0),
+            Some(())
+        );
+        assert_eq!(devices.plic.lines, 0);
+
+        // past the end, unsupported, the ID, and no interrupt
+        header(memory, BUFFER, VIRTIO_BLK_T_IN, 4);
+        submit(memory, 0, &[(BUFFER, 16, false), (data, 513, true)]);
+        header(memory, BUFFER + 0x800, 99, 0);
+        submit(memory, 2, &[(BUFFER + 0x800, 16, false), (out, 1, true)]);
+        header(memory, BUFFER + 0x600, VIRTIO_BLK_T_GET_ID, 0);
+        submit(memory, 4, &[(BUFFER + 0x600, 16, false), (input, 21, true)]);
+        store_half_word(memory, DRIVER, AVAIL_F_NO_INTERRUPT).unwrap();
+        assert_eq!(
+            devices.store(VIRTIO_START + QUEUE_NOTIFY, 4, 0, 0),
+            Some(())
+        );
+        devices.serve(memory);
+        assert_eq!(status(memory, data + 512), VIRTIO_BLK_S_IOERR);
+        assert_eq!(status(memory, out), VIRTIO_BLK_S_UNSUPP);
+        assert_eq!(memory[input as usize - MEMORY_START..][..10], *ID);
+        assert_eq!(status(memory, input + 20), VIRTIO_BLK_S_OK);
+        assert_eq!(used(memory, 4), (5, 4, 11));
+        assert_eq!(devices.plic.lines, 0);
+        // without a notification, nothing happens
+        submit(memory, 6, &[(BUFFER, 16, false), (out, 1, true)]);
+        assert_eq!(devices.serve(memory), []);
+
+        // writes fail on a read-only disk
+        devices.virtio.as_mut().unwrap().disk =
+            Disk::new(vec![0; SECTOR_SIZE], DiskMode::ReadOnly).unwrap();
+        header(memory, BUFFER, VIRTIO_BLK_T_OUT, 0);
+        assert_eq!(
+            devices.store(VIRTIO_START + QUEUE_NOTIFY, 4, 0, 0),
+            Some(())
+        );
+        devices.serve(memory);
+        assert_eq!(status(memory, out), VIRTIO_BLK_S_IOERR);
+    }
+
+    #[test]
+    fn malformed_request() {
+        let mut machine = Machine::new();
+        machine.devices.virtio = Some(disk(DiskMode::ReadWrite));
+        initialize(&mut machine.devices);
+        let (memory, devices) = (&mut machine.memory, &mut machine.devices);
+        // a buffer outside of RAM
+        submit(memory, 0, &[(BUFFER, 16, false), (0x1000_0000, 1, true)]);
+        assert_eq!(
+            devices.store(VIRTIO_START + QUEUE_NOTIFY, 4, 0, 0),
+            Some(())
+        );
+        assert_eq!(devices.serve(memory), []);
+        let virtio = devices.virtio.as_ref().unwrap();
+        assert_eq!(virtio.status, 0xf | STATUS_NEEDS_RESET);
+        assert_eq!(virtio.interrupt_status, INTERRUPT_CONFIG_CHANGE);
+        assert_eq!(used(memory, 0), (0, 0, 0));
+    }
+
+    // Notifies the device of a request which reads sector 0 over the instructions after it.
+    const NOTIFY: &[u32] = &[
+        0x100012b7, // li t0, 0x10001000
+        0x0402a823, // sw zero, 0x50(t0)
+        0x00100513, // li a0, 1
+        0xc0001073, // unimp
+    ];
+
+    #[test]
+    fn dma() {
+        for engine in [Engine::Interpreter, Engine::Blocks] {
+            let mut machine = test_utils::machine(NOTIFY);
+            machine.engine = engine;
+            let start = MEMORY_START as u32;
+            let mut data = vec![0; SECTOR_SIZE];
+            data[..8].copy_from_slice(&[0x13, 0x05, 0xa0, 0x02, 0x73, 0x10, 0x00, 0xc0]);
+            let disk = Disk::new(data, DiskMode::CopyOnWrite).unwrap();
+            machine.devices.virtio = Some(VirtioBlock::new(disk));
+            initialize(&mut machine.devices);
+            header(&mut machine.memory, BUFFER, VIRTIO_BLK_T_IN, 0);
+            let buffers = [
+                (BUFFER, 16, false),
+                (start + 8, 512, true),
+                (BUFFER + 16, 1, true),
+            ];
+            submit(&mut machine.memory, 0, &buffers);
+
+            // decode `li a0, 1` first
+            machine.registers[PC] = start + 8;
+            assert_eq!(machine.step(), Ok(false));
+            assert_eq!(machine.registers[10], 1);
+            machine.registers[PC] = start;
+            assert_eq!(machine.run(10), Ok(true));
+            // the request replaced it with `li a0, 42`
+            assert_eq!(machine.registers[10], 42);
+            assert_eq!(used(&machine.memory, 0), (1, 0, 513));
+        }
+    }
+}
@@ -4,12 +4,27 @@ use {
     leptos::{leptos_dom::helpers::IntervalHandle, *},
     leptos_meta::*,
     riscv::{
-        BType, Error, IType, Instruction, JType, Memory, RType, Registers, SType, UType,
-        MEMORY_SIZE, MEMORY_START, PC, REGISTER_NAMES,
+        framebuffer::{Framebuffer, PixelFormat},
+        pipeline::{Pipeline, Stage},
+        virtio::{Disk, DiskMode, VirtioBlock},
+        BType, Error, History, IType, Instruction, JType, Machine, Memory, RType, Registers, SType,
+        UType, MEMORY_START, PC, REGISTER_NAMES,
     },
     std::time::Duration,
+    wasm_bindgen::{Clamped, JsCast},
+    web_sys::{CanvasRenderingContext2d, ImageData},
 };
 
+/// Number of instructions which can be stepped back
+const HISTORY_CAPACITY: usize = 1000;
+
+/// Resolution of the framebuffer programs can draw to
+const SCREEN_WIDTH: u32 = 160;
+const SCREEN_HEIGHT: u32 = 120;
+
+/// Size of the in-memory disk of the virtio block device
+const DISK_SIZE: usize = 64 * 1024;
+
 #[derive(Debug, Clone)]
 enum State {
     Fresh,
@@ -31,20 +46,24 @@ pub fn App() -> impl IntoView {
     let state = RwSignal::new(State::Fresh);
     let running_state = RwSignal::new(RunningState::Idle);
     let message = move || match state.get() {
-        State::Errored(error) => match error {
-            Error::MemoryError { address } => format!(
-                "Memory Error: Address 0x{address:08x} \
-                is outside of valid address range (0x{MEMORY_START:08x}-0x{:08x})",
-                MEMORY_START + MEMORY_SIZE
-            ),
-            Error::DecodeError { code } => format!("Failed to decode instruction {code:016b}"),
-        },
+        State::Errored(error) => error.to_string(),
         _ => String::new(),
     };
 
-    let registers: RwSignal<Registers> = RwSignal::new([0; 33]);
+    let machine: RwSignal<Machine> = RwSignal::new(Machine::new());
+    let registers = create_memo(move |_| machine.with(|machine| machine.registers));
     let pc = Signal::derive(move || registers()[PC]);
-    let memory: RwSignal<Memory> = RwSignal::new([0; MEMORY_SIZE]);
+    let memory = create_memo(move |_| machine.with(|machine| machine.memory.clone()));
+    let framebuffer =
+        create_memo(move |_| machine.with(|machine| machine.devices.framebuffer.clone()));
+    let checkpoint: RwSignal<Option<(State, Vec<u8>)>> = RwSignal::new(None);
+    // steps clock the pipeline instead of executing whole instructions
+    let pipelined = RwSignal::new(false);
+    let pipeline: RwSignal<Pipeline> = RwSignal::new(Pipeline::default());
+    // everything the program wrote to the UART, as bytes since a character may span outputs
+    let terminal = RwSignal::new(Vec::new());
+    // typed keys which didn't fit into the UART's receive FIFO yet
+    let typed = RwSignal::new(Vec::new());
 
     let programs: &[(&'static str, &'static [u32])] = &[
         (
@@ -71,6 +90,13 @@ pub fn App() -> impl IntoView {
                 0xfd1ff06f, 0x01010113, 0x00008067,
             ],
         ),
+        (
+            "Diagonal",
+            &[
+                0x500002b7, 0x0042a303, 0x00c2a383, 0x00238393, 0x00001e37, 0x01c282b3, 0xfff00e13,
+                0x01c29023, 0x007282b3, 0xfff30313, 0xfe031ae3, 0x00008067,
+            ],
+        ),
     ];
 
     let selected_program = RwSignal::new(0);
@@ -88,34 +114,79 @@ pub fn App() -> impl IntoView {
     let reset = move || {
         stop();
         state.set(State::Fresh);
-        registers.update(|registers| {
-            registers.copy_from_slice(&[0; 33]);
-            registers[PC] = MEMORY_START as u32;
-            registers[2] = MEMORY_START as u32 + 0xa0;
-        });
-        memory.update(|memory| {
-            memory.copy_from_slice(&[0; MEMORY_SIZE]);
+        terminal.set(Vec::new());
+        typed.set(Vec::new());
+        machine.update(|machine| {
+            *machine = Machine::new();
+            machine.history = History::new(HISTORY_CAPACITY);
+            machine.devices.framebuffer = Some(
+                Framebuffer::new(SCREEN_WIDTH, SCREEN_HEIGHT, PixelFormat::Rgb565)
+                    .expect("the screen has a valid resolution"),
+            );
+            machine.devices.virtio = Some(VirtioBlock::new(
+                Disk::new(vec![0; DISK_SIZE], DiskMode::ReadWrite)
+                    .expect("the disk has whole sectors"),
+            ));
+            machine.registers[2] = MEMORY_START as u32 + 0xa0;
             let program = programs[selected_program.get_untracked()]
                 .1
                 .iter()
                 .copied()
                 .flat_map(u32::to_le_bytes)
                 .collect::<Vec<u8>>();
-            for (m, p) in memory[..program.len()].iter_mut().zip(program) {
+            for (m, p) in machine.memory[..program.len()].iter_mut().zip(program) {
                 *m = p;
             }
         });
+        pipeline.set(Pipeline::default());
     };
 
     let load = move |index| {
         selected_program.set(index);
+        checkpoint.set(None);
         reset();
     };
 
+    let save_checkpoint = move || {
+        checkpoint.set(Some((
+            state.get_untracked(),
+            machine.with_untracked(Machine::snapshot),
+        )));
+    };
+
+    let restore_checkpoint = move || {
+        stop();
+        if let Some((saved_state, snapshot)) = checkpoint.get_untracked() {
+            machine.update(|machine| {
+                machine
+                    .restore(&snapshot)
+                    .expect("checkpoints are taken from the same machine")
+            });
+            pipeline.update(Pipeline::flush);
+            state.set(saved_state);
+        }
+    };
+
     let step = move || {
         leptos::batch(|| {
-            update!(|registers, memory| {
-                let result = riscv::step(registers, memory);
+            machine.update(|machine| {
+                let result = if pipelined.get_untracked() {
+                    pipeline
+                        .try_update(|pipeline| pipeline.clock(machine))
+                        .expect("the pipeline is never disposed")
+                } else {
+                    machine.step()
+                };
+                let output = machine.devices.uart.take_output();
+                if !output.is_empty() {
+                    terminal.update(|terminal| terminal.extend(output));
+                }
+                if !typed.with_untracked(Vec::is_empty) {
+                    typed.update(|typed| {
+                        let received = machine.receive(typed);
+                        typed.drain(..received);
+                    });
+                }
                 state.set(match result {
                     Ok(false) => State::Started,
                     Ok(true) => {
@@ -131,6 +202,14 @@ pub fn App() -> impl IntoView {
         });
     };
 
+    let step_back = move || {
+        stop();
+        if machine.try_update(Machine::step_back).unwrap_or(false) {
+            pipeline.update(Pipeline::flush);
+            state.set(State::Started);
+        }
+    };
+
     let press_run_button = move |_| match running_state() {
         RunningState::Idle => {
             running_state.set(RunningState::Running(
@@ -181,6 +260,19 @@ pub fn App() -> impl IntoView {
                 <div class="bg-white p-4 border-2 border-gray-900 shadow">
                     <Memory memory=memory/>
                 </div>
+                <div class="grid gap-4 items-start grid-cols-[3fr_2fr]">
+                    <div class="bg-white p-4 border-2 border-gray-900 shadow">
+                        <Terminal output=terminal typed=typed machine=machine/>
+                    </div>
+                    <div class="bg-white p-4 border-2 border-gray-900 shadow">
+                        <Screen framebuffer=framebuffer/>
+                    </div>
+                </div>
+                <Show when=pipelined>
+                    <div class="bg-white p-4 border-2 border-gray-900 shadow">
+                        <Pipeline pipeline=pipeline/>
+                    </div>
+                </Show>
 
                 <div class="bg-white flex gap-4 p-4 border-2 border-gray-900 shadow">
                     <button
@@ -234,6 +326,25 @@ pub fn App() -> impl IntoView {
                         }}
 
                     </button>
+                    <button
+                        class="px-5 py-2 border-2 border-gray-900 font-medium text-lg disabled:opacity-50 flex items-center gap-3"
+                        on:click=move |_| step_back()
+                        disabled=move || machine.with(|machine| machine.history.is_empty())
+                    >
+
+                        <svg
+                            xmlns="http://www.w3.org/2000/svg"
+                            width="24"
+                            height="24"
+                            viewBox="0 0 32 32"
+                        >
+                            <path
+                                fill="currentColor"
+                                d="m14 26l1.43-1.393L7.85 17H28v-2H7.85l7.58-7.573L14 6L4 16z"
+                            ></path>
+                        </svg>
+                        Back
+                    </button>
                     <button
                         class="px-5 py-2 border-2 border-gray-900 font-medium text-lg disabled:opacity-50 flex items-center gap-3"
                         on:click=move |_| step()
@@ -253,6 +364,23 @@ pub fn App() -> impl IntoView {
                         </svg>
                         Step
                     </button>
+                    <button
+                        class=move || {
+                            format!(
+                                "px-5 py-2 border-2 border-gray-900 font-medium text-lg flex items-center gap-3 {}",
+                                if pipelined() { "bg-gray-900 text-white" } else { "" },
+                            )
+                        }
+
+                        on:click=move |_| {
+                            pipelined.update(|pipelined| *pipelined = !*pipelined);
+                            pipeline.update(Pipeline::flush);
+                        }
+
+                        title="Step through the cycles of a 5-stage pipeline"
+                    >
+                        "Pipeline"
+                    </button>
                     <button
                         class="px-5 py-2 bg-black font-medium text-lg text-white disabled:opacity-15 flex items-center gap-3"
                         on:click=move |_| reset()
@@ -272,6 +400,46 @@ pub fn App() -> impl IntoView {
                         </svg>
                         "Reset"
                     </button>
+                    <button
+                        class="px-5 py-2 border-2 border-gray-900 font-medium text-lg disabled:opacity-50 flex items-center gap-3"
+                        on:click=move |_| save_checkpoint()
+                        disabled=move || matches!(state(), State::Errored(_))
+                        title="Save the current state as checkpoint"
+                    >
+
+                        <svg
+                            xmlns="http://www.w3.org/2000/svg"
+                            width="24"
+                            height="24"
+                            viewBox="0 0 32 32"
+                        >
+                            <path
+                                fill="currentColor"
+                                d="M24 4v22.75l-7.1-3.59l-.9-.45l-.9.45L8 26.75V4zm0-2H8a2 2 0 0 0-2 2v26l10-5l10 5V4a2 2 0 0 0-2-2"
+                            ></path>
+                        </svg>
+                        "Checkpoint"
+                    </button>
+                    <button
+                        class="px-5 py-2 border-2 border-gray-900 font-medium text-lg disabled:opacity-50 flex items-center gap-3"
+                        on:click=move |_| restore_checkpoint()
+                        disabled=move || checkpoint.with(Option::is_none)
+                        title="Restore the last checkpoint"
+                    >
+
+                        <svg
+                            xmlns="http://www.w3.org/2000/svg"
+                            width="24"
+                            height="24"
+                            viewBox="0 0 32 32"
+                        >
+                            <path
+                                fill="currentColor"
+                                d="M24 2H8a2 2 0 0 0-2 2v26l10-5l10 5V4a2 2 0 0 0-2-2"
+                            ></path>
+                        </svg>
+                        "Restore"
+                    </button>
 
                     <div class="ml-auto grid place-items-center opacity-15">
                         <svg
@@ -445,7 +613,7 @@ enum View {
 }
 
 #[component]
-pub fn Program(memory: RwSignal<Memory>, pc: Signal<u32>) -> impl IntoView {
+pub fn Program(memory: Memo<Box<Memory>>, pc: Signal<u32>) -> impl IntoView {
     let n = 8;
     let start = move || pc() / (4 * n) * (4 * n);
     let program: Memo<Vec<u32>> = create_memo(move |_| {
@@ -692,7 +860,73 @@ pub fn Program(memory: RwSignal<Memory>, pc: Signal<u32>) -> impl IntoView {
 }
 
 #[component]
-pub fn Registers(registers: RwSignal<Registers>) -> impl IntoView {
+pub fn Pipeline(pipeline: RwSignal<Pipeline>) -> impl IntoView {
+    let events = move || {
+        pipeline.with(|pipeline| {
+            let mut events = Vec::new();
+            if pipeline.stalled {
+                events.push("load-use stall".to_string());
+            }
+            if pipeline.flushed {
+                events.push("flushed after a taken branch or jump".to_string());
+            }
+            for (operand, stage) in ["rs1", "rs2"].iter().zip(pipeline.forwarded) {
+                if let Some(stage) = stage {
+                    events.push(format!("{operand} forwarded from {}", stage.name()));
+                }
+            }
+            events.join(", ")
+        })
+    };
+
+    view! {
+        <div class="grid gap-2">
+            <div class="text-center">
+                {move || format!("Pipeline (cycle {})", pipeline.with(|pipeline| pipeline.cycle))}
+            </div>
+            <div class="grid grid-cols-5 gap-px bg-gray-900 border-2 border-gray-900 font-mono">
+                {Stage::ALL
+                    .iter()
+                    .map(|stage| {
+                        view! {
+                            <div class="py-2 bg-gray-100 text-center font-medium">
+                                {stage.name()}
+                            </div>
+                        }
+                    })
+                    .collect_view()}
+                {Stage::ALL
+                    .iter()
+                    .map(|&stage| {
+                        let slot = create_memo(move |_| {
+                            pipeline.with(|pipeline| pipeline.stage(stage).cloned())
+                        });
+                        view! {
+                            <div class="py-2 px-4 bg-white text-center">
+                                {move || match slot() {
+                                    None => "bubble".to_string(),
+                                    Some(slot) => {
+                                        let name = match slot.fetched {
+                                            Ok((code, _)) => code_to_name(code).0,
+                                            Err(_) => "???",
+                                        };
+                                        format!("{:08x} {name}", slot.pc)
+                                    }
+                                }}
+
+                            </div>
+                        }
+                    })
+                    .collect_view()}
+
+            </div>
+            <div class="text-center text-sm opacity-75">{events}</div>
+        </div>
+    }
+}
+
+#[component]
+pub fn Registers(registers: Memo<Registers>) -> impl IntoView {
     #[derive(Debug, Clone, Copy, PartialEq, Eq)]
     enum ViewState {
         Bytes,
@@ -818,7 +1052,7 @@ pub fn Registers(registers: RwSignal<Registers>) -> impl IntoView {
 }
 
 #[component]
-pub fn Memory(memory: RwSignal<Memory>) -> impl IntoView {
+pub fn Memory(memory: Memo<Box<Memory>>) -> impl IntoView {
     #[derive(Debug, Clone, Copy, PartialEq, Eq)]
     enum ViewState {
         Bytes,
@@ -962,6 +1196,107 @@ pub fn Memory(memory: RwSignal<Memory>) -> impl IntoView {
     }
 }
 
+#[component]
+pub fn Terminal(
+    output: RwSignal<Vec<u8>>,
+    typed: RwSignal<Vec<u8>>,
+    machine: RwSignal<Machine>,
+) -> impl IntoView {
+    // typed keys go to the UART, like from a serial console
+    let keydown = move |event: ev::KeyboardEvent| {
+        let key = event.key();
+        let input = match key.as_str() {
+            "Enter" => vec![b'\r'],
+            "Backspace" => vec![0x7f],
+            "Tab" => vec![b'\t'],
+            "Escape" => vec![0x1b],
+            _ if key.chars().count() != 1 => return,
+            _ if event.ctrl_key() => match key.as_bytes() {
+                [letter @ b'a'..=b'z'] => vec![letter & 0x1f],
+                _ => return,
+            },
+            _ => key.into_bytes(),
+        };
+        event.prevent_default();
+        // the rest is delivered as the program reads the FIFO
+        typed.update(|typed| {
+            typed.extend(input);
+            machine.update(|machine| {
+                let received = machine.receive(typed);
+                typed.drain(..received);
+            });
+        });
+    };
+    // an incomplete character at the end is shown once the rest is written
+    let text = move || {
+        output.with(|output| {
+            let end = match std::str::from_utf8(output) {
+                Err(error) if error.error_len().is_none() => error.valid_up_to(),
+                _ => output.len(),
+            };
+            String::from_utf8_lossy(&output[..end]).into_owned()
+        })
+    };
+
+    view! {
+        <div class="grid gap-2">
+            <div class="text-center">"Terminal"</div>
+            <pre
+                tabindex="0"
+                on:keydown=keydown
+                class="h-48 p-2 overflow-y-auto whitespace-pre-wrap bg-gray-900 text-gray-100 font-mono text-sm border-2 border-gray-900 focus:outline-none focus:ring-4 focus:ring-blue-300"
+            >
+                {text}
+            </pre>
+        </div>
+    }
+}
+
+#[component]
+pub fn Screen(framebuffer: Memo<Option<Framebuffer>>) -> impl IntoView {
+    let canvas = create_node_ref::<html::Canvas>();
+    create_effect(move |_| {
+        let Some(canvas) = canvas.get() else {
+            return;
+        };
+        framebuffer.with(|framebuffer| {
+            let Some(framebuffer) = framebuffer else {
+                return;
+            };
+            let (width, height) = (framebuffer.width(), framebuffer.height());
+            canvas.set_width(width);
+            canvas.set_height(height);
+            let rgba = framebuffer
+                .rgb()
+                .chunks(3)
+                .flat_map(|rgb| [rgb[0], rgb[1], rgb[2], 0xff])
+                .collect::<Vec<u8>>();
+            let image = ImageData::new_with_u8_clamped_array_and_sh(Clamped(&rgba), width, height)
+                .expect("the image has the size of the canvas");
+            let context = canvas
+                .get_context("2d")
+                .ok()
+                .flatten()
+                .and_then(|context| context.dyn_into::<CanvasRenderingContext2d>().ok())
+                .expect("canvases have a 2d context");
+            context
+                .put_image_data(&image, 0.0, 0.0)
+                .expect("the image fits the canvas");
+        });
+    });
+
+    view! {
+        <div class="grid gap-2">
+            <div class="text-center">"Screen"</div>
+            <canvas
+                node_ref=canvas
+                class="w-full bg-black border-2 border-gray-900"
+                style="image-rendering: pixelated"
+            ></canvas>
+        </div>
+    }
+}
+
 // region: Utils
 
 fn code_to_name(code: u32) -> (&'static str, Option<InstType>) {
@@ -1005,19 +1340,23 @@ fn code_to_name(code: u32) -> (&'static str, Option<InstType>) {
             Instruction::SRA(sra) => ("SRA", Some(InstType::RType(sra))),
             Instruction::OR(or) => ("OR", Some(InstType::RType(or))),
             Instruction::AND(and) => ("AND", Some(InstType::RType(and))),
-            Instruction::FENCE => ("FENCE", None),
+            Instruction::FENCE(fence) => ("FENCE", Some(InstType::IType(fence))),
+            Instruction::FENCE_I(fence_i) => ("FENCE.I", Some(InstType::IType(fence_i))),
+            Instruction::SFENCE_VMA(sfence_vma) => {
+                ("SFENCE.VMA", Some(InstType::RType(sfence_vma)))
+            }
             Instruction::ECALL => ("ECALL", None),
             Instruction::EBREAK => ("EBREAK", None),
             Instruction::URET => ("URET", None),
             Instruction::SRET => ("SRET", None),
             Instruction::MRET => ("MRET", None),
             Instruction::WFI => ("WFI", None),
-            Instruction::CSRRW => ("CSRRW", None),
-            Instruction::CSRRS => ("CSRRS", None),
-            Instruction::CSRRC => ("CSRRC", None),
-            Instruction::CSRRWI => ("CSRRWI", None),
-            Instruction::CSRRSI => ("CSRRSI", None),
-            Instruction::CSRRCI => ("CSRRCI", None),
+            Instruction::CSRRW(csrrw) => ("CSRRW", Some(InstType::IType(csrrw))),
+            Instruction::CSRRS(csrrs) => ("CSRRS", Some(InstType::IType(csrrs))),
+            Instruction::CSRRC(csrrc) => ("CSRRC", Some(InstType::IType(csrrc))),
+            Instruction::CSRRWI(csrrwi) => ("CSRRWI", Some(InstType::IType(csrrwi))),
+            Instruction::CSRRSI(csrrsi) => ("CSRRSI", Some(InstType::IType(csrrsi))),
+            Instruction::CSRRCI(csrrci) => ("CSRRCI", Some(InstType::IType(csrrci))),
         },
     }
 }